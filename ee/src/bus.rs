@@ -0,0 +1,115 @@
+// Memory interface the EE core runs against. Addresses are the EE's virtual addresses; it is up to
+// the implementation to translate segments and route accesses to memory or devices.
+//
+// Only `read8`/`write8` are required, wider accesses default to little-endian compositions of
+// byte accesses so simple memories stay simple. Implementations should override them whenever a
+// wide access is not equivalent to a sequence of byte accesses (e.g. device registers).
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BusError {
+    pub addr: u32,
+}
+
+pub trait Bus {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError>;
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError>;
+
+    fn read16(&mut self, addr: u32) -> Result<u16, BusError> {
+        Ok(self.read8(addr)? as u16 | (self.read8(addr + 1)? as u16) << 8)
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, BusError> {
+        Ok(self.read16(addr)? as u32 | (self.read16(addr + 2)? as u32) << 16)
+    }
+
+    fn read64(&mut self, addr: u32) -> Result<u64, BusError> {
+        Ok(self.read32(addr)? as u64 | (self.read32(addr + 4)? as u64) << 32)
+    }
+
+    fn read128(&mut self, addr: u32) -> Result<u128, BusError> {
+        Ok(self.read64(addr)? as u128 | (self.read64(addr + 8)? as u128) << 64)
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), BusError> {
+        self.write8(addr, value as u8)?;
+        self.write8(addr + 1, (value >> 8) as u8)
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), BusError> {
+        self.write16(addr, value as u16)?;
+        self.write16(addr + 2, (value >> 16) as u16)
+    }
+
+    fn write64(&mut self, addr: u32, value: u64) -> Result<(), BusError> {
+        self.write32(addr, value as u32)?;
+        self.write32(addr + 4, (value >> 32) as u32)
+    }
+
+    fn write128(&mut self, addr: u32, value: u128) -> Result<(), BusError> {
+        self.write64(addr, value as u64)?;
+        self.write64(addr + 8, (value >> 64) as u64)
+    }
+
+    // `len` bytes of plain memory at `addr`, for bulk copies that must not reach devices. `None`
+    // unless the whole range lies in a single memory.
+    fn memory_mut(&mut self, _addr: u32, _len: u32) -> Option<&mut [u8]> {
+        None
+    }
+    // A byte read without side effects, for debuggers. Only plain memory can be peeked, device
+    // registers give `None`.
+    fn peek8(&mut self, addr: u32) -> Option<u8> {
+        self.memory_mut(addr, 1).map(|memory| memory[0])
+    }
+
+    // Hands a quadword to a DMA channel bringing data from its device, for stand-ins of devices
+    // that live outside the bus (the HLE kernel's IOP). `false` if there is no such channel.
+    fn dma_from_device(&mut self, _channel: usize, _qword: u128) -> bool {
+        false
+    }
+}
+
+// Flat RAM with no memory map: addresses wrap around its (power of two) size. Handy for running
+// bare instruction sequences.
+pub struct Ram {
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two(), "RAM size must be a power of two");
+        Ram {
+            data: vec![0; size],
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    #[inline]
+    fn offset(&self, addr: u32) -> usize {
+        addr as usize & (self.data.len() - 1)
+    }
+}
+
+impl Bus for Ram {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+        Ok(self.data[self.offset(addr)])
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+        let offset = self.offset(addr);
+        self.data[offset] = value;
+        Ok(())
+    }
+
+    // A range that would wrap around the end isn't contiguous
+    fn memory_mut(&mut self, addr: u32, len: u32) -> Option<&mut [u8]> {
+        let offset = self.offset(addr);
+        self.data.get_mut(offset..offset.checked_add(len as usize)?)
+    }
+}
@@ -0,0 +1,312 @@
+// Shadow call stack. Linking jumps and branches (JAL, JALR, BLTZAL, BGEZAL and their likely
+// forms) push a frame when taken, `jr $ra` pops back to the frame it returns to. It is purely a
+// debugging aid: nothing about execution depends on it, and code that returns in unusual ways
+// (longjmp, hand-written assembly, thread switches) can leave it out of step with the real stack.
+
+use std::fmt::Write;
+
+use crate::Cpu;
+use crate::cop0::{self, Exception};
+use crate::observer::CpuObserver;
+
+// Deep enough for any sane program; runaway recursion just loses its oldest frames
+const MAX_DEPTH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+    // Address of the linking instruction
+    pub call_site: u32,
+    pub target: u32,
+    pub return_addr: u32,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CallStack {
+    // Outermost first
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub(crate) fn push(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // A `jr $ra` to `target`: unwinds to the innermost frame returning there. Returns that don't
+    // match any frame are ignored rather than guessed at.
+    pub(crate) fn ret(&mut self, target: u32) {
+        if let Some(index) = self
+            .frames
+            .iter()
+            .rposition(|frame| frame.return_addr == target)
+        {
+            self.frames.truncate(index);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+}
+
+// Symbolized backtrace of the guest, innermost frame first:
+//
+//     #0  00100120 <draw+0x20>
+//     #1  00100048 <main+0x48>
+//     #2  00100008 <_start+0x8>
+pub fn backtrace(cpu: &Cpu, pc: u32) -> String {
+    let mut out = String::new();
+    let call_sites = cpu
+        .call_stack()
+        .frames()
+        .iter()
+        .rev()
+        .map(|frame| frame.call_site);
+    for (index, addr) in std::iter::once(pc).chain(call_sites).enumerate() {
+        let _ = writeln!(out, "#{:<3}{}", index, cpu.describe_addr(addr));
+    }
+    out
+}
+
+// Exceptions caused by an error, i.e. anything but interrupts, syscalls and BREAK
+pub fn is_fault(exception: Exception) -> bool {
+    !matches!(
+        exception,
+        Exception::Interrupt | Exception::Syscall | Exception::Breakpoint
+    )
+}
+
+// Prints a backtrace to stderr whenever the guest takes a fault
+#[derive(Default)]
+pub struct FaultReporter;
+
+impl CpuObserver for FaultReporter {
+    fn exception_taken(&mut self, cpu: &Cpu, exception: Exception) {
+        if !is_fault(exception) {
+            return;
+        }
+        let epc = cpu.cop0().read(cop0::EPC);
+        eprintln!(
+            "guest exception {:?} at {}",
+            exception,
+            cpu.describe_addr(epc)
+        );
+        if exception == Exception::AddressErrorLoad || exception == Exception::AddressErrorStore {
+            eprintln!("  bad address {:08x}", cpu.cop0().read(cop0::BAD_VADDR));
+        }
+        eprint!("{}", backtrace(cpu, epc));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{Bus, Ram};
+    use crate::debugger::{Debugger, StopReason};
+    use crate::{Cpu, StepResult};
+
+    const JR_RA: u32 = 0x03E0_0008;
+
+    // main calls 0x1100 with JAL, 0x1200 with JALR and 0x1300 with BGEZAL. 0x1100 itself calls
+    // 0x1200, keeping its return address in t0 meanwhile.
+    fn machine() -> (Cpu, Ram) {
+        let mut ram = Ram::new(0x2000);
+        let code: [(u32, &[u32]); 4] = [
+            (
+                0x1000,
+                &[
+                    0x0C00_0440, // jal    0x1100
+                    0,           // nop
+                    0x0320_F809, // jalr   t9
+                    0,           // nop
+                    0x0411_00BB, // bgezal zero, 0x1300
+                    0,           // nop
+                ],
+            ),
+            (
+                0x1100,
+                &[
+                    0x03E0_4025, // or     t0, ra, zero
+                    0x0C00_0480, // jal    0x1200
+                    0,           // nop
+                    0x0100_F825, // or     ra, t0, zero
+                    JR_RA,
+                    0,
+                ],
+            ),
+            (0x1200, &[JR_RA, 0]),
+            (0x1300, &[JR_RA, 0]),
+        ];
+        for (base, words) in code {
+            for (addr, &raw) in (base..).step_by(4).zip(words) {
+                ram.write32(addr, raw).unwrap();
+            }
+        }
+        let mut cpu = Cpu::new();
+        cpu.gprs[25] = 0x1200;
+        cpu.set_pc(0x1000);
+        (cpu, ram)
+    }
+
+    fn step(cpu: &mut Cpu, ram: &mut Ram, count: usize) {
+        for _ in 0..count {
+            assert_eq!(cpu.step(ram), StepResult::Ok);
+        }
+    }
+
+    fn frames(cpu: &Cpu) -> Vec<(u32, u32, u32)> {
+        cpu.call_stack()
+            .frames()
+            .iter()
+            .map(|frame| (frame.call_site, frame.target, frame.return_addr))
+            .collect()
+    }
+
+    #[test]
+    fn linking_jumps_push_and_jr_ra_pops() {
+        let (mut cpu, mut ram) = machine();
+        step(&mut cpu, &mut ram, 4);
+        assert_eq!(cpu.pc(), 0x1108);
+        assert_eq!(
+            frames(&cpu),
+            [(0x1000, 0x1100, 0x1008), (0x1104, 0x1200, 0x110C)]
+        );
+        step(&mut cpu, &mut ram, 6);
+        assert_eq!(cpu.pc(), 0x1008);
+        assert!(frames(&cpu).is_empty());
+
+        step(&mut cpu, &mut ram, 2);
+        assert_eq!(frames(&cpu), [(0x1008, 0x1200, 0x1010)]);
+        step(&mut cpu, &mut ram, 4);
+        assert_eq!(frames(&cpu), [(0x1010, 0x1300, 0x1018)]);
+        step(&mut cpu, &mut ram, 2);
+        assert_eq!(cpu.pc(), 0x1018);
+        assert!(frames(&cpu).is_empty());
+    }
+
+    #[test]
+    fn returns_unwind_to_the_matching_frame() {
+        let mut stack = CallStack::default();
+        for index in 0..3 {
+            stack.push(Frame {
+                call_site: 0x1000 + 0x100 * index,
+                target: 0x2000,
+                return_addr: 0x1008 + 0x100 * index,
+            });
+        }
+        // A return nothing called from is ignored
+        stack.ret(0x4000);
+        assert_eq!(stack.depth(), 3);
+        // One that skips frames (longjmp) unwinds all of them
+        stack.ret(0x1108);
+        assert_eq!(stack.depth(), 1);
+        assert_eq!(stack.frames()[0].call_site, 0x1000);
+    }
+
+    #[test]
+    fn the_oldest_frames_go_past_max_depth() {
+        let mut stack = CallStack::default();
+        for index in 0..MAX_DEPTH as u32 + 2 {
+            stack.push(Frame {
+                call_site: index,
+                target: 0,
+                return_addr: index + 8,
+            });
+        }
+        assert_eq!(stack.depth(), MAX_DEPTH);
+        assert_eq!(stack.frames()[0].call_site, 2);
+        assert_eq!(
+            stack.frames()[MAX_DEPTH - 1].call_site,
+            MAX_DEPTH as u32 + 1
+        );
+    }
+
+    fn run_to_stop(cpu: &mut Cpu, ram: &mut Ram) -> StopReason {
+        for _ in 0..100 {
+            if let StepResult::Breakpoint(reason) = cpu.step(ram) {
+                return reason;
+            }
+        }
+        panic!("never stopped, pc {:08x}", cpu.pc());
+    }
+
+    #[test]
+    fn step_over_runs_the_whole_call() {
+        let (mut cpu, mut ram) = machine();
+        cpu.attach_debugger(Debugger::new());
+        assert!(cpu.step_over());
+        // Stops after the delay slot, at the return address
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x1008 }
+        );
+        assert_eq!(cpu.pc(), 0x1008);
+
+        // The same through a register
+        assert!(cpu.step_over());
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x1010 }
+        );
+        assert_eq!(cpu.call_stack().depth(), 0);
+    }
+
+    #[test]
+    fn step_over_anything_else_is_a_single_step() {
+        let (mut cpu, mut ram) = machine();
+        // Into 0x1100, whose first instruction isn't a call
+        step(&mut cpu, &mut ram, 2);
+        cpu.attach_debugger(Debugger::new());
+        assert!(cpu.step_over());
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x1104 }
+        );
+        assert_eq!(cpu.gprs[8], 0x1008);
+
+        // Nor is a return, which steps back out to the caller
+        step(&mut cpu, &mut ram, 5);
+        assert_eq!(cpu.pc(), 0x1110);
+        assert!(cpu.step_over());
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x1008 }
+        );
+    }
+
+    #[test]
+    fn step_out_stops_in_the_caller() {
+        let (mut cpu, mut ram) = machine();
+        // Into 0x1200 through 0x1100
+        step(&mut cpu, &mut ram, 5);
+        assert_eq!(cpu.pc(), 0x1200);
+        cpu.attach_debugger(Debugger::new());
+        assert!(cpu.step_out());
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x110C }
+        );
+        assert_eq!(cpu.call_stack().depth(), 1);
+
+        assert!(cpu.step_out());
+        assert_eq!(
+            run_to_stop(&mut cpu, &mut ram),
+            StopReason::StepDone { pc: 0x1008 }
+        );
+        assert_eq!(cpu.call_stack().depth(), 0);
+
+        // Without a debugger there is nothing to arm
+        cpu.detach_debugger();
+        assert!(!cpu.step_out());
+    }
+}
@@ -0,0 +1,200 @@
+// Boot progress milestones. A `CheckpointObserver` watches execution and reports each checkpoint
+// the first time it is reached, together with how many instructions had run by then, so a BIOS
+// or test ROM run can be followed without tracing every instruction.
+
+use std::fmt;
+
+use crate::Cpu;
+use crate::bus::Bus;
+use crate::cop0::{self, Exception};
+use crate::observer::CpuObserver;
+use crate::ps2_bus::{BIOS_BASE, BIOS_SIZE};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Checkpoint {
+    // First instruction outside the BIOS ROM after having run inside it, usually the jump into
+    // the kernel copied to RAM
+    LeftBios { pc: u32 },
+    // `epc` is the faulting instruction (or the branch, if it was in a delay slot)
+    FirstException { exception: Exception, epc: u32 },
+    // First SYSCALL into the EE kernel; `number` is the syscall number passed in v1
+    FirstSyscall { pc: u32, number: i32 },
+    // An address registered with `CheckpointObserver::add_pc`
+    Reached { pc: u32 },
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Checkpoint::LeftBios { pc } => write!(f, "left the BIOS ROM at {:08x}", pc),
+            Checkpoint::FirstException { exception, epc } => {
+                write!(f, "first exception {:?} at {:08x}", exception, epc)
+            }
+            Checkpoint::FirstSyscall { pc, number } => {
+                let sign = if number < 0 { "-" } else { "" };
+                write!(
+                    f,
+                    "first kernel syscall {}{:#x} at {:08x}",
+                    sign,
+                    number.unsigned_abs(),
+                    pc
+                )
+            }
+            Checkpoint::Reached { pc } => write!(f, "reached {:08x}", pc),
+        }
+    }
+}
+
+pub struct CheckpointObserver {
+    on_reached: Box<dyn FnMut(Checkpoint, u64)>,
+    instructions: u64,
+    in_bios: bool,
+    left_bios: bool,
+    exception_seen: bool,
+    syscall_seen: bool,
+    // Addresses not reached yet
+    pcs: Vec<u32>,
+}
+
+impl CheckpointObserver {
+    // `on_reached` gets each checkpoint and the number of instructions executed before it
+    pub fn new(on_reached: impl FnMut(Checkpoint, u64) + 'static) -> Self {
+        CheckpointObserver {
+            on_reached: Box::new(on_reached),
+            instructions: 0,
+            in_bios: false,
+            left_bios: false,
+            exception_seen: false,
+            syscall_seen: false,
+            pcs: Vec::new(),
+        }
+    }
+
+    pub fn add_pc(&mut self, pc: u32) {
+        self.pcs.push(pc);
+    }
+
+    fn reached(&mut self, checkpoint: Checkpoint) {
+        (self.on_reached)(checkpoint, self.instructions);
+    }
+}
+
+fn is_bios(pc: u32) -> bool {
+    (pc & 0x1FFF_FFFF).wrapping_sub(BIOS_BASE) < BIOS_SIZE as u32
+}
+
+impl CpuObserver for CheckpointObserver {
+    fn before_instruction(&mut self, cpu: &Cpu, _bus: &mut dyn Bus, _raw: u32) {
+        let pc = cpu.pc();
+        if !self.left_bios {
+            if is_bios(pc) {
+                self.in_bios = true;
+            } else if self.in_bios {
+                self.left_bios = true;
+                self.reached(Checkpoint::LeftBios { pc });
+            }
+        }
+        if let Some(index) = self.pcs.iter().position(|&addr| addr == pc) {
+            self.pcs.swap_remove(index);
+            self.reached(Checkpoint::Reached { pc });
+        }
+    }
+
+    fn after_instruction(&mut self, _cpu: &Cpu, _bus: &mut dyn Bus, _pc: u32, _raw: u32) {
+        self.instructions += 1;
+    }
+
+    fn exception_taken(&mut self, cpu: &Cpu, exception: Exception) {
+        if !self.exception_seen {
+            self.exception_seen = true;
+            let epc = cpu.cop0().read(cop0::EPC);
+            self.reached(Checkpoint::FirstException { exception, epc });
+        }
+    }
+
+    fn syscall(&mut self, cpu: &Cpu, _code: u32) {
+        if !self.syscall_seen {
+            self.syscall_seen = true;
+            let number = cpu.gpr(3) as i32;
+            self.reached(Checkpoint::FirstSyscall {
+                pc: cpu.pc(),
+                number,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ps2_bus::Ps2Bus;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn words(program: &[u32]) -> Vec<u8> {
+        program.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn boots_from_the_reset_vector_and_reports_checkpoints() {
+        let rom = words(&[
+            0x3C08_8000, // lui   t0, 0x8000
+            0x3508_1000, // ori   t0, t0, 0x1000
+            0x2403_003C, // addiu v1, zero, 0x3c
+            0x0100_0008, // jr    t0
+            0x0000_0000, // nop
+        ]);
+        let ram = [
+            0x2404_0007, // addiu a0, zero, 7
+            0x0000_000C, // syscall
+        ];
+        let mut bus = Ps2Bus::new();
+        bus.load_bios(&rom).unwrap();
+        for (addr, &raw) in (0x8000_1000..).step_by(4).zip(&ram) {
+            bus.write32(addr, raw).unwrap();
+        }
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let log = seen.clone();
+        let mut observer = CheckpointObserver::new(move |checkpoint, count| {
+            log.borrow_mut().push((checkpoint, count))
+        });
+        observer.add_pc(0x8000_1004);
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.add_observer(Box::new(observer));
+        assert_eq!(cpu.pc(), Cpu::RESET_VECTOR);
+
+        for _ in 0..7 {
+            cpu.step(&mut bus);
+        }
+
+        assert_eq!(cpu.gpr(8) as u64, 0xFFFF_FFFF_8000_1000);
+        assert_eq!(cpu.gpr(3), 0x3C);
+        assert_eq!(cpu.gpr(4), 7);
+        // The syscall exception goes to the bootstrap vector, BEV being set after reset
+        assert_eq!(cpu.pc(), 0xBFC0_0380);
+        assert_eq!(cpu.cop0().read(cop0::EPC), 0x8000_1004);
+        assert_eq!(
+            *seen.borrow(),
+            [
+                (Checkpoint::LeftBios { pc: 0x8000_1000 }, 5),
+                (Checkpoint::Reached { pc: 0x8000_1004 }, 6),
+                (
+                    Checkpoint::FirstSyscall {
+                        pc: 0x8000_1004,
+                        number: 0x3C
+                    },
+                    6
+                ),
+                (
+                    Checkpoint::FirstException {
+                        exception: Exception::Syscall,
+                        epc: 0x8000_1004
+                    },
+                    6
+                ),
+            ]
+        );
+    }
+}
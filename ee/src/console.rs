@@ -0,0 +1,57 @@
+// Debug text printed by the guest, from the SIO UART or the HLE kernel's print calls. Bytes are
+// collected into lines and each finished line goes to the output prefixed with `EE: `, so guest
+// output stands out among the emulator's own messages.
+
+use std::io::{self, Write};
+
+pub const PREFIX: &str = "EE: ";
+
+pub struct Console {
+    line: Vec<u8>,
+    out: Box<dyn Write>,
+}
+
+impl Console {
+    pub fn new(out: impl Write + 'static) -> Self {
+        Console {
+            line: Vec::new(),
+            out: Box::new(out),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(io::stdout())
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.flush(),
+            // CRLF line endings are common in PS2 homebrew
+            b'\r' => {}
+            _ => self.line.push(byte),
+        }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_byte(byte);
+        }
+    }
+
+    // Writes out the pending line, if any. Output errors are ignored, the guest can't act on
+    // them anyway.
+    pub fn flush(&mut self) {
+        let line = std::mem::take(&mut self.line);
+        let _ = writeln!(self.out, "{}{}", PREFIX, String::from_utf8_lossy(&line));
+        let _ = self.out.flush();
+    }
+}
+
+// A line the guest never finished is still shown
+impl Drop for Console {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.flush();
+        }
+    }
+}
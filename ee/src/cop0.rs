@@ -0,0 +1,234 @@
+// COP0, the EE's system control coprocessor. Only the parts needed for exception handling and
+// the Count/Compare timer are modelled for now; the TLB and performance counters are not.
+
+// Register numbers
+pub const INDEX: usize = 0;
+pub const RANDOM: usize = 1;
+pub const ENTRY_LO0: usize = 2;
+pub const ENTRY_LO1: usize = 3;
+pub const CONTEXT: usize = 4;
+pub const PAGE_MASK: usize = 5;
+pub const WIRED: usize = 6;
+pub const BAD_VADDR: usize = 8;
+pub const COUNT: usize = 9;
+pub const ENTRY_HI: usize = 10;
+pub const COMPARE: usize = 11;
+pub const STATUS: usize = 12;
+pub const CAUSE: usize = 13;
+pub const EPC: usize = 14;
+pub const PRID: usize = 15;
+pub const CONFIG: usize = 16;
+pub const BAD_PADDR: usize = 23;
+pub const DEBUG: usize = 24;
+pub const PERF: usize = 25;
+pub const TAG_LO: usize = 28;
+pub const TAG_HI: usize = 29;
+pub const ERROR_EPC: usize = 30;
+
+// Status bits
+pub const STATUS_IE: u32 = 1 << 0;
+pub const STATUS_EXL: u32 = 1 << 1;
+pub const STATUS_ERL: u32 = 1 << 2;
+// Operating mode: 0 is kernel, 1 supervisor and 2 user
+pub const STATUS_KSU_MASK: u32 = 0b11 << 3;
+// Interrupt mask, one bit per Cause.IP bit
+pub const STATUS_IM_MASK: u32 = 0xFF00;
+pub const STATUS_IM2: u32 = 1 << 10;
+pub const STATUS_IM3: u32 = 1 << 11;
+pub const STATUS_EIE: u32 = 1 << 16;
+// Lets EI and DI work outside kernel mode
+pub const STATUS_EDI: u32 = 1 << 17;
+pub const STATUS_BEV: u32 = 1 << 22;
+
+// Cause bits
+pub const CAUSE_EXC_CODE_SHIFT: u32 = 2;
+pub const CAUSE_EXC_CODE_MASK: u32 = 0b11111 << CAUSE_EXC_CODE_SHIFT;
+// Pending interrupts: IP2 is the INTC, IP3 the DMAC and IP7 the Count/Compare timer
+pub const CAUSE_IP_MASK: u32 = 0xFF00;
+pub const CAUSE_IP2: u32 = 1 << 10;
+pub const CAUSE_IP3: u32 = 1 << 11;
+pub const CAUSE_IP7: u32 = 1 << 15;
+pub const CAUSE_BD: u32 = 1 << 31;
+
+// Processor revision reported by PRId (implementation 0x2E, revision 2.0)
+pub const EE_PRID: u32 = 0x2E20;
+// Config after reset: 16 KiB instruction cache, 8 KiB data cache
+pub const CONFIG_RESET: u32 = 0x440;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    Interrupt = 0,
+    AddressErrorLoad = 4,
+    AddressErrorStore = 5,
+    InstructionBusError = 6,
+    DataBusError = 7,
+    Syscall = 8,
+    Breakpoint = 9,
+    ReservedInstruction = 10,
+    CoprocessorUnusable = 11,
+    Overflow = 12,
+    Trap = 13,
+}
+
+impl Exception {
+    #[inline]
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+#[derive(Default)]
+pub struct Cop0 {
+    regs: [u32; 32],
+}
+
+impl Cop0 {
+    // State after a cold reset: bootstrap exception vectors and the error level set, everything
+    // else cleared
+    pub fn reset(&mut self) {
+        self.regs = [0; 32];
+        self.regs[STATUS] = STATUS_BEV | STATUS_ERL;
+        self.regs[PRID] = EE_PRID;
+        self.regs[CONFIG] = CONFIG_RESET;
+    }
+
+    #[inline]
+    pub fn read(&self, reg: usize) -> u32 {
+        self.regs[reg]
+    }
+
+    // Write as seen by MTC0: read-only registers and bits are left alone
+    pub fn write(&mut self, reg: usize, value: u32) {
+        match reg {
+            PRID | BAD_VADDR => {}
+            // Only the software interrupt bits of Cause are writable
+            CAUSE => self.regs[CAUSE] = (self.regs[CAUSE] & !0x300) | (value & 0x300),
+            // Writing Compare acknowledges the timer interrupt
+            COMPARE => {
+                self.regs[COMPARE] = value;
+                self.regs[CAUSE] &= !CAUSE_IP7;
+            }
+            _ => self.regs[reg] = value,
+        }
+    }
+
+    // Write bypassing MTC0 semantics, for the CPU's own use
+    #[inline]
+    pub(crate) fn set(&mut self, reg: usize, value: u32) {
+        self.regs[reg] = value;
+    }
+
+    #[inline]
+    pub fn status(&self) -> u32 {
+        self.regs[STATUS]
+    }
+
+    // KSU is ignored while EXL or ERL is set
+    #[inline]
+    pub fn kernel_mode(&self) -> bool {
+        let status = self.regs[STATUS];
+        status & (STATUS_EXL | STATUS_ERL) != 0 || status & STATUS_KSU_MASK == 0
+    }
+
+    #[inline]
+    pub fn cause(&self) -> u32 {
+        self.regs[CAUSE]
+    }
+
+    // Count runs at the CPU clock. Raises IP7 when it passes through Compare.
+    pub(crate) fn advance_count(&mut self, cycles: u64) {
+        let count = self.regs[COUNT];
+        let until_match = self.regs[COMPARE].wrapping_sub(count);
+        // A full wrap of Count passes every value
+        let matched =
+            cycles > u32::MAX as u64 || (until_match != 0 && cycles >= until_match as u64);
+        if matched {
+            self.regs[CAUSE] |= CAUSE_IP7;
+        }
+        self.regs[COUNT] = count.wrapping_add(cycles as u32);
+    }
+
+    // The external lines, IP2 and IP3, follow what the devices assert
+    pub(crate) fn set_interrupt_lines(&mut self, lines: u32) {
+        let external = CAUSE_IP2 | CAUSE_IP3;
+        self.regs[CAUSE] = (self.regs[CAUSE] & !external) | (lines & external);
+    }
+
+    // An unmasked interrupt is pending and Status lets it be taken: IE and EIE set, and not
+    // already at the exception or error level
+    pub fn interrupt_pending(&self) -> bool {
+        let status = self.regs[STATUS];
+        let enabled = STATUS_IE | STATUS_EIE;
+        status & enabled == enabled
+            && status & (STATUS_EXL | STATUS_ERL) == 0
+            && self.regs[CAUSE] & status & CAUSE_IP_MASK != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_reaching_compare_raises_ip7() {
+        let mut cop0 = Cop0::default();
+        cop0.write(COMPARE, 10);
+        cop0.advance_count(9);
+        assert_eq!(cop0.read(COUNT), 9);
+        assert_eq!(cop0.cause() & CAUSE_IP7, 0);
+        cop0.advance_count(1);
+        assert_eq!(cop0.read(COUNT), 10);
+        assert_ne!(cop0.cause() & CAUSE_IP7, 0);
+
+        // Passing over Compare in one go counts too, and so does wrapping around to it
+        let mut cop0 = Cop0::default();
+        cop0.write(COUNT, 0xFFFF_FFF0);
+        cop0.write(COMPARE, 4);
+        cop0.advance_count(0x30);
+        assert_eq!(cop0.read(COUNT), 0x20);
+        assert_ne!(cop0.cause() & CAUSE_IP7, 0);
+
+        // Sitting on Compare already isn't a new match until Count comes all the way round
+        let mut cop0 = Cop0::default();
+        cop0.write(COMPARE, 0);
+        cop0.advance_count(0xFFFF_FFFF);
+        assert_eq!(cop0.cause() & CAUSE_IP7, 0);
+        cop0.write(COUNT, 0);
+        cop0.advance_count(1 << 32);
+        assert_ne!(cop0.cause() & CAUSE_IP7, 0);
+    }
+
+    #[test]
+    fn writing_compare_acknowledges_ip7() {
+        let mut cop0 = Cop0::default();
+        cop0.write(COMPARE, 1);
+        cop0.advance_count(1);
+        assert_ne!(cop0.cause() & CAUSE_IP7, 0);
+        // Writing Cause can't clear it, only Compare can
+        cop0.write(CAUSE, 0);
+        assert_ne!(cop0.cause() & CAUSE_IP7, 0);
+        cop0.write(COMPARE, 1);
+        assert_eq!(cop0.cause() & CAUSE_IP7, 0);
+    }
+
+    #[test]
+    fn ip7_is_only_taken_when_enabled_and_unmasked() {
+        let mut cop0 = Cop0::default();
+        cop0.write(COMPARE, 1);
+        cop0.advance_count(1);
+        let im7 = 1 << 15;
+        let enabled = STATUS_IE | STATUS_EIE | im7;
+        cop0.write(STATUS, enabled);
+        assert!(cop0.interrupt_pending());
+        for status in [
+            enabled & !STATUS_IE,
+            enabled & !STATUS_EIE,
+            enabled & !im7,
+            enabled | STATUS_EXL,
+            enabled | STATUS_ERL,
+        ] {
+            cop0.write(STATUS, status);
+            assert!(!cop0.interrupt_pending(), "status {status:#x}");
+        }
+    }
+}
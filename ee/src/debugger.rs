@@ -0,0 +1,461 @@
+// Breakpoints and watchpoints. A `Debugger` attached to a `Cpu` makes `step` return
+// `StepResult::Breakpoint` instead of carrying on when one of them triggers.
+//
+// Breakpoints stop *before* the instruction at their address runs. Watchpoints stop *after* the
+// instruction that made the access, so the access is visible in registers/memory. Both can carry
+// a condition, evaluated when the breakpoint is reached or the access happens; `hitcount` in it
+// counts how many times that has occurred, this time included.
+
+use std::ops::Range;
+
+use crate::Cpu;
+use crate::expr::Condition;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+    // Writes that actually change the watched bytes. Only memory can be compared, so these
+    // never trigger on device registers.
+    Change,
+}
+
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub addr: u32,
+    pub condition: Option<Condition>,
+    pub hit_count: u64,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub range: Range<u32>,
+    pub kind: WatchKind,
+    pub condition: Option<Condition>,
+    pub hit_count: u64,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint {
+        id: u32,
+        pc: u32,
+    },
+    Watchpoint {
+        id: u32,
+        pc: u32,
+        addr: u32,
+        kind: WatchKind,
+        value: u128,
+    },
+    // A BREAK instruction executed while the debugger was attached
+    BreakInstruction {
+        pc: u32,
+        code: u32,
+    },
+    // A step over or step out completed
+    StepDone {
+        pc: u32,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Finish {
+    // Stop at the next instruction at the same call depth or shallower
+    Over,
+    // Stop once back in the caller
+    Out,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FinishState {
+    kind: Finish,
+    depth: usize,
+    // The instruction we started from still has to run
+    started: bool,
+}
+
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    watchpoints: Vec<Watchpoint>,
+    next_id: u32,
+    // Breakpoint we last stopped at, skipped once so that resuming doesn't stop again right away
+    resume_pc: Option<u32>,
+    pending: Option<StopReason>,
+    finish: Option<FinishState>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn alloc_id(&mut self) -> u32 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u32, condition: Option<Condition>) -> u32 {
+        let id = self.alloc_id();
+        self.breakpoints.push(Breakpoint {
+            id,
+            addr,
+            condition,
+            hit_count: 0,
+            enabled: true,
+        });
+        id
+    }
+
+    pub fn add_watchpoint(
+        &mut self,
+        range: Range<u32>,
+        kind: WatchKind,
+        condition: Option<Condition>,
+    ) -> u32 {
+        let id = self.alloc_id();
+        self.watchpoints.push(Watchpoint {
+            id,
+            range,
+            kind,
+            condition,
+            hit_count: 0,
+            enabled: true,
+        });
+        id
+    }
+
+    // Removes the breakpoint or watchpoint with the given id
+    pub fn remove(&mut self, id: u32) -> bool {
+        let count = self.breakpoints.len() + self.watchpoints.len();
+        self.breakpoints.retain(|bp| bp.id != id);
+        self.watchpoints.retain(|wp| wp.id != id);
+        count != self.breakpoints.len() + self.watchpoints.len()
+    }
+
+    pub fn set_enabled(&mut self, id: u32, enabled: bool) -> bool {
+        let breakpoint = self.breakpoints.iter_mut().find(|bp| bp.id == id);
+        if let Some(bp) = breakpoint {
+            bp.enabled = enabled;
+            return true;
+        }
+        let watchpoint = self.watchpoints.iter_mut().find(|wp| wp.id == id);
+        if let Some(wp) = watchpoint {
+            wp.enabled = enabled;
+            return true;
+        }
+        false
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // Whether any enabled watchpoint needs the previous contents of memory written at `addr`
+    pub(crate) fn wants_old_value(&self, addr: u32, size: usize) -> bool {
+        let access = addr..addr.wrapping_add(size as u32);
+        self.watchpoints
+            .iter()
+            .any(|wp| wp.enabled && wp.kind == WatchKind::Change && overlaps(&wp.range, &access))
+    }
+
+    // Step over/out, measured against the CPU's shadow call stack. Branch delay slots are never
+    // stopped in, so a branch and its delay slot step as one.
+    pub(crate) fn finish(&mut self, kind: Finish, depth: usize) {
+        self.finish = Some(FinishState {
+            kind,
+            depth,
+            started: false,
+        });
+    }
+
+    fn check_finish(&mut self, cpu: &Cpu) -> bool {
+        let Some(finish) = self.finish.as_mut() else {
+            return false;
+        };
+        if !finish.started {
+            finish.started = true;
+            return false;
+        }
+        let depth = cpu.call_stack().depth();
+        let done = match finish.kind {
+            Finish::Over => depth <= finish.depth,
+            Finish::Out => depth < finish.depth,
+        };
+        done && !cpu.in_delay_slot
+    }
+
+    pub(crate) fn check_breakpoint(&mut self, cpu: &Cpu) -> Option<StopReason> {
+        let pc = cpu.pc;
+        if self.check_finish(cpu) {
+            self.finish = None;
+            self.resume_pc = Some(pc);
+            return Some(StopReason::StepDone { pc });
+        }
+        if self.resume_pc.take() == Some(pc) {
+            return None;
+        }
+        for bp in self.breakpoints.iter_mut() {
+            if !bp.enabled || bp.addr != pc {
+                continue;
+            }
+            bp.hit_count += 1;
+            let hit = match &bp.condition {
+                Some(condition) => condition.eval(cpu, bp.hit_count),
+                None => true,
+            };
+            if hit {
+                self.resume_pc = Some(pc);
+                self.finish = None;
+                return Some(StopReason::Breakpoint { id: bp.id, pc });
+            }
+        }
+        None
+    }
+
+    pub(crate) fn check_read(&mut self, cpu: &Cpu, addr: u32, size: usize, value: u128) {
+        self.check_access(cpu, addr, size, value, None);
+    }
+
+    // `old` is the previous value at the written location, when `wants_old_value` asked for it
+    pub(crate) fn check_write(
+        &mut self,
+        cpu: &Cpu,
+        addr: u32,
+        size: usize,
+        value: u128,
+        old: Option<u128>,
+    ) {
+        self.check_access(cpu, addr, size, value, Some(old));
+    }
+
+    fn check_access(
+        &mut self,
+        cpu: &Cpu,
+        addr: u32,
+        size: usize,
+        value: u128,
+        // None for reads, Some(old value if known) for writes
+        write: Option<Option<u128>>,
+    ) {
+        if self.pending.is_some() {
+            return;
+        }
+        let access = addr..addr.wrapping_add(size as u32);
+        for wp in self.watchpoints.iter_mut() {
+            if !wp.enabled || !overlaps(&wp.range, &access) {
+                continue;
+            }
+            let relevant = match (wp.kind, write) {
+                (WatchKind::Read, None) | (WatchKind::Access, _) => true,
+                (WatchKind::Write, Some(_)) => true,
+                (WatchKind::Change, Some(Some(old))) => {
+                    changed_in_range(&wp.range, addr, size, old, value)
+                }
+                _ => false,
+            };
+            if !relevant {
+                continue;
+            }
+            wp.hit_count += 1;
+            let hit = match &wp.condition {
+                Some(condition) => condition.eval(cpu, wp.hit_count),
+                None => true,
+            };
+            if hit {
+                self.pending = Some(StopReason::Watchpoint {
+                    id: wp.id,
+                    pc: cpu.pc,
+                    addr,
+                    kind: wp.kind,
+                    value,
+                });
+                return;
+            }
+        }
+    }
+
+    pub(crate) fn stop(&mut self, reason: StopReason) {
+        self.pending.get_or_insert(reason);
+    }
+
+    pub(crate) fn take_pending(&mut self) -> Option<StopReason> {
+        let reason = self.pending.take();
+        if reason.is_some() {
+            self.finish = None;
+        }
+        reason
+    }
+}
+
+fn overlaps(a: &Range<u32>, b: &Range<u32>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+// Compares only the bytes of an access that fall inside the watched range
+fn changed_in_range(range: &Range<u32>, addr: u32, size: usize, old: u128, new: u128) -> bool {
+    (0..size as u32)
+        .filter(|offset| range.contains(&addr.wrapping_add(*offset)))
+        .any(|offset| (old >> (offset * 8)) as u8 != (new >> (offset * 8)) as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StepResult;
+    use crate::bus::{Bus, BusError, Ram};
+
+    const NOP: u32 = 0x0000_0000;
+    const SW_T0: u32 = 0xAC08_0200; // sw t0, 0x200(zero)
+    const LW_T1: u32 = 0x8C09_0200; // lw t1, 0x200(zero)
+
+    fn machine(program: &[u32]) -> (Cpu, Ram) {
+        let mut ram = Ram::new(0x1000);
+        for (addr, &raw) in (0x100..).step_by(4).zip(program) {
+            ram.write32(addr, raw).unwrap();
+        }
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x100);
+        cpu.attach_debugger(Debugger::new());
+        (cpu, ram)
+    }
+
+    fn debugger(cpu: &mut Cpu) -> &mut Debugger {
+        cpu.debugger_mut().unwrap()
+    }
+
+    #[test]
+    fn breakpoints_stop_before_their_instruction_once() {
+        let (mut cpu, mut ram) = machine(&[NOP; 4]);
+        let id = debugger(&mut cpu).add_breakpoint(0x104, None);
+        assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+        let stop = StepResult::Breakpoint(StopReason::Breakpoint { id, pc: 0x104 });
+        assert_eq!(cpu.step(&mut ram), stop);
+        assert_eq!(cpu.pc(), 0x104);
+        // Resuming runs the instruction instead of stopping again
+        assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+        assert_eq!(cpu.pc(), 0x108);
+
+        // Back round, now disabled
+        debugger(&mut cpu).set_enabled(id, false);
+        cpu.set_pc(0x104);
+        assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+        assert!(debugger(&mut cpu).remove(id));
+        assert!(!debugger(&mut cpu).remove(id));
+    }
+
+    #[test]
+    fn conditions_see_registers_and_hit_counts() {
+        let (mut cpu, mut ram) = machine(&[NOP; 4]);
+        let condition = Condition::parse("hitcount == 3 && t0 == 1").unwrap();
+        let id = debugger(&mut cpu).add_breakpoint(0x100, Some(condition));
+        for _ in 0..2 {
+            cpu.set_pc(0x100);
+            assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+        }
+        cpu.gprs[8] = 1;
+        cpu.set_pc(0x100);
+        let stop = StepResult::Breakpoint(StopReason::Breakpoint { id, pc: 0x100 });
+        assert_eq!(cpu.step(&mut ram), stop);
+        assert_eq!(debugger(&mut cpu).breakpoints()[0].hit_count, 3);
+    }
+
+    #[test]
+    fn watchpoints_stop_after_matching_accesses() {
+        let (mut cpu, mut ram) = machine(&[SW_T0, SW_T0, LW_T1, NOP]);
+        cpu.gprs[8] = 0x55;
+        let write = debugger(&mut cpu).add_watchpoint(0x200..0x204, WatchKind::Write, None);
+        let read = debugger(&mut cpu).add_watchpoint(0x202..0x203, WatchKind::Read, None);
+        let watch = |id, pc, kind, value| {
+            StepResult::Breakpoint(StopReason::Watchpoint {
+                id,
+                pc,
+                addr: 0x200,
+                kind,
+                value,
+            })
+        };
+        assert_eq!(
+            cpu.step(&mut ram),
+            watch(write, 0x100, WatchKind::Write, 0x55)
+        );
+        assert_eq!(ram.read32(0x200).unwrap(), 0x55);
+        assert_eq!(
+            cpu.step(&mut ram),
+            watch(write, 0x104, WatchKind::Write, 0x55)
+        );
+        assert_eq!(
+            cpu.step(&mut ram),
+            watch(read, 0x108, WatchKind::Read, 0x55)
+        );
+        assert_eq!(cpu.gpr(9), 0x55);
+        assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+    }
+
+    #[test]
+    fn change_watchpoints_compare_only_the_watched_bytes() {
+        let (mut cpu, mut ram) = machine(&[SW_T0, SW_T0, SW_T0]);
+        let id = debugger(&mut cpu).add_watchpoint(0x201..0x202, WatchKind::Change, None);
+        cpu.gprs[8] = 0x0000_1100;
+        let stop = StepResult::Breakpoint(StopReason::Watchpoint {
+            id,
+            pc: 0x100,
+            addr: 0x200,
+            kind: WatchKind::Change,
+            value: 0x1100,
+        });
+        assert_eq!(cpu.step(&mut ram), stop);
+        // Same byte 1, a different byte 0
+        cpu.gprs[8] = 0x0000_11FF;
+        assert_eq!(cpu.step(&mut ram), StepResult::Ok);
+        cpu.gprs[8] = 0;
+        assert!(matches!(cpu.step(&mut ram), StepResult::Breakpoint(_)));
+    }
+
+    // A device register that counts how often it is read
+    struct Register {
+        ram: Ram,
+        reads: u32,
+    }
+
+    impl Bus for Register {
+        fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+            if addr & !3 == 0x200 {
+                self.reads += 1;
+            }
+            self.ram.read8(addr)
+        }
+
+        fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+            self.ram.write8(addr, value)
+        }
+
+        fn memory_mut(&mut self, addr: u32, len: u32) -> Option<&mut [u8]> {
+            if addr & !3 == 0x200 {
+                return None;
+            }
+            self.ram.memory_mut(addr, len)
+        }
+    }
+
+    #[test]
+    fn change_watchpoints_never_read_device_registers() {
+        let (mut cpu, ram) = machine(&[SW_T0]);
+        let mut bus = Register { ram, reads: 0 };
+        debugger(&mut cpu).add_watchpoint(0x200..0x204, WatchKind::Change, None);
+        cpu.gprs[8] = 0x55;
+        assert_eq!(cpu.step(&mut bus), StepResult::Ok);
+        assert_eq!(bus.reads, 0);
+        assert_eq!(bus.ram.read32(0x200).unwrap(), 0x55);
+    }
+}
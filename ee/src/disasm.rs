@@ -0,0 +1,311 @@
+use crate::Cpu;
+use crate::symbols::SymbolTable;
+
+pub const COP0_NAMES: [&str; 32] = [
+    "Index", "Random", "EntryLo0", "EntryLo1", "Context", "PageMask", "Wired", "$7", "BadVAddr",
+    "Count", "EntryHi", "Compare", "Status", "Cause", "EPC", "PRId", "Config", "$17", "$18", "$19",
+    "$20", "$21", "$22", "BadPAddr", "Debug", "Perf", "$26", "$27", "TagLo", "TagHi", "ErrorEPC",
+    "$31",
+];
+
+pub const GPR_NAMES: [&str; 32] = [
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3", "t0", "t1", "t2", "t3", "t4", "t5", "t6",
+    "t7", "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "t8", "t9", "k0", "k1", "gp", "sp", "fp",
+    "ra",
+];
+
+#[inline]
+fn gpr(index: usize) -> &'static str {
+    GPR_NAMES[index]
+}
+
+// Signed immediates are printed as hex with an explicit sign
+fn simm(raw: u32) -> String {
+    let imm = raw as i16 as i32;
+    if imm < 0 {
+        format!("-0x{:x}", -imm)
+    } else {
+        format!("0x{:x}", imm)
+    }
+}
+
+fn uimm(raw: u32) -> String {
+    format!("0x{:x}", raw & 0xFFFF)
+}
+
+fn branch_target(pc: u32, raw: u32) -> u32 {
+    let offset = (raw as i16 as i32) << 2;
+    (pc as i32 + 4 + offset) as u32
+}
+
+fn jump_target(pc: u32, raw: u32) -> u32 {
+    ((pc + 4) & 0xF000_0000) | ((raw & 0x03FF_FFFF) << 2)
+}
+
+fn op(mnemonic: &str, operands: String) -> String {
+    format!("{:<8}{}", mnemonic, operands)
+}
+
+/// Disassembles a single EE instruction located at `pc`.
+pub fn disassemble(pc: u32, raw: u32) -> String {
+    let rs = Cpu::extract_rs(raw);
+    let rt = Cpu::extract_rt(raw);
+
+    let mem =
+        |mnemonic: &str, reg: String| op(mnemonic, format!("{}, {}({})", reg, simm(raw), gpr(rs)));
+    let imm_arith =
+        |mnemonic: &str| op(mnemonic, format!("{}, {}, {}", gpr(rt), gpr(rs), simm(raw)));
+    let imm_logic =
+        |mnemonic: &str| op(mnemonic, format!("{}, {}, {}", gpr(rt), gpr(rs), uimm(raw)));
+    let branch1 = |mnemonic: &str| {
+        op(
+            mnemonic,
+            format!("{}, 0x{:08x}", gpr(rs), branch_target(pc, raw)),
+        )
+    };
+    let branch2 = |mnemonic: &str| {
+        op(
+            mnemonic,
+            format!("{}, {}, 0x{:08x}", gpr(rs), gpr(rt), branch_target(pc, raw)),
+        )
+    };
+
+    let opcode = (raw >> 26) & 0b111111;
+    match opcode {
+        Cpu::OPCODE_SPECIAL => disassemble_special(raw),
+        Cpu::OPCODE_REGIMM => disassemble_regimm(pc, raw),
+        Cpu::OPCODE_J => op("j", format!("0x{:08x}", jump_target(pc, raw))),
+        Cpu::OPCODE_JAL => op("jal", format!("0x{:08x}", jump_target(pc, raw))),
+        Cpu::OPCODE_BEQ if rs == 0 && rt == 0 => {
+            op("b", format!("0x{:08x}", branch_target(pc, raw)))
+        }
+        Cpu::OPCODE_BEQ => branch2("beq"),
+        Cpu::OPCODE_BNE => branch2("bne"),
+        Cpu::OPCODE_BLEZ => branch1("blez"),
+        Cpu::OPCODE_BGTZ => branch1("bgtz"),
+        Cpu::OPCODE_ADDI => imm_arith("addi"),
+        Cpu::OPCODE_ADDIU if rs == 0 => op("li", format!("{}, {}", gpr(rt), simm(raw))),
+        Cpu::OPCODE_ADDIU => imm_arith("addiu"),
+        Cpu::OPCODE_SLTI => imm_arith("slti"),
+        Cpu::OPCODE_SLTIU => imm_arith("sltiu"),
+        Cpu::OPCODE_ANDI => imm_logic("andi"),
+        Cpu::OPCODE_ORI => imm_logic("ori"),
+        Cpu::OPCODE_XORI => imm_logic("xori"),
+        Cpu::OPCODE_LUI => op("lui", format!("{}, {}", gpr(rt), uimm(raw))),
+        Cpu::OPCODE_COP0 => disassemble_cop0(raw),
+        Cpu::OPCODE_BEQL => branch2("beql"),
+        Cpu::OPCODE_BNEL => branch2("bnel"),
+        Cpu::OPCODE_BLEZL => branch1("blezl"),
+        Cpu::OPCODE_BGTZL => branch1("bgtzl"),
+        Cpu::OPCODE_DADDI => imm_arith("daddi"),
+        Cpu::OPCODE_DADDIU => imm_arith("daddiu"),
+        Cpu::OPCODE_LDL => mem("ldl", gpr(rt).to_string()),
+        Cpu::OPCODE_LDR => mem("ldr", gpr(rt).to_string()),
+        Cpu::OPCODE_LQ => mem("lq", gpr(rt).to_string()),
+        Cpu::OPCODE_SQ => mem("sq", gpr(rt).to_string()),
+        Cpu::OPCODE_LB => mem("lb", gpr(rt).to_string()),
+        Cpu::OPCODE_LH => mem("lh", gpr(rt).to_string()),
+        Cpu::OPCODE_LWL => mem("lwl", gpr(rt).to_string()),
+        Cpu::OPCODE_LW => mem("lw", gpr(rt).to_string()),
+        Cpu::OPCODE_LBU => mem("lbu", gpr(rt).to_string()),
+        Cpu::OPCODE_LHU => mem("lhu", gpr(rt).to_string()),
+        Cpu::OPCODE_LWR => mem("lwr", gpr(rt).to_string()),
+        Cpu::OPCODE_LWU => mem("lwu", gpr(rt).to_string()),
+        Cpu::OPCODE_SB => mem("sb", gpr(rt).to_string()),
+        Cpu::OPCODE_SH => mem("sh", gpr(rt).to_string()),
+        Cpu::OPCODE_SWL => mem("swl", gpr(rt).to_string()),
+        Cpu::OPCODE_SW => mem("sw", gpr(rt).to_string()),
+        Cpu::OPCODE_SDL => mem("sdl", gpr(rt).to_string()),
+        Cpu::OPCODE_SDR => mem("sdr", gpr(rt).to_string()),
+        Cpu::OPCODE_SWR => mem("swr", gpr(rt).to_string()),
+        Cpu::OPCODE_CACHE => mem("cache", format!("0x{:02x}", rt)),
+        Cpu::OPCODE_LWC1 => mem("lwc1", format!("$f{}", rt)),
+        Cpu::OPCODE_PREF => mem("pref", format!("0x{:02x}", rt)),
+        Cpu::OPCODE_LQC2 => mem("lqc2", format!("vf{}", rt)),
+        Cpu::OPCODE_LD => mem("ld", gpr(rt).to_string()),
+        Cpu::OPCODE_SWC1 => mem("swc1", format!("$f{}", rt)),
+        Cpu::OPCODE_SQC2 => mem("sqc2", format!("vf{}", rt)),
+        Cpu::OPCODE_SD => mem("sd", gpr(rt).to_string()),
+        _ => unknown(raw),
+    }
+}
+
+// Destination of a branch or jump whose target is encoded in the instruction itself
+pub fn static_target(pc: u32, raw: u32) -> Option<u32> {
+    let opcode = (raw >> 26) & 0b111111;
+    match opcode {
+        Cpu::OPCODE_J | Cpu::OPCODE_JAL => Some(jump_target(pc, raw)),
+        Cpu::OPCODE_BEQ
+        | Cpu::OPCODE_BNE
+        | Cpu::OPCODE_BLEZ
+        | Cpu::OPCODE_BGTZ
+        | Cpu::OPCODE_BEQL
+        | Cpu::OPCODE_BNEL
+        | Cpu::OPCODE_BLEZL
+        | Cpu::OPCODE_BGTZL => Some(branch_target(pc, raw)),
+        Cpu::OPCODE_REGIMM => match (raw >> 16) & 0b11111 {
+            Cpu::REGIMM_BLTZ
+            | Cpu::REGIMM_BGEZ
+            | Cpu::REGIMM_BLTZL
+            | Cpu::REGIMM_BGEZL
+            | Cpu::REGIMM_BLTZAL
+            | Cpu::REGIMM_BGEZAL
+            | Cpu::REGIMM_BLTZALL
+            | Cpu::REGIMM_BGEZALL => Some(branch_target(pc, raw)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Like `disassemble`, with branch and jump targets annotated as `<func+0x1c>`.
+pub fn disassemble_with_symbols(pc: u32, raw: u32, symbols: &SymbolTable) -> String {
+    let text = disassemble(pc, raw);
+    match static_target(pc, raw).and_then(|target| symbols.describe(target)) {
+        Some(name) => format!("{} <{}>", text, name),
+        None => text,
+    }
+}
+
+fn unknown(raw: u32) -> String {
+    op(".word", format!("0x{:08x}", raw))
+}
+
+fn disassemble_special(raw: u32) -> String {
+    let rs = gpr(Cpu::extract_rs(raw));
+    let rt = gpr(Cpu::extract_rt(raw));
+    let rd = gpr(Cpu::extract_rd(raw));
+    let sa = Cpu::extract_sa(raw);
+
+    let shift = |mnemonic: &str| op(mnemonic, format!("{}, {}, {}", rd, rt, sa));
+    let shiftv = |mnemonic: &str| op(mnemonic, format!("{}, {}, {}", rd, rt, rs));
+    let arith = |mnemonic: &str| op(mnemonic, format!("{}, {}, {}", rd, rs, rt));
+    let pair = |mnemonic: &str| op(mnemonic, format!("{}, {}", rs, rt));
+    // The EE's MULT/MULTU can also write LO to rd
+    let muldiv = |mnemonic: &str| {
+        if Cpu::extract_rd(raw) == 0 {
+            pair(mnemonic)
+        } else {
+            arith(mnemonic)
+        }
+    };
+    let code = (raw >> 6) & 0xF_FFFF;
+
+    let funct = raw & 0b111111;
+    match funct {
+        _ if raw == 0 => "nop".to_string(),
+        Cpu::SPECIAL_FUNCT_SLL => shift("sll"),
+        Cpu::SPECIAL_FUNCT_SRL => shift("srl"),
+        Cpu::SPECIAL_FUNCT_SRA => shift("sra"),
+        Cpu::SPECIAL_FUNCT_SLLV => shiftv("sllv"),
+        Cpu::SPECIAL_FUNCT_SRLV => shiftv("srlv"),
+        Cpu::SPECIAL_FUNCT_SRAV => shiftv("srav"),
+        Cpu::SPECIAL_FUNCT_JR => op("jr", rs.to_string()),
+        Cpu::SPECIAL_FUNCT_JALR if Cpu::extract_rd(raw) == 31 => op("jalr", rs.to_string()),
+        Cpu::SPECIAL_FUNCT_JALR => op("jalr", format!("{}, {}", rd, rs)),
+        Cpu::SPECIAL_FUNCT_MOVZ => arith("movz"),
+        Cpu::SPECIAL_FUNCT_MOVN => arith("movn"),
+        Cpu::SPECIAL_FUNCT_SYSCALL => op("syscall", format!("0x{:x}", code)),
+        Cpu::SPECIAL_FUNCT_BREAK => op("break", format!("0x{:x}", code)),
+        Cpu::SPECIAL_FUNCT_SYNC => "sync".to_string(),
+        Cpu::SPECIAL_FUNCT_MFHI => op("mfhi", rd.to_string()),
+        Cpu::SPECIAL_FUNCT_MTHI => op("mthi", rs.to_string()),
+        Cpu::SPECIAL_FUNCT_MFLO => op("mflo", rd.to_string()),
+        Cpu::SPECIAL_FUNCT_MTLO => op("mtlo", rs.to_string()),
+        Cpu::SPECIAL_FUNCT_DSLLV => shiftv("dsllv"),
+        Cpu::SPECIAL_FUNCT_DSRLV => shiftv("dsrlv"),
+        Cpu::SPECIAL_FUNCT_DSRAV => shiftv("dsrav"),
+        Cpu::SPECIAL_FUNCT_MULT => muldiv("mult"),
+        Cpu::SPECIAL_FUNCT_MULTU => muldiv("multu"),
+        Cpu::SPECIAL_FUNCT_DIV => pair("div"),
+        Cpu::SPECIAL_FUNCT_DIVU => pair("divu"),
+        Cpu::SPECIAL_FUNCT_ADD => arith("add"),
+        Cpu::SPECIAL_FUNCT_ADDU if Cpu::extract_rt(raw) == 0 => {
+            op("move", format!("{}, {}", rd, rs))
+        }
+        Cpu::SPECIAL_FUNCT_ADDU => arith("addu"),
+        Cpu::SPECIAL_FUNCT_SUB => arith("sub"),
+        Cpu::SPECIAL_FUNCT_SUBU => arith("subu"),
+        Cpu::SPECIAL_FUNCT_AND => arith("and"),
+        Cpu::SPECIAL_FUNCT_OR => arith("or"),
+        Cpu::SPECIAL_FUNCT_XOR => arith("xor"),
+        Cpu::SPECIAL_FUNCT_NOR => arith("nor"),
+        Cpu::SPECIAL_FUNCT_MFSA => op("mfsa", rd.to_string()),
+        Cpu::SPECIAL_FUNCT_MTSA => op("mtsa", rs.to_string()),
+        Cpu::SPECIAL_FUNCT_SLT => arith("slt"),
+        Cpu::SPECIAL_FUNCT_SLTU => arith("sltu"),
+        Cpu::SPECIAL_FUNCT_DADD => arith("dadd"),
+        Cpu::SPECIAL_FUNCT_DADDU => arith("daddu"),
+        Cpu::SPECIAL_FUNCT_DSUB => arith("dsub"),
+        Cpu::SPECIAL_FUNCT_DSUBU => arith("dsubu"),
+        Cpu::SPECIAL_FUNCT_TGE => pair("tge"),
+        Cpu::SPECIAL_FUNCT_TGEU => pair("tgeu"),
+        Cpu::SPECIAL_FUNCT_TLT => pair("tlt"),
+        Cpu::SPECIAL_FUNCT_TLTU => pair("tltu"),
+        Cpu::SPECIAL_FUNCT_TEQ => pair("teq"),
+        Cpu::SPECIAL_FUNCT_TNE => pair("tne"),
+        Cpu::SPECIAL_FUNCT_DSLL => shift("dsll"),
+        Cpu::SPECIAL_FUNCT_DSRL => shift("dsrl"),
+        Cpu::SPECIAL_FUNCT_DSRA => shift("dsra"),
+        Cpu::SPECIAL_FUNCT_DSLL32 => shift("dsll32"),
+        Cpu::SPECIAL_FUNCT_DSRL32 => shift("dsrl32"),
+        Cpu::SPECIAL_FUNCT_DSRA32 => shift("dsra32"),
+        _ => unknown(raw),
+    }
+}
+
+fn disassemble_regimm(pc: u32, raw: u32) -> String {
+    let rs = gpr(Cpu::extract_rs(raw));
+    let branch = |mnemonic: &str| {
+        op(
+            mnemonic,
+            format!("{}, 0x{:08x}", rs, branch_target(pc, raw)),
+        )
+    };
+    let trap = |mnemonic: &str| op(mnemonic, format!("{}, {}", rs, simm(raw)));
+
+    let rt = (raw >> 16) & 0b11111;
+    match rt {
+        Cpu::REGIMM_BLTZ => branch("bltz"),
+        Cpu::REGIMM_BGEZ => branch("bgez"),
+        Cpu::REGIMM_BLTZL => branch("bltzl"),
+        Cpu::REGIMM_BGEZL => branch("bgezl"),
+        Cpu::REGIMM_TGEI => trap("tgei"),
+        Cpu::REGIMM_TGEIU => trap("tgeiu"),
+        Cpu::REGIMM_TLTI => trap("tlti"),
+        Cpu::REGIMM_TLTIU => trap("tltiu"),
+        Cpu::REGIMM_TEQI => trap("teqi"),
+        Cpu::REGIMM_TNEI => trap("tnei"),
+        Cpu::REGIMM_BLTZAL => branch("bltzal"),
+        Cpu::REGIMM_BGEZAL if Cpu::extract_rs(raw) == 0 => {
+            op("bal", format!("0x{:08x}", branch_target(pc, raw)))
+        }
+        Cpu::REGIMM_BGEZAL => branch("bgezal"),
+        Cpu::REGIMM_BLTZALL => branch("bltzall"),
+        Cpu::REGIMM_BGEZALL => branch("bgezall"),
+        Cpu::REGIMM_MTSAB => trap("mtsab"),
+        Cpu::REGIMM_MTSAH => trap("mtsah"),
+        _ => unknown(raw),
+    }
+}
+
+fn disassemble_cop0(raw: u32) -> String {
+    let rt = gpr(Cpu::extract_rt(raw));
+    let rd = COP0_NAMES[Cpu::extract_rd(raw)];
+
+    match (raw >> 21) & 0b11111 {
+        Cpu::COP0_MF0 => op("mfc0", format!("{}, {}", rt, rd)),
+        Cpu::COP0_MT0 => op("mtc0", format!("{}, {}", rt, rd)),
+        Cpu::COP0_C0 => match raw & 0b111111 {
+            Cpu::C0_FUNCT_TLBR => "tlbr".to_string(),
+            Cpu::C0_FUNCT_TLBWI => "tlbwi".to_string(),
+            Cpu::C0_FUNCT_TLBWR => "tlbwr".to_string(),
+            Cpu::C0_FUNCT_TLBP => "tlbp".to_string(),
+            Cpu::C0_FUNCT_ERET => "eret".to_string(),
+            Cpu::C0_FUNCT_EI => "ei".to_string(),
+            Cpu::C0_FUNCT_DI => "di".to_string(),
+            _ => unknown(raw),
+        },
+        _ => unknown(raw),
+    }
+}
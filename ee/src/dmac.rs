@@ -0,0 +1,1080 @@
+// The EE's DMA controller: ten channels moving quadwords between memory and the devices.
+//
+// Channels that feed a device (VIF0, GIF, IPU_TO, SIF1, and VIF1/SIF2 when CHCR.DIR says so)
+// push the quadwords they read into that channel's FIFO, for the device to take. Channels coming
+// from a device pull from the FIFO instead, and wait while it's empty. The scratchpad channels
+// move data between scratchpad (at SADR) and main memory without a FIFO.
+//
+// Transfers happen in slices run from scheduled events: a whole normal or interleaved transfer,
+// or one chain tag with its data. A slice costs one BUSCLK (two EE cycles) per quadword moved,
+// tags included, and the next slice runs once that time has passed. When the last slice is done
+// the channel clears CHCR.STR and raises its D_STAT bit.
+//
+// Memory addresses (MADR, TADR and tag addresses) with bit 31 set are in scratchpad. Stall
+// control isn't modelled, D_STADR is a plain register.
+//
+// In MFIFO mode (D_CTRL.MFD naming VIF1 or GIF as the drain), SPR_FROM writes into a ring buffer
+// in main memory at D_RBOR, D_RBSR being the mask of offsets within it. The drain channel runs a
+// source chain whose tags and data sit in the ring, wrapping around its end. Whenever it catches
+// up with SPR_FROM's MADR the ring is empty: the drain stalls and raises MEIS until SPR_FROM
+// writes more.
+
+use std::collections::VecDeque;
+
+use crate::intc::{DMA_INT_MEIS, DmaInterrupts};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const VIF0: usize = 0;
+pub const VIF1: usize = 1;
+pub const GIF: usize = 2;
+pub const IPU_FROM: usize = 3;
+pub const IPU_TO: usize = 4;
+pub const SIF0: usize = 5;
+pub const SIF1: usize = 6;
+pub const SIF2: usize = 7;
+pub const SPR_FROM: usize = 8;
+pub const SPR_TO: usize = 9;
+pub const CHANNEL_COUNT: usize = 10;
+
+// Register block of each channel
+pub const CHANNEL_BASES: [u32; CHANNEL_COUNT] = [
+    0x1000_8000,
+    0x1000_9000,
+    0x1000_A000,
+    0x1000_B000,
+    0x1000_B400,
+    0x1000_C000,
+    0x1000_C400,
+    0x1000_C800,
+    0x1000_D000,
+    0x1000_D400,
+];
+
+// Channel register offsets
+pub const CHCR: u32 = 0x00;
+pub const MADR: u32 = 0x10;
+pub const QWC: u32 = 0x20;
+pub const TADR: u32 = 0x30;
+pub const ASR0: u32 = 0x40;
+pub const ASR1: u32 = 0x50;
+pub const SADR: u32 = 0x80;
+
+// Global registers
+pub const D_CTRL: u32 = 0x1000_E000;
+pub const D_PCR: u32 = 0x1000_E020;
+pub const D_SQWC: u32 = 0x1000_E030;
+pub const D_RBSR: u32 = 0x1000_E040;
+pub const D_RBOR: u32 = 0x1000_E050;
+pub const D_STADR: u32 = 0x1000_E060;
+pub const D_ENABLER: u32 = 0x1000_F520;
+pub const D_ENABLEW: u32 = 0x1000_F590;
+
+// CHCR bits
+pub const CHCR_DIR: u32 = 1 << 0;
+pub const CHCR_MOD_SHIFT: u32 = 2;
+pub const CHCR_MOD_MASK: u32 = 0x3 << CHCR_MOD_SHIFT;
+pub const CHCR_ASP_SHIFT: u32 = 4;
+pub const CHCR_ASP_MASK: u32 = 0x3 << CHCR_ASP_SHIFT;
+pub const CHCR_TTE: u32 = 1 << 6;
+pub const CHCR_TIE: u32 = 1 << 7;
+pub const CHCR_STR: u32 = 1 << 8;
+
+pub const MODE_NORMAL: u32 = 0;
+pub const MODE_CHAIN: u32 = 1;
+pub const MODE_INTERLEAVE: u32 = 2;
+
+// D_CTRL bits
+pub const D_CTRL_DMAE: u32 = 1 << 0;
+pub const D_CTRL_MFD_SHIFT: u32 = 2;
+pub const D_CTRL_MFD_MASK: u32 = 0x3 << D_CTRL_MFD_SHIFT;
+// MFIFO drain channels in D_CTRL.MFD
+pub const MFD_VIF1: u32 = 2;
+pub const MFD_GIF: u32 = 3;
+// D_PCR bits
+pub const D_PCR_PCE: u32 = 1 << 31;
+const D_PCR_CDE_SHIFT: u32 = 16;
+// D_ENABLEW bit holding every channel
+pub const D_ENABLE_CPND: u32 = 1 << 16;
+
+// DMAtag IDs
+pub const TAG_REFE: u32 = 0;
+pub const TAG_CNT: u32 = 1;
+pub const TAG_NEXT: u32 = 2;
+pub const TAG_REF: u32 = 3;
+pub const TAG_REFS: u32 = 4;
+pub const TAG_CALL: u32 = 5;
+pub const TAG_RET: u32 = 6;
+pub const TAG_END: u32 = 7;
+// Destination chain tag IDs
+pub const TAG_CNTS: u32 = 0;
+
+// EE cycles per quadword moved
+const CYCLES_PER_QWORD: u64 = 2;
+const SPR_ADDR: u32 = 1 << 31;
+const SCRATCHPAD_MASK: usize = 0x3FF0;
+const RING_ADDR_MASK: u32 = 0x7FFF_FFF0;
+
+// The memory channels transfer to and from
+pub struct DmaMemory<'a> {
+    pub ram: &'a mut [u8],
+    pub scratchpad: &'a mut [u8],
+}
+
+impl DmaMemory<'_> {
+    fn slice(&mut self, addr: u32) -> &mut [u8] {
+        let offset = if addr & SPR_ADDR != 0 {
+            addr as usize & SCRATCHPAD_MASK
+        } else {
+            addr as usize & (self.ram.len() - 1) & !0xF
+        };
+        let memory = if addr & SPR_ADDR != 0 {
+            &mut *self.scratchpad
+        } else {
+            &mut *self.ram
+        };
+        &mut memory[offset..offset + 16]
+    }
+
+    fn read(&mut self, addr: u32) -> u128 {
+        u128::from_le_bytes(self.slice(addr).try_into().unwrap())
+    }
+
+    fn write(&mut self, addr: u32, value: u128) {
+        self.slice(addr).copy_from_slice(&value.to_le_bytes());
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum State {
+    #[default]
+    Idle,
+    Running,
+    // A tag ended the chain, what's left is its data
+    LastTag,
+    // Everything has moved, the channel stops once the time it took has passed
+    Finishing,
+}
+
+impl State {
+    fn code(self) -> u8 {
+        match self {
+            State::Idle => 0,
+            State::Running => 1,
+            State::LastTag => 2,
+            State::Finishing => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(State::Idle),
+            1 => Some(State::Running),
+            2 => Some(State::LastTag),
+            3 => Some(State::Finishing),
+            _ => None,
+        }
+    }
+}
+
+// Why a slice couldn't finish
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Stall {
+    // A transfer into memory has nothing from its device
+    Device,
+    // The MFIFO drain caught up with SPR_FROM
+    RingEmpty,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Channel {
+    chcr: u32,
+    madr: u32,
+    qwc: u32,
+    tadr: u32,
+    asr: [u32; 2],
+    sadr: u32,
+    state: State,
+    fifo: VecDeque<u128>,
+}
+
+impl Channel {
+    fn mode(&self) -> u32 {
+        (self.chcr & CHCR_MOD_MASK) >> CHCR_MOD_SHIFT
+    }
+
+    fn asp(&self) -> usize {
+        ((self.chcr & CHCR_ASP_MASK) >> CHCR_ASP_SHIFT) as usize
+    }
+
+    fn set_asp(&mut self, asp: usize) {
+        self.chcr = (self.chcr & !CHCR_ASP_MASK) | (asp as u32) << CHCR_ASP_SHIFT;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Dmac {
+    channels: [Channel; CHANNEL_COUNT],
+    ctrl: u32,
+    pcr: u32,
+    sqwc: u32,
+    rbsr: u32,
+    rbor: u32,
+    stadr: u32,
+    enable: u32,
+    // Channels that need a slice scheduled, one bit each
+    kicked: u32,
+}
+
+// Whether a channel moves data into memory, from its device or from scratchpad
+fn to_memory(channel: usize, chcr: u32) -> bool {
+    match channel {
+        IPU_FROM | SIF0 | SPR_FROM => true,
+        VIF1 | SIF2 => chcr & CHCR_DIR == 0,
+        _ => false,
+    }
+}
+
+impl Dmac {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The channel and register offset `addr` falls on, if it's a channel register
+    fn decode(addr: u32) -> Option<(usize, u32)> {
+        let channel = CHANNEL_BASES
+            .iter()
+            .position(|&base| addr.wrapping_sub(base) < 0x100)?;
+        let reg = addr - CHANNEL_BASES[channel];
+        match reg {
+            CHCR | MADR | QWC | TADR | ASR0 | ASR1 | SADR => Some((channel, reg)),
+            _ => None,
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> Option<u32> {
+        if let Some((channel, reg)) = Self::decode(addr) {
+            let ch = &self.channels[channel];
+            return Some(match reg {
+                CHCR => ch.chcr,
+                MADR => ch.madr,
+                QWC => ch.qwc,
+                TADR => ch.tadr,
+                ASR0 => ch.asr[0],
+                ASR1 => ch.asr[1],
+                _ => ch.sadr,
+            });
+        }
+        let value = match addr {
+            D_CTRL => self.ctrl,
+            D_PCR => self.pcr,
+            D_SQWC => self.sqwc,
+            D_RBSR => self.rbsr,
+            D_RBOR => self.rbor,
+            D_STADR => self.stadr,
+            D_ENABLER => self.enable,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // Returns false if `addr` isn't a DMAC register. D_STAT lives with the interrupt controller.
+    pub fn write(&mut self, addr: u32, value: u32) -> bool {
+        if let Some((channel, reg)) = Self::decode(addr) {
+            let ch = &mut self.channels[channel];
+            match reg {
+                CHCR => {
+                    let was_running = ch.chcr & CHCR_STR != 0;
+                    ch.chcr = value;
+                    match (was_running, value & CHCR_STR != 0) {
+                        (false, true) => {
+                            ch.state = State::Running;
+                            self.kicked |= 1 << channel;
+                        }
+                        // Clearing STR stops the channel where it is
+                        (true, false) => ch.state = State::Idle,
+                        _ => {}
+                    }
+                }
+                MADR => ch.madr = value & !0xF,
+                QWC => ch.qwc = value & 0xFFFF,
+                TADR => ch.tadr = value & !0xF,
+                ASR0 => ch.asr[0] = value & !0xF,
+                ASR1 => ch.asr[1] = value & !0xF,
+                _ => ch.sadr = value & SCRATCHPAD_MASK as u32,
+            }
+            return true;
+        }
+        match addr {
+            D_CTRL => self.ctrl = value,
+            D_PCR => self.pcr = value,
+            D_SQWC => self.sqwc = value,
+            D_RBSR => self.rbsr = value,
+            D_RBOR => self.rbor = value,
+            D_STADR => self.stadr = value,
+            D_ENABLEW => self.enable = value,
+            _ => return false,
+        }
+        // Turning the DMAC back on resumes whatever was running
+        self.kick_running();
+        true
+    }
+
+    fn kick_running(&mut self) {
+        for (channel, ch) in self.channels.iter().enumerate() {
+            if ch.state != State::Idle {
+                self.kicked |= 1 << channel;
+            }
+        }
+    }
+
+    // Channels that need a slice scheduled since the last call
+    pub fn take_kicked(&mut self) -> impl Iterator<Item = usize> + use<> {
+        let kicked = std::mem::take(&mut self.kicked);
+        (0..CHANNEL_COUNT).filter(move |channel| kicked & 1 << channel != 0)
+    }
+
+    pub fn is_running(&self, channel: usize) -> bool {
+        self.channels[channel].state != State::Idle
+    }
+
+    // The channel draining the MFIFO ring, if MFIFO mode is on
+    pub fn mfifo_drain(&self) -> Option<usize> {
+        match (self.ctrl & D_CTRL_MFD_MASK) >> D_CTRL_MFD_SHIFT {
+            MFD_VIF1 => Some(VIF1),
+            MFD_GIF => Some(GIF),
+            _ => None,
+        }
+    }
+
+    fn ring(&self, addr: u32) -> u32 {
+        (addr & self.rbsr & RING_ADDR_MASK) | (self.rbor & RING_ADDR_MASK)
+    }
+
+    fn in_ring(&self, addr: u32) -> bool {
+        self.mfifo_drain().is_some()
+            && addr & SPR_ADDR == 0
+            && addr & !(self.rbsr & RING_ADDR_MASK) == self.rbor & RING_ADDR_MASK
+    }
+
+    // `addr` moved on by `bytes`, wrapping around the end of the ring if it's in it
+    fn advance(&self, addr: u32, bytes: u32) -> u32 {
+        let next = addr.wrapping_add(bytes);
+        if self.in_ring(addr) {
+            self.ring(next)
+        } else {
+            next
+        }
+    }
+
+    // Where a channel's data goes next; SPR_FROM writes only into the ring in MFIFO mode
+    fn data_addr(&self, channel: usize, madr: u32) -> u32 {
+        if channel == SPR_FROM && self.mfifo_drain().is_some() {
+            self.ring(madr)
+        } else {
+            madr
+        }
+    }
+
+    // Whether `channel` is the MFIFO drain and reading at `addr` would overtake SPR_FROM
+    fn ring_empty_at(&self, channel: usize, addr: u32) -> bool {
+        self.mfifo_drain() == Some(channel)
+            && self.in_ring(addr)
+            && addr == self.channels[SPR_FROM].madr
+    }
+
+    fn channel_enabled(&self, channel: usize) -> bool {
+        let priority_enabled =
+            self.pcr & D_PCR_PCE == 0 || self.pcr & 1 << (D_PCR_CDE_SHIFT + channel as u32) != 0;
+        self.ctrl & D_CTRL_DMAE != 0 && self.enable & D_ENABLE_CPND == 0 && priority_enabled
+    }
+
+    // Data a device has for a channel coming from it
+    pub fn push(&mut self, channel: usize, qword: u128) {
+        self.channels[channel].fifo.push_back(qword);
+        if self.channels[channel].state != State::Idle {
+            self.kicked |= 1 << channel;
+        }
+    }
+
+    // Data a channel has brought to its device
+    pub fn pop(&mut self, channel: usize) -> Option<u128> {
+        self.channels[channel].fifo.pop_front()
+    }
+
+    pub fn fifo_len(&self, channel: usize) -> usize {
+        self.channels[channel].fifo.len()
+    }
+
+    // The next quadword of a transfer into memory, None while the device has nothing
+    fn pull(&mut self, channel: usize, mem: &mut DmaMemory) -> Option<u128> {
+        let ch = &mut self.channels[channel];
+        if channel == SPR_FROM {
+            let qword = mem.read(ch.sadr | SPR_ADDR);
+            ch.sadr = (ch.sadr + 16) & SCRATCHPAD_MASK as u32;
+            Some(qword)
+        } else {
+            ch.fifo.pop_front()
+        }
+    }
+
+    fn send(&mut self, channel: usize, mem: &mut DmaMemory, qword: u128) {
+        let ch = &mut self.channels[channel];
+        if channel == SPR_TO {
+            mem.write(ch.sadr | SPR_ADDR, qword);
+            ch.sadr = (ch.sadr + 16) & SCRATCHPAD_MASK as u32;
+        } else {
+            ch.fifo.push_back(qword);
+        }
+    }
+
+    // Moves the channel's QWC quadwords at MADR. Returns how many moved, or why it ran out of
+    // data first.
+    fn move_data(&mut self, channel: usize, mem: &mut DmaMemory) -> Result<u64, Stall> {
+        let into_memory = to_memory(channel, self.channels[channel].chcr);
+        let mut moved = 0;
+        while self.channels[channel].qwc > 0 {
+            let madr = self.data_addr(channel, self.channels[channel].madr);
+            if self.ring_empty_at(channel, madr) {
+                return Err(Stall::RingEmpty);
+            }
+            if into_memory {
+                let qword = self.pull(channel, mem).ok_or(Stall::Device)?;
+                mem.write(madr, qword);
+            } else {
+                let qword = mem.read(madr);
+                self.send(channel, mem, qword);
+            }
+            let next = self.data_addr(channel, self.advance(madr, 16));
+            let ch = &mut self.channels[channel];
+            ch.madr = next;
+            ch.qwc -= 1;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    // Interleave mode, scratchpad channels only: TQWC quadwords are moved, then SQWC are
+    // skipped in main memory, until QWC runs out
+    fn move_interleaved(&mut self, channel: usize, mem: &mut DmaMemory) -> u64 {
+        let skip = self.sqwc & 0xFF;
+        let block = (self.sqwc >> 16) & 0xFF;
+        let mut moved = 0;
+        while self.channels[channel].qwc > 0 {
+            let ch = &mut self.channels[channel];
+            let count = if block == 0 {
+                ch.qwc
+            } else {
+                ch.qwc.min(block)
+            };
+            let remaining = ch.qwc - count;
+            ch.qwc = count;
+            // Scratchpad always has data, so this can't stall
+            let _ = self.move_data(channel, mem);
+            let ch = &mut self.channels[channel];
+            ch.qwc = remaining;
+            if remaining > 0 {
+                ch.madr = ch.madr.wrapping_add(skip * 16);
+            }
+            moved += count as u64;
+        }
+        moved
+    }
+
+    // Reads the next source chain tag at TADR and sets the channel up for its data. Returns
+    // whether the tag ends the chain.
+    fn source_tag(&mut self, channel: usize, mem: &mut DmaMemory) -> bool {
+        let tag_addr = self.channels[channel].tadr;
+        let tag = mem.read(tag_addr);
+        if self.channels[channel].chcr & CHCR_TTE != 0 {
+            self.send(channel, mem, tag);
+        }
+        let low = tag as u64;
+        let qwc = low as u32 & 0xFFFF;
+        let id = (low >> 28) as u32 & 0x7;
+        let irq = low & 1 << 31 != 0;
+        let addr = (low >> 32) as u32 & !0xF;
+        let after_tag = self.advance(tag_addr, 16);
+        let after_data = self.advance(after_tag, qwc * 16);
+        let ch = &mut self.channels[channel];
+        // The upper half of CHCR shows the last tag read
+        ch.chcr = (ch.chcr & 0xFFFF) | (low as u32 & 0xFFFF_0000);
+        ch.qwc = qwc;
+        let mut end = false;
+        match id {
+            TAG_REFE => {
+                ch.madr = addr;
+                ch.tadr = after_tag;
+                end = true;
+            }
+            TAG_CNT => {
+                ch.madr = after_tag;
+                ch.tadr = after_data;
+            }
+            TAG_NEXT => {
+                ch.madr = after_tag;
+                ch.tadr = addr;
+            }
+            TAG_REF | TAG_REFS => {
+                ch.madr = addr;
+                ch.tadr = after_tag;
+            }
+            TAG_CALL => {
+                ch.madr = after_tag;
+                let asp = ch.asp();
+                if asp < ch.asr.len() {
+                    ch.asr[asp] = after_data;
+                    ch.set_asp(asp + 1);
+                    ch.tadr = addr;
+                } else {
+                    // The address stack is full; real hardware misbehaves, end the chain
+                    end = true;
+                }
+            }
+            TAG_RET => {
+                ch.madr = after_tag;
+                match ch.asp() {
+                    0 => {
+                        ch.tadr = after_data;
+                        end = true;
+                    }
+                    asp => {
+                        ch.tadr = ch.asr[asp - 1];
+                        ch.set_asp(asp - 1);
+                    }
+                }
+            }
+            _ => {
+                ch.madr = after_tag;
+                end = true;
+            }
+        }
+        end || irq && ch.chcr & CHCR_TIE != 0
+    }
+
+    // Takes the next destination chain tag from the device. None while it has nothing,
+    // otherwise whether the tag ends the chain.
+    fn destination_tag(&mut self, channel: usize, mem: &mut DmaMemory) -> Option<bool> {
+        let tag = self.pull(channel, mem)?;
+        let ch = &mut self.channels[channel];
+        let low = tag as u64;
+        let id = (low >> 28) as u32 & 0x7;
+        let irq = low & 1 << 31 != 0;
+        ch.chcr = (ch.chcr & 0xFFFF) | (low as u32 & 0xFFFF_0000);
+        ch.qwc = low as u32 & 0xFFFF;
+        ch.madr = (low >> 32) as u32 & !0xF;
+        let end = !matches!(id, TAG_CNTS | TAG_CNT);
+        Some(end || irq && ch.chcr & CHCR_TIE != 0)
+    }
+
+    // Moves a slice's data, returning how many quadwords that took (tags included) and the
+    // state to continue in, or why it is waiting for data.
+    fn run_slice(
+        &mut self,
+        channel: usize,
+        state: State,
+        mem: &mut DmaMemory,
+    ) -> Result<(u64, State), Stall> {
+        let ch = &self.channels[channel];
+        let slice = match ch.mode() {
+            MODE_INTERLEAVE if matches!(channel, SPR_FROM | SPR_TO) => {
+                (self.move_interleaved(channel, mem), State::Finishing)
+            }
+            MODE_CHAIN if state == State::Running => {
+                let into_memory = to_memory(channel, ch.chcr);
+                let moved = self.move_data(channel, mem)?;
+                if self.ring_empty_at(channel, self.channels[channel].tadr) {
+                    return Err(Stall::RingEmpty);
+                }
+                let end = if into_memory {
+                    self.destination_tag(channel, mem).ok_or(Stall::Device)?
+                } else {
+                    self.source_tag(channel, mem)
+                };
+                let next = if end { State::LastTag } else { State::Running };
+                (moved + 1, next)
+            }
+            _ => (self.move_data(channel, mem)?, State::Finishing),
+        };
+        Ok(slice)
+    }
+
+    // Runs a channel's next slice. Returns the EE cycles it took, after which it wants another,
+    // or None if it has stopped or is waiting for its device or for the DMAC to be enabled.
+    pub fn service(
+        &mut self,
+        channel: usize,
+        mem: &mut DmaMemory,
+        interrupts: &mut DmaInterrupts,
+    ) -> Option<u64> {
+        let state = self.channels[channel].state;
+        if state == State::Idle || !self.channel_enabled(channel) {
+            return None;
+        }
+        if state == State::Finishing {
+            let ch = &mut self.channels[channel];
+            ch.chcr &= !CHCR_STR;
+            ch.state = State::Idle;
+            interrupts.raise(channel as u32);
+            return None;
+        }
+        let (moved, next) = match self.run_slice(channel, state, mem) {
+            Ok(slice) => slice,
+            Err(stall) => {
+                // Only an empty ring raises MEIS; a drain can also be a transfer from its
+                // device (VIF1 with DIR clear) waiting on the FIFO
+                if stall == Stall::RingEmpty {
+                    interrupts.raise(DMA_INT_MEIS);
+                }
+                return None;
+            }
+        };
+        self.channels[channel].state = next;
+        // What SPR_FROM wrote may be what a stalled drain is waiting for
+        if channel == SPR_FROM
+            && let Some(drain) = self.mfifo_drain()
+            && self.channels[drain].state != State::Idle
+        {
+            self.kicked |= 1 << drain;
+        }
+        Some(moved.max(1) * CYCLES_PER_QWORD)
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for ch in &self.channels {
+            w.write_u32(ch.chcr);
+            w.write_u32(ch.madr);
+            w.write_u32(ch.qwc);
+            w.write_u32(ch.tadr);
+            w.write_u32(ch.asr[0]);
+            w.write_u32(ch.asr[1]);
+            w.write_u32(ch.sadr);
+            w.write_u8(ch.state.code());
+            w.write_u32(ch.fifo.len() as u32);
+            for &qword in &ch.fifo {
+                w.write_u128(qword);
+            }
+        }
+        for value in [
+            self.ctrl,
+            self.pcr,
+            self.sqwc,
+            self.rbsr,
+            self.rbor,
+            self.stadr,
+            self.enable,
+        ] {
+            w.write_u32(value);
+        }
+    }
+
+    // Channel events are restored with the scheduler
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut channels: [Channel; CHANNEL_COUNT] = Default::default();
+        for ch in channels.iter_mut() {
+            ch.chcr = r.read_u32()?;
+            ch.madr = r.read_u32()?;
+            ch.qwc = r.read_u32()?;
+            ch.tadr = r.read_u32()?;
+            ch.asr = [r.read_u32()?, r.read_u32()?];
+            ch.sadr = r.read_u32()?;
+            let code = r.read_u8()?;
+            ch.state = State::from_code(code)
+                .ok_or_else(|| StateError::Mismatch(format!("unknown DMA state {}", code)))?;
+            let len = r.read_u32()?;
+            for _ in 0..len {
+                ch.fifo.push_back(r.read_u128()?);
+            }
+        }
+        let mut globals = [0u32; 7];
+        for value in globals.iter_mut() {
+            *value = r.read_u32()?;
+        }
+        let [ctrl, pcr, sqwc, rbsr, rbor, stadr, enable] = globals;
+        *self = Dmac {
+            channels,
+            ctrl,
+            pcr,
+            sqwc,
+            rbsr,
+            rbor,
+            stadr,
+            enable,
+            kicked: 0,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gif::{FLG_PACKED, Gif, GifSink, Path, REG_AD};
+
+    const RING: u32 = 0x1_0000;
+    // Eight quadwords
+    const RING_MASK: u32 = 0x70;
+
+    #[derive(Default)]
+    struct Sink {
+        writes: Vec<(u8, u64)>,
+    }
+
+    impl GifSink for Sink {
+        fn write_register(&mut self, reg: u8, value: u64) {
+            self.writes.push((reg, value));
+        }
+
+        fn write_image(&mut self, _data: u64) {}
+    }
+
+    struct Machine {
+        dmac: Dmac,
+        interrupts: DmaInterrupts,
+        ram: Vec<u8>,
+        scratchpad: Vec<u8>,
+        gif: Gif,
+        sink: Sink,
+    }
+
+    fn dma_tag(id: u32, qwc: u32, addr: u32) -> u128 {
+        (addr as u128) << 32 | (id << 28 | qwc) as u128
+    }
+
+    // A one-register A+D GS packet, ending with EOP
+    fn gs_packet(reg: u8, value: u64) -> [u128; 2] {
+        let giftag = 1 | 1 << 15 | (FLG_PACKED as u128) << 58 | 1 << 60 | (REG_AD as u128) << 64;
+        [giftag, (reg as u128) << 64 | value as u128]
+    }
+
+    impl Machine {
+        // DMA enabled, without an MFIFO drain
+        fn new() -> Self {
+            let mut dmac = Dmac::new();
+            dmac.write(D_CTRL, D_CTRL_DMAE);
+            Machine {
+                dmac,
+                interrupts: DmaInterrupts::new(),
+                ram: vec![0; 0x2_0000],
+                scratchpad: vec![0; 0x4000],
+                gif: Gif::new(),
+                sink: Sink::default(),
+            }
+        }
+
+        // With the ring at RING and `drain` taking from it
+        fn mfifo(drain: u32) -> Self {
+            let mut m = Machine::new();
+            m.dmac.write(D_RBOR, RING);
+            m.dmac.write(D_RBSR, RING_MASK);
+            m.dmac
+                .write(D_CTRL, D_CTRL_DMAE | drain << D_CTRL_MFD_SHIFT);
+            m
+        }
+
+        fn write_scratchpad(&mut self, addr: usize, qwords: &[u128]) {
+            for (i, qword) in qwords.iter().enumerate() {
+                let offset = addr + i * 16;
+                self.scratchpad[offset..offset + 16].copy_from_slice(&qword.to_le_bytes());
+            }
+        }
+
+        fn write_ram(&mut self, addr: u32, qwords: &[u128]) {
+            for (i, qword) in qwords.iter().enumerate() {
+                let offset = addr as usize + i * 16;
+                self.ram[offset..offset + 16].copy_from_slice(&qword.to_le_bytes());
+            }
+        }
+
+        fn scratchpad_qword(&self, addr: usize) -> u128 {
+            u128::from_le_bytes(self.scratchpad[addr..addr + 16].try_into().unwrap())
+        }
+
+        // Everything a channel has brought to its device so far
+        fn fifo(&mut self, channel: usize) -> Vec<u128> {
+            std::iter::from_fn(|| self.dmac.pop(channel)).collect()
+        }
+
+        fn ram_qword(&self, addr: u32) -> u128 {
+            let addr = addr as usize;
+            u128::from_le_bytes(self.ram[addr..addr + 16].try_into().unwrap())
+        }
+
+        fn start_chain(&mut self, channel: usize, reg: u32, addr: u32) {
+            let base = CHANNEL_BASES[channel];
+            self.dmac.write(base + reg, addr);
+            self.dmac.write(base + QWC, 0);
+            self.dmac.write(
+                base + CHCR,
+                CHCR_STR | MODE_CHAIN << CHCR_MOD_SHIFT | CHCR_DIR,
+            );
+        }
+
+        fn service(&mut self, channel: usize) -> Option<u64> {
+            let mut mem = DmaMemory {
+                ram: &mut self.ram,
+                scratchpad: &mut self.scratchpad,
+            };
+            self.dmac.service(channel, &mut mem, &mut self.interrupts)
+        }
+
+        // Runs a channel until it stops or stalls, feeding the GIF whatever reaches its FIFO
+        fn run(&mut self, channel: usize) {
+            while self.service(channel).is_some() {}
+            while let Some(qword) = self.dmac.pop(GIF) {
+                self.gif.push(Path::Path3, qword);
+            }
+            self.gif.run(&mut self.sink);
+        }
+
+        fn reg(&self, channel: usize, reg: u32) -> u32 {
+            self.dmac.read(CHANNEL_BASES[channel] + reg).unwrap()
+        }
+
+        fn done(&self, channel: usize) -> bool {
+            !self.dmac.is_running(channel)
+                && self.reg(channel, CHCR) & CHCR_STR == 0
+                && self.interrupts.read() & 1 << channel != 0
+        }
+
+        fn meis(&self) -> bool {
+            self.interrupts.read() & 1 << DMA_INT_MEIS != 0
+        }
+    }
+
+    #[test]
+    fn drain_stalls_on_empty_ring_and_resumes_after_refill() {
+        let mut m = Machine::mfifo(MFD_GIF);
+        let start = RING + 0x50;
+        let [tag1, data1] = gs_packet(0x01, 0x1111);
+        let [tag2, data2] = gs_packet(0x02, 0x2222);
+
+        // SPR_FROM hasn't written anything, so the drain finds the ring empty straight away
+        m.dmac.write(CHANNEL_BASES[SPR_FROM] + MADR, start);
+        m.start_chain(GIF, TADR, start);
+        m.run(GIF);
+        assert!(m.meis());
+        assert!(m.dmac.is_running(GIF));
+        assert!(m.sink.writes.is_empty());
+        m.interrupts.write(1 << DMA_INT_MEIS);
+
+        // The first packet fills the last three slots, the second's DMA tag wraps around to the
+        // start of the ring
+        m.write_scratchpad(
+            0,
+            &[
+                dma_tag(TAG_CNT, 4, start),
+                dma_tag(TAG_CNT, 2, 0),
+                tag1,
+                data1,
+                dma_tag(TAG_END, 2, 0),
+                dma_tag(TAG_END, 0, RING + 0x10),
+            ],
+        );
+        m.start_chain(SPR_FROM, SADR, 0);
+        m.run(SPR_FROM);
+        assert!(!m.dmac.is_running(SPR_FROM));
+        assert_eq!(m.ram_qword(RING), dma_tag(TAG_END, 2, 0));
+        assert_eq!(m.ram_qword(RING + 0x80), 0);
+        assert!(m.dmac.take_kicked().any(|channel| channel == GIF));
+
+        // The drain gets through the first packet and the second's tag, then catches up
+        m.run(GIF);
+        assert_eq!(m.sink.writes, [(0x01, 0x1111)]);
+        assert!(m.meis());
+        assert!(m.dmac.is_running(GIF));
+        m.interrupts.write(1 << DMA_INT_MEIS);
+
+        m.write_scratchpad(
+            0x100,
+            &[
+                dma_tag(TAG_CNT, 2, RING + 0x10),
+                tag2,
+                data2,
+                dma_tag(TAG_END, 0, RING + 0x30),
+            ],
+        );
+        m.start_chain(SPR_FROM, SADR, 0x100);
+        m.run(SPR_FROM);
+        assert!(m.dmac.take_kicked().any(|channel| channel == GIF));
+
+        m.run(GIF);
+        assert_eq!(m.sink.writes, [(0x01, 0x1111), (0x02, 0x2222)]);
+        assert!(!m.meis());
+        assert!(!m.dmac.is_running(GIF));
+        assert_ne!(m.interrupts.read() & 1 << GIF, 0);
+    }
+
+    #[test]
+    fn drain_waiting_on_its_device_doesnt_raise_meis() {
+        let mut m = Machine::mfifo(MFD_VIF1);
+        let base = CHANNEL_BASES[VIF1];
+        m.dmac.write(base + MADR, 0x100);
+        m.dmac.write(base + QWC, 2);
+        // DIR clear: VIF1 brings data from the VIF into memory
+        m.dmac
+            .write(base + CHCR, CHCR_STR | MODE_NORMAL << CHCR_MOD_SHIFT);
+
+        m.run(VIF1);
+        assert!(m.dmac.is_running(VIF1));
+        assert!(!m.meis());
+
+        m.dmac.push(VIF1, 0xAAAA);
+        m.run(VIF1);
+        assert!(m.dmac.is_running(VIF1));
+        assert!(!m.meis());
+
+        m.dmac.push(VIF1, 0xBBBB);
+        m.run(VIF1);
+        assert!(!m.dmac.is_running(VIF1));
+        assert!(!m.meis());
+        assert_eq!(m.ram_qword(0x100), 0xAAAA);
+        assert_eq!(m.ram_qword(0x110), 0xBBBB);
+        assert_ne!(m.interrupts.read() & 1 << VIF1, 0);
+    }
+
+    #[test]
+    fn normal_transfer_moves_qwc_quadwords_from_madr() {
+        let mut m = Machine::new();
+        m.write_ram(0x1000, &[1, 2, 3]);
+        let base = CHANNEL_BASES[VIF1];
+        m.dmac.write(base + MADR, 0x1000);
+        m.dmac.write(base + QWC, 3);
+        m.dmac.write(base + CHCR, CHCR_STR | CHCR_DIR);
+
+        // One slice for the lot, then the channel stops once its time has passed
+        assert_eq!(m.service(VIF1), Some(3 * CYCLES_PER_QWORD));
+        assert!(m.dmac.is_running(VIF1));
+        assert_eq!(m.service(VIF1), None);
+        assert!(m.done(VIF1));
+        assert_eq!(m.fifo(VIF1), [1, 2, 3]);
+        assert_eq!(m.reg(VIF1, MADR), 0x1030);
+        assert_eq!(m.reg(VIF1, QWC), 0);
+    }
+
+    #[test]
+    fn source_chain_calls_and_returns_through_the_address_stack() {
+        let mut m = Machine::new();
+        m.write_ram(0x1000, &[dma_tag(TAG_CALL, 1, 0x2000), 0xA]);
+        m.write_ram(0x1020, &[dma_tag(TAG_END, 1, 0), 0xE]);
+        m.write_ram(0x2000, &[dma_tag(TAG_CALL, 1, 0x3000), 0xB]);
+        m.write_ram(0x2020, &[dma_tag(TAG_RET, 1, 0), 0xD]);
+        m.write_ram(0x3000, &[dma_tag(TAG_RET, 1, 0), 0xC]);
+        m.start_chain(VIF1, TADR, 0x1000);
+
+        // A slice moves the last tag's data and reads the next tag, the first just the tag
+        assert_eq!(m.service(VIF1), Some(CYCLES_PER_QWORD));
+        assert_eq!(m.reg(VIF1, ASR0), 0x1020);
+        assert_eq!(m.service(VIF1), Some(2 * CYCLES_PER_QWORD));
+        assert_eq!(m.reg(VIF1, ASR1), 0x2020);
+        assert_eq!(m.reg(VIF1, CHCR) & CHCR_ASP_MASK, 2 << CHCR_ASP_SHIFT);
+        m.run(VIF1);
+        assert!(m.done(VIF1));
+        assert_eq!(m.fifo(VIF1), [0xA, 0xB, 0xC, 0xD, 0xE]);
+        assert_eq!(m.reg(VIF1, CHCR) & CHCR_ASP_MASK, 0);
+        // END leaves TADR on itself
+        assert_eq!(m.reg(VIF1, TADR), 0x1020);
+    }
+
+    #[test]
+    fn refe_ends_the_chain_with_data_from_its_address() {
+        let mut m = Machine::new();
+        let refe = dma_tag(TAG_REFE, 2, 0x4000);
+        m.write_ram(0x1000, &[dma_tag(TAG_REF, 1, 0x4020), refe]);
+        m.write_ram(0x4000, &[1, 2, 3]);
+        let base = CHANNEL_BASES[VIF1];
+        m.dmac.write(base + TADR, 0x1000);
+        // With TTE the tags go to the device ahead of their data
+        m.dmac.write(
+            base + CHCR,
+            CHCR_STR | CHCR_TTE | MODE_CHAIN << CHCR_MOD_SHIFT | CHCR_DIR,
+        );
+
+        m.run(VIF1);
+        assert!(m.done(VIF1));
+        assert_eq!(m.fifo(VIF1), [dma_tag(TAG_REF, 1, 0x4020), 3, refe, 1, 2]);
+        assert_eq!(m.reg(VIF1, TADR), 0x1020);
+        assert_eq!(m.reg(VIF1, MADR), 0x4020);
+        // The upper half of CHCR holds the last tag
+        assert_eq!(m.reg(VIF1, CHCR) >> 16, (refe as u32) >> 16);
+    }
+
+    #[test]
+    fn tag_irq_ends_the_chain_only_with_tie() {
+        let irq = 1 << 31;
+        for (tie, moved) in [(0, vec![1, 2, 3]), (CHCR_TIE, vec![1])] {
+            let mut m = Machine::new();
+            m.write_ram(
+                0x1000,
+                &[
+                    dma_tag(TAG_CNT, 1, 0) | irq,
+                    1,
+                    dma_tag(TAG_CNT, 1, 0),
+                    2,
+                    dma_tag(TAG_END, 1, 0),
+                    3,
+                ],
+            );
+            let base = CHANNEL_BASES[VIF1];
+            m.dmac.write(base + TADR, 0x1000);
+            m.dmac.write(
+                base + CHCR,
+                CHCR_STR | tie | MODE_CHAIN << CHCR_MOD_SHIFT | CHCR_DIR,
+            );
+
+            m.run(VIF1);
+            assert!(m.done(VIF1));
+            assert_eq!(m.fifo(VIF1), moved);
+        }
+    }
+
+    #[test]
+    fn destination_chain_places_data_where_its_tags_say() {
+        let mut m = Machine::new();
+        m.write_scratchpad(
+            0,
+            &[
+                dma_tag(TAG_CNTS, 1, 0x5000),
+                0xA,
+                dma_tag(TAG_CNT, 2, 0x6000),
+                0xB,
+                0xC,
+                dma_tag(TAG_END, 1, 0x7000),
+                0xD,
+            ],
+        );
+        let base = CHANNEL_BASES[SPR_FROM];
+        m.dmac.write(base + SADR, 0);
+        m.dmac.write(base + QWC, 0);
+        m.dmac
+            .write(base + CHCR, CHCR_STR | MODE_CHAIN << CHCR_MOD_SHIFT);
+
+        m.run(SPR_FROM);
+        assert!(m.done(SPR_FROM));
+        assert_eq!(m.ram_qword(0x5000), 0xA);
+        assert_eq!(m.ram_qword(0x6000), 0xB);
+        assert_eq!(m.ram_qword(0x6010), 0xC);
+        assert_eq!(m.ram_qword(0x7000), 0xD);
+        assert_eq!(m.reg(SPR_FROM, SADR), 0x70);
+    }
+
+    #[test]
+    fn interleave_skips_sqwc_between_blocks_of_tqwc() {
+        let mut m = Machine::new();
+        m.write_ram(0x1000, &[1, 2, 3, 4, 5, 6, 7]);
+        // Blocks of two quadwords, one skipped between them
+        m.dmac.write(D_SQWC, 2 << 16 | 1);
+        let base = CHANNEL_BASES[SPR_TO];
+        m.dmac.write(base + MADR, 0x1000);
+        m.dmac.write(base + QWC, 5);
+        m.dmac.write(base + SADR, 0x100);
+        m.dmac
+            .write(base + CHCR, CHCR_STR | MODE_INTERLEAVE << CHCR_MOD_SHIFT);
+
+        assert_eq!(m.service(SPR_TO), Some(5 * CYCLES_PER_QWORD));
+        m.run(SPR_TO);
+        assert!(m.done(SPR_TO));
+        let copied: Vec<u128> = (0..5).map(|i| m.scratchpad_qword(0x100 + 16 * i)).collect();
+        assert_eq!(copied, [1, 2, 4, 5, 7]);
+        assert_eq!(m.reg(SPR_TO, MADR), 0x1070);
+    }
+}
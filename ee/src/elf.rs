@@ -0,0 +1,406 @@
+// ELF loading for booting homebrew directly, without a BIOS.
+//
+// Only little-endian ELF32 MIPS executables are accepted. PT_LOAD segments are copied straight
+// into RAM or scratchpad at their virtual addresses and the part of each segment past its file
+// data (.bss) is zeroed. A segment aimed anywhere else, such as at device registers, is
+// refused. `boot` then sets up registers the way the EE kernel leaves them when it starts a
+// program with LoadExecPS2: a0/a1 hold argc/argv, sp points at a stack at the top of RAM.
+
+use std::fmt;
+
+use crate::Cpu;
+use crate::bus::Bus;
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const ELFDATA2MSB: u8 = 2;
+const ET_EXEC: u16 = 2;
+const EM_MIPS: u16 = 8;
+const PT_LOAD: u32 = 1;
+
+const EHDR_SIZE: usize = 52;
+const PHDR_SIZE: usize = 32;
+const SHDR_SIZE: usize = 40;
+
+// Where the kernel leaves the program arguments, laid out like the `_args` buffer ps2sdk's crt0
+// hands to SetupThread: argc, 16 argv pointers, then 256 bytes of strings
+pub const ARGS_BASE: u32 = 0x01FF_F000;
+pub const MAX_ARGS: usize = 16;
+const ARGS_PAYLOAD: u32 = ARGS_BASE + 4 + 4 * MAX_ARGS as u32;
+const ARGS_PAYLOAD_SIZE: usize = 256;
+// The initial stack grows down from just below the arguments
+pub const STACK_TOP: u32 = ARGS_BASE - 0x10;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    Not32Bit,
+    BigEndian,
+    NotExecutable(u16),
+    NotMips(u16),
+    // A header or segment points outside the file
+    Truncated(&'static str),
+    BadSegment { vaddr: u32, reason: &'static str },
+    // A segment or the arguments don't lie within plain memory (RAM or scratchpad)
+    Unmapped { addr: u32 },
+    TooManyArgs,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Not32Bit => write!(f, "not a 32-bit ELF"),
+            ElfError::BigEndian => write!(f, "big-endian ELF, the EE is little-endian"),
+            ElfError::NotExecutable(kind) => {
+                write!(f, "ELF type {} is not an executable (ET_EXEC)", kind)
+            }
+            ElfError::NotMips(machine) => write!(f, "ELF machine {} is not MIPS", machine),
+            ElfError::Truncated(what) => write!(f, "ELF {} lies outside the file", what),
+            ElfError::BadSegment { vaddr, reason } => {
+                write!(f, "bad segment at {:08x}: {}", vaddr, reason)
+            }
+            ElfError::Unmapped { addr } => {
+                write!(
+                    f,
+                    "data at {:08x} doesn't lie within RAM or scratchpad",
+                    addr
+                )
+            }
+            ElfError::TooManyArgs => write!(
+                f,
+                "too many or too long arguments (at most {} totalling {} bytes)",
+                MAX_ARGS, ARGS_PAYLOAD_SIZE
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ElfError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    pub vaddr: u32,
+    pub offset: u32,
+    pub file_size: u32,
+    pub mem_size: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Section {
+    pub name: u32,
+    pub kind: u32,
+    pub addr: u32,
+    pub offset: u32,
+    pub size: u32,
+    pub link: u32,
+    pub entry_size: u32,
+}
+
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_NOBITS: u32 = 8;
+
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+// `offset..offset + len` as a range into a slice of `size` bytes, if it fits
+fn range_within(offset: u32, len: u64, size: usize) -> Option<std::ops::Range<usize>> {
+    let end = offset as u64 + len;
+    (end <= size as u64).then_some(offset as usize..end as usize)
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < 16 || &data[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != ELFCLASS32 {
+            return Err(ElfError::Not32Bit);
+        }
+        match data[5] {
+            ELFDATA2LSB => {}
+            ELFDATA2MSB => return Err(ElfError::BigEndian),
+            _ => return Err(ElfError::NotElf),
+        }
+        if data.len() < EHDR_SIZE {
+            return Err(ElfError::Truncated("header"));
+        }
+        let kind = read_u16(data, 16);
+        if kind != ET_EXEC {
+            return Err(ElfError::NotExecutable(kind));
+        }
+        let machine = read_u16(data, 18);
+        if machine != EM_MIPS {
+            return Err(ElfError::NotMips(machine));
+        }
+        let entry = read_u32(data, 24);
+        let phoff = read_u32(data, 28);
+        let phentsize = read_u16(data, 42) as usize;
+        let phnum = read_u16(data, 44) as u64;
+        if phnum > 0 && phentsize < PHDR_SIZE {
+            return Err(ElfError::Truncated("program header table"));
+        }
+        let table = range_within(phoff, phnum * phentsize as u64, data.len())
+            .ok_or(ElfError::Truncated("program header table"))?;
+
+        let mut segments = Vec::new();
+        // With no program headers e_phentsize may well be 0
+        for phdr in data[table].chunks_exact(phentsize.max(PHDR_SIZE)) {
+            if read_u32(phdr, 0) != PT_LOAD {
+                continue;
+            }
+            let segment = Segment {
+                offset: read_u32(phdr, 4),
+                vaddr: read_u32(phdr, 8),
+                file_size: read_u32(phdr, 16),
+                mem_size: read_u32(phdr, 20),
+            };
+            if segment.file_size > segment.mem_size {
+                return Err(ElfError::BadSegment {
+                    vaddr: segment.vaddr,
+                    reason: "file size is larger than memory size",
+                });
+            }
+            if segment.vaddr.checked_add(segment.mem_size).is_none() {
+                return Err(ElfError::BadSegment {
+                    vaddr: segment.vaddr,
+                    reason: "wraps around the address space",
+                });
+            }
+            if range_within(segment.offset, segment.file_size as u64, data.len()).is_none() {
+                return Err(ElfError::Truncated("segment"));
+            }
+            segments.push(segment);
+        }
+
+        Ok(Elf {
+            data,
+            entry,
+            segments,
+        })
+    }
+
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    // Section headers; executables don't need them to run, so they are only parsed on demand
+    pub fn sections(&self) -> Result<Vec<Section>, ElfError> {
+        let data = self.data;
+        let shoff = read_u32(data, 32);
+        let shentsize = read_u16(data, 46) as usize;
+        let shnum = read_u16(data, 48) as u64;
+        if shnum == 0 {
+            return Ok(Vec::new());
+        }
+        if shentsize < SHDR_SIZE {
+            return Err(ElfError::Truncated("section header table"));
+        }
+        let table = range_within(shoff, shnum * shentsize as u64, data.len())
+            .ok_or(ElfError::Truncated("section header table"))?;
+        Ok(data[table]
+            .chunks_exact(shentsize)
+            .map(|shdr| Section {
+                name: read_u32(shdr, 0),
+                kind: read_u32(shdr, 4),
+                addr: read_u32(shdr, 12),
+                offset: read_u32(shdr, 16),
+                size: read_u32(shdr, 20),
+                link: read_u32(shdr, 24),
+                entry_size: read_u32(shdr, 36),
+            })
+            .collect())
+    }
+
+    // File contents of a section, which is empty for SHT_NOBITS sections like .bss
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8], ElfError> {
+        if section.kind == SHT_NOBITS {
+            return Ok(&[]);
+        }
+        let range = range_within(section.offset, section.size as u64, self.data.len())
+            .ok_or(ElfError::Truncated("section"))?;
+        Ok(&self.data[range])
+    }
+
+    // Copies every PT_LOAD segment into memory and zero fills the rest of it. Every segment is
+    // checked before any is copied, so a rejected file leaves memory untouched.
+    pub fn load(&self, bus: &mut dyn Bus) -> Result<(), ElfError> {
+        for segment in &self.segments {
+            memory(bus, segment.vaddr, segment.mem_size)?;
+        }
+        for segment in &self.segments {
+            let start = segment.offset as usize;
+            let file_data = &self.data[start..start + segment.file_size as usize];
+            let memory = memory(bus, segment.vaddr, segment.mem_size)?;
+            let (file_part, bss) = memory.split_at_mut(file_data.len());
+            file_part.copy_from_slice(file_data);
+            bss.fill(0);
+        }
+        Ok(())
+    }
+}
+
+// The plain memory behind `addr..addr + len`
+fn memory(bus: &mut dyn Bus, addr: u32, len: u32) -> Result<&mut [u8], ElfError> {
+    if len == 0 {
+        return Ok(&mut []);
+    }
+    bus.memory_mut(addr, len).ok_or(ElfError::Unmapped { addr })
+}
+
+fn write_bytes(bus: &mut dyn Bus, addr: u32, bytes: &[u8]) -> Result<(), ElfError> {
+    memory(bus, addr, bytes.len() as u32)?.copy_from_slice(bytes);
+    Ok(())
+}
+
+// Loads `data` and points the CPU at its entry with `args` as argv
+pub fn boot(data: &[u8], args: &[&str], cpu: &mut Cpu, bus: &mut dyn Bus) -> Result<(), ElfError> {
+    let elf = Elf::parse(data)?;
+    let payload_size: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    if args.len() > MAX_ARGS || payload_size > ARGS_PAYLOAD_SIZE {
+        return Err(ElfError::TooManyArgs);
+    }
+    elf.load(bus)?;
+
+    write_bytes(bus, ARGS_BASE, &(args.len() as u32).to_le_bytes())?;
+    let mut string = ARGS_PAYLOAD;
+    for (index, arg) in args.iter().enumerate() {
+        write_bytes(bus, ARGS_BASE + 4 + 4 * index as u32, &string.to_le_bytes())?;
+        write_bytes(bus, string, arg.as_bytes())?;
+        write_bytes(bus, string + arg.len() as u32, &[0])?;
+        string += arg.len() as u32 + 1;
+    }
+
+    cpu.set_pc(elf.entry);
+    cpu.write_gpr_dword(4, args.len() as u64);
+    cpu.write_gpr_dword(5, (ARGS_BASE + 4) as u64);
+    cpu.write_gpr_dword(29, STACK_TOP as u64);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+    use crate::ps2_bus::Ps2Bus;
+
+    // An executable with one PT_LOAD per (vaddr, file data, memory size), entry at the first
+    fn executable(segments: &[(u32, &[u8], u32)]) -> Vec<u8> {
+        let mut data = vec![0; EHDR_SIZE];
+        data[..4].copy_from_slice(ELF_MAGIC);
+        data[4] = ELFCLASS32;
+        data[5] = ELFDATA2LSB;
+        data[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        data[18..20].copy_from_slice(&EM_MIPS.to_le_bytes());
+        data[24..28].copy_from_slice(&segments.first().map_or(0, |s| s.0).to_le_bytes());
+        data[28..32].copy_from_slice(&(EHDR_SIZE as u32).to_le_bytes());
+        data[42..44].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        data[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        let mut offset = (EHDR_SIZE + PHDR_SIZE * segments.len()) as u32;
+        for &(vaddr, file_data, mem_size) in segments {
+            let mut phdr = [0; PHDR_SIZE];
+            phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            phdr[4..8].copy_from_slice(&offset.to_le_bytes());
+            phdr[8..12].copy_from_slice(&vaddr.to_le_bytes());
+            phdr[16..20].copy_from_slice(&(file_data.len() as u32).to_le_bytes());
+            phdr[20..24].copy_from_slice(&mem_size.to_le_bytes());
+            data.extend_from_slice(&phdr);
+            offset += file_data.len() as u32;
+        }
+        for &(_, file_data, _) in segments {
+            data.extend_from_slice(file_data);
+        }
+        data
+    }
+
+    #[test]
+    fn boot_loads_segments_zeroes_bss_and_passes_args() {
+        let data = executable(&[(0x0010_0000, &[1, 2, 3, 4], 8), (0x0020_0000, &[5, 6], 2)]);
+        let mut ram = Ram::new(0x0200_0000);
+        ram.write32(0x0010_0004, 0xFFFF_FFFF).unwrap();
+        let mut cpu = Cpu::new();
+        boot(&data, &["game", "-x"], &mut cpu, &mut ram).unwrap();
+
+        assert_eq!(ram.read64(0x0010_0000).unwrap(), 0x0403_0201);
+        assert_eq!(ram.read16(0x0020_0000).unwrap(), 0x0605);
+        assert_eq!(cpu.pc(), 0x0010_0000);
+        assert_eq!(cpu.gpr(4), 2);
+        assert_eq!(cpu.gpr(5), (ARGS_BASE + 4) as u128);
+        assert_eq!(cpu.gpr(29), STACK_TOP as u128);
+        assert_eq!(ram.read32(ARGS_BASE).unwrap(), 2);
+        let argv1 = ram.read32(ARGS_BASE + 8).unwrap();
+        assert_eq!(argv1, ARGS_PAYLOAD + 5);
+        let arg: Vec<u8> = (argv1..argv1 + 3).map(|a| ram.read8(a).unwrap()).collect();
+        assert_eq!(arg, b"-x\0");
+    }
+
+    #[test]
+    fn big_endian_files_are_refused() {
+        let mut data = executable(&[(0x0010_0000, &[0; 4], 4)]);
+        data[5] = ELFDATA2MSB;
+        assert_eq!(Elf::parse(&data).err(), Some(ElfError::BigEndian));
+    }
+
+    #[test]
+    fn files_without_program_headers_have_no_segments() {
+        let mut data = executable(&[]);
+        data[42..44].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(Elf::parse(&data).unwrap().segments, []);
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        let data = executable(&[(0x0010_0000, &[0; 16], 16)]);
+        assert_eq!(
+            Elf::parse(&data[..40]).err(),
+            Some(ElfError::Truncated("header"))
+        );
+        assert_eq!(
+            Elf::parse(&data[..EHDR_SIZE + 8]).err(),
+            Some(ElfError::Truncated("program header table"))
+        );
+        assert_eq!(
+            Elf::parse(&data[..data.len() - 1]).err(),
+            Some(ElfError::Truncated("segment"))
+        );
+    }
+
+    #[test]
+    fn segments_outside_plain_memory_are_refused_before_anything_loads() {
+        // The second segment lands on the SIO registers
+        let data = executable(&[(0x0010_0000, &[1, 2, 3, 4], 4), (0x1000_F180, b"!", 1)]);
+        let elf = Elf::parse(&data).unwrap();
+        let mut bus = Ps2Bus::new();
+        assert_eq!(
+            elf.load(&mut bus),
+            Err(ElfError::Unmapped { addr: 0x1000_F180 })
+        );
+        assert_eq!(bus.read32(0x0010_0000).unwrap(), 0);
+
+        // A segment running off the end of RAM
+        let data = executable(&[(0x01FF_FFFC, &[0; 4], 8)]);
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(
+            elf.load(&mut bus),
+            Err(ElfError::Unmapped { addr: 0x01FF_FFFC })
+        );
+
+        // Scratchpad is plain memory too
+        let data = executable(&[(0x7000_0000, &[9], 1)]);
+        Elf::parse(&data).unwrap().load(&mut bus).unwrap();
+        assert_eq!(bus.read8(0x7000_0000).unwrap(), 9);
+    }
+}
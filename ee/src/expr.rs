@@ -0,0 +1,446 @@
+// Condition expressions for breakpoints and watchpoints, e.g. `a0 == 0x1234 && hitcount > 3`.
+//
+// Operands are integer literals (decimal or 0x-prefixed hex), register names (`a0`, `$a0`, `r4`,
+// `$4`, `pc`, `hi`, `lo`, `sa`) and `hitcount`. Operators follow C precedence:
+// `||`, `&&`, `|`, `^`, `&`, `== !=`, `< <= > >=`, `<< >>`, `+ -`, `* / %`, and unary `! ~ -`.
+// All arithmetic is on unsigned 64-bit values; registers read as their low doubleword.
+
+use std::fmt;
+
+use crate::Cpu;
+use crate::disasm::GPR_NAMES;
+
+// How deep parentheses and unary operators may nest, so a hostile condition can't overflow the
+// stack while it is parsed or evaluated
+const MAX_NESTING: usize = 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExprError {
+    pub pos: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.pos + 1)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Literal(u64),
+    Gpr(usize),
+    Pc,
+    Hi,
+    Lo,
+    Sa,
+    HitCount,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum UnaryOp {
+    Not,
+    BitNot,
+    Neg,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Shl,
+    Shr,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+impl BinaryOp {
+    // Binding strength, higher binds tighter
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::BitOr => 3,
+            BinaryOp::BitXor => 4,
+            BinaryOp::BitAnd => 5,
+            BinaryOp::Eq | BinaryOp::Ne => 6,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 7,
+            BinaryOp::Shl | BinaryOp::Shr => 8,
+            BinaryOp::Add | BinaryOp::Sub => 9,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 10,
+        }
+    }
+
+    fn apply(self, a: u64, b: u64) -> u64 {
+        match self {
+            BinaryOp::Or => (a != 0 || b != 0) as u64,
+            BinaryOp::And => (a != 0 && b != 0) as u64,
+            BinaryOp::BitOr => a | b,
+            BinaryOp::BitXor => a ^ b,
+            BinaryOp::BitAnd => a & b,
+            BinaryOp::Eq => (a == b) as u64,
+            BinaryOp::Ne => (a != b) as u64,
+            BinaryOp::Lt => (a < b) as u64,
+            BinaryOp::Le => (a <= b) as u64,
+            BinaryOp::Gt => (a > b) as u64,
+            BinaryOp::Ge => (a >= b) as u64,
+            BinaryOp::Shl => a.checked_shl(b as u32).unwrap_or(0),
+            BinaryOp::Shr => a.checked_shr(b as u32).unwrap_or(0),
+            BinaryOp::Add => a.wrapping_add(b),
+            BinaryOp::Sub => a.wrapping_sub(b),
+            BinaryOp::Mul => a.wrapping_mul(b),
+            // Division by zero yields 0 rather than failing the whole condition
+            BinaryOp::Div => a.checked_div(b).unwrap_or(0),
+            BinaryOp::Rem => a.checked_rem(b).unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Expr {
+    Operand(Operand),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval(&self, cpu: &Cpu, hitcount: u64) -> u64 {
+        match self {
+            Expr::Operand(operand) => match *operand {
+                Operand::Literal(value) => value,
+                Operand::Gpr(index) => cpu.gpr(index) as u64,
+                Operand::Pc => cpu.pc as u64,
+                Operand::Hi => cpu.hi0,
+                Operand::Lo => cpu.lo0,
+                Operand::Sa => cpu.sa,
+                Operand::HitCount => hitcount,
+            },
+            Expr::Unary(op, expr) => {
+                let value = expr.eval(cpu, hitcount);
+                match op {
+                    UnaryOp::Not => (value == 0) as u64,
+                    UnaryOp::BitNot => !value,
+                    UnaryOp::Neg => value.wrapping_neg(),
+                }
+            }
+            Expr::Binary(op, a, b) => op.apply(a.eval(cpu, hitcount), b.eval(cpu, hitcount)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser {
+            src: source.as_bytes(),
+            pos: 0,
+            nesting: 0,
+        };
+        let expr = parser.expr(0)?;
+        parser.skip_whitespace();
+        if parser.pos != parser.src.len() {
+            return Err(parser.error("unexpected trailing input"));
+        }
+        Ok(Condition {
+            source: source.to_string(),
+            expr,
+        })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&self, cpu: &Cpu, hitcount: u64) -> bool {
+        self.expr.eval(cpu, hitcount) != 0
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a [u8],
+    pos: usize,
+    // Parentheses and unary operators open around the current position
+    nesting: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> ExprError {
+        ExprError {
+            pos: self.pos,
+            message: message.to_string(),
+        }
+    }
+
+    fn nest(&mut self) -> Result<(), ExprError> {
+        if self.nesting == MAX_NESTING {
+            return Err(self.error("expression nested too deeply"));
+        }
+        self.nesting += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.src.len() && self.src[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek_binary_op(&mut self) -> Option<(BinaryOp, usize)> {
+        self.skip_whitespace();
+        let rest = &self.src[self.pos..];
+        const OPS: [(&[u8], BinaryOp); 18] = [
+            (b"||", BinaryOp::Or),
+            (b"&&", BinaryOp::And),
+            (b"==", BinaryOp::Eq),
+            (b"!=", BinaryOp::Ne),
+            (b"<=", BinaryOp::Le),
+            (b">=", BinaryOp::Ge),
+            (b"<<", BinaryOp::Shl),
+            (b">>", BinaryOp::Shr),
+            (b"|", BinaryOp::BitOr),
+            (b"^", BinaryOp::BitXor),
+            (b"&", BinaryOp::BitAnd),
+            (b"<", BinaryOp::Lt),
+            (b">", BinaryOp::Gt),
+            (b"+", BinaryOp::Add),
+            (b"-", BinaryOp::Sub),
+            (b"*", BinaryOp::Mul),
+            (b"/", BinaryOp::Div),
+            (b"%", BinaryOp::Rem),
+        ];
+        OPS.iter()
+            .find(|(token, _)| rest.starts_with(token))
+            .map(|&(token, op)| (op, token.len()))
+    }
+
+    // Precedence climbing: parses operators binding tighter than `min_precedence`
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        while let Some((op, len)) = self.peek_binary_op() {
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.pos += len;
+            let rhs = self.expr(op.precedence())?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        self.skip_whitespace();
+        let op = match self.src.get(self.pos) {
+            Some(b'!') => UnaryOp::Not,
+            Some(b'~') => UnaryOp::BitNot,
+            Some(b'-') => UnaryOp::Neg,
+            _ => return self.primary(),
+        };
+        self.nest()?;
+        self.pos += 1;
+        let expr = self.unary()?;
+        self.nesting -= 1;
+        Ok(Expr::Unary(op, Box::new(expr)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        self.skip_whitespace();
+        match self.src.get(self.pos) {
+            Some(b'(') => {
+                self.nest()?;
+                self.pos += 1;
+                let expr = self.expr(0)?;
+                self.skip_whitespace();
+                if self.src.get(self.pos) != Some(&b')') {
+                    return Err(self.error("expected ')'"));
+                }
+                self.pos += 1;
+                self.nesting -= 1;
+                Ok(expr)
+            }
+            Some(c) if c.is_ascii_digit() => self.literal(),
+            Some(c) if c.is_ascii_alphabetic() || *c == b'$' || *c == b'_' => self.identifier(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn take_word(&mut self) -> &str {
+        let start = self.pos;
+        while self.pos < self.src.len()
+            && (self.src[self.pos].is_ascii_alphanumeric() || self.src[self.pos] == b'_')
+        {
+            self.pos += 1;
+        }
+        // Only ASCII bytes were consumed, so this can't split a character
+        std::str::from_utf8(&self.src[start..self.pos]).unwrap()
+    }
+
+    fn literal(&mut self) -> Result<Expr, ExprError> {
+        let start = self.pos;
+        let word = self.take_word().to_ascii_lowercase();
+        let value = match word.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+        value
+            .map(|value| Expr::Operand(Operand::Literal(value)))
+            .map_err(|_| ExprError {
+                pos: start,
+                message: format!("invalid number '{}'", word),
+            })
+    }
+
+    fn identifier(&mut self) -> Result<Expr, ExprError> {
+        let start = self.pos;
+        let dollar = self.src[self.pos] == b'$';
+        if dollar {
+            self.pos += 1;
+        }
+        let word = self.take_word().to_ascii_lowercase();
+        let operand = match word.as_str() {
+            // `$4` style register numbers
+            _ if dollar && word.parse::<usize>().is_ok_and(|index| index < 32) => {
+                Operand::Gpr(word.parse().unwrap())
+            }
+            "pc" => Operand::Pc,
+            "hi" => Operand::Hi,
+            "lo" => Operand::Lo,
+            "sa" => Operand::Sa,
+            "hitcount" => Operand::HitCount,
+            "s8" => Operand::Gpr(30),
+            _ => match GPR_NAMES.iter().position(|&name| name == word) {
+                Some(index) => Operand::Gpr(index),
+                None => match word.strip_prefix('r').and_then(|n| n.parse::<usize>().ok()) {
+                    Some(index) if index < 32 => Operand::Gpr(index),
+                    _ => {
+                        return Err(ExprError {
+                            pos: start,
+                            message: format!("unknown identifier '{}'", word),
+                        });
+                    }
+                },
+            },
+        };
+        Ok(Expr::Operand(operand))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(source: &str, cpu: &Cpu, hitcount: u64) -> u64 {
+        Condition::parse(source).unwrap().expr.eval(cpu, hitcount)
+    }
+
+    fn error(source: &str) -> ExprError {
+        Condition::parse(source).unwrap_err()
+    }
+
+    #[test]
+    fn operators_follow_c_precedence() {
+        let cpu = Cpu::new();
+        assert_eq!(value("1 + 2 * 3", &cpu, 0), 7);
+        assert_eq!(value("(1 + 2) * 3", &cpu, 0), 9);
+        assert_eq!(value("10 - 4 - 3", &cpu, 0), 3);
+        assert_eq!(value("1 << 4 | 1 == 1", &cpu, 0), 17);
+        assert_eq!(value("6 & 3 ^ 1", &cpu, 0), 3);
+        assert_eq!(value("1 < 2 == 1", &cpu, 0), 1);
+        assert_eq!(value("0 || 2 && 3", &cpu, 0), 1);
+        assert_eq!(value("-1", &cpu, 0), u64::MAX);
+        assert_eq!(value("~0x0f & 0xff", &cpu, 0), 0xF0);
+        assert_eq!(value("!5 + !0", &cpu, 0), 1);
+        assert_eq!(value("7 / 0 + 7 % 0", &cpu, 0), 0);
+        assert_eq!(value("1 << 64", &cpu, 0), 0);
+    }
+
+    #[test]
+    fn operands_read_registers_and_hitcount() {
+        let mut cpu = Cpu::new();
+        cpu.gprs[4] = 0x1234;
+        cpu.gprs[30] = 7;
+        cpu.gprs[31] = 1 << 64 | 5;
+        cpu.pc = 0x0010_0000;
+        cpu.hi0 = 2;
+        cpu.lo0 = 3;
+        cpu.sa = 8;
+        for source in ["a0", "$a0", "A0", "r4", "$4"] {
+            assert_eq!(value(source, &cpu, 0), 0x1234, "{}", source);
+        }
+        assert_eq!(value("fp + s8", &cpu, 0), 14);
+        // Only the low doubleword
+        assert_eq!(value("ra", &cpu, 0), 5);
+        assert_eq!(value("pc", &cpu, 0), 0x0010_0000);
+        assert_eq!(value("hi * 100 + lo * 10 + sa", &cpu, 0), 238);
+        let condition = Condition::parse("a0 == 0x1234 && hitcount > 3").unwrap();
+        assert!(!condition.eval(&cpu, 3));
+        assert!(condition.eval(&cpu, 4));
+        assert_eq!(condition.to_string(), "a0 == 0x1234 && hitcount > 3");
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(
+            error("a0 =="),
+            ExprError {
+                pos: 5,
+                message: "unexpected end of expression".to_string(),
+            }
+        );
+        assert_eq!(error("a0 == foo").pos, 6);
+        assert_eq!(error("a0 == foo").message, "unknown identifier 'foo'");
+        assert_eq!(error("(1 + 2").message, "expected ')'");
+        assert_eq!(error("1 2").message, "unexpected trailing input");
+        assert_eq!(error("0xzz").message, "invalid number '0xzz'");
+        assert_eq!(error("r32").message, "unknown identifier 'r32'");
+        assert_eq!(error("1 + @").pos, 4);
+    }
+
+    #[test]
+    fn nesting_is_capped() {
+        let cpu = Cpu::new();
+        let parens = |depth| format!("{}1{}", "(".repeat(depth), ")".repeat(depth));
+        assert_eq!(value(&parens(MAX_NESTING), &cpu, 0), 1);
+        assert_eq!(
+            error(&parens(MAX_NESTING + 1)),
+            ExprError {
+                pos: MAX_NESTING,
+                message: "expression nested too deeply".to_string(),
+            }
+        );
+        // Unary operators count the same, and sequential groups don't add up
+        let negs = "-".repeat(MAX_NESTING);
+        assert_eq!(value(&format!("{}1", negs), &cpu, 0), 1);
+        assert_eq!(error(&format!("-{}1", negs)).pos, MAX_NESTING);
+        assert_eq!(error(&format!("-({}1)", negs)).pos, MAX_NESTING);
+        let groups = vec![parens(MAX_NESTING); 3].join(" + ");
+        assert_eq!(value(&groups, &cpu, 0), 3);
+        // Far past the cap is an error, not a stack overflow
+        assert!(Condition::parse(&"(".repeat(1_000_000)).is_err());
+    }
+}
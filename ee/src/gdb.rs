@@ -0,0 +1,818 @@
+// GDB Remote Serial Protocol stub exposing the EE to `gdb-multiarch`.
+//
+// Registers follow GDB's mips64 layout (the one its mips target expects from a target
+// description): r0-r31, status, lo, hi, badvaddr, cause, pc, f0-f31, fcsr, fir, all 64 bits wide.
+// Only the low doubleword of the EE's 128-bit GPRs is visible. The FPU isn't emulated, so its
+// registers read as zero and writes to them are dropped.
+//
+// Supported packets: ? g G p P m M s c Z0-Z4 z0-z4 qSupported qXfer:features:read qAttached
+// qfThreadInfo qsThreadInfo qC H k D, plus Ctrl-C to interrupt a continue.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::bus::Bus;
+use crate::cop0;
+use crate::debugger::{Debugger, StopReason, WatchKind};
+use crate::scheduler::TimedBus;
+use crate::{Cpu, StepResult};
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <architecture>mips:5900</architecture>
+  <feature name="org.gnu.gdb.mips.cpu">
+    <reg name="r0" bitsize="64" regnum="0"/>
+    <reg name="r1" bitsize="64"/>
+    <reg name="r2" bitsize="64"/>
+    <reg name="r3" bitsize="64"/>
+    <reg name="r4" bitsize="64"/>
+    <reg name="r5" bitsize="64"/>
+    <reg name="r6" bitsize="64"/>
+    <reg name="r7" bitsize="64"/>
+    <reg name="r8" bitsize="64"/>
+    <reg name="r9" bitsize="64"/>
+    <reg name="r10" bitsize="64"/>
+    <reg name="r11" bitsize="64"/>
+    <reg name="r12" bitsize="64"/>
+    <reg name="r13" bitsize="64"/>
+    <reg name="r14" bitsize="64"/>
+    <reg name="r15" bitsize="64"/>
+    <reg name="r16" bitsize="64"/>
+    <reg name="r17" bitsize="64"/>
+    <reg name="r18" bitsize="64"/>
+    <reg name="r19" bitsize="64"/>
+    <reg name="r20" bitsize="64"/>
+    <reg name="r21" bitsize="64"/>
+    <reg name="r22" bitsize="64"/>
+    <reg name="r23" bitsize="64"/>
+    <reg name="r24" bitsize="64"/>
+    <reg name="r25" bitsize="64"/>
+    <reg name="r26" bitsize="64"/>
+    <reg name="r27" bitsize="64"/>
+    <reg name="r28" bitsize="64"/>
+    <reg name="r29" bitsize="64"/>
+    <reg name="r30" bitsize="64"/>
+    <reg name="r31" bitsize="64"/>
+    <reg name="lo" bitsize="64" regnum="33"/>
+    <reg name="hi" bitsize="64" regnum="34"/>
+    <reg name="pc" bitsize="64" regnum="37"/>
+  </feature>
+  <feature name="org.gnu.gdb.mips.cp0">
+    <reg name="status" bitsize="64" regnum="32"/>
+    <reg name="badvaddr" bitsize="64" regnum="35"/>
+    <reg name="cause" bitsize="64" regnum="36"/>
+  </feature>
+  <feature name="org.gnu.gdb.mips.fpu">
+    <reg name="f0" bitsize="64" type="ieee_double" regnum="38"/>
+    <reg name="f1" bitsize="64" type="ieee_double"/>
+    <reg name="f2" bitsize="64" type="ieee_double"/>
+    <reg name="f3" bitsize="64" type="ieee_double"/>
+    <reg name="f4" bitsize="64" type="ieee_double"/>
+    <reg name="f5" bitsize="64" type="ieee_double"/>
+    <reg name="f6" bitsize="64" type="ieee_double"/>
+    <reg name="f7" bitsize="64" type="ieee_double"/>
+    <reg name="f8" bitsize="64" type="ieee_double"/>
+    <reg name="f9" bitsize="64" type="ieee_double"/>
+    <reg name="f10" bitsize="64" type="ieee_double"/>
+    <reg name="f11" bitsize="64" type="ieee_double"/>
+    <reg name="f12" bitsize="64" type="ieee_double"/>
+    <reg name="f13" bitsize="64" type="ieee_double"/>
+    <reg name="f14" bitsize="64" type="ieee_double"/>
+    <reg name="f15" bitsize="64" type="ieee_double"/>
+    <reg name="f16" bitsize="64" type="ieee_double"/>
+    <reg name="f17" bitsize="64" type="ieee_double"/>
+    <reg name="f18" bitsize="64" type="ieee_double"/>
+    <reg name="f19" bitsize="64" type="ieee_double"/>
+    <reg name="f20" bitsize="64" type="ieee_double"/>
+    <reg name="f21" bitsize="64" type="ieee_double"/>
+    <reg name="f22" bitsize="64" type="ieee_double"/>
+    <reg name="f23" bitsize="64" type="ieee_double"/>
+    <reg name="f24" bitsize="64" type="ieee_double"/>
+    <reg name="f25" bitsize="64" type="ieee_double"/>
+    <reg name="f26" bitsize="64" type="ieee_double"/>
+    <reg name="f27" bitsize="64" type="ieee_double"/>
+    <reg name="f28" bitsize="64" type="ieee_double"/>
+    <reg name="f29" bitsize="64" type="ieee_double"/>
+    <reg name="f30" bitsize="64" type="ieee_double"/>
+    <reg name="f31" bitsize="64" type="ieee_double"/>
+    <reg name="fcsr" bitsize="64" group="float"/>
+    <reg name="fir" bitsize="64" group="float"/>
+  </feature>
+</target>
+"#;
+
+// Register numbers in the layout above
+const REG_STATUS: usize = 32;
+const REG_LO: usize = 33;
+const REG_HI: usize = 34;
+const REG_BADVADDR: usize = 35;
+const REG_CAUSE: usize = 36;
+const REG_PC: usize = 37;
+const REG_COUNT: usize = 72;
+
+// Largest packet we accept, as advertised in qSupported; `m` replies must fit in it
+const PACKET_SIZE: usize = 0x4000;
+
+// How many instructions run between checks for a Ctrl-C from the client
+const INTERRUPT_POLL_INTERVAL: usize = 4096;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+pub struct GdbStub {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+    // Bytes that arrived while the target was running, handled once the stop reply is out
+    deferred: Vec<u8>,
+    no_ack: bool,
+    // Debugger ids of the breakpoints/watchpoints GDB inserted, keyed by (type, addr, length)
+    inserted: HashMap<(u8, u32, u32), u32>,
+    // Set once the guest has exited, whatever GDB does afterwards
+    exit_status: Option<i32>,
+}
+
+// How a GDB session ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEnd {
+    // GDB let go of the target, which should carry on running on its own
+    Detached,
+    // GDB killed the target or the connection closed
+    Killed,
+    // The guest exited during the session, with this status
+    Exited(i32),
+}
+
+enum Resume {
+    Step,
+    Continue,
+}
+
+impl GdbStub {
+    // Waits for a single GDB connection on `addr`
+    pub fn listen(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        Ok(Self::new(stream))
+    }
+
+    pub fn new(stream: TcpStream) -> Self {
+        GdbStub {
+            stream,
+            buffer: Vec::new(),
+            deferred: Vec::new(),
+            no_ack: false,
+            inserted: HashMap::new(),
+            exit_status: None,
+        }
+    }
+
+    // Serves the connection until GDB detaches, kills the target or disconnects. A debugger is
+    // attached to `cpu` for the duration if it doesn't have one already, and taken off again on
+    // detach.
+    pub fn run(&mut self, cpu: &mut Cpu, bus: &mut dyn TimedBus) -> io::Result<SessionEnd> {
+        let attached = cpu.debugger().is_none();
+        if attached {
+            cpu.attach_debugger(Debugger::new());
+        }
+        let killed = |stub: &Self| match stub.exit_status {
+            Some(status) => SessionEnd::Exited(status),
+            None => SessionEnd::Killed,
+        };
+        loop {
+            let Some(packet) = self.read_packet()? else {
+                return Ok(killed(self));
+            };
+            let reply = match packet.first() {
+                Some(b'k') => return Ok(killed(self)),
+                Some(b'D') => {
+                    self.remove_all(cpu);
+                    if attached {
+                        cpu.detach_debugger();
+                    }
+                    self.write_packet(b"OK")?;
+                    return Ok(match self.exit_status {
+                        Some(status) => SessionEnd::Exited(status),
+                        None => SessionEnd::Detached,
+                    });
+                }
+                Some(b's') => self.resume(cpu, bus, &packet, Resume::Step)?,
+                Some(b'c') => self.resume(cpu, bus, &packet, Resume::Continue)?,
+                _ => self.handle(cpu, bus, &packet),
+            };
+            self.write_packet(&reply)?;
+            let deferred = std::mem::take(&mut self.deferred);
+            self.buffer.splice(..0, deferred);
+        }
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, bus: &mut dyn TimedBus, packet: &[u8]) -> Vec<u8> {
+        let text = String::from_utf8_lossy(packet);
+        if text.is_empty() {
+            return Vec::new();
+        }
+        let (command, args) = text.split_at(1);
+        let reply = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => Some(
+                (0..REG_COUNT)
+                    .map(|reg| hex_u64(read_register(cpu, reg)))
+                    .collect(),
+            ),
+            "G" => write_all_registers(cpu, args),
+            "p" => usize::from_str_radix(args, 16)
+                .ok()
+                .filter(|&reg| reg < REG_COUNT)
+                .map(|reg| hex_u64(read_register(cpu, reg))),
+            "P" => args.split_once('=').and_then(|(reg, value)| {
+                let reg = usize::from_str_radix(reg, 16)
+                    .ok()
+                    .filter(|&r| r < REG_COUNT)?;
+                write_register(cpu, reg, parse_u64_le(value)?);
+                Some("OK".to_string())
+            }),
+            "m" => parse_addr_len(args).map(|(addr, len)| read_memory(bus, addr, len)),
+            "M" => args.split_once(':').and_then(|(range, data)| {
+                let (addr, len) = parse_addr_len(range)?;
+                let bytes = decode_hex(data)?;
+                (bytes.len() == len as usize).then(|| write_memory(bus, addr, &bytes))
+            }),
+            "Z" => self.insert(cpu, args),
+            "z" => self.remove(cpu, args),
+            "H" => Some("OK".to_string()),
+            "q" => self.query(args),
+            "Q" if args == "StartNoAckMode" => {
+                self.no_ack = true;
+                Some("OK".to_string())
+            }
+            _ => None,
+        };
+        // An empty reply tells GDB the packet isn't supported
+        reply.unwrap_or_default().into_bytes()
+    }
+
+    fn query(&self, args: &str) -> Option<String> {
+        if args.starts_with("Supported") {
+            return Some(format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            ));
+        }
+        if let Some(annex) = args.strip_prefix("Xfer:features:read:") {
+            let (name, range) = annex.split_once(':')?;
+            if name != "target.xml" {
+                return Some("E00".to_string());
+            }
+            let (offset, len) = parse_addr_len(range)?;
+            let xml = TARGET_XML.as_bytes();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            let prefix = if end == xml.len() { 'l' } else { 'm' };
+            return Some(format!(
+                "{}{}",
+                prefix,
+                String::from_utf8_lossy(&xml[start..end])
+            ));
+        }
+        match args {
+            "Attached" => Some("1".to_string()),
+            "C" => Some("QC1".to_string()),
+            "fThreadInfo" => Some("m1".to_string()),
+            "sThreadInfo" => Some("l".to_string()),
+            _ => None,
+        }
+    }
+
+    // Z/z packets: type,addr,kind
+    fn parse_point(args: &str) -> Option<(u8, u32, u32)> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?.parse().ok()?;
+        let addr = u32::from_str_radix(fields.next()?, 16).ok()?;
+        let len = u32::from_str_radix(fields.next()?.split(';').next()?, 16).ok()?;
+        Some((kind, addr, len))
+    }
+
+    fn insert(&mut self, cpu: &mut Cpu, args: &str) -> Option<String> {
+        let (kind, addr, len) = Self::parse_point(args)?;
+        let debugger = cpu.debugger_mut()?;
+        let id = match kind {
+            0 | 1 => debugger.add_breakpoint(addr, None),
+            2..=4 => {
+                let watch = match kind {
+                    2 => WatchKind::Write,
+                    3 => WatchKind::Read,
+                    _ => WatchKind::Access,
+                };
+                debugger.add_watchpoint(addr..addr.wrapping_add(len.max(1)), watch, None)
+            }
+            _ => return None,
+        };
+        if let Some(old) = self.inserted.insert((kind, addr, len), id) {
+            debugger.remove(old);
+        }
+        Some("OK".to_string())
+    }
+
+    fn remove(&mut self, cpu: &mut Cpu, args: &str) -> Option<String> {
+        let key = Self::parse_point(args)?;
+        if key.0 > 4 {
+            return None;
+        }
+        if let (Some(id), Some(debugger)) = (self.inserted.remove(&key), cpu.debugger_mut()) {
+            debugger.remove(id);
+        }
+        Some("OK".to_string())
+    }
+
+    fn remove_all(&mut self, cpu: &mut Cpu) {
+        if let Some(debugger) = cpu.debugger_mut() {
+            for (_, id) in self.inserted.drain() {
+                debugger.remove(id);
+            }
+        }
+    }
+
+    fn resume(
+        &mut self,
+        cpu: &mut Cpu,
+        bus: &mut dyn TimedBus,
+        packet: &[u8],
+        mode: Resume,
+    ) -> io::Result<Vec<u8>> {
+        // Optional resume address
+        if packet.len() > 1 {
+            let addr = std::str::from_utf8(&packet[1..])
+                .ok()
+                .and_then(|addr| u64::from_str_radix(addr, 16).ok());
+            match addr {
+                Some(addr) => cpu.set_pc(addr as u32),
+                None => return Ok(b"E01".to_vec()),
+            }
+        }
+
+        let result = match mode {
+            Resume::Step => cpu.step_timed(bus),
+            Resume::Continue => {
+                self.stream.set_nonblocking(true)?;
+                let result = self.run_until_stop(cpu, bus);
+                self.stream.set_nonblocking(false)?;
+                match result? {
+                    Some(result) => result,
+                    None => return Ok(format!("S{:02x}", SIGINT).into_bytes()),
+                }
+            }
+        };
+        if let StepResult::Exited(status) = result {
+            self.exit_status = Some(status);
+        }
+        Ok(stop_reply(result).into_bytes())
+    }
+
+    // Runs until a step result other than `Ok`; returns None when interrupted by the client
+    fn run_until_stop(
+        &mut self,
+        cpu: &mut Cpu,
+        bus: &mut dyn TimedBus,
+    ) -> io::Result<Option<StepResult>> {
+        // Anything already read past the `c` packet waits for the stop reply too
+        self.deferred.append(&mut self.buffer);
+        loop {
+            if let Some(pos) = self.deferred.iter().position(|&b| b == 0x03) {
+                self.deferred.remove(pos);
+                return Ok(None);
+            }
+            for _ in 0..INTERRUPT_POLL_INTERVAL {
+                let result = cpu.step_timed(bus);
+                if result != StepResult::Ok {
+                    return Ok(Some(result));
+                }
+            }
+            let mut chunk = [0u8; 4096];
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(read) => self.deferred.extend_from_slice(&chunk[..read]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Packet framing
+
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0u8; 4096];
+        let read = self.stream.read(&mut chunk)?;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read != 0)
+    }
+
+    // Returns None when the client disconnected
+    fn read_packet(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Drop acks and anything else before the start of a packet
+            if let Some(start) = self.buffer.iter().position(|&b| b == b'$') {
+                self.buffer.drain(..start);
+                if let Some(end) = self.buffer.iter().position(|&b| b == b'#')
+                    && self.buffer.len() >= end + 3
+                {
+                    let packet: Vec<u8> = self.buffer[1..end].to_vec();
+                    let checksum = std::str::from_utf8(&self.buffer[end + 1..end + 3])
+                        .ok()
+                        .and_then(|cs| u8::from_str_radix(cs, 16).ok());
+                    self.buffer.drain(..end + 3);
+
+                    let valid = checksum == Some(checksum_of(&packet));
+                    if !self.no_ack {
+                        self.stream.write_all(if valid { b"+" } else { b"-" })?;
+                    }
+                    if valid {
+                        return Ok(Some(unescape(&packet)));
+                    }
+                    continue;
+                }
+            } else {
+                self.buffer.clear();
+            }
+            if !self.fill()? {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> io::Result<()> {
+        let data = escape(data);
+        let mut frame = Vec::with_capacity(data.len() + 4);
+        frame.push(b'$');
+        frame.extend_from_slice(&data);
+        frame.extend_from_slice(format!("#{:02x}", checksum_of(&data)).as_bytes());
+        loop {
+            self.stream.write_all(&frame)?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // Wait for the ack, resending on a nack
+            loop {
+                if let Some(pos) = self.buffer.iter().position(|&b| b == b'+' || b == b'-') {
+                    let ack = self.buffer.remove(pos) == b'+';
+                    if ack {
+                        return Ok(());
+                    }
+                    break;
+                }
+                if !self.fill()? {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+            }
+        }
+    }
+}
+
+fn stop_reply(result: StepResult) -> String {
+    match result {
+        // The guest program ended through the HLE kernel
+        StepResult::Exited(status) => format!("W{:02x}", status as u8),
+        StepResult::Breakpoint(StopReason::Watchpoint { addr, kind, .. }) => {
+            let name = match kind {
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+                WatchKind::Write | WatchKind::Change => "watch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+        }
+        _ => format!("T{:02x}thread:01;", SIGTRAP),
+    }
+}
+
+fn read_register(cpu: &Cpu, reg: usize) -> u64 {
+    // 32-bit COP0 registers and pc are sign-extended like the EE does when moving them to GPRs
+    let extend = |value: u32| value as i32 as i64 as u64;
+    match reg {
+        0..=31 => cpu.gprs[reg] as u64,
+        REG_STATUS => extend(cpu.cop0.read(cop0::STATUS)),
+        REG_LO => cpu.lo0,
+        REG_HI => cpu.hi0,
+        REG_BADVADDR => extend(cpu.cop0.read(cop0::BAD_VADDR)),
+        REG_CAUSE => extend(cpu.cop0.read(cop0::CAUSE)),
+        REG_PC => extend(cpu.pc),
+        // FPU
+        _ => 0,
+    }
+}
+
+fn write_register(cpu: &mut Cpu, reg: usize, value: u64) {
+    match reg {
+        0..=31 => cpu.write_gpr_dword(reg, value),
+        REG_STATUS => cpu.cop0.set(cop0::STATUS, value as u32),
+        REG_LO => cpu.lo0 = value,
+        REG_HI => cpu.hi0 = value,
+        REG_BADVADDR => cpu.cop0.set(cop0::BAD_VADDR, value as u32),
+        REG_CAUSE => cpu.cop0.set(cop0::CAUSE, value as u32),
+        REG_PC => cpu.set_pc(value as u32),
+        _ => {}
+    }
+}
+
+fn write_all_registers(cpu: &mut Cpu, hex: &str) -> Option<String> {
+    if hex.len() != REG_COUNT * 16 {
+        return None;
+    }
+    let values = (0..REG_COUNT)
+        .map(|reg| parse_u64_le(&hex[reg * 16..reg * 16 + 16]))
+        .collect::<Option<Vec<_>>>()?;
+    for (reg, value) in values.into_iter().enumerate() {
+        // Leave pc alone unless it actually changes, so a pending branch isn't lost
+        if reg != REG_PC || value as u32 != cpu.pc {
+            write_register(cpu, reg, value);
+        }
+    }
+    Some("OK".to_string())
+}
+
+fn read_memory(bus: &mut dyn Bus, addr: u32, len: u32) -> String {
+    if len as usize > PACKET_SIZE / 2 {
+        return "E01".to_string();
+    }
+    let mut hex = String::with_capacity(len as usize * 2);
+    // Peeked, so looking at device registers can't pop a FIFO or acknowledge anything; they
+    // read as unreadable instead
+    for offset in 0..len {
+        match bus.peek8(addr.wrapping_add(offset)) {
+            Some(byte) => hex.push_str(&format!("{:02x}", byte)),
+            // Partial reads are allowed, but at least one byte must be readable
+            None if offset > 0 => break,
+            None => return "E01".to_string(),
+        }
+    }
+    hex
+}
+
+fn write_memory(bus: &mut dyn Bus, addr: u32, bytes: &[u8]) -> String {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        if bus.write8(addr.wrapping_add(offset as u32), byte).is_err() {
+            return "E01".to_string();
+        }
+    }
+    "OK".to_string()
+}
+
+// Registers travel as target-endian (little-endian) byte strings
+fn hex_u64(value: u64) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_u64_le(hex: &str) -> Option<u64> {
+    let bytes = decode_hex(hex)?;
+    let bytes: [u8; 8] = bytes.try_into().ok()?;
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// "addr,length" with 64-bit addresses truncated to the EE's 32
+fn parse_addr_len(args: &str) -> Option<(u32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u64::from_str_radix(addr, 16).ok()? as u32;
+    let len = u32::from_str_radix(len, 16).ok()?;
+    Some((addr, len))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        if matches!(b, b'#' | b'$' | b'}' | b'*') {
+            out.push(b'}');
+            out.push(b ^ 0x20);
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{BusError, Ram};
+    use crate::ps2_bus::Ps2Bus;
+    use std::thread;
+    use std::time::Duration;
+
+    const ENTRY: u32 = 0x1000;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn byte(&mut self) -> u8 {
+            let mut byte = [0u8; 1];
+            self.stream.read_exact(&mut byte).unwrap();
+            byte[0]
+        }
+
+        fn send_raw(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+
+        fn send(&mut self, payload: &str) {
+            let frame = format!("${}#{:02x}", payload, checksum_of(payload.as_bytes()));
+            self.send_raw(frame.as_bytes());
+        }
+
+        // Reads one reply packet, checking its checksum, and acks it
+        fn reply(&mut self) -> String {
+            while self.byte() != b'$' {}
+            let mut payload = Vec::new();
+            loop {
+                match self.byte() {
+                    b'#' => break,
+                    b => payload.push(b),
+                }
+            }
+            let checksum = [self.byte(), self.byte()];
+            let checksum = u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16);
+            assert_eq!(checksum, Ok(checksum_of(&payload)));
+            self.send_raw(b"+");
+            String::from_utf8(unescape(&payload)).unwrap()
+        }
+
+        fn command(&mut self, payload: &str) -> String {
+            self.send(payload);
+            assert_eq!(self.byte(), b'+');
+            self.reply()
+        }
+    }
+
+    fn connect() -> (Client, thread::JoinHandle<(u32, SessionEnd)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut cpu = Cpu::new();
+            let mut bus = Ps2Bus::new();
+            // RAM is zero, so everything from ENTRY on is a nop
+            bus.write32(0x2000, 0x1234_5678).unwrap();
+            cpu.set_pc(ENTRY);
+            let end = GdbStub::new(stream).run(&mut cpu, &mut bus).unwrap();
+            assert_eq!(cpu.debugger().is_some(), end != SessionEnd::Detached);
+            (cpu.pc(), end)
+        });
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        (Client { stream }, server)
+    }
+
+    #[test]
+    fn serves_registers_memory_and_breakpoints() {
+        let (mut client, server) = connect();
+
+        assert_eq!(client.command("?"), "S05");
+
+        let regs = client.command("g");
+        assert_eq!(regs.len(), REG_COUNT * 16);
+        assert_eq!(&regs[REG_PC * 16..REG_PC * 16 + 16], "0010000000000000");
+
+        assert_eq!(client.command("m2000,4"), "78563412");
+        assert_eq!(client.command("m2000,4000"), "E01");
+
+        assert_eq!(client.command("Z0,1010,4"), "OK");
+        assert_eq!(client.command("c"), "T05thread:01;");
+        assert_eq!(client.command("p25"), "1010000000000000");
+
+        client.send("k");
+        assert_eq!(client.byte(), b'+');
+        assert_eq!(server.join().unwrap(), (0x1010, SessionEnd::Killed));
+    }
+
+    // RAM with one register in it that counts its reads
+    struct Counter {
+        ram: Ram,
+        reads: u8,
+    }
+
+    const COUNTER: u32 = 0x800;
+
+    impl Bus for Counter {
+        fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+            if addr == COUNTER {
+                self.reads += 1;
+                return Ok(self.reads);
+            }
+            self.ram.read8(addr)
+        }
+
+        fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+            self.ram.write8(addr, value)
+        }
+
+        fn memory_mut(&mut self, addr: u32, len: u32) -> Option<&mut [u8]> {
+            let register = (addr..addr.checked_add(len)?).contains(&COUNTER);
+            if register {
+                None
+            } else {
+                self.ram.memory_mut(addr, len)
+            }
+        }
+    }
+
+    #[test]
+    fn memory_reads_leave_registers_alone() {
+        let mut bus = Counter {
+            ram: Ram::new(0x1000),
+            reads: 0,
+        };
+        bus.write32(0x7FC, 0x1234_5678).unwrap();
+        assert_eq!(read_memory(&mut bus, 0x7FC, 4), "78563412");
+        // Stopping short at the register, or refusing it outright
+        assert_eq!(read_memory(&mut bus, 0x7FC, 8), "78563412");
+        assert_eq!(read_memory(&mut bus, COUNTER, 4), "E01");
+        assert_eq!(bus.reads, 0);
+    }
+
+    #[test]
+    fn detach_hands_the_target_back_running() {
+        let (mut client, server) = connect();
+
+        assert_eq!(client.command("Z0,1010,4"), "OK");
+        assert_eq!(client.command("s"), "T05thread:01;");
+        assert_eq!(client.command("D"), "OK");
+        // The stub's breakpoints and debugger are gone, so the caller can carry on running
+        assert_eq!(server.join().unwrap(), (ENTRY + 4, SessionEnd::Detached));
+    }
+
+    #[test]
+    fn nacks_bad_checksums() {
+        let (mut client, server) = connect();
+
+        client.send_raw(b"$?#00");
+        assert_eq!(client.byte(), b'-');
+        assert_eq!(client.command("?"), "S05");
+
+        // A nacked reply is sent again
+        client.send("?");
+        assert_eq!(client.byte(), b'+');
+        while client.byte() != b'#' {}
+        client.byte();
+        client.byte();
+        client.send_raw(b"-");
+        assert_eq!(client.reply(), "S05");
+
+        client.send("k");
+        assert_eq!(client.byte(), b'+');
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn handles_packets_sent_while_running_after_the_stop() {
+        let (mut client, server) = connect();
+
+        client.send("c");
+        assert_eq!(client.byte(), b'+');
+        // A packet followed by Ctrl-C: the interrupt stops the target, the packet is answered
+        // after the stop reply
+        let mut data = b"$?#3f".to_vec();
+        data.push(0x03);
+        client.send_raw(&data);
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.byte(), b'+');
+        assert_eq!(client.reply(), "S05");
+
+        client.send("k");
+        assert_eq!(client.byte(), b'+');
+        server.join().unwrap();
+    }
+}
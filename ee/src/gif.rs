@@ -0,0 +1,881 @@
+// The GIF: the interface feeding the GS with data from three paths.
+//
+// PATH1 is VU1's XGKICK, PATH2 VIF1's DIRECT/DIRECTHL and PATH3 the GIF DMA channel (or the
+// GIF_FIFO register). Each path hands over GS packets: a GIFtag followed by its data, repeated
+// until a tag with EOP set has been processed. Once a path starts a packet it keeps the GIF
+// until the packet ends; between packets the GIF picks the waiting path with the highest
+// priority, PATH1 first. GIF_MODE.M3R (and VIF1's MSKPATH3) keep PATH3 from starting a new
+// packet. In intermittent mode (GIF_MODE.IMT) a PATH3 IMAGE transfer gives way to PATH1 or
+// PATH2 every eight quadwords and resumes once they are done.
+//
+// A tag's data is expanded into GS register writes: PACKED data takes one quadword per
+// register descriptor, REGLIST data one doubleword, and IMAGE data goes to HWREG two
+// doublewords at a time. Paths aren't throttled: a path's data is queued until the GIF gets
+// to it.
+
+use std::collections::VecDeque;
+
+use crate::gs;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const GIF_CTRL: u32 = 0x1000_3000;
+pub const GIF_MODE: u32 = 0x1000_3010;
+pub const GIF_STAT: u32 = 0x1000_3020;
+pub const GIF_TAG0: u32 = 0x1000_3040;
+pub const GIF_TAG1: u32 = 0x1000_3050;
+pub const GIF_TAG2: u32 = 0x1000_3060;
+pub const GIF_TAG3: u32 = 0x1000_3070;
+pub const GIF_CNT: u32 = 0x1000_3080;
+pub const GIF_P3CNT: u32 = 0x1000_3090;
+pub const GIF_P3TAG: u32 = 0x1000_30A0;
+// PATH3's FIFO, written a quadword at a time
+pub const GIF_FIFO: u32 = 0x1000_6000;
+
+pub const GIF_CTRL_RST: u32 = 1 << 0;
+pub const GIF_CTRL_PSE: u32 = 1 << 3;
+
+pub const GIF_MODE_M3R: u32 = 1 << 0;
+pub const GIF_MODE_IMT: u32 = 1 << 2;
+
+pub const GIF_STAT_M3R: u32 = 1 << 0;
+pub const GIF_STAT_M3P: u32 = 1 << 1;
+pub const GIF_STAT_IMT: u32 = 1 << 2;
+pub const GIF_STAT_PSE: u32 = 1 << 3;
+pub const GIF_STAT_IP3: u32 = 1 << 5;
+pub const GIF_STAT_P3Q: u32 = 1 << 6;
+pub const GIF_STAT_P2Q: u32 = 1 << 7;
+pub const GIF_STAT_P1Q: u32 = 1 << 8;
+pub const GIF_STAT_OPH: u32 = 1 << 9;
+pub const GIF_STAT_APATH_SHIFT: u32 = 10;
+pub const GIF_STAT_FQC_SHIFT: u32 = 24;
+
+// GIFtag FLG
+pub const FLG_PACKED: u32 = 0;
+pub const FLG_REGLIST: u32 = 1;
+pub const FLG_IMAGE: u32 = 2;
+
+// Register descriptors with a meaning of their own
+pub const REG_AD: u32 = 0xE;
+pub const REG_NOP: u32 = 0xF;
+
+const GIF_MODE_BITS: u32 = GIF_MODE_M3R | GIF_MODE_IMT;
+// Quadwords of PATH3 IMAGE data between chances for the other paths in intermittent mode
+const IMT_SLICE: u32 = 8;
+// GIF_STAT.FQC saturates at the FIFO's depth
+const FIFO_DEPTH: usize = 16;
+// The GIF's Q starts every tag at 1.0
+const Q_ONE: u32 = 0x3F80_0000;
+
+// Where the GIF's output goes
+pub trait GifSink {
+    // A write to one of the GS's general registers
+    fn write_register(&mut self, reg: u8, value: u64);
+    // A doubleword of IMAGE data, as if written to HWREG
+    fn write_image(&mut self, data: u64);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Path {
+    Path1,
+    Path2,
+    Path3,
+}
+
+impl Path {
+    const ALL: [Path; 3] = [Path::Path1, Path::Path2, Path::Path3];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+const PATH3: usize = Path::Path3 as usize;
+
+// A GIFtag and how far through its data the path is
+#[derive(Clone, Copy, Debug)]
+struct Tag {
+    raw: u128,
+    // Loops still to come
+    loops: u32,
+    eop: bool,
+    flg: u32,
+    nreg: u32,
+    regs: u64,
+    // Descriptor the next data belongs to
+    reg: u32,
+}
+
+impl Tag {
+    fn parse(raw: u128) -> Self {
+        let low = raw as u64;
+        let nreg = gs::field(low, 60, 4);
+        Tag {
+            raw,
+            loops: gs::field(low, 0, 15),
+            eop: low & 1 << 15 != 0,
+            flg: gs::field(low, 58, 2),
+            nreg: if nreg == 0 { 16 } else { nreg },
+            regs: (raw >> 64) as u64,
+            reg: 0,
+        }
+    }
+
+    fn pre(&self) -> bool {
+        self.raw & 1 << 46 != 0
+    }
+
+    fn prim(&self) -> u64 {
+        gs::field(self.raw as u64, 47, 11) as u64
+    }
+
+    fn descriptor(&self) -> u32 {
+        gs::field(self.regs, self.reg * 4, 4)
+    }
+
+    // Moves past one register's data; false once the last loop is done
+    fn next_register(&mut self) -> bool {
+        self.reg += 1;
+        if self.reg == self.nreg {
+            self.reg = 0;
+            self.loops -= 1;
+        }
+        self.loops > 0
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct PathState {
+    queue: VecDeque<u128>,
+    tag: Option<Tag>,
+    // Between a packet's first tag and the end of its EOP tag
+    in_packet: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Gif {
+    paths: [PathState; 3],
+    // The path that has the GIF, by index
+    active: Option<usize>,
+    mode: u32,
+    paused: bool,
+    // VIF1's MSKPATH3
+    vif_mask: bool,
+    q: u32,
+    // PATH3 IMAGE quadwords since the last chance to give way
+    imt_count: u32,
+    // The last tag read, for GIF_TAG0-3
+    last_tag: u128,
+}
+
+impl Default for Gif {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gif {
+    pub fn new() -> Self {
+        Gif {
+            paths: Default::default(),
+            active: None,
+            mode: 0,
+            paused: false,
+            vif_mask: false,
+            q: Q_ONE,
+            imt_count: 0,
+            last_tag: 0,
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> Option<u32> {
+        let active_tag = self.active.and_then(|path| self.paths[path].tag);
+        let path3_tag = self.paths[PATH3].tag;
+        let value = match addr {
+            GIF_STAT => self.stat(),
+            GIF_TAG0 => self.last_tag as u32,
+            GIF_TAG1 => (self.last_tag >> 32) as u32,
+            GIF_TAG2 => (self.last_tag >> 64) as u32,
+            GIF_TAG3 => (self.last_tag >> 96) as u32,
+            GIF_CNT => active_tag.map_or(0, |tag| tag.loops | tag.reg << 16),
+            GIF_P3CNT => path3_tag.map_or(0, |tag| tag.loops),
+            GIF_P3TAG => path3_tag.map_or(0, |tag| tag.raw as u32 & 0xFFFF),
+            GIF_CTRL | GIF_MODE => 0,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    // GIF_CTRL and GIF_MODE are the writable registers; the caller runs the GIF afterwards
+    pub fn write(&mut self, addr: u32, value: u32) -> bool {
+        match addr {
+            GIF_CTRL => {
+                if value & GIF_CTRL_RST != 0 {
+                    *self = Gif::new();
+                }
+                self.paused = value & GIF_CTRL_PSE != 0;
+            }
+            GIF_MODE => self.mode = value & GIF_MODE_BITS,
+            GIF_STAT | GIF_TAG0 | GIF_TAG1 | GIF_TAG2 | GIF_TAG3 | GIF_CNT | GIF_P3CNT
+            | GIF_P3TAG => {}
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn stat(&self) -> u32 {
+        let mut stat = 0;
+        if self.mode & GIF_MODE_M3R != 0 {
+            stat |= GIF_STAT_M3R;
+        }
+        if self.vif_mask {
+            stat |= GIF_STAT_M3P;
+        }
+        if self.mode & GIF_MODE_IMT != 0 {
+            stat |= GIF_STAT_IMT;
+        }
+        if self.paused {
+            stat |= GIF_STAT_PSE;
+        }
+        if self.paths[PATH3].in_packet && self.active != Some(PATH3) {
+            stat |= GIF_STAT_IP3;
+        }
+        for (path, bit) in [GIF_STAT_P1Q, GIF_STAT_P2Q, GIF_STAT_P3Q]
+            .into_iter()
+            .enumerate()
+        {
+            if !self.paths[path].queue.is_empty() {
+                stat |= bit;
+            }
+        }
+        if let Some(path) = self.active {
+            stat |= GIF_STAT_OPH | (path as u32 + 1) << GIF_STAT_APATH_SHIFT;
+        }
+        let fqc = self.paths[PATH3].queue.len().min(FIFO_DEPTH) as u32;
+        stat | fqc << GIF_STAT_FQC_SHIFT
+    }
+
+    // VIF1's MSKPATH3
+    pub fn set_vif_mask(&mut self, masked: bool) {
+        self.vif_mask = masked;
+    }
+
+    fn path3_masked(&self) -> bool {
+        self.mode & GIF_MODE_M3R != 0 || self.vif_mask
+    }
+
+    pub fn push(&mut self, path: Path, qword: u128) {
+        self.paths[path.index()].queue.push_back(qword);
+    }
+
+    // Quadwords a path has queued that the GIF hasn't taken yet
+    pub fn queued(&self, path: Path) -> usize {
+        self.paths[path.index()].queue.len()
+    }
+
+    // The path with the GIF, if any
+    pub fn active_path(&self) -> Option<Path> {
+        self.active.map(|path| Path::ALL[path])
+    }
+
+    // Whether a path is partway through a packet
+    pub fn in_packet(&self, path: Path) -> bool {
+        self.paths[path.index()].in_packet
+    }
+
+    // The next path to get the GIF. A masked PATH3 may still finish a packet it was
+    // interrupted in.
+    fn select(&self) -> Option<usize> {
+        (0..Path::ALL.len()).find(|&path| {
+            let state = &self.paths[path];
+            !state.queue.is_empty() && (path != PATH3 || state.in_packet || !self.path3_masked())
+        })
+    }
+
+    // Processes queued data until every path is waiting or the GIF is paused
+    pub fn run(&mut self, sink: &mut dyn GifSink) {
+        while !self.paused {
+            let Some(path) = self.active.or_else(|| self.select()) else {
+                break;
+            };
+            self.active = Some(path);
+            let Some(qword) = self.paths[path].queue.pop_front() else {
+                break;
+            };
+            match self.paths[path].tag {
+                Some(tag) => self.process(path, tag, qword, sink),
+                None => self.start_tag(path, qword, sink),
+            }
+        }
+    }
+
+    fn start_tag(&mut self, path: usize, qword: u128, sink: &mut dyn GifSink) {
+        let tag = Tag::parse(qword);
+        self.last_tag = qword;
+        self.q = Q_ONE;
+        self.imt_count = 0;
+        // PRIM only applies to PACKED data
+        if tag.pre() && tag.flg == FLG_PACKED {
+            sink.write_register(gs::PRIM, tag.prim());
+        }
+        self.paths[path].in_packet = true;
+        if tag.loops == 0 {
+            self.end_tag(path, tag);
+        } else {
+            self.paths[path].tag = Some(tag);
+        }
+    }
+
+    fn end_tag(&mut self, path: usize, tag: Tag) {
+        let state = &mut self.paths[path];
+        state.tag = None;
+        if tag.eop {
+            state.in_packet = false;
+            self.active = None;
+        }
+    }
+
+    fn process(&mut self, path: usize, mut tag: Tag, qword: u128, sink: &mut dyn GifSink) {
+        let more = match tag.flg {
+            FLG_PACKED => {
+                self.write_packed(tag.descriptor(), qword, sink);
+                tag.next_register()
+            }
+            FLG_REGLIST => {
+                // An odd number of registers leaves the last quadword's upper half unused
+                let mut more = true;
+                for data in [qword as u64, (qword >> 64) as u64] {
+                    let descriptor = tag.descriptor();
+                    if descriptor < REG_AD {
+                        sink.write_register(descriptor as u8, data);
+                    }
+                    more = tag.next_register();
+                    if !more {
+                        break;
+                    }
+                }
+                more
+            }
+            _ => {
+                sink.write_image(qword as u64);
+                sink.write_image((qword >> 64) as u64);
+                tag.loops -= 1;
+                if path == PATH3 && self.mode & GIF_MODE_IMT != 0 {
+                    self.give_way();
+                }
+                tag.loops > 0
+            }
+        };
+        if more {
+            self.paths[path].tag = Some(tag);
+        } else {
+            self.end_tag(path, tag);
+        }
+    }
+
+    // In intermittent mode, lets PATH1 or PATH2 in partway through a PATH3 IMAGE transfer
+    fn give_way(&mut self) {
+        self.imt_count += 1;
+        if self.imt_count == IMT_SLICE {
+            self.imt_count = 0;
+            if self.paths[..PATH3]
+                .iter()
+                .any(|state| !state.queue.is_empty())
+            {
+                self.active = None;
+            }
+        }
+    }
+
+    fn write_packed(&mut self, descriptor: u32, qword: u128, sink: &mut dyn GifSink) {
+        let (low, high) = (qword as u64, (qword >> 64) as u64);
+        let adc = high & 1 << 47 != 0;
+        let xy = (low & 0xFFFF) | ((low >> 32) & 0xFFFF) << 16;
+        match descriptor as u8 {
+            gs::PRIM => sink.write_register(gs::PRIM, low & 0x7FF),
+            gs::RGBAQ => {
+                let rgba = (low & 0xFF)
+                    | ((low >> 32) & 0xFF) << 8
+                    | (high & 0xFF) << 16
+                    | ((high >> 32) & 0xFF) << 24;
+                sink.write_register(gs::RGBAQ, rgba | (self.q as u64) << 32);
+            }
+            // Q rides along with ST and goes out with the next RGBAQ
+            gs::ST => {
+                self.q = high as u32;
+                sink.write_register(gs::ST, low);
+            }
+            gs::UV => {
+                let uv = (low & 0x3FFF) | ((low >> 32) & 0x3FFF) << 16;
+                sink.write_register(gs::UV, uv);
+            }
+            gs::XYZF2 | gs::XYZF3 => {
+                let z = (high >> 4) & 0xFF_FFFF;
+                let f = (high >> 36) & 0xFF;
+                let reg = if adc || descriptor as u8 == gs::XYZF3 {
+                    gs::XYZF3
+                } else {
+                    gs::XYZF2
+                };
+                sink.write_register(reg, xy | z << 32 | f << 56);
+            }
+            gs::XYZ2 | gs::XYZ3 => {
+                let z = high & 0xFFFF_FFFF;
+                let reg = if adc || descriptor as u8 == gs::XYZ3 {
+                    gs::XYZ3
+                } else {
+                    gs::XYZ2
+                };
+                sink.write_register(reg, xy | z << 32);
+            }
+            gs::TEX0_1 | gs::TEX0_2 | gs::CLAMP_1 | gs::CLAMP_2 => {
+                sink.write_register(descriptor as u8, low)
+            }
+            gs::FOG => sink.write_register(gs::FOG, ((high >> 36) & 0xFF) << 56),
+            _ if descriptor == REG_AD => sink.write_register(high as u8, low),
+            _ => {}
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for state in &self.paths {
+            w.write_u32(state.queue.len() as u32);
+            for &qword in &state.queue {
+                w.write_u128(qword);
+            }
+            w.write_bool(state.tag.is_some());
+            let tag = state.tag.unwrap_or(Tag::parse(0));
+            w.write_u128(tag.raw);
+            w.write_u32(tag.loops);
+            w.write_u32(tag.reg);
+            w.write_bool(state.in_packet);
+        }
+        w.write_u8(self.active.map_or(0, |path| path as u8 + 1));
+        w.write_u32(self.mode);
+        w.write_bool(self.paused);
+        w.write_bool(self.vif_mask);
+        w.write_u32(self.q);
+        w.write_u32(self.imt_count);
+        w.write_u128(self.last_tag);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut paths: [PathState; 3] = Default::default();
+        for state in paths.iter_mut() {
+            let len = r.read_u32()?;
+            for _ in 0..len {
+                state.queue.push_back(r.read_u128()?);
+            }
+            let has_tag = r.read_bool()?;
+            let mut tag = Tag::parse(r.read_u128()?);
+            tag.loops = r.read_u32()?;
+            tag.reg = r.read_u32()?;
+            if tag.reg >= tag.nreg {
+                return Err(StateError::Mismatch(format!(
+                    "GIF register {} past the tag's {}",
+                    tag.reg, tag.nreg
+                )));
+            }
+            state.tag = has_tag.then_some(tag);
+            state.in_packet = r.read_bool()?;
+        }
+        let active = match r.read_u8()? {
+            0 => None,
+            n @ 1..=3 => Some(n as usize - 1),
+            n => return Err(StateError::Mismatch(format!("unknown GIF path {}", n))),
+        };
+        let mode = r.read_u32()? & GIF_MODE_BITS;
+        let paused = r.read_bool()?;
+        let vif_mask = r.read_bool()?;
+        let q = r.read_u32()?;
+        let imt_count = r.read_u32()?;
+        let last_tag = r.read_u128()?;
+        *self = Gif {
+            paths,
+            active,
+            mode,
+            paused,
+            vif_mask,
+            q,
+            imt_count,
+            last_tag,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum Output {
+        Register(u8, u64),
+        Image(u64),
+    }
+    use Output::{Image, Register};
+
+    #[derive(Default)]
+    struct Sink(Vec<Output>);
+
+    impl GifSink for Sink {
+        fn write_register(&mut self, reg: u8, value: u64) {
+            self.0.push(Register(reg, value));
+        }
+
+        fn write_image(&mut self, data: u64) {
+            self.0.push(Image(data));
+        }
+    }
+
+    fn giftag(nloop: u32, eop: bool, flg: u32, regs: &[u32]) -> u128 {
+        let descriptors = regs
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, &reg)| acc | (reg as u128) << (4 * i));
+        let nreg = regs.len() as u128 & 0xF;
+        nloop as u128 | (eop as u128) << 15 | (flg as u128) << 58 | nreg << 60 | descriptors << 64
+    }
+
+    fn ad(reg: u8, value: u64) -> u128 {
+        (reg as u128) << 64 | value as u128
+    }
+
+    // A one-register A+D packet
+    fn packet(reg: u8, value: u64) -> [u128; 2] {
+        [giftag(1, true, FLG_PACKED, &[REG_AD]), ad(reg, value)]
+    }
+
+    fn push(gif: &mut Gif, path: Path, qwords: &[u128]) {
+        for &qword in qwords {
+            gif.push(path, qword);
+        }
+    }
+
+    // Packs four 32-bit fields into a quadword, lowest first
+    fn words(a: u32, b: u32, c: u32, d: u32) -> u128 {
+        a as u128 | (b as u128) << 32 | (c as u128) << 64 | (d as u128) << 96
+    }
+
+    #[test]
+    fn packed_data_is_expanded_per_descriptor() {
+        let regs = [
+            gs::ST as u32,
+            gs::RGBAQ as u32,
+            gs::UV as u32,
+            gs::XYZF2 as u32,
+            gs::XYZF2 as u32,
+            gs::XYZ2 as u32,
+            gs::FOG as u32,
+            REG_AD,
+            REG_NOP,
+        ];
+        let prim = 0x5u128 << 47 | 1 << 46;
+        let tag = giftag(1, false, FLG_PACKED, &regs) | prim;
+        // The XYZF fields sit at Z << 4 and F << 36 of the upper half, ADC at bit 111
+        let xyzf = words(0x100, 0x200, 0x12_3456 << 4, 0x9A << 4);
+        let mut gif = Gif::new();
+        push(
+            &mut gif,
+            Path::Path3,
+            &[
+                tag,
+                words(0x3F80_0000, 0x4000_0000, 0x4040_0000, 0),
+                words(0x11, 0x22, 0x33, 0x44),
+                words(0xF234, 0x4567, 0, 0),
+                xyzf,
+                xyzf | 1 << 111,
+                words(0x300, 0x400, 0xDEAD_BEEF, 0),
+                words(0, 0, 0, 0x77 << 4),
+                ad(gs::SCISSOR_1, 0x0123_4567_89AB_CDEF),
+                words(1, 2, 3, 4),
+            ],
+        );
+        let mut sink = Sink::default();
+        gif.run(&mut sink);
+        let xyzf_value = 0x0200_0100 | 0x12_3456 << 32 | 0x9A << 56;
+        assert_eq!(
+            sink.0,
+            [
+                Register(gs::PRIM, 5),
+                Register(gs::ST, 0x4000_0000_3F80_0000),
+                // Q comes from the preceding ST
+                Register(gs::RGBAQ, 0x4040_0000_4433_2211),
+                Register(gs::UV, 0x0567_3234),
+                Register(gs::XYZF2, xyzf_value),
+                // ADC turns the kick off
+                Register(gs::XYZF3, xyzf_value),
+                Register(gs::XYZ2, 0xDEAD_BEEF_0400_0300),
+                Register(gs::FOG, 0x77 << 56),
+                Register(gs::SCISSOR_1, 0x0123_4567_89AB_CDEF),
+            ]
+        );
+
+        // A new tag starts Q over at 1.0
+        let mut sink = Sink::default();
+        push(
+            &mut gif,
+            Path::Path3,
+            &[
+                giftag(1, true, FLG_PACKED, &[gs::RGBAQ as u32]),
+                words(1, 2, 3, 4),
+            ],
+        );
+        gif.run(&mut sink);
+        assert_eq!(sink.0, [Register(gs::RGBAQ, 0x3F80_0000_0403_0201)]);
+    }
+
+    #[test]
+    fn reglist_packs_two_registers_per_quadword() {
+        // Three registers twice over fill three quadwords exactly
+        let regs = [gs::PRIM as u32, gs::RGBAQ as u32, gs::XYZ2 as u32];
+        let mut gif = Gif::new();
+        let mut sink = Sink::default();
+        push(
+            &mut gif,
+            Path::Path2,
+            &[
+                giftag(2, false, FLG_REGLIST, &regs),
+                1 | 2 << 64,
+                3 | 4 << 64,
+                5 | 6 << 64,
+            ],
+        );
+        gif.run(&mut sink);
+        let expected = [
+            Register(gs::PRIM, 1),
+            Register(gs::RGBAQ, 2),
+            Register(gs::XYZ2, 3),
+            Register(gs::PRIM, 4),
+            Register(gs::RGBAQ, 5),
+            Register(gs::XYZ2, 6),
+        ];
+        assert_eq!(sink.0, expected);
+
+        // Once over, the last quadword's upper half is padding and the next tag follows it.
+        // A+D and NOP descriptors write nothing in REGLIST mode.
+        let mut sink = Sink::default();
+        let regs = [gs::PRIM as u32, REG_AD, gs::XYZ2 as u32];
+        push(
+            &mut gif,
+            Path::Path2,
+            &[
+                giftag(1, false, FLG_REGLIST, &regs),
+                1 | 2 << 64,
+                3 | 0xFF << 64,
+            ],
+        );
+        push(&mut gif, Path::Path2, &packet(gs::SCISSOR_1, 7));
+        gif.run(&mut sink);
+        assert_eq!(
+            sink.0,
+            [
+                Register(gs::PRIM, 1),
+                Register(gs::XYZ2, 3),
+                Register(gs::SCISSOR_1, 7),
+            ]
+        );
+        assert!(!gif.in_packet(Path::Path2));
+    }
+
+    #[test]
+    fn empty_tags_and_sixteen_register_tags() {
+        let mut gif = Gif::new();
+        let mut sink = Sink::default();
+        // NLOOP=0 carries no data, and PRE still sets PRIM
+        push(
+            &mut gif,
+            Path::Path3,
+            &[giftag(0, false, FLG_PACKED, &[REG_AD]) | 1 << 46 | 3 << 47],
+        );
+        gif.run(&mut sink);
+        assert_eq!(sink.0, [Register(gs::PRIM, 3)]);
+        assert!(gif.in_packet(Path::Path3));
+        assert_eq!(gif.active_path(), Some(Path::Path3));
+
+        // NREG=0 means sixteen registers
+        let mut tag = giftag(1, false, FLG_REGLIST, &[]);
+        tag |= 0x0123_4567_89AB_CDEF_u128 << 64;
+        push(&mut gif, Path::Path3, &[tag]);
+        push(
+            &mut gif,
+            Path::Path3,
+            &(0..8)
+                .map(|i| (2 * i) | (2 * i + 1) << 64)
+                .collect::<Vec<_>>(),
+        );
+        let mut sink = Sink::default();
+        gif.run(&mut sink);
+        // Descriptors are taken from the lowest nibble up
+        let expected: Vec<_> = (0..16)
+            .map(|i| (0xF - i, i))
+            .filter(|&(reg, _)| reg < REG_AD as u64)
+            .map(|(reg, value)| Register(reg as u8, value))
+            .collect();
+        assert_eq!(sink.0, expected);
+
+        // An empty EOP tag ends the packet
+        push(&mut gif, Path::Path3, &[giftag(0, true, FLG_IMAGE, &[])]);
+        gif.run(&mut sink);
+        assert!(!gif.in_packet(Path::Path3));
+        assert_eq!(gif.active_path(), None);
+    }
+
+    #[test]
+    fn paths_take_turns_by_priority_between_packets() {
+        let mut gif = Gif::new();
+        let mut sink = Sink::default();
+        push(&mut gif, Path::Path3, &packet(gs::TEXFLUSH, 3));
+        push(&mut gif, Path::Path2, &packet(gs::TEXFLUSH, 2));
+        push(&mut gif, Path::Path1, &packet(gs::TEXFLUSH, 1));
+        gif.run(&mut sink);
+        assert_eq!(sink.0, [1, 2, 3].map(|value| Register(gs::TEXFLUSH, value)));
+
+        // A started packet keeps the GIF, even waiting for data, over a higher priority path
+        let mut sink = Sink::default();
+        let two_writes = giftag(2, true, FLG_PACKED, &[REG_AD]);
+        push(&mut gif, Path::Path3, &[two_writes, ad(gs::TEXFLUSH, 3)]);
+        gif.run(&mut sink);
+        push(&mut gif, Path::Path1, &packet(gs::TEXFLUSH, 1));
+        gif.run(&mut sink);
+        assert_eq!(gif.active_path(), Some(Path::Path3));
+        assert_eq!(gif.queued(Path::Path1), 2);
+        push(&mut gif, Path::Path3, &[ad(gs::TEXFLUSH, 4)]);
+        gif.run(&mut sink);
+        assert_eq!(sink.0, [3, 4, 1].map(|value| Register(gs::TEXFLUSH, value)));
+        assert_eq!(gif.active_path(), None);
+    }
+
+    #[test]
+    fn masked_path3_finishes_its_packet_but_starts_no_other() {
+        for mask in [
+            |gif: &mut Gif, on: bool| {
+                gif.write(GIF_MODE, if on { GIF_MODE_M3R } else { 0 });
+            },
+            |gif: &mut Gif, on: bool| gif.set_vif_mask(on),
+        ] {
+            let mut gif = Gif::new();
+            let mut sink = Sink::default();
+            mask(&mut gif, true);
+            push(&mut gif, Path::Path3, &packet(gs::TEXFLUSH, 1));
+            gif.run(&mut sink);
+            assert!(sink.0.is_empty());
+            assert_eq!(gif.queued(Path::Path3), 2);
+            // The other paths aren't affected
+            push(&mut gif, Path::Path2, &packet(gs::TEXFLUSH, 2));
+            gif.run(&mut sink);
+            assert_eq!(sink.0, [Register(gs::TEXFLUSH, 2)]);
+
+            mask(&mut gif, false);
+            let two_writes = giftag(2, true, FLG_PACKED, &[REG_AD]);
+            push(&mut gif, Path::Path3, &[two_writes, ad(gs::TEXFLUSH, 3)]);
+            gif.run(&mut sink);
+            mask(&mut gif, true);
+            push(&mut gif, Path::Path3, &[ad(gs::TEXFLUSH, 4)]);
+            push(&mut gif, Path::Path3, &packet(gs::TEXFLUSH, 5));
+            gif.run(&mut sink);
+            assert_eq!(
+                sink.0,
+                [2, 1, 3, 4].map(|value| Register(gs::TEXFLUSH, value))
+            );
+            assert_eq!(gif.queued(Path::Path3), 2);
+            assert!(!gif.in_packet(Path::Path3));
+        }
+    }
+
+    // A PATH3 IMAGE packet of 20 quadwords, each doubleword numbered
+    fn image_packet() -> Vec<u128> {
+        let mut qwords = vec![giftag(20, true, FLG_IMAGE, &[])];
+        qwords.extend((0..20).map(|i: u128| (2 * i) | (2 * i + 1) << 64));
+        qwords
+    }
+
+    #[test]
+    fn intermittent_mode_lets_other_paths_in_every_eight_quadwords() {
+        for imt in [false, true] {
+            let mut gif = Gif::new();
+            gif.write(GIF_MODE, if imt { GIF_MODE_IMT } else { 0 });
+            let mut sink = Sink::default();
+            let image = image_packet();
+            push(&mut gif, Path::Path3, &image[..4]);
+            gif.run(&mut sink);
+            push(&mut gif, Path::Path2, &packet(gs::TEXFLUSH, 0));
+            push(&mut gif, Path::Path3, &image[4..]);
+            gif.run(&mut sink);
+
+            let mut expected: Vec<_> = (0..40).map(Image).collect();
+            let at = if imt { 16 } else { 40 };
+            expected.insert(at, Register(gs::TEXFLUSH, 0));
+            assert_eq!(sink.0, expected, "imt {imt}");
+        }
+    }
+
+    #[test]
+    fn gif_stat_reports_the_paths() {
+        let mut gif = Gif::new();
+        let mut sink = Sink::default();
+        gif.write(GIF_MODE, GIF_MODE_IMT | GIF_MODE_M3R);
+        gif.set_vif_mask(true);
+        assert_eq!(gif.stat(), GIF_STAT_IMT | GIF_STAT_M3R | GIF_STAT_M3P);
+        gif.write(GIF_MODE, GIF_MODE_IMT);
+        gif.set_vif_mask(false);
+
+        // PATH3 gets going on an image, then PATH2 queues a packet while the GIF is paused
+        let image = image_packet();
+        push(&mut gif, Path::Path3, &image[..4]);
+        gif.run(&mut sink);
+        gif.write(GIF_CTRL, GIF_CTRL_PSE);
+        let two_writes = giftag(2, true, FLG_PACKED, &[REG_AD]);
+        push(&mut gif, Path::Path2, &[two_writes]);
+        push(&mut gif, Path::Path3, &image[4..]);
+        gif.run(&mut sink);
+        assert_eq!(sink.0.len(), 6);
+        let apath3 = 3 << GIF_STAT_APATH_SHIFT;
+        assert_eq!(
+            gif.stat(),
+            GIF_STAT_IMT
+                | GIF_STAT_PSE
+                | GIF_STAT_P2Q
+                | GIF_STAT_P3Q
+                | GIF_STAT_OPH
+                | apath3
+                | 16 << GIF_STAT_FQC_SHIFT
+        );
+
+        // Let go, PATH3 gives way after eight quadwords to PATH2, which then waits for its data
+        gif.write(GIF_CTRL, 0);
+        gif.run(&mut sink);
+        let apath2 = 2 << GIF_STAT_APATH_SHIFT;
+        assert_eq!(
+            gif.stat(),
+            GIF_STAT_IMT
+                | GIF_STAT_IP3
+                | GIF_STAT_P3Q
+                | GIF_STAT_OPH
+                | apath2
+                | 12 << GIF_STAT_FQC_SHIFT
+        );
+        assert_eq!(gif.read(GIF_TAG0), Some(two_writes as u32));
+        assert_eq!(gif.read(GIF_TAG3), Some((two_writes >> 96) as u32));
+        assert_eq!(gif.read(GIF_CNT), Some(2));
+        assert_eq!(gif.read(GIF_P3CNT), Some(12));
+        assert_eq!(gif.read(GIF_P3TAG), Some(image[0] as u32 & 0xFFFF));
+
+        // With PATH2 done, PATH3 picks up where it left off
+        push(
+            &mut gif,
+            Path::Path2,
+            &[ad(gs::TEXFLUSH, 0), ad(gs::TEXFLUSH, 0)],
+        );
+        gif.run(&mut sink);
+        assert_eq!(gif.stat(), GIF_STAT_IMT);
+        assert_eq!(sink.0.len(), 42);
+
+        // A reset clears everything
+        push(&mut gif, Path::Path3, &[giftag(1, true, FLG_IMAGE, &[])]);
+        gif.run(&mut sink);
+        assert_ne!(gif.stat() & GIF_STAT_OPH, 0);
+        gif.write(GIF_CTRL, GIF_CTRL_RST);
+        assert_eq!(gif.stat(), 0);
+        assert_eq!(gif.read(GIF_TAG0), Some(0));
+    }
+}
@@ -0,0 +1,323 @@
+// GS local memory: 4 MiB addressed in 256-byte blocks, with pixels swizzled into pages, blocks
+// and columns the way the real GS lays them out. Each pixel storage format (PSM) has its own
+// arrangement, so data written in one format and read in another lands where it would on
+// hardware.
+//
+// Buffer widths are in units of 64 pixels and base pointers in blocks, as in the GS registers.
+
+// Pixel storage formats
+pub const PSMCT32: u32 = 0x00;
+pub const PSMCT24: u32 = 0x01;
+pub const PSMCT16: u32 = 0x02;
+pub const PSMCT16S: u32 = 0x0A;
+pub const PSMT8: u32 = 0x13;
+pub const PSMT4: u32 = 0x14;
+pub const PSMT8H: u32 = 0x1B;
+pub const PSMT4HL: u32 = 0x24;
+pub const PSMT4HH: u32 = 0x2C;
+pub const PSMZ32: u32 = 0x30;
+pub const PSMZ24: u32 = 0x31;
+pub const PSMZ16: u32 = 0x32;
+pub const PSMZ16S: u32 = 0x3A;
+
+pub const LOCAL_MEMORY_SIZE: usize = 4 * 1024 * 1024;
+
+const BLOCK_COUNT: u32 = (LOCAL_MEMORY_SIZE / 256) as u32;
+
+// Block order within a page, by block row and column
+const BLOCK_32: [[u32; 8]; 4] = [
+    [0, 1, 4, 5, 16, 17, 20, 21],
+    [2, 3, 6, 7, 18, 19, 22, 23],
+    [8, 9, 12, 13, 24, 25, 28, 29],
+    [10, 11, 14, 15, 26, 27, 30, 31],
+];
+const BLOCK_32Z: [[u32; 8]; 4] = [
+    [24, 25, 28, 29, 8, 9, 12, 13],
+    [26, 27, 30, 31, 10, 11, 14, 15],
+    [16, 17, 20, 21, 0, 1, 4, 5],
+    [18, 19, 22, 23, 2, 3, 6, 7],
+];
+const BLOCK_16: [[u32; 4]; 8] = [
+    [0, 2, 8, 10],
+    [1, 3, 9, 11],
+    [4, 6, 12, 14],
+    [5, 7, 13, 15],
+    [16, 18, 24, 26],
+    [17, 19, 25, 27],
+    [20, 22, 28, 30],
+    [21, 23, 29, 31],
+];
+const BLOCK_16S: [[u32; 4]; 8] = [
+    [0, 2, 16, 18],
+    [1, 3, 17, 19],
+    [8, 10, 24, 26],
+    [9, 11, 25, 27],
+    [4, 6, 20, 22],
+    [5, 7, 21, 23],
+    [12, 14, 28, 30],
+    [13, 15, 29, 31],
+];
+const BLOCK_16Z: [[u32; 4]; 8] = [
+    [24, 26, 16, 18],
+    [25, 27, 17, 19],
+    [28, 30, 20, 22],
+    [29, 31, 21, 23],
+    [8, 10, 0, 2],
+    [9, 11, 1, 3],
+    [12, 14, 4, 6],
+    [13, 15, 5, 7],
+];
+const BLOCK_16SZ: [[u32; 4]; 8] = [
+    [24, 26, 8, 10],
+    [25, 27, 9, 11],
+    [16, 18, 0, 2],
+    [17, 19, 1, 3],
+    [28, 30, 12, 14],
+    [29, 31, 13, 15],
+    [20, 22, 4, 6],
+    [21, 23, 5, 7],
+];
+
+// Pixel order within a block
+const COLUMN_32: [[u32; 8]; 8] = [
+    [0, 1, 4, 5, 8, 9, 12, 13],
+    [2, 3, 6, 7, 10, 11, 14, 15],
+    [16, 17, 20, 21, 24, 25, 28, 29],
+    [18, 19, 22, 23, 26, 27, 30, 31],
+    [32, 33, 36, 37, 40, 41, 44, 45],
+    [34, 35, 38, 39, 42, 43, 46, 47],
+    [48, 49, 52, 53, 56, 57, 60, 61],
+    [50, 51, 54, 55, 58, 59, 62, 63],
+];
+const COLUMN_16: [[u32; 16]; 8] = [
+    [0, 2, 8, 10, 16, 18, 24, 26, 1, 3, 9, 11, 17, 19, 25, 27],
+    [4, 6, 12, 14, 20, 22, 28, 30, 5, 7, 13, 15, 21, 23, 29, 31],
+    [
+        32, 34, 40, 42, 48, 50, 56, 58, 33, 35, 41, 43, 49, 51, 57, 59,
+    ],
+    [
+        36, 38, 44, 46, 52, 54, 60, 62, 37, 39, 45, 47, 53, 55, 61, 63,
+    ],
+    [
+        64, 66, 72, 74, 80, 82, 88, 90, 65, 67, 73, 75, 81, 83, 89, 91,
+    ],
+    [
+        68, 70, 76, 78, 84, 86, 92, 94, 69, 71, 77, 79, 85, 87, 93, 95,
+    ],
+    [
+        96, 98, 104, 106, 112, 114, 120, 122, 97, 99, 105, 107, 113, 115, 121, 123,
+    ],
+    [
+        100, 102, 108, 110, 116, 118, 124, 126, 101, 103, 109, 111, 117, 119, 125, 127,
+    ],
+];
+// The first column of an 8-bit block, four rows of 16 pixels. The other three columns repeat
+// it 64 bytes further on each, odd ones with each run of four pixels swapped with its neighbour.
+const COLUMN_8: [[u32; 16]; 4] = [
+    [0, 4, 16, 20, 32, 36, 48, 52, 2, 6, 18, 22, 34, 38, 50, 54],
+    [
+        8, 12, 24, 28, 40, 44, 56, 60, 10, 14, 26, 30, 42, 46, 58, 62,
+    ],
+    [33, 37, 49, 53, 1, 5, 17, 21, 35, 39, 51, 55, 3, 7, 19, 23],
+    [
+        41, 45, 57, 61, 9, 13, 25, 29, 43, 47, 59, 63, 11, 15, 27, 31,
+    ],
+];
+// Likewise for 4-bit blocks, in nibbles, four rows of 32 pixels
+const COLUMN_4: [[u32; 32]; 4] = [
+    [
+        0, 8, 32, 40, 64, 72, 96, 104, 2, 10, 34, 42, 66, 74, 98, 106, 4, 12, 36, 44, 68, 76, 100,
+        108, 6, 14, 38, 46, 70, 78, 102, 110,
+    ],
+    [
+        16, 24, 48, 56, 80, 88, 112, 120, 18, 26, 50, 58, 82, 90, 114, 122, 20, 28, 52, 60, 84, 92,
+        116, 124, 22, 30, 54, 62, 86, 94, 118, 126,
+    ],
+    [
+        65, 73, 97, 105, 1, 9, 33, 41, 67, 75, 99, 107, 3, 11, 35, 43, 69, 77, 101, 109, 5, 13, 37,
+        45, 71, 79, 103, 111, 7, 15, 39, 47,
+    ],
+    [
+        81, 89, 113, 121, 17, 25, 49, 57, 83, 91, 115, 123, 19, 27, 51, 59, 85, 93, 117, 125, 21,
+        29, 53, 61, 87, 95, 119, 127, 23, 31, 55, 63,
+    ],
+];
+
+// Position in a column of an 8 or 4-bit block: `row` is y & 15 and `x` the pixel's x within the
+// block. `per_column` is the column's size in pixels.
+fn column_small<const W: usize>(table: &[[u32; W]; 4], row: u32, x: u32, per_column: u32) -> u32 {
+    let column = row / 4;
+    // Odd columns swap each run of four pixels with its neighbour
+    let x = if column & 1 != 0 { x ^ 4 } else { x };
+    table[(row % 4) as usize][x as usize] + column * per_column
+}
+
+// Bits a pixel takes up in local memory. PSMT8H, PSMT4HL and PSMT4HH live in the upper bits of
+// 32-bit pixels and count as 32 here, see `transfer_bits_per_pixel`.
+pub fn bits_per_pixel(psm: u32) -> u32 {
+    match psm {
+        PSMCT24 | PSMZ24 => 24,
+        PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => 16,
+        PSMT8 => 8,
+        PSMT4 => 4,
+        _ => 32,
+    }
+}
+
+// Bits a pixel takes up in a host to local transfer, where every format is packed to its own
+// width
+pub fn transfer_bits_per_pixel(psm: u32) -> u32 {
+    match psm {
+        PSMT8H => 8,
+        PSMT4HL | PSMT4HH => 4,
+        _ => bits_per_pixel(psm),
+    }
+}
+
+fn block_32(psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+    let table = if matches!(psm, PSMZ32 | PSMZ24) {
+        &BLOCK_32Z
+    } else {
+        &BLOCK_32
+    };
+    bp + (y & !0x1F) * bw
+        + ((x >> 1) & !0x1F)
+        + table[((y >> 3) & 3) as usize][((x >> 3) & 7) as usize]
+}
+
+fn block_16(psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+    let table = match psm {
+        PSMCT16S => &BLOCK_16S,
+        PSMZ16 => &BLOCK_16Z,
+        PSMZ16S => &BLOCK_16SZ,
+        _ => &BLOCK_16,
+    };
+    bp + ((y >> 1) & !0x1F) * bw
+        + ((x >> 1) & !0x1F)
+        + table[((y >> 3) & 7) as usize][((x >> 4) & 3) as usize]
+}
+
+fn block_8(bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+    bp + ((y >> 1) & !0x1F) * (bw >> 1)
+        + ((x >> 2) & !0x1F)
+        + BLOCK_32[((y >> 4) & 3) as usize][((x >> 4) & 7) as usize]
+}
+
+fn block_4(bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+    bp + ((y >> 2) & !0x1F) * (bw >> 1)
+        + ((x >> 2) & !0x1F)
+        + BLOCK_16[((y >> 4) & 7) as usize][((x >> 5) & 3) as usize]
+}
+
+pub struct LocalMemory {
+    data: Vec<u8>,
+}
+
+impl Default for LocalMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LocalMemory {
+    pub fn new() -> Self {
+        LocalMemory {
+            data: vec![0; LOCAL_MEMORY_SIZE],
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    fn word(&self, index: u32) -> u32 {
+        let offset = (index as usize * 4) % LOCAL_MEMORY_SIZE;
+        u32::from_le_bytes(self.data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn set_word(&mut self, index: u32, value: u32) {
+        let offset = (index as usize * 4) % LOCAL_MEMORY_SIZE;
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    // Word index of a pixel in one of the 32-bit layouts
+    fn word_32(psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+        let block = block_32(psm, bp, bw, x, y) % BLOCK_COUNT;
+        block * 64 + COLUMN_32[(y & 7) as usize][(x & 7) as usize]
+    }
+
+    // Halfword index of a pixel in one of the 16-bit layouts
+    fn halfword_16(psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+        let block = block_16(psm, bp, bw, x, y) % BLOCK_COUNT;
+        block * 128 + COLUMN_16[(y & 7) as usize][(x & 15) as usize]
+    }
+
+    fn byte_8(bp: u32, bw: u32, x: u32, y: u32) -> usize {
+        let block = block_8(bp, bw, x, y) % BLOCK_COUNT;
+        (block * 256 + column_small(&COLUMN_8, y & 15, x & 15, 64)) as usize
+    }
+
+    fn nibble_4(bp: u32, bw: u32, x: u32, y: u32) -> usize {
+        let block = block_4(bp, bw, x, y) % BLOCK_COUNT;
+        (block * 512 + column_small(&COLUMN_4, y & 15, x & 31, 128)) as usize
+    }
+
+    // Reads a pixel, zero extended. The H formats read their bits out of a 32-bit word.
+    pub fn read_pixel(&self, psm: u32, bp: u32, bw: u32, x: u32, y: u32) -> u32 {
+        match psm {
+            PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => {
+                let index = Self::halfword_16(psm, bp, bw, x, y) as usize * 2;
+                u16::from_le_bytes([self.data[index], self.data[index + 1]]) as u32
+            }
+            PSMT8 => self.data[Self::byte_8(bp, bw, x, y)] as u32,
+            PSMT4 => {
+                let nibble = Self::nibble_4(bp, bw, x, y);
+                (self.data[nibble / 2] >> ((nibble & 1) * 4)) as u32 & 0xF
+            }
+            _ => {
+                let word = self.word(Self::word_32(psm, bp, bw, x, y));
+                match psm {
+                    PSMCT24 | PSMZ24 => word & 0xFF_FFFF,
+                    PSMT8H => word >> 24,
+                    PSMT4HL => (word >> 24) & 0xF,
+                    PSMT4HH => word >> 28,
+                    _ => word,
+                }
+            }
+        }
+    }
+
+    // Writes a pixel, leaving the bits of the word other formats share untouched
+    pub fn write_pixel(&mut self, psm: u32, bp: u32, bw: u32, x: u32, y: u32, value: u32) {
+        match psm {
+            PSMCT16 | PSMCT16S | PSMZ16 | PSMZ16S => {
+                let index = Self::halfword_16(psm, bp, bw, x, y) as usize * 2;
+                self.data[index..index + 2].copy_from_slice(&(value as u16).to_le_bytes());
+            }
+            PSMT8 => self.data[Self::byte_8(bp, bw, x, y)] = value as u8,
+            PSMT4 => {
+                let nibble = Self::nibble_4(bp, bw, x, y);
+                let shift = (nibble & 1) * 4;
+                let byte = &mut self.data[nibble / 2];
+                *byte = (*byte & !(0xF << shift)) | ((value as u8 & 0xF) << shift);
+            }
+            _ => {
+                let index = Self::word_32(psm, bp, bw, x, y);
+                let (mask, shift) = match psm {
+                    PSMCT24 | PSMZ24 => (0xFF_FFFF, 0),
+                    PSMT8H => (0xFF, 24),
+                    PSMT4HL => (0xF, 24),
+                    PSMT4HH => (0xF, 28),
+                    _ => (0xFFFF_FFFF, 0),
+                };
+                let word = self.word(index);
+                let word = (word & !(mask << shift)) | ((value & mask) << shift);
+                self.set_word(index, word);
+            }
+        }
+    }
+}
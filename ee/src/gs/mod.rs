@@ -0,0 +1,577 @@
+// The Graphics Synthesizer's drawing side: its general registers and local memory.
+//
+// Writing XYZ2 or XYZF2 adds a vertex and, once there are enough for the primitive PRIM names,
+// draws it into the current context's FRAME and ZBUF buffers (see `raster`); XYZ3 and XYZF3 add
+// a vertex without drawing. Strips and fans keep the vertices the next primitive shares, and
+// writing PRIM starts over. A vertex takes its colour and Q from RGBAQ, its texture coordinates
+// from ST and UV, and its position less the context's XYOFFSET. With texture mapping on, each
+// fragment's colour is combined with its texel (see `texture`) before the fragment goes through
+// the tests and blending on its way into the buffers (see `pixel`).
+//
+// Writing TEX0 or TEX2 loads the CLUT when its CLD field asks for it.
+//
+// Registers are written by the GIF, by number. Writing TRXDIR starts a transfer set up by
+// BITBLTBUF, TRXPOS and TRXREG: a host to local transfer takes the image data that follows
+// through HWREG and stores it pixel by pixel into the destination rectangle, wrapping at its
+// width; a local to local transfer copies a rectangle at once. Local to host transfers (reading
+// back through the GIF FIFO) aren't emulated, so starting one does nothing.
+
+pub mod memory;
+pub mod pixel;
+pub mod raster;
+pub mod texture;
+
+use crate::gif::GifSink;
+use crate::savestate::{StateError, StateReader, StateWriter};
+use memory::LocalMemory;
+use raster::{Fragment, Scissor, Vertex};
+use texture::{Clut, Sampler};
+
+// General register numbers
+pub const PRIM: u8 = 0x00;
+pub const RGBAQ: u8 = 0x01;
+pub const ST: u8 = 0x02;
+pub const UV: u8 = 0x03;
+pub const XYZF2: u8 = 0x04;
+pub const XYZ2: u8 = 0x05;
+pub const TEX0_1: u8 = 0x06;
+pub const TEX0_2: u8 = 0x07;
+pub const CLAMP_1: u8 = 0x08;
+pub const CLAMP_2: u8 = 0x09;
+pub const FOG: u8 = 0x0A;
+pub const XYZF3: u8 = 0x0C;
+pub const XYZ3: u8 = 0x0D;
+pub const TEX1_1: u8 = 0x14;
+pub const TEX1_2: u8 = 0x15;
+pub const TEX2_1: u8 = 0x16;
+pub const TEX2_2: u8 = 0x17;
+pub const XYOFFSET_1: u8 = 0x18;
+pub const XYOFFSET_2: u8 = 0x19;
+pub const PRMODECONT: u8 = 0x1A;
+pub const PRMODE: u8 = 0x1B;
+pub const TEXCLUT: u8 = 0x1C;
+pub const SCANMSK: u8 = 0x22;
+pub const MIPTBP1_1: u8 = 0x34;
+pub const MIPTBP1_2: u8 = 0x35;
+pub const MIPTBP2_1: u8 = 0x36;
+pub const MIPTBP2_2: u8 = 0x37;
+pub const TEXA: u8 = 0x3B;
+pub const FOGCOL: u8 = 0x3D;
+pub const TEXFLUSH: u8 = 0x3F;
+pub const SCISSOR_1: u8 = 0x40;
+pub const SCISSOR_2: u8 = 0x41;
+pub const ALPHA_1: u8 = 0x42;
+pub const ALPHA_2: u8 = 0x43;
+pub const DIMX: u8 = 0x44;
+pub const DTHE: u8 = 0x45;
+pub const COLCLAMP: u8 = 0x46;
+pub const TEST_1: u8 = 0x47;
+pub const TEST_2: u8 = 0x48;
+pub const PABE: u8 = 0x49;
+pub const FBA_1: u8 = 0x4A;
+pub const FBA_2: u8 = 0x4B;
+pub const FRAME_1: u8 = 0x4C;
+pub const FRAME_2: u8 = 0x4D;
+pub const ZBUF_1: u8 = 0x4E;
+pub const ZBUF_2: u8 = 0x4F;
+pub const BITBLTBUF: u8 = 0x50;
+pub const TRXPOS: u8 = 0x51;
+pub const TRXREG: u8 = 0x52;
+pub const TRXDIR: u8 = 0x53;
+pub const HWREG: u8 = 0x54;
+pub const SIGNAL: u8 = 0x60;
+pub const FINISH: u8 = 0x61;
+pub const LABEL: u8 = 0x62;
+
+pub const REG_COUNT: usize = 0x63;
+
+// Drawing attributes, in PRIM (after the primitive type) and PRMODE
+pub const PRIM_TYPE_MASK: u64 = 0x7;
+pub const PRIM_IIP: u64 = 1 << 3;
+pub const PRIM_TME: u64 = 1 << 4;
+pub const PRIM_FGE: u64 = 1 << 5;
+pub const PRIM_ABE: u64 = 1 << 6;
+pub const PRIM_AA1: u64 = 1 << 7;
+pub const PRIM_FST: u64 = 1 << 8;
+pub const PRIM_CTXT: u64 = 1 << 9;
+pub const PRIM_FIX: u64 = 1 << 10;
+
+// PRMODECONT.AC: take the attributes from PRIM rather than PRMODE
+pub const PRMODECONT_AC: u64 = 1 << 0;
+
+// The fields of TEX0 that TEX2 writes: PSM and CBP through CLD
+const TEX2_FIELDS: u64 = 0x3F << 20 | !0 << 37;
+
+// TRXDIR.XDIR
+pub const XDIR_HOST_TO_LOCAL: u64 = 0;
+pub const XDIR_LOCAL_TO_HOST: u64 = 1;
+pub const XDIR_LOCAL_TO_LOCAL: u64 = 2;
+
+// Transfer coordinates wrap at the GS's 2048x2048 address space
+const COORD_MASK: u32 = 0x7FF;
+
+// `bits` bits of `value` starting at `shift`
+pub(crate) fn field(value: u64, shift: u32, bits: u32) -> u32 {
+    ((value >> shift) & ((1 << bits) - 1)) as u32
+}
+
+// A buffer as BITBLTBUF describes it
+#[derive(Clone, Copy, Debug)]
+struct Buffer {
+    base: u32,
+    width: u32,
+    psm: u32,
+}
+
+// A host to local transfer in progress
+#[derive(Clone, Copy, Debug, Default)]
+struct Transfer {
+    // Next pixel, relative to the rectangle's corner
+    x: u32,
+    y: u32,
+    // Image data not yet making up a whole pixel, low bits first
+    pending: u128,
+    pending_bits: u32,
+}
+
+pub struct Gs {
+    regs: [u64; REG_COUNT],
+    memory: LocalMemory,
+    transfer: Option<Transfer>,
+    // Vertices waiting for the rest of their primitive
+    vertices: Vec<Vertex>,
+    clut: Clut,
+    // CLUT base pointers CLD last recorded, for its load-if-changed modes
+    cbp0: u32,
+    cbp1: u32,
+}
+
+impl Default for Gs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gs {
+    pub fn new() -> Self {
+        let mut regs = [0; REG_COUNT];
+        regs[PRMODECONT as usize] = PRMODECONT_AC;
+        Gs {
+            regs,
+            memory: LocalMemory::new(),
+            transfer: None,
+            vertices: Vec::new(),
+            clut: Clut::default(),
+            cbp0: 0,
+            cbp1: 0,
+        }
+    }
+
+    pub fn reg(&self, reg: u8) -> u64 {
+        self.regs.get(reg as usize).copied().unwrap_or(0)
+    }
+
+    pub fn memory(&self) -> &LocalMemory {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut LocalMemory {
+        &mut self.memory
+    }
+
+    // Writes to unused register numbers are dropped
+    pub fn write_register(&mut self, reg: u8, value: u64) {
+        let Some(slot) = self.regs.get_mut(reg as usize) else {
+            return;
+        };
+        *slot = value;
+        match reg {
+            TRXDIR => self.start_transfer(value & 3),
+            HWREG => self.write_image(value),
+            PRIM => self.vertices.clear(),
+            XYZ2 | XYZF2 => self.add_vertex(reg, value, true),
+            XYZ3 | XYZF3 => self.add_vertex(reg, value, false),
+            TEX0_1 | TEX0_2 => self.update_clut(value),
+            TEX2_1 | TEX2_2 => {
+                let tex0 = &mut self.regs[(reg - TEX2_1 + TEX0_1) as usize];
+                *tex0 = (*tex0 & !TEX2_FIELDS) | (value & TEX2_FIELDS);
+                let tex0 = *tex0;
+                self.update_clut(tex0);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn clut(&self) -> &Clut {
+        &self.clut
+    }
+
+    // Loads the CLUT if TEX0.CLD says to
+    fn update_clut(&mut self, tex0: u64) {
+        let cbp = field(tex0, 37, 14);
+        let load = match field(tex0, 61, 3) {
+            1 => true,
+            2 => {
+                self.cbp0 = cbp;
+                true
+            }
+            3 => {
+                self.cbp1 = cbp;
+                true
+            }
+            4 => std::mem::replace(&mut self.cbp0, cbp) != cbp,
+            5 => std::mem::replace(&mut self.cbp1, cbp) != cbp,
+            _ => false,
+        };
+        if load {
+            let texclut = self.regs[TEXCLUT as usize];
+            self.clut.load(&self.memory, tex0, texclut);
+        }
+    }
+
+    // The drawing attributes in effect
+    fn attributes(&self) -> u64 {
+        if self.regs[PRMODECONT as usize] & PRMODECONT_AC != 0 {
+            self.regs[PRIM as usize]
+        } else {
+            self.regs[PRMODE as usize]
+        }
+    }
+
+    fn primitive(&self) -> u32 {
+        (self.regs[PRIM as usize] & PRIM_TYPE_MASK) as u32
+    }
+
+    // 0 or 1, to add to a context 1 register number for its context 2 twin
+    fn context(&self) -> u8 {
+        (self.attributes() & PRIM_CTXT != 0) as u8
+    }
+
+    fn add_vertex(&mut self, reg: u8, value: u64, kick: bool) {
+        let context = self.context();
+        let offset = self.regs[(XYOFFSET_1 + context) as usize];
+        let rgbaq = self.regs[RGBAQ as usize];
+        let (st, uv) = (self.regs[ST as usize], self.regs[UV as usize]);
+        let with_fog = matches!(reg, XYZF2 | XYZF3);
+        let (z, fog) = if with_fog {
+            (field(value, 32, 24), field(value, 56, 8))
+        } else {
+            (field(value, 32, 32), field(self.regs[FOG as usize], 56, 8))
+        };
+        self.vertices.push(Vertex {
+            x: field(value, 0, 16) as i32 - field(offset, 0, 16) as i32,
+            y: field(value, 16, 16) as i32 - field(offset, 32, 16) as i32,
+            z,
+            rgba: (rgbaq as u32).to_le_bytes(),
+            fog: fog as u8,
+            s: f32::from_bits(st as u32),
+            t: f32::from_bits((st >> 32) as u32),
+            q: f32::from_bits((rgbaq >> 32) as u32),
+            u: field(uv, 0, 14) as i32,
+            v: field(uv, 16, 14) as i32,
+        });
+
+        let prim = self.primitive();
+        if self.vertices.len() < raster::vertex_count(prim) {
+            return;
+        }
+        if kick {
+            self.draw(prim, context);
+        }
+        match prim {
+            raster::PRIM_LINE_STRIP | raster::PRIM_TRIANGLE_STRIP => {
+                self.vertices.remove(0);
+            }
+            raster::PRIM_TRIANGLE_FAN => {
+                self.vertices.remove(1);
+            }
+            _ => self.vertices.clear(),
+        }
+    }
+
+    fn draw(&mut self, prim: u32, context: u8) {
+        let scissor = self.regs[(SCISSOR_1 + context) as usize];
+        let scissor = Scissor {
+            x0: field(scissor, 0, 11) as i32,
+            x1: field(scissor, 16, 11) as i32,
+            y0: field(scissor, 32, 11) as i32,
+            y1: field(scissor, 48, 11) as i32,
+        };
+        let gouraud = self.attributes() & PRIM_IIP != 0;
+        let vertices = std::mem::take(&mut self.vertices);
+        raster::draw(prim, &vertices, gouraud, scissor, &mut |fragment| {
+            self.write_fragment(context, fragment)
+        });
+        self.vertices = vertices;
+    }
+
+    // A fragment's colour after texture mapping
+    fn shade(&self, context: u8, fragment: &Fragment) -> [u8; 4] {
+        let attributes = self.attributes();
+        if attributes & PRIM_TME == 0 {
+            return fragment.rgba;
+        }
+        let reg = |reg: u8| self.regs[(reg + context) as usize];
+        let tex0 = reg(TEX0_1);
+        let sampler = Sampler {
+            memory: &self.memory,
+            clut: &self.clut,
+            tex0,
+            tex1: reg(TEX1_1),
+            clamp: reg(CLAMP_1),
+            texa: self.regs[TEXA as usize],
+            miptbp1: reg(MIPTBP1_1),
+            miptbp2: reg(MIPTBP2_1),
+        };
+        let (u, v) = if attributes & PRIM_FST != 0 {
+            (fragment.u as f32 / 16.0, fragment.v as f32 / 16.0)
+        } else {
+            let width = (1 << field(tex0, 26, 4).min(10)) as f32;
+            let height = (1 << field(tex0, 30, 4).min(10)) as f32;
+            (
+                fragment.s / fragment.q * width,
+                fragment.t / fragment.q * height,
+            )
+        };
+        let texel = sampler.sample(u, v, fragment.q);
+        let tcc = tex0 & 1 << 34 != 0;
+        texture::apply(field(tex0, 35, 2), tcc, texel, fragment.rgba)
+    }
+
+    fn source(&self) -> Buffer {
+        let bitbltbuf = self.regs[BITBLTBUF as usize];
+        Buffer {
+            base: field(bitbltbuf, 0, 14),
+            width: field(bitbltbuf, 16, 6),
+            psm: field(bitbltbuf, 24, 6),
+        }
+    }
+
+    fn destination(&self) -> Buffer {
+        let bitbltbuf = self.regs[BITBLTBUF as usize];
+        Buffer {
+            base: field(bitbltbuf, 32, 14),
+            width: field(bitbltbuf, 48, 6),
+            psm: field(bitbltbuf, 56, 6),
+        }
+    }
+
+    // The rectangle's width and height
+    fn transfer_size(&self) -> (u32, u32) {
+        let trxreg = self.regs[TRXREG as usize];
+        (field(trxreg, 0, 12), field(trxreg, 32, 12))
+    }
+
+    fn start_transfer(&mut self, direction: u64) {
+        self.transfer = None;
+        match direction {
+            XDIR_HOST_TO_LOCAL => self.transfer = Some(Transfer::default()),
+            XDIR_LOCAL_TO_LOCAL => self.copy_rectangle(),
+            _ => {}
+        }
+    }
+
+    // Image data for a host to local transfer. Data past the end of the rectangle is dropped.
+    pub fn write_image(&mut self, data: u64) {
+        let Some(mut transfer) = self.transfer.take() else {
+            return;
+        };
+        let dst = self.destination();
+        let (width, height) = self.transfer_size();
+        let trxpos = self.regs[TRXPOS as usize];
+        let (dsax, dsay) = (field(trxpos, 32, 11), field(trxpos, 48, 11));
+        let bits = memory::transfer_bits_per_pixel(dst.psm);
+
+        transfer.pending |= (data as u128) << transfer.pending_bits;
+        transfer.pending_bits += 64;
+        while transfer.pending_bits >= bits {
+            if width == 0 || transfer.y >= height {
+                return;
+            }
+            let pixel = (transfer.pending & ((1 << bits) - 1)) as u32;
+            transfer.pending >>= bits;
+            transfer.pending_bits -= bits;
+            let x = (dsax + transfer.x) & COORD_MASK;
+            let y = (dsay + transfer.y) & COORD_MASK;
+            self.memory
+                .write_pixel(dst.psm, dst.base, dst.width, x, y, pixel);
+            transfer.x += 1;
+            if transfer.x == width {
+                transfer.x = 0;
+                transfer.y += 1;
+            }
+        }
+        if transfer.y < height {
+            self.transfer = Some(transfer);
+        }
+    }
+
+    // Copies pixels in the destination's format; TRXPOS.DIR only matters to overlapping copies
+    // on hardware and is ignored
+    fn copy_rectangle(&mut self) {
+        let (src, dst) = (self.source(), self.destination());
+        let (width, height) = self.transfer_size();
+        let trxpos = self.regs[TRXPOS as usize];
+        let (ssax, ssay) = (field(trxpos, 0, 11), field(trxpos, 16, 11));
+        let (dsax, dsay) = (field(trxpos, 32, 11), field(trxpos, 48, 11));
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = ((ssax + x) & COORD_MASK, (ssay + y) & COORD_MASK);
+                let (dx, dy) = ((dsax + x) & COORD_MASK, (dsay + y) & COORD_MASK);
+                let pixel = self.memory.read_pixel(dst.psm, src.base, src.width, sx, sy);
+                self.memory
+                    .write_pixel(dst.psm, dst.base, dst.width, dx, dy, pixel);
+            }
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.vertices.len() as u32);
+        for vertex in &self.vertices {
+            w.write_u32(vertex.x as u32);
+            w.write_u32(vertex.y as u32);
+            w.write_u32(vertex.z);
+            w.write_u32(u32::from_le_bytes(vertex.rgba));
+            w.write_u8(vertex.fog);
+            w.write_u32(vertex.s.to_bits());
+            w.write_u32(vertex.t.to_bits());
+            w.write_u32(vertex.q.to_bits());
+            w.write_u32(vertex.u as u32);
+            w.write_u32(vertex.v as u32);
+        }
+        for &entry in self.clut.as_slice() {
+            w.write_u32(entry as u32);
+        }
+        w.write_u32(self.cbp0);
+        w.write_u32(self.cbp1);
+        for &reg in &self.regs {
+            w.write_u64(reg);
+        }
+        w.write_bytes(self.memory.as_slice());
+        w.write_bool(self.transfer.is_some());
+        let transfer = self.transfer.unwrap_or_default();
+        w.write_u32(transfer.x);
+        w.write_u32(transfer.y);
+        w.write_u128(transfer.pending);
+        w.write_u32(transfer.pending_bits);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let count = r.read_u32()?;
+        if count > 3 {
+            return Err(StateError::Mismatch(format!(
+                "{} GS vertices queued",
+                count
+            )));
+        }
+        let mut vertices = Vec::new();
+        for _ in 0..count {
+            vertices.push(Vertex {
+                x: r.read_u32()? as i32,
+                y: r.read_u32()? as i32,
+                z: r.read_u32()?,
+                rgba: r.read_u32()?.to_le_bytes(),
+                fog: r.read_u8()?,
+                s: f32::from_bits(r.read_u32()?),
+                t: f32::from_bits(r.read_u32()?),
+                q: f32::from_bits(r.read_u32()?),
+                u: r.read_u32()? as i32,
+                v: r.read_u32()? as i32,
+            });
+        }
+        let mut clut = Clut::default();
+        for entry in clut.as_mut_slice() {
+            *entry = r.read_u32()? as u16;
+        }
+        let cbp0 = r.read_u32()?;
+        let cbp1 = r.read_u32()?;
+        let mut regs = [0; REG_COUNT];
+        for reg in regs.iter_mut() {
+            *reg = r.read_u64()?;
+        }
+        let memory = r.read_bytes()?;
+        if memory.len() != memory::LOCAL_MEMORY_SIZE {
+            return Err(StateError::Mismatch(format!(
+                "GS local memory is {} bytes, state has {}",
+                memory::LOCAL_MEMORY_SIZE,
+                memory.len()
+            )));
+        }
+        let in_transfer = r.read_bool()?;
+        let transfer = Transfer {
+            x: r.read_u32()?,
+            y: r.read_u32()?,
+            pending: r.read_u128()?,
+            pending_bits: r.read_u32()?,
+        };
+        self.regs = regs;
+        self.memory.as_mut_slice().copy_from_slice(memory);
+        self.transfer = in_transfer.then_some(transfer);
+        self.vertices = vertices;
+        self.clut = clut;
+        self.cbp0 = cbp0;
+        self.cbp1 = cbp1;
+        Ok(())
+    }
+}
+
+impl GifSink for Gs {
+    fn write_register(&mut self, reg: u8, value: u64) {
+        Gs::write_register(self, reg, value);
+    }
+
+    fn write_image(&mut self, data: u64) {
+        Gs::write_image(self, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gs::memory::{PSMCT32, PSMT4HL, PSMT8H};
+
+    // Uploads `data` as a width x 1 image at block 0 of a 64 pixel wide buffer that already holds
+    // 0x00ABCDEF everywhere
+    fn upload(psm: u32, width: u64, data: &[u64]) -> Gs {
+        let mut gs = Gs::new();
+        gs.write_register(BITBLTBUF, 1 << 48 | (PSMCT32 as u64) << 56);
+        gs.write_register(TRXREG, 64 | 1 << 32);
+        gs.write_register(TRXDIR, XDIR_HOST_TO_LOCAL);
+        gs.write_register(HWREG, 0x00AB_CDEF_00AB_CDEF);
+        for _ in 1..32 {
+            gs.write_register(HWREG, 0x00AB_CDEF_00AB_CDEF);
+        }
+
+        gs.write_register(BITBLTBUF, 1 << 48 | (psm as u64) << 56);
+        gs.write_register(TRXREG, width | 1 << 32);
+        gs.write_register(TRXDIR, XDIR_HOST_TO_LOCAL);
+        for &word in data {
+            gs.write_register(HWREG, word);
+        }
+        gs
+    }
+
+    #[test]
+    fn high_bit_formats_upload_at_their_own_width() {
+        let gs = upload(PSMT8H, 8, &[0x8877_6655_4433_2211]);
+        for x in 0..8 {
+            let expected = 0x11 * (x + 1);
+            assert_eq!(gs.memory().read_pixel(PSMT8H, 0, 1, x, 0), expected);
+            assert_eq!(
+                gs.memory().read_pixel(PSMCT32, 0, 1, x, 0),
+                expected << 24 | 0xAB_CDEF
+            );
+        }
+        // Eight pixels took one doubleword, the ninth is untouched
+        assert_eq!(gs.memory().read_pixel(PSMCT32, 0, 1, 8, 0), 0x00AB_CDEF);
+
+        let gs = upload(PSMT4HL, 16, &[0xFEDC_BA98_7654_3210]);
+        for x in 0..16 {
+            assert_eq!(gs.memory().read_pixel(PSMT4HL, 0, 1, x, 0), x);
+            assert_eq!(
+                gs.memory().read_pixel(PSMCT32, 0, 1, x, 0),
+                x << 24 | 0xAB_CDEF
+            );
+        }
+    }
+}
@@ -0,0 +1,544 @@
+// The per-pixel pipeline: what happens to a fragment between texture mapping and the buffers.
+//
+// In order: fog blends the colour towards FOGCOL; the alpha test (TEST.ATE) compares alpha
+// with AREF, a failing fragment writing only what AFAIL allows; the destination alpha test
+// (TEST.DATE) keeps out fragments over frame buffer pixels whose alpha bit doesn't match DATM;
+// the depth test (TEST.ZTE) compares Z with ZBUF's. A fragment that passes is blended with the
+// frame buffer by ALPHA's (A - B) * C >> 7 + D when PRIM.ABE is set, unless PABE is on and its
+// own alpha is below 0x80. 16-bit frame buffers are dithered with DIMX when DTHE is set, then
+// COLCLAMP either clamps each channel or keeps its low 8 bits, FBA forces the top alpha bit and
+// the result is written through FRAME.FBMSK, with Z going to ZBUF unless ZMSK is set.
+
+use super::memory;
+use super::raster::Fragment;
+use super::{
+    ALPHA_1, COLCLAMP, DIMX, DTHE, FBA_1, FOGCOL, FRAME_1, Gs, PABE, PRIM_ABE, PRIM_FGE, TEST_1,
+    ZBUF_1, field,
+};
+
+// TEST bits
+pub const TEST_ATE: u64 = 1 << 0;
+pub const TEST_DATE: u64 = 1 << 14;
+pub const TEST_DATM: u64 = 1 << 15;
+pub const TEST_ZTE: u64 = 1 << 16;
+
+// TEST.ATST
+pub const ATST_NEVER: u32 = 0;
+pub const ATST_ALWAYS: u32 = 1;
+pub const ATST_LESS: u32 = 2;
+pub const ATST_LEQUAL: u32 = 3;
+pub const ATST_EQUAL: u32 = 4;
+pub const ATST_GEQUAL: u32 = 5;
+pub const ATST_GREATER: u32 = 6;
+pub const ATST_NOTEQUAL: u32 = 7;
+
+// TEST.AFAIL
+pub const AFAIL_KEEP: u32 = 0;
+pub const AFAIL_FB_ONLY: u32 = 1;
+pub const AFAIL_ZB_ONLY: u32 = 2;
+pub const AFAIL_RGB_ONLY: u32 = 3;
+
+// TEST.ZTST
+pub const ZTST_NEVER: u32 = 0;
+pub const ZTST_ALWAYS: u32 = 1;
+pub const ZTST_GEQUAL: u32 = 2;
+pub const ZTST_GREATER: u32 = 3;
+
+pub const ZBUF_ZMSK: u64 = 1 << 32;
+
+fn alpha_test(test: u64, alpha: u8) -> bool {
+    let reference = field(test, 4, 8) as u8;
+    match field(test, 1, 3) {
+        ATST_NEVER => false,
+        ATST_ALWAYS => true,
+        ATST_LESS => alpha < reference,
+        ATST_LEQUAL => alpha <= reference,
+        ATST_EQUAL => alpha == reference,
+        ATST_GEQUAL => alpha >= reference,
+        ATST_GREATER => alpha > reference,
+        _ => alpha != reference,
+    }
+}
+
+fn depth_test(test: u64, z: u32, old: u32) -> bool {
+    match field(test, 17, 2) {
+        ZTST_NEVER => false,
+        ZTST_ALWAYS => true,
+        ZTST_GEQUAL => z >= old,
+        _ => z > old,
+    }
+}
+
+// Mixes in FOGCOL by 255 - F
+fn fog(color: [u8; 4], f: u8, fogcol: u64) -> [u8; 4] {
+    let mut out = color;
+    for (i, channel) in out.iter_mut().take(3).enumerate() {
+        let fog = field(fogcol, i as u32 * 8, 8);
+        *channel = ((f as u32 * color[i] as u32 + (255 - f as u32) * fog) >> 8) as u8;
+    }
+    out
+}
+
+// (A - B) * C >> 7 + D on each colour channel, A, B and D each picking the source colour, the
+// destination colour or zero and C the source alpha, destination alpha or ALPHA.FIX
+fn blend(alpha: u64, source: [u8; 4], dest: [u8; 4]) -> [i32; 3] {
+    let pick = |select: u32, i: usize| match select {
+        0 => source[i] as i32,
+        1 => dest[i] as i32,
+        _ => 0,
+    };
+    let (a, b, d) = (field(alpha, 0, 2), field(alpha, 2, 2), field(alpha, 6, 2));
+    let c = match field(alpha, 4, 2) {
+        0 => source[3] as i32,
+        1 => dest[3] as i32,
+        _ => field(alpha, 32, 8) as i32,
+    };
+    [0, 1, 2].map(|i| (((pick(a, i) - pick(b, i)) * c) >> 7) + pick(d, i))
+}
+
+// The DIMX entry for a pixel, a signed 3-bit value
+fn dither(dimx: u64, x: u32, y: u32) -> i32 {
+    let entry = field(dimx, (y & 3) * 16 + (x & 3) * 4, 3) as i32;
+    entry << 29 >> 29
+}
+
+// A frame buffer pixel as RGBA. Formats without alpha read as 0x80, and a 16-bit pixel's alpha
+// bit as 0x80 or 0.
+fn to_rgba(psm: u32, value: u32) -> [u8; 4] {
+    match memory::bits_per_pixel(psm) {
+        16 => {
+            let expand = |shift: u32| ((value >> shift & 0x1F) << 3) as u8;
+            let alpha = if value & 0x8000 != 0 { 0x80 } else { 0 };
+            [expand(0), expand(5), expand(10), alpha]
+        }
+        24 => (value & 0xFF_FFFF | 0x80 << 24).to_le_bytes(),
+        _ => value.to_le_bytes(),
+    }
+}
+
+// Packs a 32-bit colour into the 16-bit formats' 5:5:5:1
+fn to_rgb5a1(color: u32) -> u32 {
+    let [r, g, b, a] = color.to_le_bytes().map(u32::from);
+    r >> 3 | (g >> 3) << 5 | (b >> 3) << 10 | (a >> 7) << 15
+}
+
+impl Gs {
+    // Puts a fragment through the pipeline into the current context's buffers
+    pub(super) fn write_fragment(&mut self, context: u8, fragment: Fragment) {
+        let attributes = self.attributes();
+        let reg = |reg: u8| self.regs[(reg + context) as usize];
+        let (frame, zbuf, test) = (reg(FRAME_1), reg(ZBUF_1), reg(TEST_1));
+        let (x, y) = (fragment.x as u32, fragment.y as u32);
+
+        let mut color = self.shade(context, &fragment);
+        if attributes & PRIM_FGE != 0 {
+            color = fog(color, fragment.fog, self.regs[FOGCOL as usize]);
+        }
+
+        let (mut write_rgb, mut write_alpha) = (true, true);
+        let mut write_z = zbuf & ZBUF_ZMSK == 0;
+        if test & TEST_ATE != 0 && !alpha_test(test, color[3]) {
+            match field(test, 12, 2) {
+                AFAIL_KEEP => return,
+                AFAIL_FB_ONLY => write_z = false,
+                AFAIL_ZB_ONLY => (write_rgb, write_alpha) = (false, false),
+                _ => (write_alpha, write_z) = (false, false),
+            }
+        }
+
+        let (base, width, psm) = (
+            field(frame, 0, 9) * 32,
+            field(frame, 16, 6),
+            field(frame, 24, 6),
+        );
+        let old = self.memory.read_pixel(psm, base, width, x, y);
+        let dest = to_rgba(psm, old);
+        if test & TEST_DATE != 0 && (dest[3] & 0x80 != 0) != (test & TEST_DATM != 0) {
+            return;
+        }
+
+        let (z_base, z_psm) = (field(zbuf, 0, 9) * 32, 0x30 | field(zbuf, 24, 4));
+        let z = match memory::bits_per_pixel(z_psm) {
+            32 => fragment.z,
+            24 => fragment.z.min(0xFF_FFFF),
+            _ => fragment.z.min(0xFFFF),
+        };
+        if test & TEST_ZTE != 0 {
+            let old_z = self.memory.read_pixel(z_psm, z_base, width, x, y);
+            if !depth_test(test, z, old_z) {
+                return;
+            }
+        }
+
+        if write_rgb || write_alpha {
+            let held_back = self.regs[PABE as usize] & 1 != 0 && color[3] & 0x80 == 0;
+            let mut rgb = if attributes & PRIM_ABE != 0 && !held_back {
+                blend(reg(ALPHA_1), color, dest)
+            } else {
+                [0, 1, 2].map(|i| color[i] as i32)
+            };
+            let bits = memory::bits_per_pixel(psm);
+            if self.regs[DTHE as usize] & 1 != 0 && bits == 16 {
+                let offset = dither(self.regs[DIMX as usize], x, y);
+                rgb = rgb.map(|channel| channel + offset);
+            }
+            let clamp = self.regs[COLCLAMP as usize] & 1 != 0;
+            let rgb = rgb.map(|channel| {
+                if clamp {
+                    channel.clamp(0, 255) as u32
+                } else {
+                    channel as u32 & 0xFF
+                }
+            });
+            let mut alpha = color[3] as u32;
+            if reg(FBA_1) & 1 != 0 {
+                alpha |= 0x80;
+            }
+            let mut value = rgb[0] | rgb[1] << 8 | rgb[2] << 16 | alpha << 24;
+            let mut mask = field(frame, 32, 32);
+            if !write_rgb {
+                mask |= 0x00FF_FFFF;
+            }
+            if !write_alpha {
+                mask |= 0xFF00_0000;
+            }
+            if bits == 16 {
+                value = to_rgb5a1(value);
+                mask = to_rgb5a1(mask);
+            }
+            self.memory
+                .write_pixel(psm, base, width, x, y, (value & !mask) | (old & mask));
+        }
+        if write_z {
+            self.memory.write_pixel(z_psm, z_base, width, x, y, z);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gs::memory::{PSMCT16, PSMCT32, PSMZ16, PSMZ24, PSMZ32};
+    use crate::gs::raster::PRIM_SPRITE;
+    use crate::gs::{PRIM, RGBAQ, SCISSOR_1, XYZ2, XYZF2};
+
+    // The Z buffer sits four pages after the frame buffer, both 64 pixels wide
+    const ZBP: u64 = 4;
+
+    // A GS drawing to a `psm` frame buffer, with Z writes masked
+    fn gs(psm: u32) -> Gs {
+        let mut gs = Gs::new();
+        gs.write_register(FRAME_1, 1 << 16 | (psm as u64) << 24);
+        gs.write_register(ZBUF_1, ZBP | ZBUF_ZMSK);
+        gs.write_register(SCISSOR_1, 63 << 16 | 63 << 48);
+        gs
+    }
+
+    fn frame_psm(gs: &Gs) -> u32 {
+        field(gs.regs[FRAME_1 as usize], 24, 6)
+    }
+
+    fn z_psm(gs: &Gs) -> u32 {
+        0x30 | field(gs.regs[ZBUF_1 as usize], 24, 4)
+    }
+
+    fn set_pixel(gs: &mut Gs, x: u32, value: u32) {
+        let psm = frame_psm(gs);
+        gs.memory_mut().write_pixel(psm, 0, 1, x, 0, value);
+    }
+
+    fn pixel(gs: &Gs, x: u32) -> u32 {
+        gs.memory().read_pixel(frame_psm(gs), 0, 1, x, 0)
+    }
+
+    fn set_z(gs: &mut Gs, x: u32, value: u32) {
+        let psm = z_psm(gs);
+        gs.memory_mut()
+            .write_pixel(psm, ZBP as u32 * 32, 1, x, 0, value);
+    }
+
+    fn z(gs: &Gs, x: u32) -> u32 {
+        gs.memory().read_pixel(z_psm(gs), ZBP as u32 * 32, 1, x, 0)
+    }
+
+    // Draws pixel (x, 0) as a one pixel sprite through XYZF2
+    fn draw(gs: &mut Gs, prim: u64, x: u32, rgba: u32, z: u32, fog: u8) {
+        gs.write_register(PRIM, PRIM_SPRITE as u64 | prim);
+        gs.write_register(RGBAQ, rgba as u64);
+        for (x, y) in [(x, 0), (x + 1, 1)] {
+            let xy = (x * 16) as u64 | ((y * 16) as u64) << 16;
+            gs.write_register(XYZF2, xy | (z as u64) << 32 | (fog as u64) << 56);
+        }
+    }
+
+    // Same, with a full 32-bit Z through XYZ2
+    fn draw_z32(gs: &mut Gs, x: u32, rgba: u32, z: u32) {
+        gs.write_register(PRIM, PRIM_SPRITE as u64);
+        gs.write_register(RGBAQ, rgba as u64);
+        for (x, y) in [(x, 0), (x + 1, 1)] {
+            let xy = (x * 16) as u64 | ((y * 16) as u64) << 16;
+            gs.write_register(XYZ2, xy | (z as u64) << 32);
+        }
+    }
+
+    fn alpha(a: u64, b: u64, c: u64, d: u64, fix: u64) -> u64 {
+        a | b << 2 | c << 4 | d << 6 | fix << 32
+    }
+
+    // Source and destination for the blending tests
+    const SOURCE: u32 = 0x40A0_6020;
+    const DEST: u32 = 0x4020_4080;
+
+    fn blended(alpha_reg: u64, source: u32) -> u32 {
+        let mut gs = gs(PSMCT32);
+        gs.write_register(ALPHA_1, alpha_reg);
+        gs.write_register(COLCLAMP, 1);
+        set_pixel(&mut gs, 0, DEST);
+        draw(&mut gs, PRIM_ABE, 0, source, 0, 0);
+        pixel(&gs, 0)
+    }
+
+    #[test]
+    fn alpha_blending_picks_a_b_c_and_d() {
+        // (Cs - Cd) * As >> 7 + Cd, with As a half. Alpha is the source's.
+        assert_eq!(blended(alpha(0, 1, 0, 1, 0), SOURCE), 0x4060_5050);
+        // Cd * Ad >> 7 + Cs
+        assert_eq!(blended(alpha(1, 2, 1, 0, 0), SOURCE), 0x40B0_8060);
+        // Cs * FIX >> 7, FIX a half
+        assert_eq!(blended(alpha(0, 2, 2, 2, 0x40), SOURCE), 0x4050_3010);
+        // Cs + Cd, FIX 0x80 being one
+        assert_eq!(blended(alpha(0, 2, 2, 1, 0x80), SOURCE), 0x40C0_A0A0);
+
+        // Without ABE the colour goes in as it is
+        let mut gs = gs(PSMCT32);
+        gs.write_register(ALPHA_1, alpha(0, 2, 2, 1, 0x80));
+        set_pixel(&mut gs, 0, DEST);
+        draw(&mut gs, 0, 0, SOURCE, 0, 0);
+        assert_eq!(pixel(&gs, 0), SOURCE);
+    }
+
+    #[test]
+    fn pabe_blends_only_alpha_from_0x80() {
+        let additive = alpha(0, 2, 2, 1, 0x80);
+        for (source, expected) in [(SOURCE, SOURCE), (0x80A0_6020, 0x80C0_A0A0)] {
+            let mut gs = gs(PSMCT32);
+            gs.write_register(ALPHA_1, additive);
+            gs.write_register(PABE, 1);
+            set_pixel(&mut gs, 0, DEST);
+            draw(&mut gs, PRIM_ABE, 0, source, 0, 0);
+            assert_eq!(pixel(&gs, 0), expected, "{source:#x}");
+        }
+    }
+
+    fn test_reg(atst: u32, aref: u32, afail: u32) -> u64 {
+        TEST_ATE | (atst as u64) << 1 | (aref as u64) << 4 | (afail as u64) << 12
+    }
+
+    #[test]
+    fn alpha_test_compares_with_aref() {
+        let alphas = [0x3F, 0x40, 0x41];
+        for (atst, passes) in [
+            (ATST_NEVER, [false, false, false]),
+            (ATST_ALWAYS, [true, true, true]),
+            (ATST_LESS, [true, false, false]),
+            (ATST_LEQUAL, [true, true, false]),
+            (ATST_EQUAL, [false, true, false]),
+            (ATST_GEQUAL, [false, true, true]),
+            (ATST_GREATER, [false, false, true]),
+            (ATST_NOTEQUAL, [true, false, true]),
+        ] {
+            let mut gs = gs(PSMCT32);
+            gs.write_register(TEST_1, test_reg(atst, 0x40, AFAIL_KEEP));
+            for (x, alpha) in (0..).zip(alphas) {
+                draw(&mut gs, 0, x, alpha << 24 | 0xFF_FFFF, 0, 0);
+            }
+            let drawn = [0, 1, 2].map(|x| pixel(&gs, x) != 0);
+            assert_eq!(drawn, passes, "atst {atst}");
+        }
+    }
+
+    #[test]
+    fn afail_picks_what_a_failing_fragment_writes() {
+        for (afail, frame, z_value) in [
+            (AFAIL_KEEP, 0x1122_3344, 0x10),
+            (AFAIL_FB_ONLY, 0x40AA_BBCC, 0x10),
+            (AFAIL_ZB_ONLY, 0x1122_3344, 0x99),
+            (AFAIL_RGB_ONLY, 0x11AA_BBCC, 0x10),
+        ] {
+            let mut gs = gs(PSMCT32);
+            gs.write_register(ZBUF_1, ZBP);
+            gs.write_register(TEST_1, test_reg(ATST_NEVER, 0, afail));
+            set_pixel(&mut gs, 0, 0x1122_3344);
+            set_z(&mut gs, 0, 0x10);
+            draw(&mut gs, 0, 0, 0x40AA_BBCC, 0x99, 0);
+            assert_eq!(
+                (pixel(&gs, 0), z(&gs, 0)),
+                (frame, z_value),
+                "afail {afail}"
+            );
+        }
+    }
+
+    #[test]
+    fn date_keeps_to_pixels_whose_alpha_bit_matches_datm() {
+        for (psm, set, clear) in [
+            (PSMCT32, 0x8000_0000, 0x7F00_0000),
+            (PSMCT16, 0x8000, 0x7FFF),
+        ] {
+            for datm in [false, true] {
+                let mut gs = gs(psm);
+                let test = TEST_DATE | if datm { TEST_DATM } else { 0 };
+                gs.write_register(TEST_1, test);
+                set_pixel(&mut gs, 0, set);
+                set_pixel(&mut gs, 1, clear);
+                draw(&mut gs, 0, 0, 0, 0, 0);
+                draw(&mut gs, 0, 1, 0, 0, 0);
+                let drawn = [pixel(&gs, 0) != set, pixel(&gs, 1) != clear];
+                assert_eq!(drawn, [datm, !datm], "psm {psm:#x} datm {datm}");
+            }
+        }
+    }
+
+    #[test]
+    fn depth_test_compares_with_the_z_buffer() {
+        for (ztst, passes) in [
+            (ZTST_NEVER, [false, false, false]),
+            (ZTST_ALWAYS, [true, true, true]),
+            (ZTST_GEQUAL, [false, true, true]),
+            (ZTST_GREATER, [false, false, true]),
+        ] {
+            for zmsk in [false, true] {
+                let mut gs = gs(PSMCT32);
+                gs.write_register(ZBUF_1, ZBP | if zmsk { ZBUF_ZMSK } else { 0 });
+                gs.write_register(TEST_1, TEST_ZTE | (ztst as u64) << 17);
+                let zs = [0xFF, 0x100, 0x101];
+                for (x, z) in (0..).zip(zs) {
+                    set_z(&mut gs, x, 0x100);
+                    draw(&mut gs, 0, x, 0x80FF_FFFF, z, 0);
+                }
+                let drawn = [0, 1, 2].map(|x| pixel(&gs, x) != 0);
+                assert_eq!(drawn, passes, "ztst {ztst}");
+                let expected = [0, 1, 2].map(|i| if passes[i] && !zmsk { zs[i] } else { 0x100 });
+                assert_eq!(
+                    [0, 1, 2].map(|x| z(&gs, x)),
+                    expected,
+                    "ztst {ztst} zmsk {zmsk}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn z_is_clamped_to_the_buffer_format() {
+        for (psm, max) in [(PSMZ32, u32::MAX), (PSMZ24, 0xFF_FFFF), (PSMZ16, 0xFFFF)] {
+            let mut gs = gs(PSMCT32);
+            let zbuf = ZBP | ((psm & 0xF) as u64) << 24;
+            gs.write_register(ZBUF_1, zbuf);
+            draw_z32(&mut gs, 0, 0x80FF_FFFF, 0x0123_4567);
+            assert_eq!(z(&gs, 0), 0x0123_4567.min(max), "psm {psm:#x}");
+
+            // The test compares the clamped value
+            gs.write_register(TEST_1, TEST_ZTE | (ZTST_GREATER as u64) << 17);
+            set_z(&mut gs, 1, 0x0123_4566.min(max));
+            draw_z32(&mut gs, 1, 0x80FF_FFFF, 0x0123_4567);
+            assert_eq!(pixel(&gs, 1) != 0, psm == PSMZ32, "psm {psm:#x}");
+        }
+    }
+
+    #[test]
+    fn fog_mixes_in_fogcol_by_255_minus_f() {
+        let color = 0x8060_4020;
+        for (fge, f, expected) in [
+            (true, 0x80, 0x804F_5F6F),
+            (true, 0xFF, 0x805F_3F1F),
+            (true, 0, 0x803F_7FBF),
+            (false, 0, color),
+        ] {
+            let mut gs = gs(PSMCT32);
+            gs.write_register(FOGCOL, 0x40_80C0);
+            let prim = if fge { PRIM_FGE } else { 0 };
+            draw(&mut gs, prim, 0, color, 0, f);
+            assert_eq!(pixel(&gs, 0), expected, "fge {fge} f {f:#x}");
+        }
+    }
+
+    #[test]
+    fn dithering_applies_dimx_to_16_bit_buffers() {
+        // -4 at (0, 0), 3 at (1, 0) and 0 for the rest of the row, repeating every four pixels
+        let dimx = 0b100 | 0b011 << 4;
+        let grey = 0x8040_4040;
+        for (psm, dthe, expected) in [
+            (PSMCT16, true, [0x9CE7, 0xA108, 0xA108, 0xA108, 0x9CE7]),
+            (PSMCT16, false, [0xA108; 5]),
+            (PSMCT32, true, [grey; 5]),
+        ] {
+            let mut gs = gs(psm);
+            gs.write_register(DIMX, dimx);
+            gs.write_register(DTHE, dthe as u64);
+            for x in 0..5 {
+                draw(&mut gs, 0, x, grey, 0, 0);
+            }
+            assert_eq!(
+                [0, 1, 2, 3, 4].map(|x| pixel(&gs, x)),
+                expected,
+                "psm {psm:#x}"
+            );
+        }
+    }
+
+    #[test]
+    fn colclamp_clamps_or_wraps() {
+        let dest = 0x0080_8080;
+        // Cs + Cd and Cs - Cd, with red 0x60 and green and blue 0xC0 over 0x80
+        let add = alpha(0, 2, 2, 1, 0x80);
+        let subtract = alpha(0, 1, 2, 2, 0x80);
+        for (colclamp, added, subtracted) in [
+            (true, 0x80FF_FFE0, 0x8040_4000),
+            (false, 0x8040_40E0, 0x8040_40E0),
+        ] {
+            let mut results = Vec::new();
+            for mode in [add, subtract] {
+                let mut gs = gs(PSMCT32);
+                gs.write_register(ALPHA_1, mode);
+                gs.write_register(COLCLAMP, colclamp as u64);
+                set_pixel(&mut gs, 0, dest);
+                draw(&mut gs, PRIM_ABE, 0, 0x80C0_C060, 0, 0);
+                results.push(pixel(&gs, 0));
+            }
+            assert_eq!(results, [added, subtracted], "colclamp {colclamp}");
+        }
+    }
+
+    #[test]
+    fn fba_sets_the_top_alpha_bit() {
+        for (psm, fba, expected) in [
+            (PSMCT32, false, 0x1000_00FF),
+            (PSMCT32, true, 0x9000_00FF),
+            (PSMCT16, false, 0x001F),
+            (PSMCT16, true, 0x801F),
+        ] {
+            let mut gs = gs(psm);
+            gs.write_register(FBA_1, fba as u64);
+            draw(&mut gs, 0, 0, 0x1000_00FF, 0, 0);
+            assert_eq!(pixel(&gs, 0), expected, "psm {psm:#x} fba {fba}");
+        }
+    }
+
+    #[test]
+    fn fbmsk_keeps_masked_bits() {
+        let draw_masked = |psm: u32, old: u32, fbmsk: u64| {
+            let mut gs = gs(psm);
+            gs.write_register(FRAME_1, 1 << 16 | (psm as u64) << 24 | fbmsk << 32);
+            set_pixel(&mut gs, 0, old);
+            draw(&mut gs, 0, 0, 0x8000_0000, 0, 0);
+            pixel(&gs, 0)
+        };
+        assert_eq!(draw_masked(PSMCT32, 0x7F12_3456, 0x0000_00FF), 0x8000_0056);
+        assert_eq!(draw_masked(PSMCT32, 0x7F12_3456, 0xFF0F_0000), 0x7F02_0000);
+        // On 16-bit buffers a channel is kept by the top five bits of its mask, and alpha by
+        // its top bit
+        assert_eq!(draw_masked(PSMCT16, 0x7FFF, 0x80F8_0000), 0x7C00);
+        assert_eq!(draw_masked(PSMCT16, 0x7FFF, 0x0000_00F8), 0x801F);
+        assert_eq!(draw_masked(PSMCT16, 0x7FFF, 0x7F07_0707), 0x8000);
+    }
+}
@@ -0,0 +1,483 @@
+// Turning primitives into fragments.
+//
+// Vertices are in window coordinates, 12.4 fixed point with the context's XYOFFSET already
+// taken off, and pixels are sampled at their integer coordinates. A triangle covers a pixel
+// whose sample lies inside it, or exactly on a top or left edge, so triangles sharing an edge
+// don't both draw it. A sprite covers the pixels from its first corner up to but not including
+// the second. Lines step one pixel at a time along their major axis, leaving out the final
+// pixel so strips don't draw shared ends twice, and points draw the pixel nearest to them.
+//
+// Colours are interpolated across the primitive when Gouraud shading is on, otherwise the last
+// vertex's colour is used throughout. Sprites are always flat and take their depth and Q from
+// the second vertex too, while their texture coordinates run from one corner to the other.
+
+// Primitive types in PRIM
+pub const PRIM_POINT: u32 = 0;
+pub const PRIM_LINE: u32 = 1;
+pub const PRIM_LINE_STRIP: u32 = 2;
+pub const PRIM_TRIANGLE: u32 = 3;
+pub const PRIM_TRIANGLE_STRIP: u32 = 4;
+pub const PRIM_TRIANGLE_FAN: u32 = 5;
+pub const PRIM_SPRITE: u32 = 6;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vertex {
+    // Window coordinates, 12.4 fixed point
+    pub x: i32,
+    pub y: i32,
+    pub z: u32,
+    pub rgba: [u8; 4],
+    pub fog: u8,
+    pub s: f32,
+    pub t: f32,
+    pub q: f32,
+    // Texel coordinates, 12.4 fixed point
+    pub u: i32,
+    pub v: i32,
+}
+
+// A pixel a primitive covers, with its interpolated attributes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Fragment {
+    pub x: i32,
+    pub y: i32,
+    pub z: u32,
+    pub rgba: [u8; 4],
+    pub fog: u8,
+    pub s: f32,
+    pub t: f32,
+    pub q: f32,
+    // Texel coordinates, 12.4 fixed point
+    pub u: i32,
+    pub v: i32,
+}
+
+// Inclusive pixel bounds, as SCISSOR gives them
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scissor {
+    pub x0: i32,
+    pub x1: i32,
+    pub y0: i32,
+    pub y1: i32,
+}
+
+impl Scissor {
+    fn contains(&self, x: i32, y: i32) -> bool {
+        (self.x0..=self.x1).contains(&x) && (self.y0..=self.y1).contains(&y)
+    }
+}
+
+// Vertices each primitive type takes
+pub fn vertex_count(prim: u32) -> usize {
+    match prim {
+        PRIM_POINT => 1,
+        PRIM_LINE | PRIM_LINE_STRIP | PRIM_SPRITE => 2,
+        // Including the reserved type 7, which draws nothing
+        _ => 3,
+    }
+}
+
+// The first pixel coordinate at or after a 12.4 coordinate
+fn ceil_pixel(coord: i32) -> i32 {
+    (coord + 15) >> 4
+}
+
+fn round_pixel(coord: f64) -> i32 {
+    ((coord + 8.0) / 16.0).floor() as i32
+}
+
+// Weighted sum of the vertices' attributes. Without Gouraud shading the colour comes from
+// `flat`.
+fn interpolate(vertices: &[Vertex], weights: &[f64], flat: &Vertex, gouraud: bool) -> Fragment {
+    let sum = |attr: &dyn Fn(&Vertex) -> f64| -> f64 {
+        vertices
+            .iter()
+            .zip(weights)
+            .map(|(vertex, weight)| attr(vertex) * weight)
+            .sum()
+    };
+    let channel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+    let rgba = if gouraud {
+        [0, 1, 2, 3].map(|i| channel(sum(&|v| v.rgba[i] as f64)))
+    } else {
+        flat.rgba
+    };
+    Fragment {
+        x: 0,
+        y: 0,
+        z: sum(&|v| v.z as f64).round().clamp(0.0, u32::MAX as f64) as u32,
+        rgba,
+        fog: channel(sum(&|v| v.fog as f64)),
+        s: sum(&|v| v.s as f64) as f32,
+        t: sum(&|v| v.t as f64) as f32,
+        q: sum(&|v| v.q as f64) as f32,
+        u: sum(&|v| v.u as f64).round() as i32,
+        v: sum(&|v| v.v as f64).round() as i32,
+    }
+}
+
+// Draws a primitive of `prim`'s type from its vertices, passing each covered pixel inside the
+// scissor rectangle to `plot`
+pub fn draw(
+    prim: u32,
+    vertices: &[Vertex],
+    gouraud: bool,
+    scissor: Scissor,
+    plot: &mut dyn FnMut(Fragment),
+) {
+    match prim {
+        PRIM_POINT => point(&vertices[0], scissor, plot),
+        PRIM_LINE | PRIM_LINE_STRIP => line(&vertices[0], &vertices[1], gouraud, scissor, plot),
+        PRIM_SPRITE => sprite(&vertices[0], &vertices[1], scissor, plot),
+        PRIM_TRIANGLE | PRIM_TRIANGLE_STRIP | PRIM_TRIANGLE_FAN => {
+            triangle(vertices, gouraud, scissor, plot)
+        }
+        _ => {}
+    }
+}
+
+fn point(vertex: &Vertex, scissor: Scissor, plot: &mut dyn FnMut(Fragment)) {
+    let (x, y) = (round_pixel(vertex.x as f64), round_pixel(vertex.y as f64));
+    if scissor.contains(x, y) {
+        plot(Fragment {
+            x,
+            y,
+            z: vertex.z,
+            rgba: vertex.rgba,
+            fog: vertex.fog,
+            s: vertex.s,
+            t: vertex.t,
+            q: vertex.q,
+            u: vertex.u,
+            v: vertex.v,
+        });
+    }
+}
+
+fn line(a: &Vertex, b: &Vertex, gouraud: bool, scissor: Scissor, plot: &mut dyn FnMut(Fragment)) {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    if dx == 0 && dy == 0 {
+        return;
+    }
+    // Steps along the major axis, with the minor coordinate rounded to the nearest pixel
+    let x_major = dx.abs() >= dy.abs();
+    let (start, end, length) = if x_major {
+        (a.x, b.x, dx)
+    } else {
+        (a.y, b.y, dy)
+    };
+    let (first, last) = if length > 0 {
+        (ceil_pixel(start), ceil_pixel(end))
+    } else {
+        ((end >> 4) + 1, (start >> 4) + 1)
+    };
+    for major in first..last {
+        let t = (major * 16 - start) as f64 / length as f64;
+        let (x, y) = if x_major {
+            (major, round_pixel(a.y as f64 + t * dy as f64))
+        } else {
+            (round_pixel(a.x as f64 + t * dx as f64), major)
+        };
+        if !scissor.contains(x, y) {
+            continue;
+        }
+        let mut fragment = interpolate(&[*a, *b], &[1.0 - t, t], b, gouraud);
+        fragment.x = x;
+        fragment.y = y;
+        plot(fragment);
+    }
+}
+
+fn sprite(a: &Vertex, b: &Vertex, scissor: Scissor, plot: &mut dyn FnMut(Fragment)) {
+    let x0 = ceil_pixel(a.x.min(b.x)).max(scissor.x0);
+    let x1 = ceil_pixel(a.x.max(b.x)).min(scissor.x1 + 1);
+    let y0 = ceil_pixel(a.y.min(b.y)).max(scissor.y0);
+    let y1 = ceil_pixel(a.y.max(b.y)).min(scissor.y1 + 1);
+    // How far a pixel is from the first corner to the second
+    let along = |pixel: i32, from: i32, to: i32| {
+        if from == to {
+            0.0
+        } else {
+            (pixel * 16 - from) as f32 / (to - from) as f32
+        }
+    };
+    let mix = |from: f32, to: f32, t: f32| from + (to - from) * t;
+    for y in y0..y1 {
+        let ty = along(y, a.y, b.y);
+        for x in x0..x1 {
+            let tx = along(x, a.x, b.x);
+            plot(Fragment {
+                x,
+                y,
+                z: b.z,
+                rgba: b.rgba,
+                fog: b.fog,
+                s: mix(a.s, b.s, tx),
+                t: mix(a.t, b.t, ty),
+                q: b.q,
+                u: mix(a.u as f32, b.u as f32, tx).round() as i32,
+                v: mix(a.v as f32, b.v as f32, ty).round() as i32,
+            });
+        }
+    }
+}
+
+// Twice the signed area of the triangle (a, b, p), positive when p is on the inner side of
+// a -> b for a triangle wound the way `triangle` arranges
+fn edge(a: &Vertex, b: &Vertex, px: i64, py: i64) -> i64 {
+    (b.x - a.x) as i64 * (py - a.y as i64) - (b.y - a.y) as i64 * (px - a.x as i64)
+}
+
+// With that winding, top edges run in +x and left edges run up the screen
+fn top_left(a: &Vertex, b: &Vertex) -> bool {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    dy < 0 || (dy == 0 && dx > 0)
+}
+
+fn triangle(vertices: &[Vertex], gouraud: bool, scissor: Scissor, plot: &mut dyn FnMut(Fragment)) {
+    let flat = &vertices[2];
+    let (a, mut b, mut c) = (vertices[0], vertices[1], vertices[2]);
+    let mut area = edge(&a, &b, c.x as i64, c.y as i64);
+    if area == 0 {
+        return;
+    }
+    if area < 0 {
+        std::mem::swap(&mut b, &mut c);
+        area = -area;
+    }
+    let corners = [a, b, c];
+    let edges = [(b, c), (c, a), (a, b)];
+    let biases = edges.map(|(from, to)| if top_left(&from, &to) { 0 } else { -1 });
+
+    let x0 = ceil_pixel(a.x.min(b.x).min(c.x)).max(scissor.x0);
+    let x1 = ((a.x.max(b.x).max(c.x)) >> 4).min(scissor.x1);
+    let y0 = ceil_pixel(a.y.min(b.y).min(c.y)).max(scissor.y0);
+    let y1 = ((a.y.max(b.y).max(c.y)) >> 4).min(scissor.y1);
+    for y in y0..=y1 {
+        for x in x0..=x1 {
+            let (px, py) = (x as i64 * 16, y as i64 * 16);
+            let w = [0, 1, 2].map(|i| edge(&edges[i].0, &edges[i].1, px, py));
+            if (0..3).any(|i| w[i] + biases[i] < 0) {
+                continue;
+            }
+            // Each edge function weighs the corner opposite it
+            let weights = w.map(|w| w as f64 / area as f64);
+            let mut fragment = interpolate(&corners, &weights, flat, gouraud);
+            fragment.x = x;
+            fragment.y = y;
+            plot(fragment);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gs::memory::{LocalMemory, PSMCT32};
+    use crate::gs::{self, Gs};
+    use crate::savestate::crc32;
+    use std::collections::HashSet;
+
+    const SCISSOR: Scissor = Scissor {
+        x0: 0,
+        x1: 63,
+        y0: 0,
+        y1: 63,
+    };
+    const RED: [u8; 4] = [0xFF, 0, 0, 0x80];
+
+    fn vertex(x: i32, y: i32, rgba: [u8; 4]) -> Vertex {
+        Vertex {
+            x: x * 16,
+            y: y * 16,
+            rgba,
+            ..Vertex::default()
+        }
+    }
+
+    // Draws into a 64 pixel wide PSMCT32 buffer at block 0. Returns the VRAM checksum and how
+    // many pixels were drawn, failing if any was drawn twice.
+    fn render(prims: &[(u32, &[Vertex])]) -> (u32, usize) {
+        let mut memory = LocalMemory::new();
+        let mut drawn = HashSet::new();
+        for &(prim, vertices) in prims {
+            draw(prim, vertices, true, SCISSOR, &mut |fragment| {
+                assert!(drawn.insert((fragment.x, fragment.y)), "{:?}", fragment);
+                let color = u32::from_le_bytes(fragment.rgba);
+                memory.write_pixel(PSMCT32, 0, 1, fragment.x as u32, fragment.y as u32, color);
+            });
+        }
+        (crc32(memory.as_slice()), drawn.len())
+    }
+
+    // The pixels drawn and their colours, in drawing order
+    fn fragments(prim: u32, vertices: &[Vertex]) -> Vec<((i32, i32), [u8; 4])> {
+        let mut drawn = Vec::new();
+        draw(prim, vertices, true, SCISSOR, &mut |fragment| {
+            drawn.push(((fragment.x, fragment.y), fragment.rgba))
+        });
+        drawn
+    }
+
+    // The same through GS registers, so strips and fans are assembled as the GS does it
+    fn render_gs(prim: u32, vertices: &[Vertex]) -> u32 {
+        let mut gs = Gs::new();
+        gs.write_register(gs::FRAME_1, 1 << 16 | (PSMCT32 as u64) << 24);
+        gs.write_register(gs::ZBUF_1, 1 << 32);
+        gs.write_register(gs::SCISSOR_1, 63 << 16 | 63 << 48);
+        gs.write_register(gs::PRIM, prim as u64 | gs::PRIM_IIP);
+        for vertex in vertices {
+            gs.write_register(gs::RGBAQ, u32::from_le_bytes(vertex.rgba) as u64);
+            gs.write_register(gs::XYZ2, vertex.x as u64 | (vertex.y as u64) << 16);
+        }
+        crc32(gs.memory().as_slice())
+    }
+
+    // Just where the pixels were drawn
+    fn covered(prim: u32, vertices: &[Vertex]) -> Vec<(i32, i32)> {
+        fragments(prim, vertices)
+            .into_iter()
+            .map(|(xy, _)| xy)
+            .collect()
+    }
+
+    // Row by row and left to right, the pixels of an 8x8 square at the origin that `inside`
+    // picks
+    fn square_pixels(inside: impl Fn(i32, i32) -> bool) -> Vec<(i32, i32)> {
+        (0..8)
+            .flat_map(|y| (0..8).map(move |x| (x, y)))
+            .filter(|&(x, y)| inside(x, y))
+            .collect()
+    }
+
+    #[test]
+    fn flat_triangle() {
+        // Wound (2,1) -> (20,6) -> (7,17), only the edge back to (2,1) is a top or left one, so
+        // every corner is left out, and the pixel centres on the left edge are kept. No other
+        // pixel centre lies exactly on an edge.
+        let triangle = [vertex(2, 1, RED), vertex(20, 6, RED), vertex(7, 17, RED)];
+        let spans = [
+            (2, 3..6),
+            (3, 3..10),
+            (4, 3..13),
+            (5, 4..17),
+            (6, 4..20),
+            (7, 4..19),
+            (8, 5..18),
+            (9, 5..17),
+            (10, 5..16),
+            (11, 6..15),
+            (12, 6..13),
+            (13, 6..12),
+            (14, 7..11),
+            (15, 7..10),
+            (16, 7..9),
+        ];
+        let expected: Vec<_> = spans
+            .into_iter()
+            .flat_map(|(y, xs)| xs.map(move |x| ((x, y), RED)))
+            .collect();
+        assert_eq!(expected.len(), 131);
+        assert_eq!(fragments(PRIM_TRIANGLE, &triangle), expected);
+        assert_eq!(
+            render_gs(PRIM_TRIANGLE, &triangle),
+            render(&[(PRIM_TRIANGLE, &triangle)]).0
+        );
+    }
+
+    #[test]
+    fn top_left_rule_splits_shared_edges() {
+        // Two halves of an 8x8 square: the diagonal goes to one of them, and the square's right
+        // and bottom edges to neither
+        let upper = [vertex(0, 0, RED), vertex(8, 0, RED), vertex(0, 8, RED)];
+        let lower = [vertex(8, 0, RED), vertex(8, 8, RED), vertex(0, 8, RED)];
+        let (checksum, drawn) = render(&[(PRIM_TRIANGLE, &upper), (PRIM_TRIANGLE, &lower)]);
+        assert_eq!(drawn, 64);
+        let square = [vertex(0, 0, RED), vertex(8, 8, RED)];
+        assert_eq!(render(&[(PRIM_SPRITE, &square)]), (checksum, 64));
+    }
+
+    #[test]
+    fn sprite_excludes_its_second_corner() {
+        let sprite = [vertex(1, 1, RED), vertex(5, 3, RED)];
+        let mut expected = Vec::new();
+        for y in 1..3 {
+            for x in 1..5 {
+                expected.push(((x, y), RED));
+            }
+        }
+        assert_eq!(fragments(PRIM_SPRITE, &sprite), expected);
+        // Whichever way round the corners come
+        let reversed = [sprite[1], sprite[0]];
+        assert_eq!(fragments(PRIM_SPRITE, &reversed), expected);
+    }
+
+    #[test]
+    fn line_leaves_out_its_last_pixel() {
+        // The minor axis rounds halves up, and the colour is shaded along the major axis
+        let green = [0, 0xFF, 0, 0x80];
+        let line = [vertex(0, 0, RED), vertex(8, 4, green)];
+        let ys = [0, 1, 1, 2, 2, 3, 3, 4];
+        let reds = [255, 223, 191, 159, 128, 96, 64, 32];
+        let greens = [0, 32, 64, 96, 128, 159, 191, 223];
+        let expected: Vec<_> = (0..8)
+            .map(|x| ((x as i32, ys[x]), [reds[x], greens[x], 0, 0x80]))
+            .collect();
+        assert_eq!(fragments(PRIM_LINE, &line), expected);
+
+        // Drawn towards the origin along y, it's the origin that's left out
+        let line = [vertex(2, 8, RED), vertex(0, 0, RED)];
+        let expected = [
+            (0, 1),
+            (1, 2),
+            (1, 3),
+            (1, 4),
+            (1, 5),
+            (2, 6),
+            (2, 7),
+            (2, 8),
+        ];
+        let drawn: Vec<_> = fragments(PRIM_LINE, &line)
+            .into_iter()
+            .map(|(xy, _)| xy)
+            .collect();
+        assert_eq!(drawn, expected);
+    }
+
+    #[test]
+    fn strip_and_fan_cover_a_square_once() {
+        let blue = [0, 0, 0xFF, 0x80];
+        let (a, b, c, d) = (
+            vertex(0, 0, RED),
+            vertex(8, 0, blue),
+            vertex(0, 8, blue),
+            vertex(8, 8, RED),
+        );
+        // Split along b-c, the diagonal is the lower triangle's left edge. Neither gets the
+        // square's right column or bottom row.
+        assert_eq!(
+            covered(PRIM_TRIANGLE, &[a, b, c]),
+            square_pixels(|x, y| x + y < 8)
+        );
+        assert_eq!(
+            covered(PRIM_TRIANGLE, &[b, c, d]),
+            square_pixels(|x, y| x + y >= 8)
+        );
+        let halves = render(&[(PRIM_TRIANGLE, &[a, b, c]), (PRIM_TRIANGLE, &[b, c, d])]);
+        assert_eq!(halves.1, 64);
+        assert_eq!(render_gs(PRIM_TRIANGLE_STRIP, &[a, b, c, d]), halves.0);
+
+        // Split along a-d, the diagonal is the left edge of the triangle above it
+        assert_eq!(
+            covered(PRIM_TRIANGLE, &[a, b, d]),
+            square_pixels(|x, y| y <= x)
+        );
+        assert_eq!(
+            covered(PRIM_TRIANGLE, &[a, d, c]),
+            square_pixels(|x, y| x < y)
+        );
+        let fan = render(&[(PRIM_TRIANGLE, &[a, b, d]), (PRIM_TRIANGLE, &[a, d, c])]);
+        assert_eq!(fan.1, 64);
+        assert_eq!(render_gs(PRIM_TRIANGLE_FAN, &[a, b, d, c]), fan.0);
+    }
+}
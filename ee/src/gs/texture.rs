@@ -0,0 +1,773 @@
+// Texture mapping.
+//
+// A textured fragment looks its texture up at (S/Q, T/Q) scaled to the texture's size, or at
+// its UV texel coordinates when PRIM.FST is set, with S, T and Q interpolated linearly across
+// the primitive so the division makes the mapping perspective correct. Texel coordinates
+// outside the texture are brought back in by CLAMP's wrap modes.
+//
+// TEX1 picks the filter: the level of detail comes from Q (or is TEX1.K alone with LCM set),
+// magnification uses MMAG and minification MMIN, which may read one or two of the mipmap levels
+// TEX0 and MIPTBP1/2 describe. Bilinear filtering blends the four texels around the sample
+// point, taken half a texel up and to the left as on hardware.
+//
+// Indexed formats go through the CLUT buffer, which TEX0.CLD loads from local memory: with
+// CSM1 from a 16x16 (or 8x2 for 4-bit indices) block, with CSM2 from a row at TEXCLUT's
+// position. The buffer holds 512 16-bit halves; a 32-bit colour keeps its low half in the first
+// 256 and its high half in the last. Texels without their own alpha take it from TEXA.
+
+use super::field;
+use super::memory::{self, LocalMemory};
+
+// TEX0.TFX
+pub const TFX_MODULATE: u32 = 0;
+pub const TFX_DECAL: u32 = 1;
+pub const TFX_HIGHLIGHT: u32 = 2;
+pub const TFX_HIGHLIGHT2: u32 = 3;
+
+// CLAMP.WMS and WMT
+pub const WRAP_REPEAT: u32 = 0;
+pub const WRAP_CLAMP: u32 = 1;
+pub const WRAP_REGION_CLAMP: u32 = 2;
+pub const WRAP_REGION_REPEAT: u32 = 3;
+
+// TEX1.MMIN
+pub const FILTER_NEAREST: u32 = 0;
+pub const FILTER_LINEAR: u32 = 1;
+pub const FILTER_NEAREST_MIPMAP_NEAREST: u32 = 2;
+pub const FILTER_NEAREST_MIPMAP_LINEAR: u32 = 3;
+pub const FILTER_LINEAR_MIPMAP_NEAREST: u32 = 4;
+pub const FILTER_LINEAR_MIPMAP_LINEAR: u32 = 5;
+
+const CLUT_ENTRIES: usize = 512;
+
+pub fn is_indexed(psm: u32) -> bool {
+    matches!(
+        psm,
+        memory::PSMT8 | memory::PSMT4 | memory::PSMT8H | memory::PSMT4HL | memory::PSMT4HH
+    )
+}
+
+fn is_indexed4(psm: u32) -> bool {
+    matches!(psm, memory::PSMT4 | memory::PSMT4HL | memory::PSMT4HH)
+}
+
+#[derive(Clone, Debug)]
+pub struct Clut {
+    entries: [u16; CLUT_ENTRIES],
+}
+
+impl Default for Clut {
+    fn default() -> Self {
+        Clut {
+            entries: [0; CLUT_ENTRIES],
+        }
+    }
+}
+
+impl Clut {
+    // Where an index's entry starts: 4-bit CLUTs sit CSA 16-entry groups in
+    fn slot(index: u32, cpsm: u32, csa: u32, indexed4: bool) -> usize {
+        let offset = if !indexed4 {
+            0
+        } else if cpsm == memory::PSMCT32 {
+            (csa & 0xF) * 16
+        } else {
+            csa * 16
+        };
+        ((offset + index) as usize) % CLUT_ENTRIES
+    }
+
+    fn get(&self, index: u32, cpsm: u32, csa: u32, indexed4: bool) -> u32 {
+        let slot = Self::slot(index, cpsm, csa, indexed4);
+        let low = self.entries[slot] as u32;
+        if cpsm == memory::PSMCT32 {
+            low | (self.entries[(slot + 256) % CLUT_ENTRIES] as u32) << 16
+        } else {
+            low
+        }
+    }
+
+    fn set(&mut self, index: u32, cpsm: u32, csa: u32, indexed4: bool, value: u32) {
+        let slot = Self::slot(index, cpsm, csa, indexed4);
+        self.entries[slot] = value as u16;
+        if cpsm == memory::PSMCT32 {
+            self.entries[(slot + 256) % CLUT_ENTRIES] = (value >> 16) as u16;
+        }
+    }
+
+    // Loads the CLUT `tex0` describes (TEX0 or TEX2), for an indexed texture format
+    pub fn load(&mut self, memory: &LocalMemory, tex0: u64, texclut: u64) {
+        let psm = field(tex0, 20, 6);
+        if !is_indexed(psm) {
+            return;
+        }
+        let cbp = field(tex0, 37, 14);
+        let cpsm = field(tex0, 51, 4);
+        let csm2 = tex0 & 1 << 55 != 0;
+        let csa = field(tex0, 56, 5);
+        let indexed4 = is_indexed4(psm);
+        let count = if indexed4 { 16 } else { 256 };
+        for index in 0..count {
+            let value = if csm2 {
+                let (cbw, cou, cov) = (
+                    field(texclut, 0, 6),
+                    field(texclut, 6, 6),
+                    field(texclut, 12, 10),
+                );
+                memory.read_pixel(cpsm, cbp, cbw, cou * 16 + index, cov)
+            } else if indexed4 {
+                memory.read_pixel(cpsm, cbp, 1, index % 8, index / 8)
+            } else {
+                // Entries are stored with index bits 3 and 4 swapped
+                let at = (index & !0x18) | (index & 0x08) << 1 | (index & 0x10) >> 1;
+                memory.read_pixel(cpsm, cbp, 1, at % 16, at / 16)
+            };
+            self.set(index, cpsm, csa, indexed4, value);
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u16] {
+        &self.entries
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u16] {
+        &mut self.entries
+    }
+}
+
+// One mipmap level: where it is and its size as a power of two
+#[derive(Clone, Copy, Debug)]
+struct Level {
+    base: u32,
+    width: u32,
+    log_width: u32,
+    log_height: u32,
+    // Halvings from level 0
+    shift: u32,
+}
+
+// Everything a texture lookup needs from the GS
+pub struct Sampler<'a> {
+    pub memory: &'a LocalMemory,
+    pub clut: &'a Clut,
+    pub tex0: u64,
+    pub tex1: u64,
+    pub clamp: u64,
+    pub texa: u64,
+    pub miptbp1: u64,
+    pub miptbp2: u64,
+}
+
+impl Sampler<'_> {
+    fn psm(&self) -> u32 {
+        field(self.tex0, 20, 6)
+    }
+
+    fn level(&self, level: u32) -> Level {
+        let log_width = field(self.tex0, 26, 4).min(10);
+        let log_height = field(self.tex0, 30, 4).min(10);
+        let (base, width) = match level {
+            0 => (field(self.tex0, 0, 14), field(self.tex0, 14, 6)),
+            // MTBA lays levels 1-3 out one after another from level 0
+            1..=3 if self.tex1 & 1 << 9 != 0 => {
+                let mut base = field(self.tex0, 0, 14);
+                let mut width = field(self.tex0, 14, 6);
+                let bits = memory::bits_per_pixel(self.psm());
+                for previous in 0..level {
+                    let w = 1 << log_width.saturating_sub(previous);
+                    let h = 1 << log_height.saturating_sub(previous);
+                    base += (w * h * bits / 8).div_ceil(256);
+                    width = (width / 2).max(1);
+                }
+                (base, width)
+            }
+            1..=3 => {
+                let shift = (level - 1) * 20;
+                (
+                    field(self.miptbp1, shift, 14),
+                    field(self.miptbp1, shift + 14, 6),
+                )
+            }
+            _ => {
+                let shift = (level - 4) * 20;
+                (
+                    field(self.miptbp2, shift, 14),
+                    field(self.miptbp2, shift + 14, 6),
+                )
+            }
+        };
+        Level {
+            base,
+            width,
+            log_width: log_width.saturating_sub(level),
+            log_height: log_height.saturating_sub(level),
+            shift: level,
+        }
+    }
+
+    // Brings a texel coordinate inside the texture according to a CLAMP wrap mode
+    fn wrap(&self, coord: i32, log_size: u32, mode: u32, min: u32, max: u32, shift: u32) -> u32 {
+        let size = 1 << log_size;
+        match mode {
+            WRAP_REPEAT => coord.rem_euclid(size) as u32,
+            WRAP_CLAMP => coord.clamp(0, size - 1) as u32,
+            WRAP_REGION_CLAMP => {
+                let (min, max) = ((min >> shift) as i32, (max >> shift) as i32);
+                coord.clamp(min, max.max(min)) as u32
+            }
+            // MINU/MINV is a mask and MAXU/MAXV the bits to force
+            _ => (coord as u32 & (min >> shift)) | (max >> shift),
+        }
+    }
+
+    // A texel as RGBA, converted from the texture's format
+    fn texel(&self, level: &Level, x: i32, y: i32) -> [u8; 4] {
+        let x = self.wrap(
+            x,
+            level.log_width,
+            field(self.clamp, 0, 2),
+            field(self.clamp, 4, 10),
+            field(self.clamp, 14, 10),
+            level.shift,
+        );
+        let y = self.wrap(
+            y,
+            level.log_height,
+            field(self.clamp, 2, 2),
+            field(self.clamp, 24, 10),
+            field(self.clamp, 34, 10),
+            level.shift,
+        );
+        let psm = self.psm();
+        let value = self.memory.read_pixel(psm, level.base, level.width, x, y);
+        let (value, format) = if is_indexed(psm) {
+            let cpsm = field(self.tex0, 51, 4);
+            let csa = field(self.tex0, 56, 5);
+            (self.clut.get(value, cpsm, csa, is_indexed4(psm)), cpsm)
+        } else {
+            (value, psm)
+        };
+        match memory::bits_per_pixel(format) {
+            32 => value.to_le_bytes(),
+            24 => self.with_alpha(value, false),
+            _ => {
+                let rgb =
+                    (value & 0x1F) << 3 | (value >> 5 & 0x1F) << 11 | (value >> 10 & 0x1F) << 19;
+                self.with_alpha(rgb, value & 0x8000 != 0)
+            }
+        }
+    }
+
+    // Supplies alpha for a 24 or 16-bit colour from TEXA: TA1 where a 16-bit colour's alpha bit
+    // is set, otherwise TA0, or zero for black with AEM set
+    fn with_alpha(&self, rgb: u32, alpha_bit: bool) -> [u8; 4] {
+        let alpha = if alpha_bit {
+            field(self.texa, 32, 8)
+        } else if self.texa & 1 << 15 != 0 && rgb & 0xFF_FFFF == 0 {
+            0
+        } else {
+            field(self.texa, 0, 8)
+        };
+        (rgb & 0xFF_FFFF | alpha << 24).to_le_bytes()
+    }
+
+    // Samples one level at level 0 texel coordinates (u, v)
+    fn sample_level(&self, level: u32, u: f32, v: f32, linear: bool) -> [f32; 4] {
+        let level = self.level(level);
+        let scale = 1.0 / (1 << level.shift) as f32;
+        let (u, v) = (u * scale, v * scale);
+        if !linear {
+            return self
+                .texel(&level, u.floor() as i32, v.floor() as i32)
+                .map(f32::from);
+        }
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x, y) = (u.floor() as i32, v.floor() as i32);
+        let (fx, fy) = (u - u.floor(), v - v.floor());
+        let corners = [
+            (self.texel(&level, x, y), (1.0 - fx) * (1.0 - fy)),
+            (self.texel(&level, x + 1, y), fx * (1.0 - fy)),
+            (self.texel(&level, x, y + 1), (1.0 - fx) * fy),
+            (self.texel(&level, x + 1, y + 1), fx * fy),
+        ];
+        let mut out = [0.0; 4];
+        for (texel, weight) in corners {
+            for (sum, channel) in out.iter_mut().zip(texel) {
+                *sum += channel as f32 * weight;
+            }
+        }
+        out
+    }
+
+    // Filters the texture at level 0 texel coordinates (u, v) for a fragment with `q`
+    pub fn sample(&self, u: f32, v: f32, q: f32) -> [u8; 4] {
+        let k = ((field(self.tex1, 32, 12) as i32) << 20 >> 20) as f32 / 16.0;
+        let lod = if self.tex1 & 1 != 0 {
+            k
+        } else {
+            let l = field(self.tex1, 19, 2);
+            (1.0 / q.abs()).log2() * (1 << l) as f32 + k
+        };
+        let max_level = field(self.tex1, 2, 3).min(6);
+        let (filter, lod) = if lod.is_nan() || lod <= 0.0 {
+            let linear = self.tex1 & 1 << 5 != 0;
+            (
+                if linear {
+                    FILTER_LINEAR
+                } else {
+                    FILTER_NEAREST
+                },
+                0.0,
+            )
+        } else {
+            (field(self.tex1, 6, 3), lod.min(max_level as f32))
+        };
+        let texel = match filter {
+            FILTER_NEAREST => self.sample_level(0, u, v, false),
+            FILTER_NEAREST_MIPMAP_NEAREST | FILTER_LINEAR_MIPMAP_NEAREST => {
+                let linear = filter == FILTER_LINEAR_MIPMAP_NEAREST;
+                self.sample_level(lod.round() as u32, u, v, linear)
+            }
+            FILTER_NEAREST_MIPMAP_LINEAR | FILTER_LINEAR_MIPMAP_LINEAR => {
+                let linear = filter == FILTER_LINEAR_MIPMAP_LINEAR;
+                let lower = lod.floor() as u32;
+                let upper = (lower + 1).min(max_level);
+                let t = lod - lod.floor();
+                let a = self.sample_level(lower, u, v, linear);
+                let b = self.sample_level(upper, u, v, linear);
+                [0, 1, 2, 3].map(|i| a[i] + (b[i] - a[i]) * t)
+            }
+            // FILTER_LINEAR, and the undefined 6 and 7
+            _ => self.sample_level(0, u, v, true),
+        };
+        texel.map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+    }
+}
+
+// Combines a texel with the fragment's colour according to TEX0.TFX. Without TEX0.TCC the
+// fragment keeps its own alpha.
+pub fn apply(tfx: u32, tcc: bool, texel: [u8; 4], color: [u8; 4]) -> [u8; 4] {
+    let modulate = |t: u8, c: u8| (t as u32 * c as u32) >> 7;
+    let clamp = |value: u32| value.min(255) as u8;
+    let mut out = [0; 4];
+    for i in 0..3 {
+        out[i] = match tfx {
+            TFX_MODULATE => clamp(modulate(texel[i], color[i])),
+            TFX_DECAL => texel[i],
+            _ => clamp(modulate(texel[i], color[i]) + color[3] as u32),
+        };
+    }
+    out[3] = if !tcc {
+        color[3]
+    } else {
+        match tfx {
+            TFX_MODULATE => clamp(modulate(texel[3], color[3])),
+            TFX_HIGHLIGHT => clamp(texel[3] as u32 + color[3] as u32),
+            _ => texel[3],
+        }
+    };
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gs::memory::{PSMCT16, PSMCT24, PSMCT32, PSMT4, PSMT8};
+    use crate::gs::{self, Gs};
+
+    // Textures sit at block 0, 128 pixels wide, and CLUTs a page further on
+    const TBW: u32 = 2;
+    const CLUT_BP: u32 = 32;
+
+    fn tex0(psm: u32, log_size: u32) -> u64 {
+        (TBW as u64) << 14 | (psm as u64) << 20 | (log_size as u64) << 26 | (log_size as u64) << 30
+    }
+
+    fn with_clut(tex0: u64, cpsm: u32, csm2: bool, csa: u32) -> u64 {
+        tex0 | (CLUT_BP as u64) << 37
+            | (cpsm as u64) << 51
+            | (csm2 as u64) << 55
+            | (csa as u64) << 56
+    }
+
+    fn sampler<'a>(memory: &'a LocalMemory, clut: &'a Clut, tex0: u64) -> Sampler<'a> {
+        Sampler {
+            memory,
+            clut,
+            tex0,
+            tex1: 0,
+            clamp: 0,
+            texa: 0,
+            miptbp1: 0,
+            miptbp2: 0,
+        }
+    }
+
+    // The texel at the centre of (x, y), unfiltered
+    fn at(sampler: &Sampler, x: f32, y: f32) -> [u8; 4] {
+        sampler.sample(x + 0.5, y + 0.5, 1.0)
+    }
+
+    #[test]
+    fn csm1_swaps_index_bits_3_and_4() {
+        let mut memory = LocalMemory::new();
+        // Each CLUT entry's red is its position in the 16x16 block
+        for position in 0..256 {
+            let color = 0x8000_0000 | position;
+            memory.write_pixel(PSMCT32, CLUT_BP, 1, position % 16, position / 16, color);
+        }
+        let indices = [8, 16, 0x1F, 0x2A, 7];
+        for (x, &index) in indices.iter().enumerate() {
+            memory.write_pixel(PSMT8, 0, TBW, x as u32, 0, index);
+        }
+        let indexed8 = with_clut(tex0(PSMT8, 3), PSMCT32, false, 0);
+        let mut clut = Clut::default();
+        clut.load(&memory, indexed8, 0);
+        let sampler = sampler(&memory, &clut, indexed8);
+        let reds: Vec<_> = (0..5).map(|x| at(&sampler, x as f32, 0.0)[0]).collect();
+        assert_eq!(reds, [16, 8, 0x1F, 0x32, 7]);
+        assert_eq!(at(&sampler, 0.0, 0.0), [16, 0, 0, 0x80]);
+
+        // 4-bit indices come from an 8x2 block as they are
+        let mut memory = LocalMemory::new();
+        for index in 0..16 {
+            memory.write_pixel(PSMCT32, CLUT_BP, 1, index % 8, index / 8, 0x100 * index);
+        }
+        memory.write_pixel(PSMT4, 0, TBW, 0, 0, 9);
+        let tex0 = with_clut(tex0(PSMT4, 3), PSMCT32, false, 0);
+        clut.load(&memory, tex0, 0);
+        assert_eq!(
+            at(&sampler_for(&memory, &clut, tex0), 0.0, 0.0),
+            [0, 9, 0, 0]
+        );
+    }
+
+    fn sampler_for<'a>(memory: &'a LocalMemory, clut: &'a Clut, tex0: u64) -> Sampler<'a> {
+        sampler(memory, clut, tex0)
+    }
+
+    #[test]
+    fn csm2_reads_a_row_at_texclut() {
+        let mut memory = LocalMemory::new();
+        // CBW 2, COU 1 and COV 3: entries from (16, 3) along, in a 128 pixel wide buffer
+        let texclut = 2 | 1 << 6 | 3 << 12;
+        for index in 0..16 {
+            let color = index << 10 | (index & 1) << 15;
+            memory.write_pixel(PSMCT16, CLUT_BP, 2, 16 + index, 3, color);
+        }
+        memory.write_pixel(PSMT4, 0, TBW, 0, 0, 5);
+        memory.write_pixel(PSMT4, 0, TBW, 1, 0, 4);
+        let tex0 = with_clut(tex0(PSMT4, 3), PSMCT16, true, 0);
+        let mut clut = Clut::default();
+        clut.load(&memory, tex0, texclut);
+        let sampler = Sampler {
+            texa: 0x10 | 0x70 << 32,
+            ..sampler(&memory, &clut, tex0)
+        };
+        // 16-bit entries take their alpha from TEXA
+        assert_eq!(at(&sampler, 0.0, 0.0), [0, 0, 40, 0x70]);
+        assert_eq!(at(&sampler, 1.0, 0.0), [0, 0, 32, 0x10]);
+    }
+
+    #[test]
+    fn csa_picks_the_entries_of_a_4_bit_clut() {
+        let mut memory = LocalMemory::new();
+        memory.write_pixel(PSMT4, 0, TBW, 0, 0, 3);
+        let mut clut = Clut::default();
+
+        // 16-bit entries: CSA counts 16-entry groups through all 512
+        let write = |memory: &mut LocalMemory, shift: u32| {
+            for index in 0..16 {
+                let color = 0x8000 | index << shift;
+                memory.write_pixel(PSMCT16, CLUT_BP, 1, index % 8, index / 8, color);
+            }
+        };
+        write(&mut memory, 0);
+        clut.load(&memory, with_clut(tex0(PSMT4, 3), PSMCT16, false, 0), 0);
+        write(&mut memory, 5);
+        clut.load(&memory, with_clut(tex0(PSMT4, 3), PSMCT16, false, 20), 0);
+        assert_eq!(clut.as_slice()[3], 0x8003);
+        assert_eq!(clut.as_slice()[20 * 16 + 3], 0x8060);
+        let texa = 0xFF << 32;
+        for (csa, expected) in [(0, [24, 0, 0, 0xFF]), (20, [0, 24, 0, 0xFF])] {
+            let tex0 = with_clut(tex0(PSMT4, 3), PSMCT16, false, csa);
+            let sampler = Sampler {
+                texa,
+                ..sampler(&memory, &clut, tex0)
+            };
+            assert_eq!(at(&sampler, 0.0, 0.0), expected, "csa {csa}");
+        }
+
+        // 32-bit entries keep their high halves 256 entries on, so only CSA 0-15 count
+        for index in 0..16 {
+            let color = 0x8000_0000 | index << 16 | index;
+            memory.write_pixel(PSMCT32, CLUT_BP, 1, index % 8, index / 8, color);
+        }
+        clut.load(&memory, with_clut(tex0(PSMT4, 3), PSMCT32, false, 2), 0);
+        assert_eq!(clut.as_slice()[32 + 3], 3);
+        assert_eq!(clut.as_slice()[256 + 32 + 3], 0x8003);
+        for csa in [2, 18] {
+            let tex0 = with_clut(tex0(PSMT4, 3), PSMCT32, false, csa);
+            let sampler = sampler(&memory, &clut, tex0);
+            assert_eq!(at(&sampler, 0.0, 0.0), [3, 0, 3, 0x80], "csa {csa}");
+        }
+    }
+
+    #[test]
+    fn cld_loads_on_its_own_terms() {
+        const OTHER_BP: u32 = 64;
+        let mut gs = Gs::new();
+        let set_entry = |gs: &mut Gs, bp: u32, value: u32| {
+            gs.memory_mut().write_pixel(PSMCT32, bp, 1, 0, 0, value);
+        };
+        let load = |gs: &mut Gs, cld: u64, cbp: u32| {
+            let tex0 = with_clut(tex0(PSMT4, 3), PSMCT32, false, 0) & !(0x3FFF << 37);
+            gs.write_register(gs::TEX0_1, tex0 | (cbp as u64) << 37 | cld << 61);
+            gs.clut().as_slice()[0]
+        };
+
+        set_entry(&mut gs, CLUT_BP, 1);
+        assert_eq!(load(&mut gs, 0, CLUT_BP), 0);
+        assert_eq!(load(&mut gs, 1, CLUT_BP), 1);
+        // 2 loads and records CBP0, 4 loads only if CBP differs from it and records it
+        set_entry(&mut gs, CLUT_BP, 2);
+        assert_eq!(load(&mut gs, 2, CLUT_BP), 2);
+        set_entry(&mut gs, CLUT_BP, 3);
+        assert_eq!(load(&mut gs, 4, CLUT_BP), 2);
+        set_entry(&mut gs, OTHER_BP, 4);
+        assert_eq!(load(&mut gs, 4, OTHER_BP), 4);
+        set_entry(&mut gs, OTHER_BP, 5);
+        assert_eq!(load(&mut gs, 4, OTHER_BP), 4);
+        // 3 and 5 do the same with CBP1, which 4 left alone
+        assert_eq!(load(&mut gs, 5, OTHER_BP), 5);
+        set_entry(&mut gs, OTHER_BP, 6);
+        assert_eq!(load(&mut gs, 5, OTHER_BP), 5);
+        assert_eq!(load(&mut gs, 3, OTHER_BP), 6);
+        assert_eq!(load(&mut gs, 5, CLUT_BP), 3);
+        // Modes 6 and 7 don't load
+        assert_eq!(load(&mut gs, 6, OTHER_BP), 3);
+        assert_eq!(load(&mut gs, 7, OTHER_BP), 3);
+    }
+
+    // A 4x4 PSMCT32 texture whose texels hold their own coordinates in red and green
+    fn grid(scale: u32) -> LocalMemory {
+        let mut memory = LocalMemory::new();
+        for y in 0..4 {
+            for x in 0..4 {
+                let color = 0x8000_0000 | (y * scale) << 8 | (x * scale);
+                memory.write_pixel(PSMCT32, 0, TBW, x, y, color);
+            }
+        }
+        memory
+    }
+
+    #[test]
+    fn wrap_modes() {
+        let memory = grid(1);
+        let clut = Clut::default();
+        let tex0 = tex0(PSMCT32, 2);
+        // WMS and WMT the same, MINU/MINV 1 and MAXU/MAXV 2
+        let sample = |mode: u64, x: f32| {
+            let clamp = mode | mode << 2 | 1 << 4 | 2 << 14 | 1 << 24 | 2 << 34;
+            let sampler = Sampler {
+                clamp,
+                ..sampler(&memory, &clut, tex0)
+            };
+            let texel = at(&sampler, x, x);
+            assert_eq!(texel[0], texel[1]);
+            texel[0]
+        };
+        let xs = [-5.0, -1.0, 0.0, 1.0, 2.0, 3.0, 4.0, 6.0];
+        let wrapped = |mode: u32| xs.map(|x| sample(mode as u64, x));
+        assert_eq!(wrapped(WRAP_REPEAT), [3, 3, 0, 1, 2, 3, 0, 2]);
+        assert_eq!(wrapped(WRAP_CLAMP), [0, 0, 0, 1, 2, 3, 3, 3]);
+        assert_eq!(wrapped(WRAP_REGION_CLAMP), [1, 1, 1, 1, 2, 2, 2, 2]);
+        // (coordinate & MIN) | MAX
+        assert_eq!(wrapped(WRAP_REGION_REPEAT), [3, 3, 2, 3, 2, 3, 2, 2]);
+    }
+
+    #[test]
+    fn region_modes_scale_with_the_mip_level() {
+        // Level 1 of an 8x8 texture: red is x and green y
+        let mut memory = LocalMemory::new();
+        for y in 0..4 {
+            for x in 0..4 {
+                memory.write_pixel(PSMCT32, 32, 1, x, y, 0x8000_0000 | y << 8 | x);
+            }
+        }
+        let clut = Clut::default();
+        // LCM with K 1.0 picks level 1, MXL 2, mipmap nearest
+        let tex1 = 1 | 2 << 2 | (FILTER_NEAREST_MIPMAP_NEAREST as u64) << 6 | 0x10 << 32;
+        let sample = |mode: u64, min: u64, max: u64, x: u32| {
+            let clamp = mode | mode << 2 | min << 4 | max << 14 | min << 24 | max << 34;
+            let sampler = Sampler {
+                tex1,
+                clamp,
+                miptbp1: 32 | 1 << 14,
+                ..sampler(&memory, &clut, tex0(PSMCT32, 3))
+            };
+            // In level 0 texels, so the centre of level 1 texel x
+            let u = (2 * x + 1) as f32;
+            let texel = sampler.sample(u, u, 1.0);
+            assert_eq!(texel[0], texel[1]);
+            texel[0]
+        };
+        let wrapped = |mode: u32, min, max| [0, 1, 2, 3].map(|x| sample(mode as u64, min, max, x));
+        // Level 0 limits of 2 and 4 are 1 and 2 at level 1
+        assert_eq!(wrapped(WRAP_REGION_CLAMP, 2, 4), [1, 1, 2, 2]);
+        // A mask of 4 and forced bits of 2 become a mask of 2 forcing bit 0
+        assert_eq!(wrapped(WRAP_REGION_REPEAT, 4, 2), [1, 1, 3, 3]);
+    }
+
+    #[test]
+    fn bilinear_samples_half_a_texel_up_and_left() {
+        // Red is 60 per texel across and green 60 down
+        let memory = grid(60);
+        let clut = Clut::default();
+        let sampler = Sampler {
+            tex1: 1 << 5,
+            ..sampler(&memory, &clut, tex0(PSMCT32, 2))
+        };
+        // Texel centres come back as they are
+        assert_eq!(sampler.sample(1.5, 2.5, 1.0), [60, 120, 0, 0x80]);
+        assert_eq!(sampler.sample(1.0, 0.5, 1.0), [30, 0, 0, 0x80]);
+        assert_eq!(sampler.sample(1.75, 1.25, 1.0), [75, 45, 0, 0x80]);
+        // Between the last texel and the first again when repeating
+        assert_eq!(sampler.sample(0.25, 0.5, 1.0), [45, 0, 0, 0x80]);
+        // And nothing is blended with MMAG nearest
+        let nearest = Sampler { tex1: 0, ..sampler };
+        assert_eq!(nearest.sample(1.75, 1.25, 1.0), [60, 60, 0, 0x80]);
+    }
+
+    // An 8x8 PSMCT32 texture with levels 1 and 2 at MIPTBP1's pointers, each level a solid
+    // colour with its number in red
+    fn mipmapped() -> (LocalMemory, u64) {
+        let mut memory = LocalMemory::new();
+        for (level, bp) in [(0, 0), (1, 32), (2, 64)] {
+            let size = 8 >> level;
+            for y in 0..size {
+                for x in 0..size {
+                    memory.write_pixel(PSMCT32, bp, 1, x, y, 0x8000_0000 | level);
+                }
+            }
+        }
+        let miptbp1 = 32 | 1 << 14 | 64 << 20 | 1 << 34;
+        (memory, miptbp1)
+    }
+
+    #[test]
+    fn lod_follows_q_l_and_k() {
+        let (memory, miptbp1) = mipmapped();
+        let clut = Clut::default();
+        let tex0 = tex0(PSMCT32, 3) & !(0x3F << 14) | 1 << 14;
+        // MXL 2, MMIN nearest, mipmap nearest
+        let tex1 = 2 << 2 | (FILTER_NEAREST_MIPMAP_NEAREST as u64) << 6;
+        let level = |tex1: u64, q: f32| {
+            let sampler = Sampler {
+                tex1,
+                miptbp1,
+                ..sampler(&memory, &clut, tex0)
+            };
+            sampler.sample(0.5, 0.5, q)[0]
+        };
+        // LOD = log2(1/Q) << L + K, clamped to MXL
+        assert_eq!(level(tex1, 1.0), 0);
+        assert_eq!(level(tex1, 0.5), 1);
+        assert_eq!(level(tex1, 0.25), 2);
+        assert_eq!(level(tex1, 0.125), 2);
+        assert_eq!(level(tex1, 2.0), 0);
+        assert_eq!(level(tex1 | 1 << 19, 0.5), 2);
+        let minus_one = 0xFF0 << 32;
+        assert_eq!(level(tex1 | minus_one, 0.25), 1);
+        assert_eq!(level(tex1 | minus_one, 0.5), 0);
+        // LCM takes K alone
+        let two = 0x20 << 32;
+        assert_eq!(level(tex1 | 1 | two, 1.0), 2);
+        assert_eq!(level(tex1 | 1, 0.125), 0);
+
+        // Blending between the two nearest levels
+        let linear = 2 << 2 | (FILTER_NEAREST_MIPMAP_LINEAR as u64) << 6;
+        assert_eq!(level(linear, 0.5f32.powf(1.5)), 2);
+        assert_eq!(level(linear, 0.5f32.powf(1.25)), 1);
+    }
+
+    #[test]
+    fn mtba_packs_the_levels_after_level_0() {
+        let (memory, miptbp1) = mipmapped();
+        // Level 0 takes one block, so level 1 starts at block 1 and level 2 at block 2
+        let mut memory = memory;
+        for (level, bp) in [(1, 1), (2, 2)] {
+            let size = 8 >> level;
+            for y in 0..size {
+                for x in 0..size {
+                    memory.write_pixel(PSMCT32, bp, 1, x, y, 0x8000_0010 | level);
+                }
+            }
+        }
+        let clut = Clut::default();
+        let tex0 = tex0(PSMCT32, 3) & !(0x3F << 14) | 1 << 14;
+        let tex1 = 2 << 2 | (FILTER_NEAREST_MIPMAP_NEAREST as u64) << 6;
+        for (mtba, expected) in [(false, [0, 1, 2]), (true, [0, 0x11, 0x12])] {
+            let sampler = Sampler {
+                tex1: tex1 | (mtba as u64) << 9,
+                miptbp1,
+                ..sampler(&memory, &clut, tex0)
+            };
+            let reds = [1.0, 0.5, 0.25].map(|q| sampler.sample(0.5, 0.5, q)[0]);
+            assert_eq!(reds, expected, "mtba {mtba}");
+        }
+    }
+
+    #[test]
+    fn texa_supplies_alpha_for_24_and_16_bit_texels() {
+        let mut memory = LocalMemory::new();
+        for (x, color) in [0x0012_3456, 0].into_iter().enumerate() {
+            memory.write_pixel(PSMCT24, 0, TBW, x as u32, 0, color);
+        }
+        let clut = Clut::default();
+        let texa = 0x10 | 0x70 << 32;
+        let aem = 1 << 15;
+        let rgb24 = |texa: u64| {
+            let sampler = Sampler {
+                texa,
+                ..sampler(&memory, &clut, tex0(PSMCT24, 2))
+            };
+            [at(&sampler, 0.0, 0.0), at(&sampler, 1.0, 0.0)]
+        };
+        assert_eq!(rgb24(texa), [[0x56, 0x34, 0x12, 0x10], [0, 0, 0, 0x10]]);
+        // AEM makes black transparent
+        assert_eq!(rgb24(texa | aem), [[0x56, 0x34, 0x12, 0x10], [0, 0, 0, 0]]);
+
+        let mut memory = LocalMemory::new();
+        for (x, color) in [0x801F, 0x001F, 0x8000, 0].into_iter().enumerate() {
+            memory.write_pixel(PSMCT16, 0, TBW, x as u32, 0, color);
+        }
+        let rgb16 = |texa: u64| {
+            let sampler = Sampler {
+                texa,
+                ..sampler(&memory, &clut, tex0(PSMCT16, 2))
+            };
+            [0.0, 1.0, 2.0, 3.0].map(|x| at(&sampler, x, 0.0))
+        };
+        // TA1 where the alpha bit is set, TA0 where it isn't
+        assert_eq!(
+            rgb16(texa),
+            [
+                [0xF8, 0, 0, 0x70],
+                [0xF8, 0, 0, 0x10],
+                [0, 0, 0, 0x70],
+                [0, 0, 0, 0x10]
+            ]
+        );
+        // Only black without the alpha bit counts for AEM
+        assert_eq!(
+            rgb16(texa | aem),
+            [
+                [0xF8, 0, 0, 0x70],
+                [0xF8, 0, 0, 0x10],
+                [0, 0, 0, 0x70],
+                [0, 0, 0, 0]
+            ]
+        );
+    }
+}
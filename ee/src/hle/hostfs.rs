@@ -0,0 +1,460 @@
+// The `host:` device, backed by a directory on the host. Paths are resolved inside that
+// directory only: `..` components, absolute paths and symlinks leading outside it are refused.
+//
+// Results follow the IOP's fio conventions: a file descriptor, byte count or offset on success
+// and a negated errno on failure.
+//
+// EE programs get here through fileio RPCs, served by the IOP stand-in in `sif`.
+
+use std::fs::{self, File, OpenOptions, ReadDir};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// fio open flags
+pub const O_RDONLY: u32 = 0x0001;
+pub const O_WRONLY: u32 = 0x0002;
+pub const O_RDWR: u32 = 0x0003;
+pub const O_APPEND: u32 = 0x0100;
+pub const O_CREAT: u32 = 0x0200;
+pub const O_TRUNC: u32 = 0x0400;
+pub const O_EXCL: u32 = 0x0800;
+
+pub const SEEK_SET: u32 = 0;
+pub const SEEK_CUR: u32 = 1;
+pub const SEEK_END: u32 = 2;
+
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const EACCES: i32 = 13;
+pub const EEXIST: i32 = 17;
+pub const ENODEV: i32 = 19;
+pub const ENOTDIR: i32 = 20;
+pub const EISDIR: i32 = 21;
+pub const EINVAL: i32 = 22;
+pub const EMFILE: i32 = 24;
+
+// fio mode bits reported by dread
+pub const S_IFDIR: u32 = 0x1000;
+pub const S_IFREG: u32 = 0x2000;
+const S_IRWXUGO: u32 = 0x1FF;
+
+const MAX_HANDLES: usize = 32;
+
+// What dread returns for one directory entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub mode: u32,
+    pub size: u64,
+}
+
+enum Handle {
+    File(File),
+    Dir(ReadDir),
+}
+
+pub struct HostFs {
+    root: PathBuf,
+    // Indexed by descriptor
+    handles: Vec<Option<Handle>>,
+}
+
+fn errno(err: &io::Error) -> i32 {
+    let code = match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::NotADirectory => ENOTDIR,
+        io::ErrorKind::IsADirectory => EISDIR,
+        _ => EIO,
+    };
+    -code
+}
+
+impl HostFs {
+    // Fails if `root` isn't an existing directory
+    pub fn new(root: impl AsRef<Path>) -> io::Result<Self> {
+        let root = root.as_ref().canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(HostFs {
+            root,
+            handles: Vec::new(),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    // `host:dir/file.bin` (also `host0:`, with `/` or `\` separators) to a path under the root.
+    // The longest existing prefix is canonicalized so a symlink can't lead out of the sandbox.
+    // Existence is checked without following links: a dangling one counts as existing and then
+    // fails to canonicalize, rather than letting O_CREAT create its target wherever it points.
+    pub fn resolve(&self, guest_path: &str) -> Result<PathBuf, i32> {
+        let path = guest_path
+            .split_once(':')
+            .filter(|(device, _)| device.starts_with("host"))
+            .map_or(guest_path, |(_, path)| path);
+        let mut resolved = self.root.clone();
+        for part in path.split(['/', '\\']) {
+            match part {
+                "" | "." => {}
+                ".." => return Err(-EACCES),
+                _ if part.contains(':') => return Err(-EACCES),
+                _ => resolved.push(part),
+            }
+        }
+        let mut existing = resolved.as_path();
+        while fs::symlink_metadata(existing).is_err() {
+            existing = existing.parent().ok_or(-ENOENT)?;
+        }
+        let canonical = existing.canonicalize().map_err(|err| errno(&err))?;
+        if !canonical.starts_with(&self.root) {
+            return Err(-EACCES);
+        }
+        match resolved.strip_prefix(existing) {
+            Ok(rest) if !rest.as_os_str().is_empty() => Ok(canonical.join(rest)),
+            _ => Ok(canonical),
+        }
+    }
+
+    fn insert(&mut self, handle: Handle) -> i32 {
+        let slot = match self.handles.iter().position(Option::is_none) {
+            Some(slot) => slot,
+            None if self.handles.len() < MAX_HANDLES => {
+                self.handles.push(None);
+                self.handles.len() - 1
+            }
+            None => return -EMFILE,
+        };
+        self.handles[slot] = Some(handle);
+        slot as i32
+    }
+
+    fn file(&mut self, fd: i32) -> Result<&mut File, i32> {
+        match self.handles.get_mut(fd as usize) {
+            Some(Some(Handle::File(file))) if fd >= 0 => Ok(file),
+            _ => Err(-EBADF),
+        }
+    }
+
+    pub fn open(&mut self, path: &str, flags: u32) -> i32 {
+        let path = match self.resolve(path) {
+            Ok(path) => path,
+            Err(err) => return err,
+        };
+        if path.is_dir() {
+            return -EISDIR;
+        }
+        let access = flags & O_RDWR;
+        if access == 0 {
+            return -EINVAL;
+        }
+        let mut options = OpenOptions::new();
+        options
+            .read(access & O_RDONLY != 0)
+            .write(access & O_WRONLY != 0)
+            .append(flags & O_APPEND != 0)
+            .truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+        match options.open(&path) {
+            Ok(file) => self.insert(Handle::File(file)),
+            Err(err) => errno(&err),
+        }
+    }
+
+    pub fn close(&mut self, fd: i32) -> i32 {
+        match self.handles.get_mut(fd as usize) {
+            Some(handle @ Some(Handle::File(_))) if fd >= 0 => {
+                *handle = None;
+                0
+            }
+            _ => -EBADF,
+        }
+    }
+
+    pub fn read(&mut self, fd: i32, buf: &mut [u8]) -> i32 {
+        let file = match self.file(fd) {
+            Ok(file) => file,
+            Err(err) => return err,
+        };
+        let mut total = 0;
+        while total < buf.len() {
+            match file.read(&mut buf[total..]) {
+                Ok(0) => break,
+                Ok(count) => total += count,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return errno(&err),
+            }
+        }
+        total as i32
+    }
+
+    pub fn write(&mut self, fd: i32, data: &[u8]) -> i32 {
+        match self.file(fd).map(|file| file.write_all(data)) {
+            Ok(Ok(())) => data.len() as i32,
+            Ok(Err(err)) => errno(&err),
+            Err(err) => err,
+        }
+    }
+
+    // Returns the new offset
+    pub fn lseek(&mut self, fd: i32, offset: i32, whence: u32) -> i32 {
+        let from = match whence {
+            SEEK_SET if offset >= 0 => SeekFrom::Start(offset as u64),
+            SEEK_CUR => SeekFrom::Current(offset as i64),
+            SEEK_END => SeekFrom::End(offset as i64),
+            _ => return -EINVAL,
+        };
+        match self.file(fd).map(|file| file.seek(from)) {
+            Ok(Ok(position)) => i32::try_from(position).unwrap_or(-EINVAL),
+            Ok(Err(err)) => errno(&err),
+            Err(err) => err,
+        }
+    }
+
+    pub fn dopen(&mut self, path: &str) -> i32 {
+        let path = match self.resolve(path) {
+            Ok(path) => path,
+            Err(err) => return err,
+        };
+        match fs::read_dir(&path) {
+            Ok(entries) => self.insert(Handle::Dir(entries)),
+            Err(err) => errno(&err),
+        }
+    }
+
+    // The next entry, `Ok(None)` at the end of the directory
+    pub fn dread(&mut self, fd: i32) -> Result<Option<DirEntry>, i32> {
+        let entries = match self.handles.get_mut(fd as usize) {
+            Some(Some(Handle::Dir(entries))) if fd >= 0 => entries,
+            _ => return Err(-EBADF),
+        };
+        let Some(entry) = entries.next() else {
+            return Ok(None);
+        };
+        let entry = entry.map_err(|err| errno(&err))?;
+        let metadata = entry.metadata().map_err(|err| errno(&err))?;
+        let kind = if metadata.is_dir() { S_IFDIR } else { S_IFREG };
+        Ok(Some(DirEntry {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            mode: kind | S_IRWXUGO,
+            size: metadata.len(),
+        }))
+    }
+
+    pub fn dclose(&mut self, fd: i32) -> i32 {
+        match self.handles.get_mut(fd as usize) {
+            Some(handle @ Some(Handle::Dir(_))) if fd >= 0 => {
+                *handle = None;
+                0
+            }
+            _ => -EBADF,
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn dangling_symlink_cannot_create_outside_the_root() {
+        let base = std::env::temp_dir().join(format!("hostfs-test-{}", std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("outside.bin");
+        fs::create_dir_all(&root).unwrap();
+        symlink(&outside, root.join("link.bin")).unwrap();
+
+        let mut fs = HostFs::new(&root).unwrap();
+        assert!(fs.resolve("host:link.bin").is_err());
+        assert!(fs.open("host:link.bin", O_WRONLY | O_CREAT) < 0);
+        assert!(fs.resolve("host:link.bin/file.bin").is_err());
+        assert!(!outside.exists());
+
+        // Ordinary creation still works
+        let fd = fs.open("host:new.bin", O_WRONLY | O_CREAT);
+        assert!(fd >= 0);
+        assert_eq!(fs.close(fd), 0);
+        assert!(root.join("new.bin").exists());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    // A fresh `root` directory under a per-test base, which is returned too for cleanup
+    fn sandbox(name: &str) -> (PathBuf, HostFs) {
+        let base = std::env::temp_dir().join(format!("hostfs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("root")).unwrap();
+        let fs = HostFs::new(base.join("root")).unwrap();
+        (base, fs)
+    }
+
+    #[test]
+    fn paths_resolve_inside_the_root() {
+        let (base, fs) = sandbox("resolve");
+        let root = fs.root().to_path_buf();
+        fs::create_dir(root.join("sub")).unwrap();
+
+        assert_eq!(fs.resolve("host:sub/a.bin"), Ok(root.join("sub/a.bin")));
+        assert_eq!(
+            fs.resolve("host0:\\sub\\.\\a.bin"),
+            Ok(root.join("sub/a.bin"))
+        );
+        assert_eq!(fs.resolve("host:"), Ok(root.clone()));
+        // Absolute paths are taken from the root, not the host's
+        assert_eq!(fs.resolve("host:/etc/passwd"), Ok(root.join("etc/passwd")));
+        assert_eq!(fs.resolve("host:\\sub"), Ok(root.join("sub")));
+
+        for path in [
+            "host:..",
+            "host:../outside.bin",
+            "host:sub/../../outside.bin",
+            "host:sub/..",
+            // Another device, or a drive letter, partway through the path
+            "host:sub/cdrom0:a.bin",
+            "host:C:\\Windows",
+            "cdrom0:a.bin",
+        ] {
+            assert_eq!(fs.resolve(path), Err(-EACCES), "{}", path);
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn symlinks_resolve_only_while_they_stay_inside() {
+        let (base, fs) = sandbox("symlink");
+        let root = fs.root().to_path_buf();
+        fs::create_dir(base.join("outside")).unwrap();
+        fs::write(base.join("outside/secret.bin"), b"secret").unwrap();
+        fs::create_dir(root.join("sub")).unwrap();
+        symlink(base.join("outside"), root.join("out")).unwrap();
+        symlink(root.join("sub"), root.join("alias")).unwrap();
+
+        // Through an existing prefix, to a file that exists or one that doesn't yet
+        assert_eq!(fs.resolve("host:out"), Err(-EACCES));
+        assert_eq!(fs.resolve("host:out/secret.bin"), Err(-EACCES));
+        assert_eq!(fs.resolve("host:out/new/file.bin"), Err(-EACCES));
+        assert_eq!(fs.resolve("host:alias/a.bin"), Ok(root.join("sub/a.bin")));
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn descriptors_read_write_and_seek() {
+        let (base, mut fs) = sandbox("files");
+        let fd = fs.open("host:a.bin", O_WRONLY | O_CREAT | O_TRUNC);
+        assert_eq!(fd, 0);
+        assert_eq!(fs.write(fd, b"hello world"), 11);
+        assert_eq!(fs.close(fd), 0);
+
+        let fd = fs.open("host:a.bin", O_RDONLY);
+        assert_eq!(fd, 0, "closed descriptors are reused");
+        let mut buf = [0; 16];
+        assert_eq!(fs.lseek(fd, 6, SEEK_SET), 6);
+        assert_eq!(fs.read(fd, &mut buf), 5);
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(fs.read(fd, &mut buf), 0);
+        assert_eq!(fs.lseek(fd, -5, SEEK_CUR), 6);
+        assert_eq!(fs.lseek(fd, -11, SEEK_END), 0);
+        assert_eq!(fs.lseek(fd, -1, SEEK_SET), -EINVAL);
+        assert_eq!(fs.lseek(fd, 0, 3), -EINVAL);
+
+        let append = fs.open("host:a.bin", O_WRONLY | O_APPEND);
+        assert_eq!(append, 1);
+        assert_eq!(fs.write(append, b"!"), 1);
+        assert_eq!(fs.lseek(fd, 0, SEEK_END), 12);
+        assert_eq!(fs.close(append), 0);
+        assert_eq!(fs.close(fd), 0);
+
+        for fd in [fd, -1, MAX_HANDLES as i32] {
+            assert_eq!(fs.read(fd, &mut buf), -EBADF);
+            assert_eq!(fs.write(fd, b"x"), -EBADF);
+            assert_eq!(fs.lseek(fd, 0, SEEK_SET), -EBADF);
+            assert_eq!(fs.close(fd), -EBADF);
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn opens_fail_with_fio_errors() {
+        let (base, mut fs) = sandbox("open");
+        fs::create_dir(fs.root().join("sub")).unwrap();
+        assert_eq!(fs.open("host:missing.bin", O_RDONLY), -ENOENT);
+        assert_eq!(fs.open("host:sub", O_RDONLY), -EISDIR);
+        assert_eq!(fs.open("host:a.bin", O_CREAT), -EINVAL);
+        assert_eq!(fs.open("host:a.bin", O_WRONLY | O_CREAT | O_EXCL), 0);
+        assert_eq!(fs.open("host:a.bin", O_WRONLY | O_CREAT | O_EXCL), -EEXIST);
+
+        // The table is full once every descriptor is open
+        for fd in 1..MAX_HANDLES as i32 {
+            assert_eq!(fs.open("host:a.bin", O_RDONLY), fd);
+        }
+        assert_eq!(fs.open("host:a.bin", O_RDONLY), -EMFILE);
+        assert_eq!(fs.dopen("host:sub"), -EMFILE);
+        assert_eq!(fs.close(5), 0);
+        assert_eq!(fs.dopen("host:sub"), 5);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn dread_lists_a_directory_once() {
+        let (base, mut fs) = sandbox("dread");
+        fs::write(fs.root().join("a.bin"), b"12345").unwrap();
+        fs::create_dir(fs.root().join("sub")).unwrap();
+        assert_eq!(fs.dopen("host:missing"), -ENOENT);
+        assert_eq!(fs.dopen("host:a.bin"), -ENOTDIR);
+
+        let fd = fs.dopen("host:");
+        let mut entries = Vec::new();
+        while let Some(entry) = fs.dread(fd).unwrap() {
+            entries.push(entry);
+        }
+        assert_eq!(fs.dread(fd), Ok(None));
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries[0],
+            DirEntry {
+                name: "a.bin".to_string(),
+                mode: S_IFREG | S_IRWXUGO,
+                size: 5,
+            }
+        );
+        assert_eq!(
+            (entries[1].name.as_str(), entries[1].mode),
+            ("sub", S_IFDIR | S_IRWXUGO)
+        );
+
+        // Directory and file descriptors don't stand in for each other
+        let mut buf = [0; 4];
+        assert_eq!(fs.read(fd, &mut buf), -EBADF);
+        assert_eq!(fs.close(fd), -EBADF);
+        let file = fs.open("host:a.bin", O_RDONLY);
+        assert_eq!(fs.dread(file), Err(-EBADF));
+        assert_eq!(fs.dclose(file), -EBADF);
+
+        assert_eq!(fs.dclose(fd), 0);
+        assert_eq!(fs.dread(fd), Err(-EBADF));
+        assert_eq!(fs.dclose(fd), -EBADF);
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
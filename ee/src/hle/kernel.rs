@@ -0,0 +1,1107 @@
+// The HLE kernel proper: interrupt handlers, heap setup and the SIF calls games need to get
+// going, with threads and semaphores left to `thread::Scheduler` and the IOP end of the SIF to
+// `sif::Iop`. Every call is logged by name.
+//
+// SetupThread puts a SYSCALL at the interrupt vector and unmasks IP2/IP3, and EnableIntc and
+// EnableDmac unmask the cause in INTC_MASK or D_STAT as well as in the kernel's own masks. When
+// an interrupt is taken the kernel acknowledges the raised causes and calls each enabled
+// handler as func(cause, arg, epc) on a stack of its own, with the gp it was registered with.
+// A handler returning 0 ends the chain for its cause. Handlers return into another SYSCALL,
+// after the last one the interrupted registers come back and an ERET resumes the program.
+//
+// Errors are reported the way the EE kernel does, with -1 in v0. Calls the kernel doesn't know
+// are logged and return 0 rather than trapping, since there is no BIOS exception handler behind
+// a direct ELF boot.
+
+use std::collections::{HashMap, VecDeque};
+
+use super::hostfs::HostFs;
+use super::sif::Iop;
+use super::syscalls::*;
+use super::thread::{CONTEXT_FRAME_SIZE, MAIN_THREAD, Scheduler, ThreadContext, resume_at};
+use super::{SyscallHandler, SyscallOutcome};
+use crate::bus::Bus;
+use crate::dmac::{
+    self, CHCR, CHCR_MOD_SHIFT, CHCR_STR, CHCR_TIE, D_CTRL, D_CTRL_DMAE, MODE_CHAIN, QWC,
+};
+use crate::elf::{ARGS_BASE, MAX_ARGS};
+use crate::intc::{D_STAT, DMA_INT_BEIS, INTC_MASK, INTC_STAT};
+use crate::ps2_bus::{RAM_SIZE, SIO_TXFIFO};
+use crate::savestate::{Snapshot, StateError, StateReader, StateWriter};
+use crate::{Cpu, cop0};
+
+const ARGS_SIZE: u32 = 4 + 4 * MAX_ARGS as u32 + 256;
+// Deci2Call function that prints the string its parameter block points to
+const DECI2_KPUTS: u32 = 0x10;
+// Longest string printed in one call, in case a pointer runs into garbage
+const MAX_PRINT: u32 = 4096;
+
+// Where the CPU goes on an interrupt, and where handlers return to, past the scheduler's stubs.
+// Each is a SYSCALL the kernel knows by its address, followed by the ERET ending the interrupt.
+pub const INTERRUPT_VECTOR: u32 = 0x8000_0200;
+pub const HANDLER_RETURN: u32 = 0x8000_1010;
+const INTERRUPT_STUB: [u32; 2] = [
+    0x0000_000C, // syscall
+    0x4200_0018, // eret
+];
+// Top of the stack handlers run on
+pub const INTERRUPT_STACK: u32 = 0x8000_4000;
+
+const GPR_V0: usize = 2;
+const GPR_A0: usize = 4;
+const GPR_GP: usize = 28;
+const GPR_SP: usize = 29;
+const GPR_RA: usize = 31;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Handler {
+    pub id: u32,
+    pub cause: u32,
+    pub func: u32,
+    pub arg: u32,
+    // The caller's gp when the handler was added
+    pub gp: u32,
+    pub enabled: bool,
+}
+
+// An interrupt being serviced: the registers of whatever it interrupted and the handlers still
+// to call, the one running first. `true` marks a DMAC handler.
+struct Interrupt {
+    context: ThreadContext,
+    handlers: VecDeque<(bool, Handler)>,
+}
+
+pub struct Kernel {
+    scheduler: Scheduler,
+    intc_handlers: Vec<Handler>,
+    dmac_handlers: Vec<Handler>,
+    next_handler_id: u32,
+    intc_mask: u32,
+    dmac_mask: u32,
+    heap_end: u32,
+    gs_crt: (u32, u32, u32),
+    gs_imr: u32,
+    sif_regs: HashMap<u32, u32>,
+    sif_transfer_id: u32,
+    iop: Iop,
+    interrupt: Option<Interrupt>,
+    log: Box<dyn FnMut(&str)>,
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read32(bus: &mut dyn Bus, addr: u32) -> Option<u32> {
+    bus.read32(addr).ok()
+}
+
+fn write32(bus: &mut dyn Bus, addr: u32, value: u32) -> Option<()> {
+    bus.write32(addr, value).ok()
+}
+
+fn write_words(bus: &mut dyn Bus, addr: u32, words: &[u32]) -> Option<()> {
+    for (index, &word) in words.iter().enumerate() {
+        write32(bus, addr.wrapping_add(4 * index as u32), word)?;
+    }
+    Some(())
+}
+
+fn save_handler(w: &mut StateWriter, handler: &Handler) {
+    w.write_u32(handler.id);
+    w.write_u32(handler.cause);
+    w.write_u32(handler.func);
+    w.write_u32(handler.arg);
+    w.write_u32(handler.gp);
+    w.write_bool(handler.enabled);
+}
+
+fn load_handler(r: &mut StateReader) -> Result<Handler, StateError> {
+    Ok(Handler {
+        id: r.read_u32()?,
+        cause: r.read_u32()?,
+        func: r.read_u32()?,
+        arg: r.read_u32()?,
+        gp: r.read_u32()?,
+        enabled: r.read_bool()?,
+    })
+}
+
+fn save_handlers(w: &mut StateWriter, handlers: &[Handler]) {
+    w.write_u32(handlers.len() as u32);
+    for handler in handlers {
+        save_handler(w, handler);
+    }
+}
+
+fn load_handlers(r: &mut StateReader) -> Result<Vec<Handler>, StateError> {
+    let len = r.read_u32()?;
+    (0..len).map(|_| load_handler(r)).collect()
+}
+
+fn save_interrupt(w: &mut StateWriter, interrupt: &Option<Interrupt>) {
+    w.write_bool(interrupt.is_some());
+    let Some(interrupt) = interrupt else {
+        return;
+    };
+    let context = &interrupt.context;
+    for &gpr in &context.gprs {
+        w.write_u128(gpr);
+    }
+    w.write_u64(context.hi);
+    w.write_u64(context.lo);
+    w.write_u64(context.sa);
+    w.write_u32(context.pc);
+    w.write_u32(interrupt.handlers.len() as u32);
+    for (dmac, handler) in &interrupt.handlers {
+        w.write_bool(*dmac);
+        save_handler(w, handler);
+    }
+}
+
+fn load_interrupt(r: &mut StateReader) -> Result<Option<Interrupt>, StateError> {
+    if !r.read_bool()? {
+        return Ok(None);
+    }
+    let mut gprs = [0; 32];
+    for gpr in gprs.iter_mut() {
+        *gpr = r.read_u128()?;
+    }
+    let context = ThreadContext {
+        gprs,
+        hi: r.read_u64()?,
+        lo: r.read_u64()?,
+        sa: r.read_u64()?,
+        pc: r.read_u32()?,
+    };
+    let len = r.read_u32()?;
+    let handlers = (0..len)
+        .map(|_| Ok((r.read_bool()?, load_handler(r)?)))
+        .collect::<Result<_, StateError>>()?;
+    Ok(Some(Interrupt { context, handlers }))
+}
+
+impl Kernel {
+    // Starts with the main thread running at priority 0, logging to stderr
+    pub fn new() -> Self {
+        Kernel {
+            scheduler: Scheduler::new(),
+            intc_handlers: Vec::new(),
+            dmac_handlers: Vec::new(),
+            next_handler_id: 1,
+            intc_mask: 0,
+            dmac_mask: 0,
+            heap_end: 0,
+            gs_crt: (0, 0, 0),
+            gs_imr: 0,
+            sif_regs: HashMap::from(Iop::registers()),
+            sif_transfer_id: 0,
+            iop: Iop::new(),
+            interrupt: None,
+            log: Box::new(|line| eprintln!("{}", line)),
+        }
+    }
+
+    // Replaces the default stderr logging
+    pub fn set_logger(&mut self, log: impl FnMut(&str) + 'static) {
+        self.log = Box::new(log);
+    }
+
+    // The directory behind `host:`, for fileio RPCs
+    pub fn set_host_fs(&mut self, host_fs: HostFs) {
+        self.iop.set_host_fs(host_fs);
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn intc_handlers(&self) -> &[Handler] {
+        &self.intc_handlers
+    }
+
+    pub fn dmac_handlers(&self) -> &[Handler] {
+        &self.dmac_handlers
+    }
+
+    // INTC/DMAC causes enabled with EnableIntc/EnableDmac, one bit per cause
+    pub fn intc_mask(&self) -> u32 {
+        self.intc_mask
+    }
+
+    pub fn dmac_mask(&self) -> u32 {
+        self.dmac_mask
+    }
+
+    // (interlace, mode, field/frame) from the last SetGsCrt
+    pub fn gs_crt(&self) -> (u32, u32, u32) {
+        self.gs_crt
+    }
+
+    fn add_handler(
+        &mut self,
+        dmac: bool,
+        cause: u32,
+        func: u32,
+        next: i32,
+        arg: u32,
+        gp: u32,
+    ) -> i32 {
+        let id = self.next_handler_id;
+        self.next_handler_id += 1;
+        let handler = Handler {
+            id,
+            cause,
+            func,
+            arg,
+            gp,
+            enabled: true,
+        };
+        let handlers = if dmac {
+            &mut self.dmac_handlers
+        } else {
+            &mut self.intc_handlers
+        };
+        // `next` 0 puts the handler in front of the others for the cause, -1 behind them
+        if next == 0 {
+            handlers.insert(0, handler);
+        } else {
+            handlers.push(handler);
+        }
+        id as i32
+    }
+
+    fn remove_handler(&mut self, dmac: bool, cause: u32, id: u32) -> i32 {
+        let handlers = if dmac {
+            &mut self.dmac_handlers
+        } else {
+            &mut self.intc_handlers
+        };
+        let count = handlers.len();
+        handlers.retain(|handler| !(handler.cause == cause && handler.id == id));
+        if handlers.len() == count { -1 } else { 0 }
+    }
+
+    // Returns 1 when the cause's state changed, like the kernel does. The hardware mask bits
+    // toggle when written, so they are only written on a change.
+    fn set_mask(&mut self, bus: &mut dyn Bus, dmac: bool, cause: u32, enable: bool) -> i32 {
+        let (mask, causes) = if dmac {
+            (&mut self.dmac_mask, 16)
+        } else {
+            (&mut self.intc_mask, 32)
+        };
+        if cause >= causes {
+            return 0;
+        }
+        let old = *mask;
+        if enable {
+            *mask |= 1 << cause;
+        } else {
+            *mask &= !(1 << cause);
+        }
+        if old == *mask {
+            return 0;
+        }
+        let _ = if dmac {
+            write32(bus, D_STAT, 1 << (16 + cause))
+        } else {
+            write32(bus, INTC_MASK, 1 << cause)
+        };
+        1
+    }
+
+    // SetupThread(gp, stack, stack_size, args, root): returns the main thread's initial sp. A
+    // stack of -1 means "at the top of RAM". The program arguments left by the ELF loader are
+    // copied into `args`. The DMAC and interrupts are enabled from here on, as the EE kernel
+    // starts programs. A stack that doesn't fit the address space, or an `args` block that
+    // would run off its end, fails with -1 before anything is set up.
+    fn setup_thread(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> i32 {
+        let gp = cpu.gpr(4) as u32;
+        let stack = cpu.gpr(5) as u32;
+        let stack_size = cpu.gpr(6) as u32;
+        let args = cpu.gpr(7) as u32;
+        let base = if stack == u32::MAX {
+            (RAM_SIZE as u32).checked_sub(stack_size)
+        } else {
+            Some(stack)
+        };
+        let Some((base, sp)) = base.and_then(|base| {
+            let sp = base
+                .checked_add(stack_size)?
+                .checked_sub(CONTEXT_FRAME_SIZE)?;
+            Some((base, sp))
+        }) else {
+            return -1;
+        };
+        if args.checked_add(ARGS_SIZE).is_none() {
+            return -1;
+        }
+        if let Some(main) = self.scheduler.thread_mut(MAIN_THREAD) {
+            main.gp = gp;
+            main.stack = base;
+            main.stack_size = stack_size;
+        }
+        if args != 0 {
+            self.copy_args(bus, args);
+        }
+        let _ = write_words(bus, INTERRUPT_VECTOR, &INTERRUPT_STUB);
+        let _ = write_words(bus, HANDLER_RETURN, &INTERRUPT_STUB);
+        let _ = read32(bus, D_CTRL).and_then(|ctrl| write32(bus, D_CTRL, ctrl | D_CTRL_DMAE));
+        let enabled = cop0::STATUS_IE | cop0::STATUS_EIE | cop0::STATUS_IM2 | cop0::STATUS_IM3;
+        cpu.set_status(cpu.cop0.status() | enabled);
+        sp as i32
+    }
+
+    // `dest` has been checked to leave room for the whole block
+    fn copy_args(&mut self, bus: &mut dyn Bus, dest: u32) -> Option<()> {
+        for offset in (0..ARGS_SIZE).step_by(4) {
+            let value = read32(bus, ARGS_BASE + offset)?;
+            // argv entries point into the buffer and move with it. A pointer that doesn't is
+            // moved just the same; it was garbage to begin with.
+            let is_pointer = (4..4 + 4 * MAX_ARGS as u32).contains(&offset) && value != 0;
+            let value = if is_pointer {
+                value.wrapping_sub(ARGS_BASE).wrapping_add(dest)
+            } else {
+                value
+            };
+            write32(bus, dest + offset, value)?;
+        }
+        Some(())
+    }
+
+    // The text goes out through the SIO like the rest of the guest's debug output, so it ends up
+    // on the console in order with it
+    fn print(&mut self, bus: &mut dyn Bus, addr: u32) -> i32 {
+        let mut len = 0;
+        while len < MAX_PRINT {
+            match bus.read8(addr.wrapping_add(len)) {
+                Ok(0) | Err(_) => break,
+                Ok(byte) => {
+                    let _ = bus.write8(SIO_TXFIFO, byte);
+                }
+            }
+            len += 1;
+        }
+        len as i32
+    }
+
+    // Deci2Call(function, param): only kputs, whose parameter block starts with the string's
+    // address, is supported; the DECI2 protocol itself needs a debugger on the other end
+    fn deci2_call(&mut self, bus: &mut dyn Bus, function: u32, param: u32) -> i32 {
+        if function != DECI2_KPUTS {
+            return -1;
+        }
+        match read32(bus, param) {
+            Some(addr) => self.print(bus, addr),
+            None => -1,
+        }
+    }
+
+    // SetupHeap(start, size): a size of -1 extends the heap up to the main thread's stack
+    fn setup_heap(&mut self, start: u32, size: u32) -> i32 {
+        self.heap_end = if size == u32::MAX {
+            self.scheduler
+                .thread(MAIN_THREAD)
+                .map(|main| main.stack)
+                .filter(|&stack| stack != 0)
+                .unwrap_or(RAM_SIZE as u32)
+        } else {
+            start.wrapping_add(size)
+        };
+        self.heap_end as i32
+    }
+
+    // The SYSCALL at the interrupt vector. Acknowledges every cause that is raised and unmasked,
+    // then calls the first of their handlers. With none to call the ERET after the SYSCALL
+    // returns at once.
+    fn enter_interrupt(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> SyscallOutcome {
+        let intc = read32(bus, INTC_STAT).unwrap_or(0) & read32(bus, INTC_MASK).unwrap_or(0);
+        if intc != 0 {
+            let _ = write32(bus, INTC_STAT, intc);
+        }
+        // A bus error interrupts whatever the mask says
+        let d_stat = read32(bus, D_STAT).unwrap_or(0);
+        let dmac = d_stat & 0xFFFF & (d_stat >> 16 | 1 << DMA_INT_BEIS);
+        if dmac != 0 {
+            let _ = write32(bus, D_STAT, dmac);
+        }
+
+        let mut handlers = VecDeque::new();
+        for (is_dmac, raised, mask, list) in [
+            (false, intc, self.intc_mask, &self.intc_handlers),
+            (true, dmac, self.dmac_mask, &self.dmac_handlers),
+        ] {
+            for cause in (0..32).filter(|cause| raised & mask & 1 << cause != 0) {
+                handlers.extend(
+                    list.iter()
+                        .filter(|handler| handler.cause == cause && handler.enabled)
+                        .map(|&handler| (is_dmac, handler)),
+                );
+            }
+        }
+        if handlers.is_empty() {
+            return SyscallOutcome::Handled;
+        }
+        self.interrupt = Some(Interrupt {
+            context: ThreadContext {
+                gprs: cpu.gprs,
+                hi: cpu.hi0,
+                lo: cpu.lo0,
+                sa: cpu.sa,
+                pc: cpu.cop0.read(cop0::EPC),
+            },
+            handlers,
+        });
+        self.call_handler(cpu);
+        SyscallOutcome::Handled
+    }
+
+    fn call_handler(&mut self, cpu: &mut Cpu) {
+        let Some(interrupt) = &self.interrupt else {
+            return;
+        };
+        let Some(&(dmac, handler)) = interrupt.handlers.front() else {
+            return;
+        };
+        (self.log)(&format!(
+            "hle: calling {} handler {} for cause {}",
+            if dmac { "DMAC" } else { "INTC" },
+            handler.id,
+            handler.cause
+        ));
+        cpu.write_gpr_dword(GPR_A0, handler.cause as u64);
+        cpu.write_gpr_dword(GPR_A0 + 1, handler.arg as u64);
+        cpu.write_gpr_dword(GPR_A0 + 2, interrupt.context.pc as i32 as u64);
+        cpu.write_gpr_dword(GPR_GP, handler.gp as i32 as u64);
+        cpu.write_gpr_dword(GPR_SP, INTERRUPT_STACK as i32 as u64);
+        cpu.write_gpr_dword(GPR_RA, HANDLER_RETURN as i32 as u64);
+        resume_at(cpu, handler.func);
+    }
+
+    // The SYSCALL handlers return to. Calls the next handler, or puts the interrupted registers
+    // back for the ERET after the SYSCALL. A handler that readied a thread while the CPU idled
+    // has the ERET go to that thread; a running thread carries on until its next call.
+    fn leave_handler(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> SyscallOutcome {
+        let Some(interrupt) = self.interrupt.as_mut() else {
+            return SyscallOutcome::Handled;
+        };
+        let result = cpu.gpr(GPR_V0) as u32;
+        if let Some((dmac, handler)) = interrupt.handlers.pop_front()
+            && result == 0
+        {
+            interrupt
+                .handlers
+                .retain(|&(next_dmac, next)| (next_dmac, next.cause) != (dmac, handler.cause));
+        }
+        if !interrupt.handlers.is_empty() {
+            self.call_handler(cpu);
+            return SyscallOutcome::Handled;
+        }
+
+        let Some(interrupt) = self.interrupt.take() else {
+            return SyscallOutcome::Handled;
+        };
+        let context = interrupt.context;
+        cpu.gprs = context.gprs;
+        cpu.hi0 = context.hi;
+        cpu.lo0 = context.lo;
+        cpu.sa = context.sa;
+        if self.scheduler.current().is_none()
+            && let Some(id) = self.scheduler.reschedule(cpu, bus)
+        {
+            (self.log)(&format!("hle: switching to thread {}", id));
+            cpu.cop0.set(cop0::EPC, cpu.next_pc);
+            resume_at(cpu, HANDLER_RETURN + 4);
+        }
+        SyscallOutcome::Handled
+    }
+
+    // `None` for calls the HLE kernel doesn't implement
+    fn dispatch(
+        &mut self,
+        cpu: &mut Cpu,
+        bus: &mut dyn Bus,
+        number: u32,
+    ) -> Option<SyscallOutcome> {
+        let arg = |index: usize| cpu.gpr(GPR_A0 + index) as u32;
+        let (a0, a1, a2, a3) = (arg(0), arg(1), arg(2), arg(3));
+        let gp = cpu.gpr(GPR_GP) as u32;
+        let result = match number {
+            EXIT => return Some(SyscallOutcome::Exit(a0 as i32)),
+            FLUSH_CACHE | I_FLUSH_CACHE => 0,
+            SET_GS_CRT => {
+                self.gs_crt = (a0, a1, a2);
+                0
+            }
+            GS_GET_IMR => self.gs_imr as i32,
+            GS_PUT_IMR => {
+                let old = self.gs_imr;
+                self.gs_imr = a0;
+                old as i32
+            }
+            CREATE_THREAD => self.scheduler.create_thread(bus, a0),
+            DELETE_THREAD => self.scheduler.delete_thread(a0),
+            START_THREAD => self.scheduler.start_thread(bus, a0, a1),
+            EXIT_THREAD => self.scheduler.exit_thread(false),
+            EXIT_DELETE_THREAD => self.scheduler.exit_thread(true),
+            TERMINATE_THREAD | I_TERMINATE_THREAD => self.scheduler.terminate_thread(a0),
+            CHANGE_THREAD_PRIORITY | I_CHANGE_THREAD_PRIORITY => {
+                self.scheduler.change_thread_priority(a0, a1)
+            }
+            ROTATE_THREAD_READY_QUEUE => self.scheduler.rotate_thread_ready_queue(a0, false),
+            I_ROTATE_THREAD_READY_QUEUE => self.scheduler.rotate_thread_ready_queue(a0, true),
+            RELEASE_WAIT_THREAD | I_RELEASE_WAIT_THREAD => self.scheduler.release_wait_thread(a0),
+            GET_THREAD_ID => self.scheduler.current().unwrap_or(0) as i32,
+            REFER_THREAD_STATUS | I_REFER_THREAD_STATUS => {
+                self.scheduler.refer_thread_status(bus, a0, a1)
+            }
+            SLEEP_THREAD => self.scheduler.sleep_thread(),
+            WAKEUP_THREAD | I_WAKEUP_THREAD => self.scheduler.wakeup_thread(a0),
+            CANCEL_WAKEUP_THREAD | I_CANCEL_WAKEUP_THREAD => {
+                self.scheduler.cancel_wakeup_thread(a0)
+            }
+            SUSPEND_THREAD | I_SUSPEND_THREAD => self.scheduler.suspend_thread(a0),
+            RESUME_THREAD | I_RESUME_THREAD => self.scheduler.resume_thread(a0),
+            CREATE_SEMA => self.scheduler.create_sema(bus, a0),
+            DELETE_SEMA => self.scheduler.delete_sema(a0),
+            SIGNAL_SEMA | I_SIGNAL_SEMA => self.scheduler.signal_sema(a0),
+            WAIT_SEMA => self.scheduler.wait_sema(a0),
+            POLL_SEMA | I_POLL_SEMA => self.scheduler.poll_sema(a0),
+            REFER_SEMA_STATUS | I_REFER_SEMA_STATUS => {
+                self.scheduler.refer_sema_status(bus, a0, a1)
+            }
+            ADD_INTC_HANDLER => self.add_handler(false, a0, a1, a2 as i32, a3, gp),
+            REMOVE_INTC_HANDLER => self.remove_handler(false, a0, a1),
+            ADD_DMAC_HANDLER => self.add_handler(true, a0, a1, a2 as i32, a3, gp),
+            REMOVE_DMAC_HANDLER => self.remove_handler(true, a0, a1),
+            ENABLE_INTC | I_ENABLE_INTC => self.set_mask(bus, false, a0, true),
+            DISABLE_INTC | I_DISABLE_INTC => self.set_mask(bus, false, a0, false),
+            ENABLE_DMAC | I_ENABLE_DMAC => self.set_mask(bus, true, a0, true),
+            DISABLE_DMAC | I_DISABLE_DMAC => self.set_mask(bus, true, a0, false),
+            SETUP_THREAD => self.setup_thread(cpu, bus),
+            SETUP_HEAP => self.setup_heap(a0, a1),
+            END_OF_HEAP => self.heap_end as i32,
+            GET_MEMORY_SIZE => RAM_SIZE as i32,
+            MACHINE_TYPE => 0,
+            DECI2_CALL => self.deci2_call(bus, a0, a1),
+            // The format string is printed as is, the arguments aren't substituted
+            PRINT => self.print(bus, a0),
+            // Transfers to the IOP stand-in complete immediately
+            SIF_SET_DMA => {
+                let _ = self.iop.set_dma(bus, a0, a1);
+                self.sif_transfer_id = self.sif_transfer_id.wrapping_add(1).max(1);
+                self.sif_transfer_id as i32
+            }
+            SIF_DMA_STAT => -1,
+            // Starts SIF0 on a destination chain, the IOP's tags saying where packets go
+            SIF_SET_DCHAIN => {
+                let base = dmac::CHANNEL_BASES[dmac::SIF0];
+                let chcr = MODE_CHAIN << CHCR_MOD_SHIFT | CHCR_TIE | CHCR_STR;
+                let _ = write32(bus, base + QWC, 0).and_then(|()| write32(bus, base + CHCR, chcr));
+                0
+            }
+            SIF_SET_REG => {
+                let old = self.sif_regs.insert(a0, a1).unwrap_or(0);
+                old as i32
+            }
+            SIF_GET_REG => self.sif_regs.get(&a0).copied().unwrap_or(0) as i32,
+            _ => return None,
+        };
+        cpu.write_gpr_dword(GPR_V0, result as i64 as u64);
+        Some(SyscallOutcome::Handled)
+    }
+}
+
+impl SyscallHandler for Kernel {
+    // The logger is host-side and stays as it is
+    fn save_state(&self, w: &mut StateWriter) {
+        self.scheduler.save_state(w);
+        save_handlers(w, &self.intc_handlers);
+        save_handlers(w, &self.dmac_handlers);
+        w.write_u32(self.next_handler_id);
+        w.write_u32(self.intc_mask);
+        w.write_u32(self.dmac_mask);
+        w.write_u32(self.heap_end);
+        w.write_u32(self.gs_crt.0);
+        w.write_u32(self.gs_crt.1);
+        w.write_u32(self.gs_crt.2);
+        w.write_u32(self.gs_imr);
+        // Sorted so the same machine always saves the same bytes
+        let mut sif_regs: Vec<_> = self.sif_regs.iter().collect();
+        sif_regs.sort();
+        w.write_u32(sif_regs.len() as u32);
+        for (&reg, &value) in sif_regs {
+            w.write_u32(reg);
+            w.write_u32(value);
+        }
+        w.write_u32(self.sif_transfer_id);
+        self.iop.save_state(w);
+        save_interrupt(w, &self.interrupt);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let mut scheduler = Scheduler::new();
+        scheduler.load_state(r)?;
+        let intc_handlers = load_handlers(r)?;
+        let dmac_handlers = load_handlers(r)?;
+        let next_handler_id = r.read_u32()?;
+        let intc_mask = r.read_u32()?;
+        let dmac_mask = r.read_u32()?;
+        let heap_end = r.read_u32()?;
+        let gs_crt = (r.read_u32()?, r.read_u32()?, r.read_u32()?);
+        let gs_imr = r.read_u32()?;
+        let sif_reg_count = r.read_u32()?;
+        let sif_regs = (0..sif_reg_count)
+            .map(|_| Ok((r.read_u32()?, r.read_u32()?)))
+            .collect::<Result<HashMap<_, _>, StateError>>()?;
+        let sif_transfer_id = r.read_u32()?;
+        let mut iop = Iop::new();
+        iop.load_state(r)?;
+        let interrupt = load_interrupt(r)?;
+
+        self.scheduler = scheduler;
+        self.intc_handlers = intc_handlers;
+        self.dmac_handlers = dmac_handlers;
+        self.next_handler_id = next_handler_id;
+        self.intc_mask = intc_mask;
+        self.dmac_mask = dmac_mask;
+        self.heap_end = heap_end;
+        self.gs_crt = gs_crt;
+        self.gs_imr = gs_imr;
+        self.sif_regs = sif_regs;
+        self.sif_transfer_id = sif_transfer_id;
+        // The host directory stays
+        iop.host_fs = self.iop.host_fs.take();
+        self.iop = iop;
+        self.interrupt = interrupt;
+        Ok(())
+    }
+
+    fn syscall(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> SyscallOutcome {
+        match cpu.pc() {
+            INTERRUPT_VECTOR => return self.enter_interrupt(cpu, bus),
+            HANDLER_RETURN => return self.leave_handler(cpu, bus),
+            _ => {}
+        }
+        let raw_number = cpu.gpr(3) as u32 as i32;
+        let number = raw_number.unsigned_abs();
+        let args: Vec<u32> = (4..8).map(|index| cpu.gpr(index) as u32).collect();
+        let name = match name(number) {
+            Some(name) => name.to_string(),
+            None => format!("syscall_{:x}", number),
+        };
+        let call = format!(
+            "hle: {}({:#x}, {:#x}, {:#x}, {:#x})",
+            name, args[0], args[1], args[2], args[3]
+        );
+        match self.dispatch(cpu, bus, number) {
+            Some(SyscallOutcome::Exit(status)) => {
+                (self.log)(&format!("hle: {}({})", name, status));
+                SyscallOutcome::Exit(status)
+            }
+            Some(outcome) => {
+                (self.log)(&format!("{} = {:#x}", call, cpu.gpr(2) as u32));
+                // Only now, as a switch replaces v0 with the incoming thread's. Interrupt handlers
+                // never switch, their dispatch waits for the next ordinary call.
+                let switched = if is_interrupt_variant(number) {
+                    None
+                } else {
+                    self.scheduler.reschedule(cpu, bus)
+                };
+                match switched {
+                    Some(0) => (self.log)("hle: no thread is ready, idling"),
+                    Some(id) => (self.log)(&format!("hle: switching to thread {}", id)),
+                    None => {}
+                }
+                outcome
+            }
+            None => {
+                (self.log)(&format!("{} not implemented, returning 0", call));
+                cpu.write_gpr_dword(2, 0);
+                SyscallOutcome::Handled
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::hle::thread::IDLE_STUB;
+    use crate::intc::{INT_TIM0, INT_TIM1};
+    use crate::ps2_bus::Ps2Bus;
+
+    const MAIN_CODE: u32 = 0x10_0000;
+    const HANDLER_CONTINUE: u32 = 0x11_0000;
+    const HANDLER_STOP: u32 = 0x11_0100;
+    const HANDLER_WAKEUP: u32 = 0x11_0200;
+    const RESULTS: u32 = 0x12_0000;
+    const HANDLER_GP: u32 = 0x0013_8000;
+
+    fn machine() -> (Cpu, Ps2Bus, Rc<RefCell<Vec<String>>>) {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let mut kernel = Kernel::new();
+        let log = lines.clone();
+        kernel.set_logger(move |line| log.borrow_mut().push(line.to_string()));
+        let mut cpu = Cpu::new();
+        cpu.set_syscall_handler(Box::new(kernel));
+        cpu.set_pc(MAIN_CODE);
+        let mut bus = Ps2Bus::new();
+        // Handlers store the cause (and gp) where their argument points and say whether the
+        // chain goes on
+        let store = [0xACA4_0000, 0xACBC_0004, 0x03E0_0008]; // sw a0, 0(a1); sw gp, 4(a1); jr ra
+        write_words(&mut bus, HANDLER_CONTINUE, &store).unwrap();
+        write32(&mut bus, HANDLER_CONTINUE + 12, 0x2402_FFFF).unwrap(); // addiu v0, zero, -1
+        write_words(&mut bus, HANDLER_STOP, &store).unwrap();
+        write32(&mut bus, HANDLER_STOP + 12, 0x2402_0000).unwrap(); // addiu v0, zero, 0
+        write_words(
+            &mut bus,
+            HANDLER_WAKEUP,
+            &[
+                0x2404_0001,                   // addiu a0, zero, MAIN_THREAD
+                0x2403_0000 | I_WAKEUP_THREAD, // addiu v1, zero, iWakeupThread
+                0x0000_000C,                   // syscall
+                0x03E0_0008,                   // jr ra
+                0x2402_0000,                   // addiu v0, zero, 0
+            ],
+        )
+        .unwrap();
+        (cpu, bus, lines)
+    }
+
+    // Runs a SYSCALL wherever the CPU is and returns v0
+    fn call(cpu: &mut Cpu, bus: &mut Ps2Bus, number: u32, args: &[u32]) -> i32 {
+        write32(bus, cpu.pc(), 0x0000_000C).unwrap();
+        cpu.gprs[3] = number as u128;
+        for (index, &arg) in args.iter().enumerate() {
+            cpu.gprs[4 + index] = arg as u128;
+        }
+        cpu.step(bus);
+        cpu.gpr(2) as i32
+    }
+
+    // Steps until the CPU is back at `pc` with the interrupt over
+    fn run_to(cpu: &mut Cpu, bus: &mut Ps2Bus, pc: u32) {
+        for _ in 0..100 {
+            cpu.step_timed(bus);
+            if cpu.pc() == pc && cpu.cop0().status() & cop0::STATUS_EXL == 0 {
+                return;
+            }
+        }
+        panic!("never got back to {:#x}, at {:#x}", pc, cpu.pc());
+    }
+
+    fn result(bus: &mut Ps2Bus, offset: u32) -> u32 {
+        read32(bus, RESULTS + offset).unwrap()
+    }
+
+    #[test]
+    fn setup_thread_places_the_main_stack_and_enables_interrupts() {
+        let (mut cpu, mut bus, _) = machine();
+        let sp = call(
+            &mut cpu,
+            &mut bus,
+            SETUP_THREAD,
+            &[0x1234, u32::MAX, 0x1000, 0],
+        );
+        assert_eq!(sp as u32, RAM_SIZE as u32 - CONTEXT_FRAME_SIZE);
+        let status = cpu.cop0().status();
+        assert_eq!(status & cop0::STATUS_IE, cop0::STATUS_IE);
+        assert_eq!(status & cop0::STATUS_EIE, cop0::STATUS_EIE);
+        assert_eq!(status & 0xFF00, cop0::STATUS_IM2 | cop0::STATUS_IM3);
+        assert_eq!(read32(&mut bus, INTERRUPT_VECTOR), Some(0x0000_000C));
+
+        // An explicit stack is used as given
+        let sp = call(&mut cpu, &mut bus, SETUP_THREAD, &[0, 0x8_0000, 0x4000, 0]);
+        assert_eq!(sp as u32, 0x8_4000 - CONTEXT_FRAME_SIZE);
+    }
+
+    #[test]
+    fn setup_thread_refuses_stacks_and_args_outside_the_address_space() {
+        let (mut cpu, mut bus, _) = machine();
+        for (stack, size, args) in [
+            // Bigger than RAM, at the top of it
+            (u32::MAX, RAM_SIZE as u32 + 0x1000, 0),
+            // No room for the initial context below the top of the address space
+            (0, 0, 0),
+            // Running past the end of the address space
+            (0xFFFF_F000, 0x2000, 0),
+            (0x8_0000, 0x4000, 0xFFFF_FF00),
+        ] {
+            assert_eq!(
+                call(&mut cpu, &mut bus, SETUP_THREAD, &[0, stack, size, args]),
+                -1,
+                "stack {stack:#x} size {size:#x} args {args:#x}"
+            );
+        }
+        // Nothing was set up along the way
+        assert_eq!(cpu.cop0().status() & cop0::STATUS_IE, 0);
+        assert_eq!(read32(&mut bus, INTERRUPT_VECTOR), Some(0));
+
+        // A bad argv pointer is carried along rather than trusted
+        write32(&mut bus, ARGS_BASE, 1).unwrap();
+        write32(&mut bus, ARGS_BASE + 4, 0xFFFF_FFF0).unwrap();
+        let sp = call(
+            &mut cpu,
+            &mut bus,
+            SETUP_THREAD,
+            &[0, 0x8_0000, 0x4000, 0x1_0000],
+        );
+        assert_eq!(sp as u32, 0x8_4000 - CONTEXT_FRAME_SIZE);
+        let moved = 0xFFFF_FFF0u32
+            .wrapping_sub(ARGS_BASE)
+            .wrapping_add(0x1_0000);
+        assert_eq!(read32(&mut bus, 0x1_0004), Some(moved));
+    }
+
+    #[test]
+    fn heap_ends_at_the_main_stack_or_after_its_size() {
+        let (mut cpu, mut bus, _) = machine();
+        call(
+            &mut cpu,
+            &mut bus,
+            SETUP_THREAD,
+            &[0, u32::MAX, 0x1_0000, 0],
+        );
+        let stack = RAM_SIZE as u32 - 0x1_0000;
+        assert_eq!(
+            call(&mut cpu, &mut bus, SETUP_HEAP, &[0x20_0000, u32::MAX]),
+            stack as i32
+        );
+        assert_eq!(call(&mut cpu, &mut bus, END_OF_HEAP, &[]), stack as i32);
+        assert_eq!(
+            call(&mut cpu, &mut bus, SETUP_HEAP, &[0x20_0000, 0x1000]),
+            0x20_1000
+        );
+        assert_eq!(call(&mut cpu, &mut bus, END_OF_HEAP, &[]), 0x20_1000);
+    }
+
+    #[test]
+    fn setup_thread_moves_argv_with_the_arguments() {
+        let (mut cpu, mut bus, _) = machine();
+        // What the ELF loader leaves: argc, argv and the strings after it
+        let strings = ARGS_BASE + 4 + 4 * MAX_ARGS as u32;
+        write_words(&mut bus, ARGS_BASE, &[2, strings, strings + 14]).unwrap();
+        for (offset, &byte) in b"host:game.elf\0-x\0".iter().enumerate() {
+            bus.write8(strings + offset as u32, byte).unwrap();
+        }
+        let dest = 0x18_0000;
+        call(
+            &mut cpu,
+            &mut bus,
+            SETUP_THREAD,
+            &[0, u32::MAX, 0x1000, dest],
+        );
+
+        assert_eq!(read32(&mut bus, dest), Some(2));
+        let argv0 = read32(&mut bus, dest + 4).unwrap();
+        let argv1 = read32(&mut bus, dest + 8).unwrap();
+        assert_eq!(argv0, strings - ARGS_BASE + dest);
+        assert_eq!(argv1, argv0 + 14);
+        assert_eq!(read32(&mut bus, dest + 12), Some(0));
+        let text = |bus: &mut Ps2Bus, addr: u32, len: usize| -> Vec<u8> {
+            (0..len as u32)
+                .map(|offset| bus.read8(addr + offset).unwrap())
+                .collect()
+        };
+        assert_eq!(text(&mut bus, argv0, 14), b"host:game.elf\0");
+        assert_eq!(text(&mut bus, argv1, 3), b"-x\0");
+    }
+
+    #[test]
+    fn calls_are_logged_by_name() {
+        let (mut cpu, mut bus, lines) = machine();
+        call(&mut cpu, &mut bus, GET_MEMORY_SIZE, &[1, 2, 3, 4]);
+        // Known by name but not implemented, and not known at all
+        call(&mut cpu, &mut bus, 0x0C, &[]);
+        call(&mut cpu, &mut bus, 0x08, &[]);
+        // An i-variant is numbered negatively
+        call(&mut cpu, &mut bus, -(I_WAKEUP_THREAD as i32) as u32, &[5]);
+        call(&mut cpu, &mut bus, EXIT, &[3]);
+        assert_eq!(
+            *lines.borrow(),
+            [
+                "hle: GetMemorySize(0x1, 0x2, 0x3, 0x4) = 0x2000000",
+                "hle: Interrupt2Iop(0x1, 0x2, 0x3, 0x4) not implemented, returning 0",
+                "hle: syscall_8(0x1, 0x2, 0x3, 0x4) not implemented, returning 0",
+                "hle: iWakeupThread(0x5, 0x2, 0x3, 0x4) = 0xffffffff",
+                "hle: Exit(3)",
+            ]
+        );
+    }
+
+    #[test]
+    fn failures_return_minus_one() {
+        let (mut cpu, mut bus, _) = machine();
+        let id = call(
+            &mut cpu,
+            &mut bus,
+            ADD_INTC_HANDLER,
+            &[INT_TIM0, HANDLER_STOP, 0, 0],
+        );
+        assert_eq!(
+            call(
+                &mut cpu,
+                &mut bus,
+                REMOVE_INTC_HANDLER,
+                &[INT_TIM1, id as u32]
+            ),
+            -1
+        );
+        assert_eq!(
+            call(
+                &mut cpu,
+                &mut bus,
+                REMOVE_INTC_HANDLER,
+                &[INT_TIM0, id as u32]
+            ),
+            0
+        );
+        assert_eq!(
+            call(
+                &mut cpu,
+                &mut bus,
+                REMOVE_INTC_HANDLER,
+                &[INT_TIM0, id as u32]
+            ),
+            -1
+        );
+        assert_eq!(call(&mut cpu, &mut bus, REMOVE_DMAC_HANDLER, &[5, 1]), -1);
+        // Only kputs is supported
+        assert_eq!(call(&mut cpu, &mut bus, DECI2_CALL, &[0x01, RESULTS]), -1);
+        assert_eq!(call(&mut cpu, &mut bus, SIF_DMA_STAT, &[1]), -1);
+        assert_eq!(call(&mut cpu, &mut bus, WAIT_SEMA, &[99]), -1);
+    }
+
+    #[test]
+    fn enabling_a_cause_unmasks_it_in_hardware() {
+        let (mut cpu, mut bus, _) = machine();
+        assert_eq!(call(&mut cpu, &mut bus, ENABLE_INTC, &[INT_TIM0]), 1);
+        assert_eq!(call(&mut cpu, &mut bus, ENABLE_INTC, &[INT_TIM0]), 0);
+        assert_eq!(bus.intc().mask(), 1 << INT_TIM0);
+        assert_eq!(call(&mut cpu, &mut bus, ENABLE_DMAC, &[5]), 1);
+        assert_eq!(bus.dma_interrupts().read(), 1 << (16 + 5));
+
+        assert_eq!(call(&mut cpu, &mut bus, DISABLE_INTC, &[INT_TIM0]), 1);
+        assert_eq!(call(&mut cpu, &mut bus, DISABLE_INTC, &[INT_TIM0]), 0);
+        assert_eq!(bus.intc().mask(), 0);
+        assert_eq!(call(&mut cpu, &mut bus, DISABLE_DMAC, &[5]), 1);
+        assert_eq!(bus.dma_interrupts().read(), 0);
+    }
+
+    #[test]
+    fn interrupts_run_the_handler_chain_and_resume() {
+        let (mut cpu, mut bus, lines) = machine();
+        call(&mut cpu, &mut bus, SETUP_THREAD, &[0, u32::MAX, 0x1000, 0]);
+        cpu.gprs[GPR_GP] = HANDLER_GP as u128;
+        // Run in list order: the first passes on, the second ends the chain before the third
+        for (func, next, slot) in [
+            (HANDLER_CONTINUE, -1, 0),
+            (HANDLER_STOP, -1, 8),
+            (HANDLER_CONTINUE, -1, 16),
+            (HANDLER_CONTINUE, 0, 24),
+        ] {
+            let args = [INT_TIM0, func, next as u32, RESULTS + slot];
+            call(&mut cpu, &mut bus, ADD_INTC_HANDLER, &args);
+        }
+        call(
+            &mut cpu,
+            &mut bus,
+            ADD_INTC_HANDLER,
+            &[INT_TIM1, HANDLER_STOP, 0, RESULTS + 32],
+        );
+        call(&mut cpu, &mut bus, ENABLE_INTC, &[INT_TIM0]);
+
+        let resume = cpu.pc();
+        write32(&mut bus, resume, 0).unwrap();
+        cpu.gprs[GPR_A0] = 0x1111;
+        cpu.gprs[GPR_SP] = 0x4444;
+        cpu.gprs[GPR_RA] = 0x5555;
+        cpu.gprs[GPR_GP] = 0x6666;
+        cpu.gprs[16] = 0x0123_4567_89AB_CDEF_0011_2233_4455_6677;
+        bus.raise_interrupt(INT_TIM0);
+        bus.raise_interrupt(INT_TIM1);
+        run_to(&mut cpu, &mut bus, resume);
+
+        assert_eq!(
+            [result(&mut bus, 24), result(&mut bus, 28)],
+            [INT_TIM0, HANDLER_GP]
+        );
+        assert_eq!(
+            [result(&mut bus, 0), result(&mut bus, 4)],
+            [INT_TIM0, HANDLER_GP]
+        );
+        assert_eq!(result(&mut bus, 8), INT_TIM0);
+        assert_eq!(result(&mut bus, 16), 0);
+        // Masked, so neither acknowledged nor handled
+        assert_eq!(result(&mut bus, 32), 0);
+        assert_eq!(bus.intc().stat(), 1 << INT_TIM1);
+        assert_eq!(
+            [GPR_A0, GPR_SP, GPR_RA, GPR_GP].map(|index| cpu.gpr(index)),
+            [0x1111, 0x4444, 0x5555, 0x6666]
+        );
+        assert_eq!(cpu.gpr(16), 0x0123_4567_89AB_CDEF_0011_2233_4455_6677);
+        assert!(
+            lines
+                .borrow()
+                .iter()
+                .any(|line| line == "hle: calling INTC handler 4 for cause 9")
+        );
+    }
+
+    #[test]
+    fn handlers_get_their_own_stack_and_the_interrupted_pc() {
+        let (mut cpu, mut bus, _) = machine();
+        call(&mut cpu, &mut bus, SETUP_THREAD, &[0, u32::MAX, 0x1000, 0]);
+        // sw sp, 0(a1); sw a2, 4(a1); jr ra; addiu v0, zero, 0
+        let code = [0xACBD_0000, 0xACA6_0004, 0x03E0_0008, 0x2402_0000];
+        write_words(&mut bus, HANDLER_STOP, &code).unwrap();
+        call(
+            &mut cpu,
+            &mut bus,
+            ADD_DMAC_HANDLER,
+            &[5, HANDLER_STOP, 0, RESULTS],
+        );
+        call(&mut cpu, &mut bus, ENABLE_DMAC, &[5]);
+
+        let resume = cpu.pc();
+        write32(&mut bus, resume, 0).unwrap();
+        bus.dma_interrupts_mut().raise(5);
+        run_to(&mut cpu, &mut bus, resume);
+        assert_eq!(result(&mut bus, 0), INTERRUPT_STACK);
+        assert_eq!(result(&mut bus, 4), resume);
+        // Acknowledged, still enabled
+        assert_eq!(bus.dma_interrupts().read(), 1 << (16 + 5));
+    }
+
+    #[test]
+    fn a_handler_waking_a_thread_ends_the_idle_loop() {
+        let (mut cpu, mut bus, _) = machine();
+        call(&mut cpu, &mut bus, SETUP_THREAD, &[0, u32::MAX, 0x1000, 0]);
+        call(
+            &mut cpu,
+            &mut bus,
+            ADD_INTC_HANDLER,
+            &[INT_TIM0, HANDLER_WAKEUP, 0, 0],
+        );
+        call(&mut cpu, &mut bus, ENABLE_INTC, &[INT_TIM0]);
+        let resume = cpu.pc() + 4;
+        call(&mut cpu, &mut bus, SLEEP_THREAD, &[]);
+        for _ in 0..4 {
+            cpu.step_timed(&mut bus);
+        }
+        assert!((IDLE_STUB..IDLE_STUB + 8).contains(&cpu.pc()));
+
+        bus.raise_interrupt(INT_TIM0);
+        run_to(&mut cpu, &mut bus, resume);
+        assert_eq!(
+            call(&mut cpu, &mut bus, GET_THREAD_ID, &[]),
+            MAIN_THREAD as i32
+        );
+    }
+}
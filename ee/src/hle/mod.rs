@@ -0,0 +1,37 @@
+// High-level emulation of the EE kernel, for booting ELFs without a BIOS. A `SyscallHandler`
+// installed on the `Cpu` gets every SYSCALL before the guest's exception handler would; `Kernel`
+// implements the calls commercial games and ps2sdk homebrew commonly make.
+
+pub mod hostfs;
+pub mod kernel;
+pub mod sif;
+pub mod syscalls;
+pub mod thread;
+
+use crate::Cpu;
+use crate::bus::Bus;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyscallOutcome {
+    // The call was serviced; execution continues after the SYSCALL
+    Handled,
+    // Raise the syscall exception as if no handler was installed
+    Unhandled,
+    // The guest program asked to stop with this status
+    Exit(i32),
+}
+
+pub trait SyscallHandler {
+    // Called with the CPU positioned on the SYSCALL; the syscall number is in v1 and arguments
+    // in a0-a3 and t0-t3
+    fn syscall(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> SyscallOutcome;
+
+    // Guest-visible state kept by the handler, saved in a section of its own. A failed load
+    // must leave the handler as it was.
+    fn save_state(&self, _w: &mut StateWriter) {}
+
+    fn load_state(&mut self, _r: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
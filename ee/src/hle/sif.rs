@@ -0,0 +1,576 @@
+// A stand-in for the IOP end of the SIF, enough for the EE's command and RPC libraries and the
+// fileio server behind `host:`. There is no IOP: command packets the EE sends with SifSetDma are
+// decoded as they're sent, and the answers go back through the SIF0 DMA channel like the IOP's
+// would, ending in the channel interrupt the EE's command handler runs from. Data an RPC returns
+// is written straight into EE memory.
+//
+// Only fileio is served, through `HostFs`: open, close, read, write, lseek, dopen, dclose and
+// dread. Binding any other server gets a null server back, which is what the IOP answers until a
+// module registers it.
+
+use super::hostfs::{self, HostFs};
+use crate::bus::Bus;
+use crate::dmac::{self, TAG_CNT};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// SIF registers as SifGetReg numbers them
+pub const SIF_REG_MAINADDR: u32 = 1;
+pub const SIF_REG_SUBADDR: u32 = 2;
+pub const SIF_REG_MSFLAG: u32 = 3;
+pub const SIF_REG_SMFLAG: u32 = 4;
+// SMFLAG bits the IOP sets as it comes up
+pub const SIF_STAT_SIFINIT: u32 = 0x1_0000;
+pub const SIF_STAT_CMDINIT: u32 = 0x2_0000;
+pub const SIF_STAT_BOOTEND: u32 = 0x4_0000;
+
+// Command ids
+pub const SIF_CMD_CHANGE_SADDR: u32 = 0x8000_0000;
+pub const SIF_CMD_SET_SREG: u32 = 0x8000_0001;
+pub const SIF_CMD_INIT_CMD: u32 = 0x8000_0002;
+pub const SIF_CMD_RPC_END: u32 = 0x8000_0008;
+pub const SIF_CMD_RPC_BIND: u32 = 0x8000_0009;
+pub const SIF_CMD_RPC_CALL: u32 = 0x8000_000A;
+
+// The fileio server and its function numbers
+pub const FILEIO_SID: u32 = 0x8000_0001;
+pub const FIO_OPEN: u32 = 0;
+pub const FIO_CLOSE: u32 = 1;
+pub const FIO_READ: u32 = 2;
+pub const FIO_WRITE: u32 = 3;
+pub const FIO_LSEEK: u32 = 4;
+pub const FIO_DOPEN: u32 = 9;
+pub const FIO_DCLOSE: u32 = 10;
+pub const FIO_DREAD: u32 = 11;
+
+// Made-up IOP addresses: where the EE sends command packets, and the fileio server with its
+// receive buffer. The EE never looks behind them, it only hands them back.
+pub const IOP_CMD_BUFFER: u32 = 0x0001_F000;
+pub const FILEIO_SERVER: u32 = 0x0001_E000;
+pub const FILEIO_BUFFER: u32 = 0x0001_E100;
+const FILEIO_BUFFER_SIZE: usize = 0x400;
+
+const FIO_PATH_MAX: usize = 256;
+// An io_dirent_t: the stat fields, then the name
+const DIRENT_NAME: usize = 40;
+const DIRENT_NAME_SIZE: usize = 256;
+// Where the unaligned head of a write travels, in its argument block
+const WRITE_ALIGNED: usize = 16;
+
+fn word(bytes: &[u8], index: usize) -> u32 {
+    bytes
+        .get(4 * index..4 * index + 4)
+        .map_or(0, |word| u32::from_le_bytes(word.try_into().unwrap()))
+}
+
+fn read_bytes(bus: &mut dyn Bus, addr: u32, len: u32) -> Option<Vec<u8>> {
+    (0..len)
+        .map(|offset| bus.read8(addr.wrapping_add(offset)).ok())
+        .collect()
+}
+
+fn write_bytes(bus: &mut dyn Bus, addr: u32, bytes: &[u8]) -> Option<()> {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        bus.write8(addr.wrapping_add(offset as u32), byte).ok()?;
+    }
+    Some(())
+}
+
+// A NUL terminated path at the start of `bytes`
+fn path(bytes: &[u8]) -> String {
+    let bytes = &bytes[..bytes.len().min(FIO_PATH_MAX)];
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+pub struct Iop {
+    pub(super) host_fs: Option<HostFs>,
+    // Where the EE takes command packets in, 0 until it says
+    ee_buffer: u32,
+    // What the EE last sent the fileio server
+    fileio_buffer: Vec<u8>,
+}
+
+impl Default for Iop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Iop {
+    pub fn new() -> Self {
+        Iop {
+            host_fs: None,
+            ee_buffer: 0,
+            fileio_buffer: vec![0; FILEIO_BUFFER_SIZE],
+        }
+    }
+
+    // Without a host directory every fileio call fails with ENODEV
+    pub fn set_host_fs(&mut self, host_fs: HostFs) {
+        self.host_fs = Some(host_fs);
+    }
+
+    // The registers as the IOP leaves them once it has booted
+    pub fn registers() -> [(u32, u32); 2] {
+        [
+            (SIF_REG_SUBADDR, IOP_CMD_BUFFER),
+            (
+                SIF_REG_SMFLAG,
+                SIF_STAT_SIFINIT | SIF_STAT_CMDINIT | SIF_STAT_BOOTEND,
+            ),
+        ]
+    }
+
+    // SifSetDma(transfers, count): each transfer is (src, dest, size, attr). Packets sent to the
+    // command buffer are acted on at once, so later transfers see their effects.
+    pub fn set_dma(&mut self, bus: &mut dyn Bus, transfers: u32, count: u32) -> Option<()> {
+        for index in 0..count {
+            let transfer = read_bytes(bus, transfers.wrapping_add(16 * index), 12)?;
+            let (src, dest, size) = (word(&transfer, 0), word(&transfer, 1), word(&transfer, 2));
+            let data = read_bytes(bus, src, size)?;
+            if dest == IOP_CMD_BUFFER {
+                self.command(bus, &data);
+            } else if let Some(offset) = dest
+                .checked_sub(FILEIO_BUFFER)
+                .map(|offset| offset as usize)
+                .filter(|&offset| offset < FILEIO_BUFFER_SIZE)
+            {
+                let len = data.len().min(FILEIO_BUFFER_SIZE - offset);
+                self.fileio_buffer[offset..offset + len].copy_from_slice(&data[..len]);
+            }
+        }
+        Some(())
+    }
+
+    fn command(&mut self, bus: &mut dyn Bus, packet: &[u8]) {
+        let (cid, opt) = (word(packet, 2), word(packet, 3));
+        match cid {
+            SIF_CMD_CHANGE_SADDR => self.ee_buffer = word(packet, 4),
+            // Asked to start RPC, the IOP says so in software register 0
+            SIF_CMD_INIT_CMD if opt == 1 => self.send(bus, SIF_CMD_SET_SREG, &[0, 1]),
+            SIF_CMD_RPC_BIND => {
+                let (server, buff) = if word(packet, 8) == FILEIO_SID {
+                    (FILEIO_SERVER, FILEIO_BUFFER)
+                } else {
+                    (0, 0)
+                };
+                self.rpc_end(bus, packet, SIF_CMD_RPC_BIND, server, buff);
+            }
+            SIF_CMD_RPC_CALL if word(packet, 13) == FILEIO_SERVER => {
+                let result = self.fileio(bus, word(packet, 8));
+                let (receive, recv_size) = (word(packet, 10), word(packet, 11));
+                if receive != 0 && recv_size >= 4 {
+                    let _ = bus.write32(receive, result as u32);
+                }
+                self.rpc_end(bus, packet, SIF_CMD_RPC_CALL, FILEIO_SERVER, FILEIO_BUFFER);
+            }
+            _ => {}
+        }
+    }
+
+    // Answers a bind or call with the client's own packet header fields
+    fn rpc_end(&mut self, bus: &mut dyn Bus, request: &[u8], cid: u32, server: u32, buff: u32) {
+        let (rec_id, pkt_addr, rpc_id, client) = (
+            word(request, 4),
+            word(request, 5),
+            word(request, 6),
+            word(request, 7),
+        );
+        let body = [rec_id, pkt_addr, rpc_id, client, cid, server, buff, 0];
+        self.send(bus, SIF_CMD_RPC_END, &body);
+    }
+
+    // One packet into the EE's buffer through SIF0, its tag asking for the channel interrupt
+    fn send(&mut self, bus: &mut dyn Bus, cid: u32, body: &[u32]) {
+        if self.ee_buffer == 0 {
+            return;
+        }
+        let mut words = vec![16 + 4 * body.len() as u32, 0, cid, 0];
+        words.extend_from_slice(body);
+        words.resize(words.len().next_multiple_of(4), 0);
+        let qwc = words.len() as u64 / 4;
+        let tag = qwc | (TAG_CNT as u64) << 28 | 1 << 31 | (self.ee_buffer as u64) << 32;
+        bus.dma_from_device(dmac::SIF0, tag as u128);
+        for qword in words.chunks(4) {
+            let qword = qword
+                .iter()
+                .rev()
+                .fold(0u128, |qword, &word| qword << 32 | word as u128);
+            bus.dma_from_device(dmac::SIF0, qword);
+        }
+    }
+
+    // Runs a fileio function on the arguments in the server's buffer
+    fn fileio(&mut self, bus: &mut dyn Bus, function: u32) -> i32 {
+        let Some(host_fs) = self.host_fs.as_mut() else {
+            return -hostfs::ENODEV;
+        };
+        let args = &self.fileio_buffer;
+        let arg = |index: usize| word(args, index);
+        let fd = arg(0) as i32;
+        match function {
+            FIO_OPEN => host_fs.open(&path(&args[4..]), arg(0)),
+            FIO_CLOSE => host_fs.close(fd),
+            // (fd, ptr, size, read_data). The data goes straight to ptr, so the unaligned ends
+            // the EE would otherwise copy in from read_data are left empty.
+            FIO_READ => {
+                let (ptr, size, read_data) = (arg(1), arg(2), arg(3));
+                let result = match bus.memory_mut(ptr, size) {
+                    Some(buf) => host_fs.read(fd, buf),
+                    None => -hostfs::EINVAL,
+                };
+                if read_data != 0 {
+                    let _ = write_bytes(bus, read_data, &[0; 8]);
+                }
+                result
+            }
+            // (fd, ptr, size, mis, aligned): the first `mis` bytes come in `aligned`
+            FIO_WRITE => {
+                let (ptr, size, mis) = (arg(1), arg(2), arg(3).min(16));
+                let mut data = args[WRITE_ALIGNED..WRITE_ALIGNED + mis as usize].to_vec();
+                match read_bytes(bus, ptr.wrapping_add(mis), size.saturating_sub(mis)) {
+                    Some(rest) => data.extend(rest),
+                    None => return -hostfs::EINVAL,
+                }
+                host_fs.write(fd, &data)
+            }
+            FIO_LSEEK => host_fs.lseek(fd, arg(1) as i32, arg(2)),
+            FIO_DOPEN => host_fs.dopen(&path(args)),
+            FIO_DCLOSE => host_fs.dclose(fd),
+            // (fd, buf): fills in the io_dirent_t at buf, returning 1 per entry and 0 at the end
+            FIO_DREAD => match host_fs.dread(fd) {
+                Ok(Some(entry)) => {
+                    let mut dirent = vec![0; DIRENT_NAME + DIRENT_NAME_SIZE];
+                    dirent[0..4].copy_from_slice(&entry.mode.to_le_bytes());
+                    dirent[8..12].copy_from_slice(&(entry.size as u32).to_le_bytes());
+                    dirent[36..40].copy_from_slice(&((entry.size >> 32) as u32).to_le_bytes());
+                    let name = entry.name.as_bytes();
+                    let len = name.len().min(DIRENT_NAME_SIZE - 1);
+                    dirent[DIRENT_NAME..][..len].copy_from_slice(&name[..len]);
+                    match write_bytes(bus, arg(1), &dirent) {
+                        Some(()) => 1,
+                        None => -hostfs::EINVAL,
+                    }
+                }
+                Ok(None) => 0,
+                Err(err) => err,
+            },
+            _ => -hostfs::EINVAL,
+        }
+    }
+
+    // Files open on the host aren't part of a state and stay as they are across a load
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.ee_buffer);
+        w.write_bytes(&self.fileio_buffer);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let ee_buffer = r.read_u32()?;
+        let fileio_buffer = r.read_bytes()?;
+        if fileio_buffer.len() != FILEIO_BUFFER_SIZE {
+            return Err(StateError::Mismatch(format!(
+                "the fileio buffer is {} bytes, state has {}",
+                FILEIO_BUFFER_SIZE,
+                fileio_buffer.len()
+            )));
+        }
+        self.ee_buffer = ee_buffer;
+        self.fileio_buffer.copy_from_slice(fileio_buffer);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::Cpu;
+    use crate::hle::kernel::Kernel;
+    use crate::hle::syscalls::*;
+    use crate::ps2_bus::Ps2Bus;
+
+    const MAIN_CODE: u32 = 0x10_0000;
+    const HANDLER: u32 = 0x11_0000;
+    const PKTBUF: u32 = 0x12_0000;
+    const CMD_PACKET: u32 = 0x13_0000;
+    const SEND: u32 = 0x13_1000;
+    const TRANSFERS: u32 = 0x13_2000;
+    const CLIENT: u32 = 0x13_3000;
+    const RECV: u32 = 0x13_4000;
+    const DATA: u32 = 0x13_5000;
+    const READ_DATA: u32 = 0x13_6000;
+    // Packets received, then the id of the last one
+    const RESULTS: u32 = 0x14_0000;
+
+    // The EE's SIF0 handler: counts packets, notes the last id and rearms the channel
+    const HANDLER_CODE: [u32; 10] = [
+        0x3C08_0012, // lui t0, PKTBUF >> 16
+        0x8D09_0008, // lw t1, 8(t0)
+        0x8CAA_0000, // lw t2, 0(a1)
+        0x254A_0001, // addiu t2, t2, 1
+        0xACAA_0000, // sw t2, 0(a1)
+        0xACA9_0004, // sw t1, 4(a1)
+        0x2403_FF88, // addiu v1, zero, -sceSifSetDChain
+        0x0000_000C, // syscall
+        0x03E0_0008, // jr ra
+        0x2402_0000, // addiu v0, zero, 0
+    ];
+
+    struct Guest {
+        cpu: Cpu,
+        bus: Ps2Bus,
+        packets: u32,
+        server: u32,
+        buff: u32,
+    }
+
+    fn host_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sif-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    impl Guest {
+        // Booted, with the command handler on SIF0 and the EE's packet buffer known to the IOP
+        fn new(host_dir: Option<&PathBuf>) -> Self {
+            let mut kernel = Kernel::new();
+            kernel.set_logger(|_| {});
+            if let Some(dir) = host_dir {
+                kernel.set_host_fs(HostFs::new(dir).unwrap());
+            }
+            let mut cpu = Cpu::new();
+            cpu.set_syscall_handler(Box::new(kernel));
+            cpu.set_pc(MAIN_CODE);
+            let mut guest = Guest {
+                cpu,
+                bus: Ps2Bus::new(),
+                packets: 0,
+                server: 0,
+                buff: 0,
+            };
+            for (index, &word) in HANDLER_CODE.iter().enumerate() {
+                guest.write32(HANDLER + 4 * index as u32, word);
+            }
+            guest.syscall(SETUP_THREAD, &[0, u32::MAX, 0x1000, 0]);
+            guest.syscall(ADD_DMAC_HANDLER, &[dmac::SIF0 as u32, HANDLER, 0, RESULTS]);
+            guest.syscall(ENABLE_DMAC, &[dmac::SIF0 as u32]);
+            guest.syscall(SIF_SET_DCHAIN, &[]);
+            assert_eq!(
+                guest.syscall(SIF_GET_REG, &[SIF_REG_SUBADDR]),
+                IOP_CMD_BUFFER as i32
+            );
+            let smflag = guest.syscall(SIF_GET_REG, &[SIF_REG_SMFLAG]) as u32;
+            assert_eq!(smflag & SIF_STAT_CMDINIT, SIF_STAT_CMDINIT);
+            guest.send(&[20, 0, SIF_CMD_CHANGE_SADDR, 0, PKTBUF], &[]);
+            guest
+        }
+
+        fn write32(&mut self, addr: u32, value: u32) {
+            self.bus.write32(addr, value).unwrap();
+        }
+
+        fn read32(&mut self, addr: u32) -> u32 {
+            self.bus.read32(addr).unwrap()
+        }
+
+        fn write_bytes(&mut self, addr: u32, bytes: &[u8]) {
+            write_bytes(&mut self.bus, addr, bytes).unwrap();
+        }
+
+        fn syscall(&mut self, number: u32, args: &[u32]) -> i32 {
+            let pc = self.cpu.pc();
+            self.write32(pc, 0x0000_000C);
+            self.cpu.gprs[3] = number as u128;
+            for (index, &arg) in args.iter().enumerate() {
+                self.cpu.gprs[4 + index] = arg as u128;
+            }
+            self.cpu.step(&mut self.bus);
+            self.cpu.gpr(2) as i32
+        }
+
+        // SifSetDma of `extra` to the server's buffer, if any, then the packet
+        fn send(&mut self, packet: &[u32], extra: &[u8]) {
+            for (index, &word) in packet.iter().enumerate() {
+                self.write32(CMD_PACKET + 4 * index as u32, word);
+            }
+            self.write_bytes(SEND, extra);
+            let mut transfers = Vec::new();
+            if !extra.is_empty() {
+                transfers.push([SEND, self.buff, extra.len() as u32, 0]);
+            }
+            transfers.push([CMD_PACKET, IOP_CMD_BUFFER, 4 * packet.len() as u32, 0]);
+            for (index, transfer) in transfers.iter().enumerate() {
+                for (field, &value) in transfer.iter().enumerate() {
+                    self.write32(TRANSFERS + 16 * index as u32 + 4 * field as u32, value);
+                }
+            }
+            self.syscall(SIF_SET_DMA, &[TRANSFERS, transfers.len() as u32]);
+        }
+
+        // Runs until the handler has taken the next packet, returning its words
+        fn receive(&mut self) -> [u32; 12] {
+            self.packets += 1;
+            for _ in 0..1000 {
+                self.cpu.step_timed(&mut self.bus);
+                if self.read32(RESULTS) == self.packets {
+                    return std::array::from_fn(|index| self.read32(PKTBUF + 4 * index as u32));
+                }
+            }
+            panic!("no packet came back");
+        }
+
+        fn bind(&mut self, sid: u32) -> [u32; 12] {
+            self.send(
+                &[36, 0, SIF_CMD_RPC_BIND, 0, 0, CMD_PACKET, 1, CLIENT, sid],
+                &[],
+            );
+            let end = self.receive();
+            (self.server, self.buff) = (end[9], end[10]);
+            end
+        }
+
+        // SifCallRpc with `args` as the send buffer and four bytes to receive
+        fn call(&mut self, function: u32, args: &[u32], bytes: &[u8]) -> i32 {
+            let mut send: Vec<u8> = args.iter().flat_map(|arg| arg.to_le_bytes()).collect();
+            send.extend_from_slice(bytes);
+            let packet = [
+                56,
+                0,
+                SIF_CMD_RPC_CALL,
+                0,
+                0,
+                CMD_PACKET,
+                2,
+                CLIENT,
+                function,
+                send.len() as u32,
+                RECV,
+                4,
+                0,
+                self.server,
+            ];
+            self.send(&packet, &send);
+            let end = self.receive();
+            assert_eq!(end[2..4], [SIF_CMD_RPC_END, 0]);
+            assert_eq!(end[7..9], [CLIENT, SIF_CMD_RPC_CALL]);
+            self.read32(RECV) as i32
+        }
+
+        fn path(name: &str) -> Vec<u8> {
+            let mut path = name.as_bytes().to_vec();
+            path.resize(FIO_PATH_MAX, 0);
+            path
+        }
+    }
+
+    #[test]
+    fn a_guest_reads_a_host_file_over_rpc() {
+        let dir = host_dir("read");
+        fs::write(dir.join("data.bin"), b"hello, host file").unwrap();
+        let mut guest = Guest::new(Some(&dir));
+
+        // Starting RPC, the IOP sets software register 0
+        guest.send(&[16, 0, SIF_CMD_INIT_CMD, 1], &[]);
+        assert_eq!(guest.receive()[..6], [24, 0, SIF_CMD_SET_SREG, 0, 0, 1]);
+        assert_eq!(
+            guest.bind(FILEIO_SID)[..11],
+            [
+                48,
+                0,
+                SIF_CMD_RPC_END,
+                0,
+                0,
+                CMD_PACKET,
+                1,
+                CLIENT,
+                SIF_CMD_RPC_BIND,
+                FILEIO_SERVER,
+                FILEIO_BUFFER
+            ]
+        );
+
+        let fd = guest.call(FIO_OPEN, &[hostfs::O_RDONLY], &Guest::path("host:data.bin"));
+        assert!(fd >= 0);
+        assert_eq!(
+            guest.call(FIO_LSEEK, &[fd as u32, 7, hostfs::SEEK_SET], &[]),
+            7
+        );
+        guest.write32(READ_DATA, 0xFFFF_FFFF);
+        assert_eq!(
+            guest.call(FIO_READ, &[fd as u32, DATA + 3, 20, READ_DATA], &[]),
+            9
+        );
+        let data: Vec<u8> = (0..9)
+            .map(|offset| guest.bus.read8(DATA + 3 + offset).unwrap())
+            .collect();
+        assert_eq!(data, b"host file");
+        // Nothing left for the EE to copy from the read's unaligned ends
+        assert_eq!(guest.read32(READ_DATA), 0);
+        assert_eq!(guest.call(FIO_CLOSE, &[fd as u32], &[]), 0);
+        assert_eq!(
+            guest.call(FIO_READ, &[fd as u32, DATA, 4, 0], &[]),
+            -hostfs::EBADF
+        );
+        assert_eq!(
+            guest.call(FIO_OPEN, &[hostfs::O_RDONLY], &Guest::path("host:../x")),
+            -hostfs::EACCES
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn writes_and_directory_listings_reach_the_host() {
+        let dir = host_dir("write");
+        fs::write(dir.join("data.bin"), b"1234").unwrap();
+        let mut guest = Guest::new(Some(&dir));
+        guest.bind(FILEIO_SID);
+
+        let flags = hostfs::O_WRONLY | hostfs::O_CREAT;
+        let fd = guest.call(FIO_OPEN, &[flags], &Guest::path("host:out.bin")) as u32;
+        // The first three bytes travel in the arguments, the rest is read from EE memory
+        guest.write_bytes(DATA + 1, b"xyzdef");
+        assert_eq!(guest.call(FIO_WRITE, &[fd, DATA + 1, 6, 3], b"abc"), 6);
+        assert_eq!(guest.call(FIO_CLOSE, &[fd], &[]), 0);
+        assert_eq!(fs::read(dir.join("out.bin")).unwrap(), b"abcdef");
+
+        let fd = guest.call(FIO_DOPEN, &[], &Guest::path("host:")) as u32;
+        let mut names = Vec::new();
+        while guest.call(FIO_DREAD, &[fd, DATA], &[]) == 1 {
+            let mode = guest.read32(DATA);
+            let size = guest.read32(DATA + 8);
+            let name: Vec<u8> = (DATA + DIRENT_NAME as u32..)
+                .map(|addr| guest.bus.read8(addr).unwrap())
+                .take_while(|&byte| byte != 0)
+                .collect();
+            names.push((String::from_utf8(name).unwrap(), mode & 0xF000, size));
+        }
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ("data.bin".to_string(), hostfs::S_IFREG, 4),
+                ("out.bin".to_string(), hostfs::S_IFREG, 6),
+            ]
+        );
+        assert_eq!(guest.call(FIO_DCLOSE, &[fd], &[]), 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn other_servers_stay_unbound_and_no_host_dir_means_no_device() {
+        let mut guest = Guest::new(None);
+        assert_eq!(guest.bind(0x8000_0003)[9..11], [0, 0]);
+        guest.bind(FILEIO_SID);
+        let result = guest.call(FIO_OPEN, &[hostfs::O_RDONLY], &Guest::path("host:a"));
+        assert_eq!(result, -hostfs::ENODEV);
+    }
+}
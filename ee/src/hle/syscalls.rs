@@ -0,0 +1,198 @@
+// EE kernel syscall numbers, as passed in v1. Interrupt-context variants (`iSignalSema` and
+// friends) are called with the negated number of their table slot.
+
+pub const SET_GS_CRT: u32 = 0x02;
+pub const EXIT: u32 = 0x04;
+pub const ADD_INTC_HANDLER: u32 = 0x10;
+pub const REMOVE_INTC_HANDLER: u32 = 0x11;
+pub const ADD_DMAC_HANDLER: u32 = 0x12;
+pub const REMOVE_DMAC_HANDLER: u32 = 0x13;
+pub const ENABLE_INTC: u32 = 0x14;
+pub const DISABLE_INTC: u32 = 0x15;
+pub const ENABLE_DMAC: u32 = 0x16;
+pub const DISABLE_DMAC: u32 = 0x17;
+pub const I_ENABLE_INTC: u32 = 0x1A;
+pub const I_DISABLE_INTC: u32 = 0x1B;
+pub const I_ENABLE_DMAC: u32 = 0x1C;
+pub const I_DISABLE_DMAC: u32 = 0x1D;
+pub const CREATE_THREAD: u32 = 0x20;
+pub const DELETE_THREAD: u32 = 0x21;
+pub const START_THREAD: u32 = 0x22;
+pub const EXIT_THREAD: u32 = 0x23;
+pub const EXIT_DELETE_THREAD: u32 = 0x24;
+pub const TERMINATE_THREAD: u32 = 0x25;
+pub const I_TERMINATE_THREAD: u32 = 0x26;
+pub const CHANGE_THREAD_PRIORITY: u32 = 0x29;
+pub const I_CHANGE_THREAD_PRIORITY: u32 = 0x2A;
+pub const ROTATE_THREAD_READY_QUEUE: u32 = 0x2B;
+pub const I_ROTATE_THREAD_READY_QUEUE: u32 = 0x2C;
+pub const RELEASE_WAIT_THREAD: u32 = 0x2D;
+pub const I_RELEASE_WAIT_THREAD: u32 = 0x2E;
+pub const GET_THREAD_ID: u32 = 0x2F;
+pub const REFER_THREAD_STATUS: u32 = 0x30;
+pub const I_REFER_THREAD_STATUS: u32 = 0x31;
+pub const SLEEP_THREAD: u32 = 0x32;
+pub const WAKEUP_THREAD: u32 = 0x33;
+pub const I_WAKEUP_THREAD: u32 = 0x34;
+pub const CANCEL_WAKEUP_THREAD: u32 = 0x35;
+pub const I_CANCEL_WAKEUP_THREAD: u32 = 0x36;
+pub const SUSPEND_THREAD: u32 = 0x37;
+pub const I_SUSPEND_THREAD: u32 = 0x38;
+pub const RESUME_THREAD: u32 = 0x39;
+pub const I_RESUME_THREAD: u32 = 0x3A;
+pub const SETUP_THREAD: u32 = 0x3C;
+pub const SETUP_HEAP: u32 = 0x3D;
+pub const END_OF_HEAP: u32 = 0x3E;
+pub const CREATE_SEMA: u32 = 0x40;
+pub const DELETE_SEMA: u32 = 0x41;
+pub const SIGNAL_SEMA: u32 = 0x42;
+pub const I_SIGNAL_SEMA: u32 = 0x43;
+pub const WAIT_SEMA: u32 = 0x44;
+pub const POLL_SEMA: u32 = 0x45;
+pub const I_POLL_SEMA: u32 = 0x46;
+pub const REFER_SEMA_STATUS: u32 = 0x47;
+pub const I_REFER_SEMA_STATUS: u32 = 0x48;
+pub const FLUSH_CACHE: u32 = 0x64;
+pub const I_FLUSH_CACHE: u32 = 0x68;
+pub const GS_GET_IMR: u32 = 0x70;
+pub const GS_PUT_IMR: u32 = 0x71;
+pub const PRINT: u32 = 0x75;
+pub const SIF_DMA_STAT: u32 = 0x76;
+pub const SIF_SET_DMA: u32 = 0x77;
+pub const SIF_SET_DCHAIN: u32 = 0x78;
+pub const SIF_SET_REG: u32 = 0x79;
+pub const SIF_GET_REG: u32 = 0x7A;
+pub const DECI2_CALL: u32 = 0x7C;
+pub const MACHINE_TYPE: u32 = 0x7E;
+pub const GET_MEMORY_SIZE: u32 = 0x7F;
+
+// Calls meant for interrupt handlers, which must not switch threads
+pub fn is_interrupt_variant(number: u32) -> bool {
+    matches!(
+        number,
+        I_ENABLE_INTC
+            | I_DISABLE_INTC
+            | I_ENABLE_DMAC
+            | I_DISABLE_DMAC
+            | I_TERMINATE_THREAD
+            | I_CHANGE_THREAD_PRIORITY
+            | I_ROTATE_THREAD_READY_QUEUE
+            | I_RELEASE_WAIT_THREAD
+            | I_REFER_THREAD_STATUS
+            | I_WAKEUP_THREAD
+            | I_CANCEL_WAKEUP_THREAD
+            | I_SUSPEND_THREAD
+            | I_RESUME_THREAD
+            | I_SIGNAL_SEMA
+            | I_POLL_SEMA
+            | I_REFER_SEMA_STATUS
+            | I_FLUSH_CACHE
+    )
+}
+
+// Names for logging, including calls the HLE kernel doesn't implement
+pub fn name(number: u32) -> Option<&'static str> {
+    let name = match number {
+        0x01 => "ResetEE",
+        SET_GS_CRT => "SetGsCrt",
+        EXIT => "Exit",
+        0x06 => "LoadExecPS2",
+        0x07 => "ExecPS2",
+        0x0A => "AddSbusIntcHandler",
+        0x0B => "RemoveSbusIntcHandler",
+        0x0C => "Interrupt2Iop",
+        0x0D => "SetVTLBRefillHandler",
+        0x0E => "SetVCommonHandler",
+        0x0F => "SetVInterruptHandler",
+        ADD_INTC_HANDLER => "AddIntcHandler",
+        REMOVE_INTC_HANDLER => "RemoveIntcHandler",
+        ADD_DMAC_HANDLER => "AddDmacHandler",
+        REMOVE_DMAC_HANDLER => "RemoveDmacHandler",
+        ENABLE_INTC => "EnableIntc",
+        DISABLE_INTC => "DisableIntc",
+        ENABLE_DMAC => "EnableDmac",
+        DISABLE_DMAC => "DisableDmac",
+        0x18 => "SetAlarm",
+        0x19 => "ReleaseAlarm",
+        I_ENABLE_INTC => "iEnableIntc",
+        I_DISABLE_INTC => "iDisableIntc",
+        I_ENABLE_DMAC => "iEnableDmac",
+        I_DISABLE_DMAC => "iDisableDmac",
+        0x1E => "iSetAlarm",
+        0x1F => "iReleaseAlarm",
+        CREATE_THREAD => "CreateThread",
+        DELETE_THREAD => "DeleteThread",
+        START_THREAD => "StartThread",
+        EXIT_THREAD => "ExitThread",
+        EXIT_DELETE_THREAD => "ExitDeleteThread",
+        TERMINATE_THREAD => "TerminateThread",
+        I_TERMINATE_THREAD => "iTerminateThread",
+        0x27 => "DisableDispatchThread",
+        0x28 => "EnableDispatchThread",
+        CHANGE_THREAD_PRIORITY => "ChangeThreadPriority",
+        I_CHANGE_THREAD_PRIORITY => "iChangeThreadPriority",
+        ROTATE_THREAD_READY_QUEUE => "RotateThreadReadyQueue",
+        I_ROTATE_THREAD_READY_QUEUE => "iRotateThreadReadyQueue",
+        RELEASE_WAIT_THREAD => "ReleaseWaitThread",
+        I_RELEASE_WAIT_THREAD => "iReleaseWaitThread",
+        GET_THREAD_ID => "GetThreadId",
+        REFER_THREAD_STATUS => "ReferThreadStatus",
+        I_REFER_THREAD_STATUS => "iReferThreadStatus",
+        SLEEP_THREAD => "SleepThread",
+        WAKEUP_THREAD => "WakeupThread",
+        I_WAKEUP_THREAD => "iWakeupThread",
+        CANCEL_WAKEUP_THREAD => "CancelWakeupThread",
+        I_CANCEL_WAKEUP_THREAD => "iCancelWakeupThread",
+        SUSPEND_THREAD => "SuspendThread",
+        I_SUSPEND_THREAD => "iSuspendThread",
+        RESUME_THREAD => "ResumeThread",
+        I_RESUME_THREAD => "iResumeThread",
+        0x3B => "JoinThread",
+        SETUP_THREAD => "SetupThread",
+        SETUP_HEAP => "SetupHeap",
+        END_OF_HEAP => "EndOfHeap",
+        CREATE_SEMA => "CreateSema",
+        DELETE_SEMA => "DeleteSema",
+        SIGNAL_SEMA => "SignalSema",
+        I_SIGNAL_SEMA => "iSignalSema",
+        WAIT_SEMA => "WaitSema",
+        POLL_SEMA => "PollSema",
+        I_POLL_SEMA => "iPollSema",
+        REFER_SEMA_STATUS => "ReferSemaStatus",
+        I_REFER_SEMA_STATUS => "iReferSemaStatus",
+        0x4A => "SetOsdConfigParam",
+        0x4B => "GetOsdConfigParam",
+        0x4C => "GetGsHParam",
+        0x4D => "GetGsVParam",
+        0x4E => "SetGsHParam",
+        0x4F => "SetGsVParam",
+        0x5C => "EnableIntcHandler",
+        0x5D => "iEnableIntcHandler",
+        0x5E => "DisableIntcHandler",
+        0x5F => "iDisableIntcHandler",
+        0x60 => "EnableDmacHandler",
+        0x61 => "iEnableDmacHandler",
+        0x62 => "DisableDmacHandler",
+        0x63 => "iDisableDmacHandler",
+        FLUSH_CACHE => "FlushCache",
+        I_FLUSH_CACHE => "iFlushCache",
+        GS_GET_IMR => "GsGetIMR",
+        GS_PUT_IMR => "GsPutIMR",
+        0x72 => "SetPgifHandler",
+        0x73 => "SetVSyncFlag",
+        0x74 => "SetSyscall",
+        PRINT => "_print",
+        SIF_DMA_STAT => "sceSifDmaStat",
+        SIF_SET_DMA => "sceSifSetDma",
+        SIF_SET_DCHAIN => "sceSifSetDChain",
+        SIF_SET_REG => "sceSifSetReg",
+        SIF_GET_REG => "sceSifGetReg",
+        0x7B => "ExecOSD",
+        DECI2_CALL => "Deci2Call",
+        0x7D => "PSMode",
+        MACHINE_TYPE => "MachineType",
+        GET_MEMORY_SIZE => "GetMemorySize",
+        _ => return None,
+    };
+    Some(name)
+}
@@ -0,0 +1,1027 @@
+// Threads and semaphores of the HLE kernel. Scheduling follows the EE kernel: threads are
+// cooperative and strictly prioritised, the highest priority ready thread runs until it blocks,
+// yields or is preempted by a call that readies a higher priority thread. A switch saves the
+// whole register context of the outgoing thread, 128-bit GPRs included, and loads the incoming
+// one's, so the guest resumes after the SYSCALL of whichever thread now runs.
+//
+// Calls made from interrupt handlers (the `i` variants) change thread states but never switch;
+// the dispatch they leave pending happens once the interrupted thread makes an ordinary call.
+
+use std::collections::VecDeque;
+
+use crate::Cpu;
+use crate::bus::Bus;
+use crate::savestate::{Snapshot, StateError, StateReader, StateWriter};
+
+pub const MAX_THREADS: usize = 256;
+pub const MAX_SEMAPHORES: usize = 256;
+// Priorities run from 0 (highest) to 127
+pub const LOWEST_PRIORITY: u32 = 127;
+pub const MAIN_THREAD: u32 = 1;
+// Thread id 0 stands for the calling thread
+const TH_SELF: u32 = 0;
+// Room left at the top of a thread's stack for its saved context
+pub(super) const CONTEXT_FRAME_SIZE: u32 = 0x2A0;
+
+// Thread states, as reported by ReferThreadStatus. A waiting thread can also be suspended.
+pub const THS_RUN: u32 = 0x01;
+pub const THS_READY: u32 = 0x02;
+pub const THS_WAIT: u32 = 0x04;
+pub const THS_SUSPEND: u32 = 0x08;
+pub const THS_DORMANT: u32 = 0x10;
+
+// Code the kernel runs on the guest's behalf, in the low RAM the EE kernel keeps for itself.
+// Threads return into an ExitThread call, and the CPU spins in the idle loop while no thread is
+// ready.
+pub const EXIT_THREAD_STUB: u32 = 0x8000_1000;
+pub const IDLE_STUB: u32 = 0x8000_1008;
+const STUB_CODE: [u32; 4] = [
+    0x2403_0023, // addiu v1, zero, ExitThread
+    0x0000_000C, // syscall
+    0x1000_FFFF, // b IDLE_STUB
+    0x0000_0000, // nop
+];
+
+const GPR_V0: usize = 2;
+const GPR_A0: usize = 4;
+const GPR_GP: usize = 28;
+const GPR_SP: usize = 29;
+const GPR_FP: usize = 30;
+const GPR_RA: usize = 31;
+
+// Registers of a thread that isn't running
+#[derive(Clone, Debug)]
+pub struct ThreadContext {
+    pub gprs: [u128; 32],
+    pub hi: u64,
+    pub lo: u64,
+    pub sa: u64,
+    // Where the thread resumes
+    pub pc: u32,
+}
+
+impl ThreadContext {
+    // Taken during a SYSCALL, so the thread resumes at the instruction after it
+    fn capture(cpu: &Cpu) -> Self {
+        ThreadContext {
+            gprs: cpu.gprs,
+            hi: cpu.hi0,
+            lo: cpu.lo0,
+            sa: cpu.sa,
+            pc: cpu.next_pc,
+        }
+    }
+
+    fn restore(&self, cpu: &mut Cpu) {
+        cpu.gprs = self.gprs;
+        cpu.hi0 = self.hi;
+        cpu.lo0 = self.lo;
+        cpu.sa = self.sa;
+        resume_at(cpu, self.pc);
+    }
+}
+
+// The SYSCALL's step ends by moving to next_pc, same as an exception
+pub(super) fn resume_at(cpu: &mut Cpu, pc: u32) {
+    cpu.branch_target = None;
+    cpu.next_pc = pc;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Wait {
+    None,
+    Sleep,
+    Sema(u32),
+}
+
+impl Wait {
+    // waitType and waitId as ReferThreadStatus reports them
+    fn kind_and_id(self) -> (u32, u32) {
+        match self {
+            Wait::None => (0, 0),
+            Wait::Sleep => (1, 0),
+            Wait::Sema(id) => (2, id),
+        }
+    }
+
+    fn from_kind_and_id(kind: u32, id: u32) -> Option<Self> {
+        match kind {
+            0 => Some(Wait::None),
+            1 => Some(Wait::Sleep),
+            2 => Some(Wait::Sema(id)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Thread {
+    pub status: u32,
+    pub entry: u32,
+    pub stack: u32,
+    pub stack_size: u32,
+    pub gp: u32,
+    pub init_priority: u32,
+    pub priority: u32,
+    pub attr: u32,
+    pub option: u32,
+    pub wait: Wait,
+    pub wakeup_count: u32,
+    // Saved registers while the thread isn't running
+    pub context: Option<ThreadContext>,
+}
+
+impl Thread {
+    fn new(status: u32, priority: u32) -> Self {
+        Thread {
+            status,
+            entry: 0,
+            stack: 0,
+            stack_size: 0,
+            gp: 0,
+            init_priority: priority,
+            priority,
+            attr: 0,
+            option: 0,
+            wait: Wait::None,
+            wakeup_count: 0,
+            context: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Semaphore {
+    pub count: u32,
+    pub max_count: u32,
+    pub init_count: u32,
+    pub attr: u32,
+    pub option: u32,
+    // Threads blocked in WaitSema, first come first served
+    pub waiters: VecDeque<u32>,
+}
+
+fn read32(bus: &mut dyn Bus, addr: u32) -> Option<u32> {
+    bus.read32(addr).ok()
+}
+
+fn write_words(bus: &mut dyn Bus, addr: u32, words: &[u32]) -> Option<()> {
+    for (index, &word) in words.iter().enumerate() {
+        bus.write32(addr.wrapping_add(4 * index as u32), word)
+            .ok()?;
+    }
+    Some(())
+}
+
+pub struct Scheduler {
+    // Indexed by id, 0 is never used
+    threads: Vec<Option<Thread>>,
+    // 0 while idling
+    current: u32,
+    semaphores: Vec<Option<Semaphore>>,
+    // One FIFO of ready threads per priority
+    ready: Vec<VecDeque<u32>>,
+    // Set by calls after which a different thread may have to run. An `i` variant leaves it set
+    // for the next ordinary call.
+    dispatch: bool,
+    stubs_written: bool,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    // The main thread is running at priority 0
+    pub fn new() -> Self {
+        let mut threads = vec![None; MAX_THREADS];
+        threads[MAIN_THREAD as usize] = Some(Thread::new(THS_RUN, 0));
+        Scheduler {
+            threads,
+            current: MAIN_THREAD,
+            semaphores: vec![None; MAX_SEMAPHORES],
+            ready: vec![VecDeque::new(); LOWEST_PRIORITY as usize + 1],
+            dispatch: false,
+            stubs_written: false,
+        }
+    }
+
+    // The running thread, or `None` while the CPU idles
+    pub fn current(&self) -> Option<u32> {
+        (self.current != 0).then_some(self.current)
+    }
+
+    pub fn thread(&self, id: u32) -> Option<&Thread> {
+        self.threads.get(id as usize)?.as_ref()
+    }
+
+    pub fn semaphore(&self, id: u32) -> Option<&Semaphore> {
+        self.semaphores.get(id as usize)?.as_ref()
+    }
+
+    // Ready thread ids in the order they would run
+    pub fn ready_queue(&self) -> Vec<u32> {
+        self.ready.iter().flatten().copied().collect()
+    }
+
+    pub(super) fn thread_mut(&mut self, id: u32) -> Option<&mut Thread> {
+        self.threads.get_mut(id as usize)?.as_mut()
+    }
+
+    fn semaphore_mut(&mut self, id: u32) -> Option<&mut Semaphore> {
+        self.semaphores.get_mut(id as usize)?.as_mut()
+    }
+
+    fn resolve(&self, id: u32) -> u32 {
+        if id == TH_SELF { self.current } else { id }
+    }
+
+    // Ids start at 1 so 0 never names anything
+    fn free_slot<T>(slots: &[Option<T>]) -> Option<u32> {
+        (1..slots.len())
+            .find(|&id| slots[id].is_none())
+            .map(|id| id as u32)
+    }
+
+    fn make_ready(&mut self, id: u32) {
+        let Some(thread) = self.thread_mut(id) else {
+            return;
+        };
+        thread.status = THS_READY;
+        let priority = thread.priority as usize;
+        self.ready[priority].push_back(id);
+    }
+
+    fn unready(&mut self, id: u32) {
+        for queue in self.ready.iter_mut() {
+            queue.retain(|&ready| ready != id);
+        }
+    }
+
+    // Drops `id` from whatever it waits on
+    fn unwait(&mut self, id: u32) {
+        if let Some(Wait::Sema(sema)) = self.thread(id).map(|thread| thread.wait)
+            && let Some(sema) = self.semaphore_mut(sema)
+        {
+            sema.waiters.retain(|&waiter| waiter != id);
+        }
+    }
+
+    // Ends a wait. `result` replaces what the blocking call returns; a thread that is also
+    // suspended stays suspended.
+    fn release(&mut self, id: u32, result: Option<i32>) {
+        self.unwait(id);
+        let Some(thread) = self.thread_mut(id) else {
+            return;
+        };
+        thread.wait = Wait::None;
+        thread.status &= !THS_WAIT;
+        if let (Some(result), Some(context)) = (result, thread.context.as_mut()) {
+            context.gprs[GPR_V0] = result as i64 as u64 as u128;
+        }
+        if thread.status == 0 {
+            self.make_ready(id);
+        }
+    }
+
+    fn write_stubs(&mut self, bus: &mut dyn Bus) {
+        if !self.stubs_written {
+            self.stubs_written = write_words(bus, EXIT_THREAD_STUB, &STUB_CODE).is_some();
+        }
+    }
+
+    // Switches to the highest priority ready thread if the running one has to give way. Called
+    // with the calling thread's v0 already set. Returns the thread now running, 0 when idle, or
+    // `None` if nothing changed.
+    pub(super) fn reschedule(&mut self, cpu: &mut Cpu, bus: &mut dyn Bus) -> Option<u32> {
+        if !std::mem::take(&mut self.dispatch) {
+            return None;
+        }
+        let best = self.ready.iter().position(|queue| !queue.is_empty());
+        let running = self
+            .thread(self.current)
+            .filter(|thread| thread.status == THS_RUN)
+            .map(|thread| thread.priority as usize);
+        match (running, best) {
+            (Some(priority), Some(best)) if best >= priority => return None,
+            (Some(_), None) => return None,
+            (None, None) if self.current == 0 => return None,
+            _ => {}
+        }
+        let next = best.and_then(|best| self.ready[best].pop_front());
+
+        let outgoing = self.current;
+        if let Some(thread) = self.thread_mut(outgoing)
+            && thread.status != THS_DORMANT
+        {
+            thread.context = Some(ThreadContext::capture(cpu));
+            // Preempted rather than yielding, so it goes back in front of its queue
+            if thread.status == THS_RUN {
+                thread.status = THS_READY;
+                let priority = thread.priority as usize;
+                self.ready[priority].push_front(outgoing);
+            }
+        }
+
+        match next.and_then(|id| Some((id, self.thread_mut(id)?))) {
+            Some((id, thread)) => {
+                thread.status = THS_RUN;
+                if let Some(context) = thread.context.take() {
+                    context.restore(cpu);
+                }
+                self.current = id;
+            }
+            None => {
+                self.write_stubs(bus);
+                resume_at(cpu, IDLE_STUB);
+                self.current = 0;
+            }
+        }
+        Some(self.current)
+    }
+
+    pub(super) fn create_thread(&mut self, bus: &mut dyn Bus, param: u32) -> i32 {
+        let field = |bus: &mut dyn Bus, offset| read32(bus, param.wrapping_add(offset));
+        let read = |bus: &mut dyn Bus| -> Option<Thread> {
+            let mut thread = Thread::new(THS_DORMANT, field(bus, 20)?);
+            thread.entry = field(bus, 4)?;
+            thread.stack = field(bus, 8)?;
+            thread.stack_size = field(bus, 12)?;
+            thread.gp = field(bus, 16)?;
+            thread.attr = field(bus, 28)?;
+            thread.option = field(bus, 32)?;
+            Some(thread)
+        };
+        let Some(thread) = read(bus) else {
+            return -1;
+        };
+        if thread.init_priority > LOWEST_PRIORITY || thread.entry == 0 {
+            return -1;
+        }
+        let Some(id) = Self::free_slot(&self.threads) else {
+            return -1;
+        };
+        self.threads[id as usize] = Some(thread);
+        id as i32
+    }
+
+    pub(super) fn delete_thread(&mut self, id: u32) -> i32 {
+        match self.thread(id) {
+            Some(thread) if thread.status == THS_DORMANT && id != self.current => {
+                self.threads[id as usize] = None;
+                id as i32
+            }
+            _ => -1,
+        }
+    }
+
+    // The thread starts at its entry point with `arg` in a0 and returns into ExitThread
+    pub(super) fn start_thread(&mut self, bus: &mut dyn Bus, id: u32, arg: u32) -> i32 {
+        self.write_stubs(bus);
+        let Some(thread) = self.thread_mut(id).filter(|t| t.status == THS_DORMANT) else {
+            return -1;
+        };
+        let sp = thread
+            .stack
+            .wrapping_add(thread.stack_size)
+            .wrapping_sub(CONTEXT_FRAME_SIZE);
+        let mut gprs = [0; 32];
+        gprs[GPR_A0] = arg as i32 as i64 as u64 as u128;
+        gprs[GPR_GP] = thread.gp as i32 as i64 as u64 as u128;
+        gprs[GPR_SP] = sp as i32 as i64 as u64 as u128;
+        gprs[GPR_FP] = gprs[GPR_SP];
+        gprs[GPR_RA] = EXIT_THREAD_STUB as i32 as i64 as u64 as u128;
+        thread.context = Some(ThreadContext {
+            gprs,
+            hi: 0,
+            lo: 0,
+            sa: 0,
+            pc: thread.entry,
+        });
+        thread.priority = thread.init_priority;
+        thread.wakeup_count = 0;
+        self.make_ready(id);
+        self.dispatch = true;
+        id as i32
+    }
+
+    pub(super) fn exit_thread(&mut self, delete: bool) -> i32 {
+        let id = self.current;
+        let Some(thread) = self.thread_mut(id) else {
+            return -1;
+        };
+        thread.status = THS_DORMANT;
+        thread.wakeup_count = 0;
+        thread.context = None;
+        if delete {
+            self.threads[id as usize] = None;
+        }
+        self.dispatch = true;
+        0
+    }
+
+    pub(super) fn terminate_thread(&mut self, id: u32) -> i32 {
+        if id == self.current || self.thread(id).is_none() {
+            return -1;
+        }
+        self.unready(id);
+        self.unwait(id);
+        if let Some(thread) = self.thread_mut(id) {
+            thread.status = THS_DORMANT;
+            thread.wait = Wait::None;
+            thread.wakeup_count = 0;
+            thread.context = None;
+        }
+        id as i32
+    }
+
+    pub(super) fn sleep_thread(&mut self) -> i32 {
+        let id = self.current;
+        let Some(thread) = self.thread_mut(id) else {
+            return -1;
+        };
+        // A wakeup that came early is used up instead of sleeping
+        if thread.wakeup_count > 0 {
+            thread.wakeup_count -= 1;
+        } else {
+            thread.status = THS_WAIT;
+            thread.wait = Wait::Sleep;
+            self.dispatch = true;
+        }
+        id as i32
+    }
+
+    pub(super) fn wakeup_thread(&mut self, id: u32) -> i32 {
+        if id == self.current {
+            return -1;
+        }
+        let Some(thread) = self.thread_mut(id) else {
+            return -1;
+        };
+        if thread.status == THS_DORMANT {
+            return -1;
+        }
+        if thread.wait == Wait::Sleep {
+            self.release(id, None);
+            self.dispatch = true;
+        } else {
+            thread.wakeup_count = thread.wakeup_count.saturating_add(1);
+        }
+        id as i32
+    }
+
+    // Returns the wakeups that were pending
+    pub(super) fn cancel_wakeup_thread(&mut self, id: u32) -> i32 {
+        let id = self.resolve(id);
+        match self.thread_mut(id) {
+            Some(thread) => std::mem::take(&mut thread.wakeup_count) as i32,
+            None => -1,
+        }
+    }
+
+    pub(super) fn release_wait_thread(&mut self, id: u32) -> i32 {
+        match self.thread(id) {
+            Some(thread) if thread.status & THS_WAIT != 0 => {
+                self.release(id, Some(-1));
+                self.dispatch = true;
+                id as i32
+            }
+            _ => -1,
+        }
+    }
+
+    pub(super) fn suspend_thread(&mut self, id: u32) -> i32 {
+        if id == self.current {
+            return -1;
+        }
+        let status = match self.thread(id) {
+            Some(thread) => thread.status,
+            None => return -1,
+        };
+        match status {
+            THS_READY => self.unready(id),
+            THS_WAIT => {}
+            _ => return -1,
+        }
+        if let Some(thread) = self.thread_mut(id) {
+            thread.status = (status & THS_WAIT) | THS_SUSPEND;
+        }
+        id as i32
+    }
+
+    pub(super) fn resume_thread(&mut self, id: u32) -> i32 {
+        let Some(thread) = self.thread_mut(id) else {
+            return -1;
+        };
+        if thread.status & THS_SUSPEND == 0 {
+            return -1;
+        }
+        thread.status &= !THS_SUSPEND;
+        if thread.status == 0 {
+            self.make_ready(id);
+            self.dispatch = true;
+        }
+        id as i32
+    }
+
+    // Returns the old priority. A ready thread moves to the back of its new priority's queue.
+    pub(super) fn change_thread_priority(&mut self, id: u32, priority: u32) -> i32 {
+        let id = self.resolve(id);
+        if priority > LOWEST_PRIORITY {
+            return -1;
+        }
+        let Some(thread) = self.thread_mut(id) else {
+            return -1;
+        };
+        if thread.status == THS_DORMANT {
+            return -1;
+        }
+        let old = std::mem::replace(&mut thread.priority, priority);
+        if thread.status == THS_READY {
+            self.unready(id);
+            self.ready[priority as usize].push_back(id);
+        }
+        self.dispatch = true;
+        old as i32
+    }
+
+    // Sends the first thread of `priority` to the back of its queue. From a thread of that
+    // priority this is a yield.
+    pub(super) fn rotate_thread_ready_queue(&mut self, priority: u32, interrupt: bool) -> i32 {
+        if priority > LOWEST_PRIORITY {
+            return -1;
+        }
+        let current = self.current;
+        let yielding = !interrupt
+            && self
+                .thread(current)
+                .is_some_and(|thread| thread.status == THS_RUN && thread.priority == priority);
+        if yielding {
+            self.make_ready(current);
+            self.dispatch = true;
+        } else {
+            let queue = &mut self.ready[priority as usize];
+            if !queue.is_empty() {
+                queue.rotate_left(1);
+            }
+        }
+        priority as i32
+    }
+
+    // Fills in an ee_thread_status_t: status, func, stack, stack_size, gp_reg, initial_priority,
+    // current_priority, attr, option, waitType, waitId, wakeupCount
+    pub(super) fn refer_thread_status(&mut self, bus: &mut dyn Bus, id: u32, info: u32) -> i32 {
+        let id = self.resolve(id);
+        let Some(thread) = self.thread(id) else {
+            return -1;
+        };
+        let (wait_type, wait_id) = thread.wait.kind_and_id();
+        let fields = [
+            thread.status,
+            thread.entry,
+            thread.stack,
+            thread.stack_size,
+            thread.gp,
+            thread.init_priority,
+            thread.priority,
+            thread.attr,
+            thread.option,
+            wait_type,
+            wait_id,
+            thread.wakeup_count,
+        ];
+        let status = thread.status as i32;
+        if info != 0 && write_words(bus, info, &fields).is_none() {
+            return -1;
+        }
+        status
+    }
+
+    pub(super) fn create_sema(&mut self, bus: &mut dyn Bus, param: u32) -> i32 {
+        let field = |bus: &mut dyn Bus, offset| read32(bus, param.wrapping_add(offset));
+        let read = |bus: &mut dyn Bus| -> Option<Semaphore> {
+            Some(Semaphore {
+                max_count: field(bus, 4)?,
+                init_count: field(bus, 8)?,
+                count: field(bus, 8)?,
+                attr: field(bus, 16)?,
+                option: field(bus, 20)?,
+                waiters: VecDeque::new(),
+            })
+        };
+        let Some(sema) = read(bus) else {
+            return -1;
+        };
+        if (sema.init_count as i32) < 0 {
+            return -1;
+        }
+        let Some(id) = Self::free_slot(&self.semaphores) else {
+            return -1;
+        };
+        self.semaphores[id as usize] = Some(sema);
+        id as i32
+    }
+
+    // Threads still waiting on the semaphore are released with -1
+    pub(super) fn delete_sema(&mut self, id: u32) -> i32 {
+        let Some(sema) = self.semaphores.get_mut(id as usize).and_then(Option::take) else {
+            return -1;
+        };
+        for waiter in sema.waiters {
+            self.release(waiter, Some(-1));
+        }
+        self.dispatch = true;
+        id as i32
+    }
+
+    // Hands the signal straight to the first waiter if there is one. A count already at
+    // max_count is left there and the signal fails.
+    pub(super) fn signal_sema(&mut self, id: u32) -> i32 {
+        let Some(sema) = self.semaphore_mut(id) else {
+            return -1;
+        };
+        match sema.waiters.front().copied() {
+            Some(waiter) => {
+                self.release(waiter, None);
+                self.dispatch = true;
+            }
+            None if sema.count >= sema.max_count => return -1,
+            None => sema.count += 1,
+        }
+        id as i32
+    }
+
+    pub(super) fn wait_sema(&mut self, id: u32) -> i32 {
+        let current = self.current;
+        let Some(sema) = self.semaphore_mut(id) else {
+            return -1;
+        };
+        if sema.count > 0 {
+            sema.count -= 1;
+            return id as i32;
+        }
+        sema.waiters.push_back(current);
+        if let Some(thread) = self.thread_mut(current) {
+            thread.status = THS_WAIT;
+            thread.wait = Wait::Sema(id);
+        }
+        self.dispatch = true;
+        id as i32
+    }
+
+    pub(super) fn poll_sema(&mut self, id: u32) -> i32 {
+        match self.semaphore_mut(id) {
+            Some(sema) if sema.count > 0 => {
+                sema.count -= 1;
+                id as i32
+            }
+            _ => -1,
+        }
+    }
+
+    // Fills in an ee_sema_t: count, max_count, init_count, wait_threads, attr, option
+    pub(super) fn refer_sema_status(&mut self, bus: &mut dyn Bus, id: u32, info: u32) -> i32 {
+        let Some(sema) = self.semaphore(id) else {
+            return -1;
+        };
+        let fields = [
+            sema.count,
+            sema.max_count,
+            sema.init_count,
+            sema.waiters.len() as u32,
+            sema.attr,
+            sema.option,
+        ];
+        match write_words(bus, info, &fields) {
+            Some(()) => id as i32,
+            None => -1,
+        }
+    }
+}
+
+fn write_ids<'a>(w: &mut StateWriter, ids: impl ExactSizeIterator<Item = &'a u32>) {
+    w.write_u32(ids.len() as u32);
+    for &id in ids {
+        w.write_u32(id);
+    }
+}
+
+fn read_ids(r: &mut StateReader) -> Result<VecDeque<u32>, StateError> {
+    let len = r.read_u32()?;
+    (0..len).map(|_| r.read_u32()).collect()
+}
+
+fn save_thread(w: &mut StateWriter, thread: &Thread) {
+    let (wait_kind, wait_id) = thread.wait.kind_and_id();
+    for value in [
+        thread.status,
+        thread.entry,
+        thread.stack,
+        thread.stack_size,
+        thread.gp,
+        thread.init_priority,
+        thread.priority,
+        thread.attr,
+        thread.option,
+        wait_kind,
+        wait_id,
+        thread.wakeup_count,
+    ] {
+        w.write_u32(value);
+    }
+    w.write_bool(thread.context.is_some());
+    if let Some(context) = &thread.context {
+        for &gpr in &context.gprs {
+            w.write_u128(gpr);
+        }
+        w.write_u64(context.hi);
+        w.write_u64(context.lo);
+        w.write_u64(context.sa);
+        w.write_u32(context.pc);
+    }
+}
+
+fn load_thread(r: &mut StateReader) -> Result<Thread, StateError> {
+    let mut fields = [0; 12];
+    for field in fields.iter_mut() {
+        *field = r.read_u32()?;
+    }
+    let [
+        status,
+        entry,
+        stack,
+        stack_size,
+        gp,
+        init_priority,
+        priority,
+        attr,
+        option,
+        wait_kind,
+        wait_id,
+        wakeup_count,
+    ] = fields;
+    if init_priority > LOWEST_PRIORITY || priority > LOWEST_PRIORITY {
+        return Err(StateError::Mismatch(format!(
+            "thread priority {} is out of range",
+            priority.max(init_priority)
+        )));
+    }
+    let wait = Wait::from_kind_and_id(wait_kind, wait_id)
+        .ok_or_else(|| StateError::Mismatch(format!("unknown thread wait type {}", wait_kind)))?;
+    let context = if r.read_bool()? {
+        let mut gprs = [0; 32];
+        for gpr in gprs.iter_mut() {
+            *gpr = r.read_u128()?;
+        }
+        Some(ThreadContext {
+            gprs,
+            hi: r.read_u64()?,
+            lo: r.read_u64()?,
+            sa: r.read_u64()?,
+            pc: r.read_u32()?,
+        })
+    } else {
+        None
+    };
+    Ok(Thread {
+        status,
+        entry,
+        stack,
+        stack_size,
+        gp,
+        init_priority,
+        priority,
+        attr,
+        option,
+        wait,
+        wakeup_count,
+        context,
+    })
+}
+
+fn save_table<T>(w: &mut StateWriter, slots: &[Option<T>], save: fn(&mut StateWriter, &T)) {
+    w.write_u32(slots.len() as u32);
+    for slot in slots {
+        w.write_bool(slot.is_some());
+        if let Some(item) = slot {
+            save(w, item);
+        }
+    }
+}
+
+fn load_table<T>(
+    r: &mut StateReader,
+    what: &str,
+    size: usize,
+    load: fn(&mut StateReader) -> Result<T, StateError>,
+) -> Result<Vec<Option<T>>, StateError> {
+    let len = r.read_u32()? as usize;
+    if len != size {
+        return Err(StateError::Mismatch(format!(
+            "the HLE kernel has {} {}, state has {}",
+            size, what, len
+        )));
+    }
+    (0..len)
+        .map(|_| {
+            if r.read_bool()? {
+                load(r).map(Some)
+            } else {
+                Ok(None)
+            }
+        })
+        .collect()
+}
+
+fn save_semaphore(w: &mut StateWriter, sema: &Semaphore) {
+    for value in [
+        sema.count,
+        sema.max_count,
+        sema.init_count,
+        sema.attr,
+        sema.option,
+    ] {
+        w.write_u32(value);
+    }
+    write_ids(w, sema.waiters.iter());
+}
+
+fn load_semaphore(r: &mut StateReader) -> Result<Semaphore, StateError> {
+    Ok(Semaphore {
+        count: r.read_u32()?,
+        max_count: r.read_u32()?,
+        init_count: r.read_u32()?,
+        attr: r.read_u32()?,
+        option: r.read_u32()?,
+        waiters: read_ids(r)?,
+    })
+}
+
+impl Snapshot for Scheduler {
+    fn save_state(&self, w: &mut StateWriter) {
+        save_table(w, &self.threads, save_thread);
+        w.write_u32(self.current);
+        save_table(w, &self.semaphores, save_semaphore);
+        for queue in &self.ready {
+            write_ids(w, queue.iter());
+        }
+        w.write_bool(self.dispatch);
+        w.write_bool(self.stubs_written);
+    }
+
+    // Everything is read before anything changes
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let threads = load_table(r, "thread slots", MAX_THREADS, load_thread)?;
+        let current = r.read_u32()?;
+        let semaphores = load_table(r, "semaphore slots", MAX_SEMAPHORES, load_semaphore)?;
+        let ready = (0..=LOWEST_PRIORITY)
+            .map(|_| read_ids(r))
+            .collect::<Result<Vec<_>, _>>()?;
+        let dispatch = r.read_bool()?;
+        let stubs_written = r.read_bool()?;
+        if current as usize >= MAX_THREADS {
+            return Err(StateError::Mismatch(format!(
+                "current thread {} is out of range",
+                current
+            )));
+        }
+        *self = Scheduler {
+            threads,
+            current,
+            semaphores,
+            ready,
+            dispatch,
+            stubs_written,
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+    use crate::hle::kernel::Kernel;
+    use crate::hle::syscalls::*;
+
+    const MAIN_CODE: u32 = 0x200;
+    const THREAD_ENTRY: u32 = 0x3000;
+    const THREAD_PARAM: u32 = 0x100;
+    const SEMA_PARAM: u32 = 0x140;
+
+    fn machine() -> (Cpu, Ram) {
+        let mut kernel = Kernel::new();
+        kernel.set_logger(|_| {});
+        let mut cpu = Cpu::new();
+        cpu.set_syscall_handler(Box::new(kernel));
+        cpu.set_pc(MAIN_CODE);
+        let mut ram = Ram::new(0x1_0000);
+        // entry, stack, stack_size, gp, priority 10
+        for (offset, value) in [
+            (4, THREAD_ENTRY),
+            (8, 0x8000),
+            (12, 0x1000),
+            (16, 0),
+            (20, 10),
+        ] {
+            ram.write32(THREAD_PARAM + offset, value).unwrap();
+        }
+        // max_count 1, init_count 0
+        ram.write32(SEMA_PARAM + 4, 1).unwrap();
+        (cpu, ram)
+    }
+
+    // Runs a SYSCALL wherever the running thread is, returning v0 as the thread that runs next
+    // sees it
+    fn call(cpu: &mut Cpu, ram: &mut Ram, number: u32, args: &[u32]) -> i32 {
+        ram.write32(cpu.pc(), 0x0000_000C).unwrap();
+        cpu.gprs[3] = number as u128;
+        for (index, &arg) in args.iter().enumerate() {
+            cpu.gprs[4 + index] = arg as u128;
+        }
+        cpu.step(ram);
+        cpu.gpr(2) as i32
+    }
+
+    // Main drops below the thread it starts, which then runs at once
+    fn start_thread(cpu: &mut Cpu, ram: &mut Ram) -> u32 {
+        call(cpu, ram, CHANGE_THREAD_PRIORITY, &[0, 20]);
+        let id = call(cpu, ram, CREATE_THREAD, &[THREAD_PARAM]) as u32;
+        call(cpu, ram, START_THREAD, &[id, 0]);
+        assert_eq!(cpu.pc(), THREAD_ENTRY);
+        id
+    }
+
+    #[test]
+    fn interrupt_wakeup_dispatches_at_the_next_call() {
+        let (mut cpu, mut ram) = machine();
+        let id = start_thread(&mut cpu, &mut ram);
+        call(&mut cpu, &mut ram, SLEEP_THREAD, &[]);
+        let main_pc = cpu.pc();
+        assert_eq!(main_pc, MAIN_CODE + 12);
+
+        // Woken from an interrupt handler the thread has to wait for main to call in
+        assert_eq!(call(&mut cpu, &mut ram, I_WAKEUP_THREAD, &[id]), id as i32);
+        assert_eq!(cpu.pc(), main_pc + 4);
+        call(&mut cpu, &mut ram, GET_THREAD_ID, &[]);
+        assert_eq!(cpu.pc(), THREAD_ENTRY + 4);
+        assert_eq!(call(&mut cpu, &mut ram, GET_THREAD_ID, &[]), id as i32);
+    }
+
+    #[test]
+    fn signal_hands_the_semaphore_to_its_waiter() {
+        let (mut cpu, mut ram) = machine();
+        let sema = call(&mut cpu, &mut ram, CREATE_SEMA, &[SEMA_PARAM]) as u32;
+        start_thread(&mut cpu, &mut ram);
+        call(&mut cpu, &mut ram, WAIT_SEMA, &[sema]);
+        assert_eq!(cpu.pc(), MAIN_CODE + 16);
+
+        // The waiter outranks main and returns from WaitSema with the signal
+        call(&mut cpu, &mut ram, SIGNAL_SEMA, &[sema]);
+        assert_eq!(cpu.pc(), THREAD_ENTRY + 4);
+        assert_eq!(cpu.gpr(2) as i32, sema as i32);
+        // Handed over rather than counted
+        assert_eq!(call(&mut cpu, &mut ram, POLL_SEMA, &[sema]), -1);
+    }
+
+    #[test]
+    fn signals_stop_at_max_count() {
+        let (mut cpu, mut ram) = machine();
+        let sema = call(&mut cpu, &mut ram, CREATE_SEMA, &[SEMA_PARAM]) as u32;
+        assert_eq!(call(&mut cpu, &mut ram, SIGNAL_SEMA, &[sema]), sema as i32);
+        assert_eq!(call(&mut cpu, &mut ram, SIGNAL_SEMA, &[sema]), -1);
+        assert_eq!(call(&mut cpu, &mut ram, I_SIGNAL_SEMA, &[sema]), -1);
+
+        // Only the one signal was counted
+        assert_eq!(call(&mut cpu, &mut ram, POLL_SEMA, &[sema]), sema as i32);
+        assert_eq!(call(&mut cpu, &mut ram, POLL_SEMA, &[sema]), -1);
+        assert_eq!(call(&mut cpu, &mut ram, SIGNAL_SEMA, &[sema]), sema as i32);
+    }
+
+    #[test]
+    fn switches_keep_full_128_bit_contexts() {
+        let (mut cpu, mut ram) = machine();
+        let main_s0 = 0x0123_4567_89AB_CDEF_FEDC_BA98_7654_3210;
+        let thread_s1 = 0xAAAA_BBBB_CCCC_DDDD_1111_2222_3333_4444;
+        cpu.gprs[16] = main_s0;
+        cpu.hi0 = 0x1111;
+        cpu.lo0 = 0x2222;
+        let id = start_thread(&mut cpu, &mut ram);
+        assert_eq!(cpu.gprs[16], 0);
+
+        cpu.gprs[16] = 0x5555;
+        cpu.gprs[17] = thread_s1;
+        cpu.hi0 = 0x3333;
+        call(&mut cpu, &mut ram, SLEEP_THREAD, &[]);
+        assert_eq!(cpu.gprs[16], main_s0);
+        assert_eq!((cpu.hi0, cpu.lo0), (0x1111, 0x2222));
+
+        cpu.gprs[17] = 0;
+        call(&mut cpu, &mut ram, WAKEUP_THREAD, &[id]);
+        assert_eq!(cpu.pc(), THREAD_ENTRY + 4);
+        assert_eq!((cpu.gprs[16], cpu.gprs[17]), (0x5555, thread_s1));
+        assert_eq!(cpu.hi0, 0x3333);
+    }
+}
@@ -0,0 +1,194 @@
+// The EE's interrupt controller and the DMAC's interrupt status.
+//
+// INTC_STAT latches interrupt requests from the other devices and INTC_MASK selects which of
+// them reach the CPU; any unmasked request asserts INT0, seen as Cause.IP2. Writing a 1 to a
+// STAT bit clears it, writing a 1 to a MASK bit toggles it.
+//
+// D_STAT does the same for the DMAC's INT1 (Cause.IP3), with the status bits in its low half
+// and their masks in the high half: writing 1s clears status bits and toggles mask bits. A bus
+// error always interrupts, whatever the mask.
+
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const INTC_STAT: u32 = 0x1000_F000;
+pub const INTC_MASK: u32 = 0x1000_F010;
+pub const D_STAT: u32 = 0x1000_E010;
+
+// INTC sources, as bit numbers in INTC_STAT and INTC_MASK
+pub const INT_GS: u32 = 0;
+pub const INT_SBUS: u32 = 1;
+pub const INT_VBON: u32 = 2;
+pub const INT_VBOF: u32 = 3;
+pub const INT_VIF0: u32 = 4;
+pub const INT_VIF1: u32 = 5;
+pub const INT_VU0: u32 = 6;
+pub const INT_VU1: u32 = 7;
+pub const INT_IPU: u32 = 8;
+pub const INT_TIM0: u32 = 9;
+pub const INT_TIM1: u32 = 10;
+pub const INT_TIM2: u32 = 11;
+pub const INT_TIM3: u32 = 12;
+pub const INT_SFIFO: u32 = 13;
+pub const INT_VU0WD: u32 = 14;
+
+const INTC_BITS: u32 = 0x7FFF;
+
+// D_STAT sources besides the channels 0-9, as bit numbers: stall, MFIFO empty and bus error
+pub const DMA_INT_SIS: u32 = 13;
+pub const DMA_INT_MEIS: u32 = 14;
+pub const DMA_INT_BEIS: u32 = 15;
+
+const D_STAT_STATUS_BITS: u32 = 0xE3FF;
+const D_STAT_MASK_BITS: u32 = 0x63FF;
+
+#[derive(Clone, Debug, Default)]
+pub struct Intc {
+    stat: u32,
+    mask: u32,
+}
+
+impl Intc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn raise(&mut self, source: u32) {
+        self.stat |= 1 << source;
+    }
+
+    pub fn stat(&self) -> u32 {
+        self.stat
+    }
+
+    pub fn mask(&self) -> u32 {
+        self.mask
+    }
+
+    pub fn write_stat(&mut self, value: u32) {
+        self.stat &= !value;
+    }
+
+    pub fn write_mask(&mut self, value: u32) {
+        self.mask ^= value & INTC_BITS;
+    }
+
+    // INT0
+    pub fn asserted(&self) -> bool {
+        self.stat & self.mask != 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.stat);
+        w.write_u32(self.mask);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let stat = r.read_u32()?;
+        let mask = r.read_u32()?;
+        self.stat = stat;
+        self.mask = mask;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DmaInterrupts {
+    stat: u32,
+    mask: u32,
+}
+
+impl DmaInterrupts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // `source` is a channel number or one of the `DMA_INT_*` bits
+    pub fn raise(&mut self, source: u32) {
+        self.stat |= (1 << source) & D_STAT_STATUS_BITS;
+    }
+
+    pub fn read(&self) -> u32 {
+        self.stat | self.mask << 16
+    }
+
+    pub fn write(&mut self, value: u32) {
+        self.stat &= !(value & D_STAT_STATUS_BITS);
+        self.mask ^= (value >> 16) & D_STAT_MASK_BITS;
+    }
+
+    // INT1
+    pub fn asserted(&self) -> bool {
+        self.stat & self.mask != 0 || self.stat & 1 << DMA_INT_BEIS != 0
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(self.read());
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let value = r.read_u32()?;
+        self.stat = value & D_STAT_STATUS_BITS;
+        self.mask = (value >> 16) & D_STAT_MASK_BITS;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intc_stat_clears_on_1_and_mask_toggles() {
+        let mut intc = Intc::new();
+        intc.raise(INT_VBON);
+        intc.raise(INT_TIM0);
+        assert_eq!(intc.stat(), 1 << INT_VBON | 1 << INT_TIM0);
+        // Nothing reaches the CPU until it's unmasked
+        assert!(!intc.asserted());
+
+        intc.write_mask(1 << INT_TIM0 | 1 << INT_GS);
+        assert_eq!(intc.mask(), 1 << INT_TIM0 | 1 << INT_GS);
+        assert!(intc.asserted());
+        intc.write_mask(1 << INT_TIM0 | 1 << INT_VU0);
+        assert_eq!(intc.mask(), 1 << INT_GS | 1 << INT_VU0);
+        assert!(!intc.asserted());
+        intc.write_mask(1 << INT_VBON | 0xFFFF_8000);
+        assert_eq!(intc.mask(), 1 << INT_GS | 1 << INT_VU0 | 1 << INT_VBON);
+        assert!(intc.asserted());
+
+        // Zeros leave STAT alone
+        intc.write_stat(0);
+        assert_eq!(intc.stat(), 1 << INT_VBON | 1 << INT_TIM0);
+        intc.write_stat(1 << INT_VBON | 1 << INT_SBUS);
+        assert_eq!(intc.stat(), 1 << INT_TIM0);
+        assert!(!intc.asserted());
+    }
+
+    #[test]
+    fn d_stat_clears_the_low_half_and_toggles_the_high_half() {
+        let mut dma = DmaInterrupts::new();
+        dma.raise(2);
+        dma.raise(DMA_INT_SIS);
+        // Bits 10-12 aren't sources
+        dma.raise(11);
+        assert_eq!(dma.read(), 1 << 2 | 1 << DMA_INT_SIS);
+        assert!(!dma.asserted());
+
+        dma.write(1 << (16 + 2) | 1 << (16 + 5));
+        assert_eq!(dma.read(), 1 << 2 | 1 << DMA_INT_SIS | 0x0024_0000);
+        assert!(dma.asserted());
+        // The same write toggles the masks back off and a status bit is cleared alongside
+        dma.write(1 << (16 + 2) | 1 << (16 + 5) | 1 << DMA_INT_SIS);
+        assert_eq!(dma.read(), 1 << 2);
+        assert!(!dma.asserted());
+        dma.write(1 << 2 | 1 << (16 + DMA_INT_SIS));
+        assert_eq!(dma.read(), 1 << (16 + DMA_INT_SIS));
+        // There's no mask for bus errors, they always interrupt
+        dma.write(1 << (16 + DMA_INT_BEIS));
+        assert_eq!(dma.read(), 1 << (16 + DMA_INT_SIS));
+        dma.raise(DMA_INT_BEIS);
+        assert!(dma.asserted());
+        dma.write(1 << DMA_INT_BEIS);
+        assert!(!dma.asserted());
+    }
+}
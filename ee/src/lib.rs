@@ -1,3 +1,48 @@
+pub mod bus;
+pub mod callstack;
+pub mod checkpoint;
+pub mod console;
+pub mod cop0;
+pub mod debugger;
+pub mod disasm;
+pub mod dmac;
+pub mod elf;
+pub mod expr;
+pub mod gdb;
+pub mod gif;
+pub mod gs;
+pub mod hle;
+pub mod intc;
+pub mod observer;
+pub mod ps2_bus;
+pub mod savestate;
+pub mod scheduler;
+pub mod symbols;
+pub mod timer;
+pub mod trace;
+
+use std::fmt;
+use std::sync::Arc;
+
+use bus::Bus;
+use callstack::{CallStack, Frame};
+use cop0::{Cop0, Exception};
+use debugger::{Debugger, Finish, StopReason};
+use hle::{SyscallHandler, SyscallOutcome};
+use observer::CpuObserver;
+use scheduler::TimedBus;
+use symbols::SymbolTable;
+use trace::{RegSnapshot, TraceRecord, Tracer};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepResult {
+    Ok,
+    // Execution stopped for the attached debugger
+    Breakpoint(StopReason),
+    // The guest asked the HLE kernel to exit with this status
+    Exited(i32),
+}
+
 #[derive(Default)]
 pub struct Cpu {
     gprs: [u128; 32],
@@ -8,6 +53,22 @@ pub struct Cpu {
     // lo1: u64,
     sa: u64,
     next_pc: u32,
+    // Target of a taken branch, applied once its delay slot has executed
+    branch_target: Option<u32>,
+    in_delay_slot: bool,
+    cop0: Cop0,
+    tracer: Option<Box<Tracer>>,
+    observers: Vec<Box<dyn CpuObserver>>,
+    debugger: Option<Box<Debugger>>,
+    symbols: Option<Arc<SymbolTable>>,
+    call_stack: CallStack,
+    syscall_handler: Option<Box<dyn SyscallHandler>>,
+    // Set by an HLE Exit, reported once the step finishes
+    exit_status: Option<i32>,
+    // EE cycles since power-on
+    cycles: u64,
+    // Cycle the multiply/divide unit's result lands in HI/LO
+    hilo_ready: u64,
 }
 
 impl Cpu {
@@ -51,10 +112,13 @@ impl Cpu {
         self.gprs[index] as u64
     }
 
-    // Write GPR as 64-bit doubleword (sign-extended to 128 bits)
+    // Write GPR as 64-bit doubleword (upper 64 bits are left untouched)
     #[inline]
     fn write_gpr_dword(&mut self, index: usize, value: u64) {
-        self.gprs[index] &= 0xffff_0000;
+        if index == 0 {
+            return;
+        }
+        self.gprs[index] &= !(u64::MAX as u128);
         self.gprs[index] |= value as u128;
     }
 
@@ -65,10 +129,12 @@ impl Cpu {
     }
 
     // Write GPR as 128-bit quadword (full width)
-    // #[inline]
-    // fn write_gpr_qword(&mut self, index: usize, value: u128) {
-    //     self.gprs[index] = value;
-    // }
+    #[inline]
+    fn write_gpr_qword(&mut self, index: usize, value: u128) {
+        if index != 0 {
+            self.gprs[index] = value;
+        }
+    }
 
     const OPCODE_SPECIAL: u32 = 0b000000;
     const OPCODE_REGIMM: u32 = 0b000001;
@@ -86,6 +152,7 @@ impl Cpu {
     const OPCODE_ORI: u32 = 0b001101;
     const OPCODE_XORI: u32 = 0b001110;
     const OPCODE_LUI: u32 = 0b001111;
+    const OPCODE_COP0: u32 = 0b010000;
     const OPCODE_BEQL: u32 = 0b010100;
     const OPCODE_BNEL: u32 = 0b010101;
     const OPCODE_BLEZL: u32 = 0b010110;
@@ -191,6 +258,27 @@ impl Cpu {
     const REGIMM_MTSAB: u32 = 0b11000; // 0x18
     const REGIMM_MTSAH: u32 = 0b11001; // 0x19
 
+    // COP0 rs field values
+    const COP0_MF0: u32 = 0b00000; // 0x00
+    const COP0_MT0: u32 = 0b00100; // 0x04
+    const COP0_C0: u32 = 0b10000; // 0x10
+
+    // COP0 C0 funct values
+    const C0_FUNCT_TLBR: u32 = 0b000001; // 0x01
+    const C0_FUNCT_TLBWI: u32 = 0b000010; // 0x02
+    const C0_FUNCT_TLBWR: u32 = 0b000110; // 0x06
+    const C0_FUNCT_TLBP: u32 = 0b001000; // 0x08
+    const C0_FUNCT_ERET: u32 = 0b011000; // 0x18
+    const C0_FUNCT_EI: u32 = 0b111000; // 0x38
+    const C0_FUNCT_DI: u32 = 0b111001; // 0x39
+
+    pub const RESET_VECTOR: u32 = 0xBFC0_0000;
+
+    // Result latencies of the multiply/divide unit, in cycles. Reading HI/LO or issuing another
+    // multiply or divide before then stalls.
+    pub const MULT_LATENCY: u64 = 4;
+    pub const DIV_LATENCY: u64 = 37;
+
     pub fn new() -> Self {
         Cpu {
             gprs: [0; 32],
@@ -201,19 +289,495 @@ impl Cpu {
             // lo1: 0,
             // hi1: 0,
             sa: 0,
+            branch_target: None,
+            in_delay_slot: false,
+            cop0: Cop0::default(),
+            tracer: None,
+            observers: Vec::new(),
+            debugger: None,
+            symbols: None,
+            call_stack: CallStack::default(),
+            syscall_handler: None,
+            exit_status: None,
+            cycles: 0,
+            hilo_ready: 0,
         }
     }
 
+    #[inline]
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    // Puts the CPU in its power-on state: registers cleared, COP0 reset and execution starting
+    // at the BIOS reset vector. The tracer, observers and debugger stay installed.
+    pub fn reset(&mut self) {
+        self.gprs = [0; 32];
+        self.hi0 = 0;
+        self.lo0 = 0;
+        self.sa = 0;
+        self.hilo_ready = 0;
+        self.cop0.reset();
+        self.call_stack.clear();
+        self.set_pc(Self::RESET_VECTOR);
+    }
+
+    // Redirects execution to `pc`, dropping any branch in flight
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.next_pc = pc.wrapping_add(4);
+        self.branch_target = None;
+        self.in_delay_slot = false;
+    }
+
+    #[inline]
+    pub fn gpr(&self, index: usize) -> u128 {
+        self.gprs[index]
+    }
+
+    pub fn hi(&self) -> u64 {
+        self.hi0
+    }
+
+    pub fn lo(&self) -> u64 {
+        self.lo0
+    }
+
+    pub fn sa(&self) -> u64 {
+        self.sa
+    }
+
+    // Time doesn't restart on reset, devices scheduled against it keep running
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(Box::new(tracer));
+    }
+
+    pub fn take_tracer(&mut self) -> Option<Tracer> {
+        self.tracer.take().map(|tracer| *tracer)
+    }
+
+    #[inline]
+    pub fn cop0(&self) -> &Cop0 {
+        &self.cop0
+    }
+
+    pub fn add_observer(&mut self, observer: Box<dyn CpuObserver>) {
+        self.observers.push(observer);
+    }
+
+    pub fn clear_observers(&mut self) -> Vec<Box<dyn CpuObserver>> {
+        std::mem::take(&mut self.observers)
+    }
+
+    // Services SYSCALLs in Rust instead of raising the exception, see `hle`
+    pub fn set_syscall_handler(&mut self, handler: Box<dyn SyscallHandler>) {
+        self.syscall_handler = Some(handler);
+    }
+
+    pub fn take_syscall_handler(&mut self) -> Option<Box<dyn SyscallHandler>> {
+        self.syscall_handler.take()
+    }
+
+    pub fn attach_debugger(&mut self, debugger: Debugger) {
+        self.debugger = Some(Box::new(debugger));
+    }
+
+    pub fn detach_debugger(&mut self) -> Option<Debugger> {
+        self.debugger.take().map(|debugger| *debugger)
+    }
+
+    pub fn debugger(&self) -> Option<&Debugger> {
+        self.debugger.as_deref()
+    }
+
+    pub fn debugger_mut(&mut self) -> Option<&mut Debugger> {
+        self.debugger.as_deref_mut()
+    }
+
+    // Symbols used to name guest addresses in diagnostics
+    pub fn set_symbols(&mut self, symbols: Option<Arc<SymbolTable>>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> Option<&Arc<SymbolTable>> {
+        self.symbols.as_ref()
+    }
+
+    // `00100004 <main+0x4>`, or just the address when no symbol covers it
+    pub fn describe_addr(&self, addr: u32) -> String {
+        match self
+            .symbols
+            .as_ref()
+            .and_then(|symbols| symbols.describe(addr))
+        {
+            Some(name) => format!("{:08x} <{}>", addr, name),
+            None => format!("{:08x}", addr),
+        }
+    }
+
+    pub fn call_stack(&self) -> &CallStack {
+        &self.call_stack
+    }
+
+    // Symbolized guest backtrace from the current instruction, see `callstack::backtrace`
+    pub fn backtrace(&self) -> String {
+        callstack::backtrace(self, self.pc)
+    }
+
+    // Arms the attached debugger to stop once the current instruction has completed, including
+    // any call it makes. Returns false when no debugger is attached.
+    pub fn step_over(&mut self) -> bool {
+        let depth = self.call_stack.depth();
+        self.debugger
+            .as_mut()
+            .map(|debugger| debugger.finish(Finish::Over, depth))
+            .is_some()
+    }
+
+    // Like `step_over`, but runs until the current function returns
+    pub fn step_out(&mut self) -> bool {
+        let depth = self.call_stack.depth();
+        self.debugger
+            .as_mut()
+            .map(|debugger| debugger.finish(Finish::Out, depth))
+            .is_some()
+    }
+
+    // Panics for an instruction the core can't execute, saying where it was
+    #[cold]
+    fn unimplemented(&self, what: fmt::Arguments) -> ! {
+        panic!("{} at {}", what, self.describe_addr(self.pc))
+    }
+
+    // Runs `f` with the attached debugger, if any, while still giving it access to the CPU
+    #[inline]
+    fn with_debugger<R>(&mut self, f: impl FnOnce(&mut Debugger, &Cpu) -> R) -> Option<R> {
+        let mut debugger = self.debugger.take()?;
+        let result = f(&mut debugger, self);
+        self.debugger = Some(debugger);
+        Some(result)
+    }
+
+    #[inline]
+    fn notify(&mut self, mut callback: impl FnMut(&mut dyn CpuObserver, &Cpu)) {
+        if self.observers.is_empty() {
+            return;
+        }
+        let mut observers = std::mem::take(&mut self.observers);
+        for observer in observers.iter_mut() {
+            callback(observer.as_mut(), self);
+        }
+        self.observers = observers;
+    }
+
     #[inline]
     pub fn update_pc(&mut self) {
         self.pc = self.next_pc;
-        self.next_pc += 4;
+        // A taken branch lands once its delay slot (the instruction now at pc) has run
+        self.in_delay_slot = self.branch_target.is_some();
+        self.next_pc = match self.branch_target.take() {
+            Some(target) => target,
+            None => self.next_pc.wrapping_add(4),
+        };
     }
 
-    pub fn exec(&mut self, raw: u32) {
+    // Fetch, execute and advance past a single instruction
+    pub fn step(&mut self, bus: &mut dyn Bus) -> StepResult {
+        if let Some(Some(reason)) =
+            self.with_debugger(|debugger, cpu| debugger.check_breakpoint(cpu))
+        {
+            return StepResult::Breakpoint(reason);
+        }
+        let start = self.cycles;
+        if self.cop0.interrupt_pending() {
+            // Taken in place of the instruction at pc, which runs after the handler returns
+            self.raise_exception(Exception::Interrupt);
+            self.cycles += 1;
+        } else {
+            match self.fetch(bus) {
+                Some(raw) => self.exec(bus, raw),
+                // A faulting fetch still takes a cycle
+                None => self.cycles += 1,
+            }
+        }
+        self.cop0.advance_count(self.cycles - start);
+        self.update_pc();
+        if let Some(status) = self.exit_status.take() {
+            return StepResult::Exited(status);
+        }
+        match self
+            .debugger
+            .as_mut()
+            .and_then(|debugger| debugger.take_pending())
+        {
+            Some(reason) => StepResult::Breakpoint(reason),
+            None => StepResult::Ok,
+        }
+    }
+
+    // Like `step`, but first handles any events the bus has due by now and updates the
+    // interrupt lines
+    pub fn step_timed(&mut self, bus: &mut dyn TimedBus) -> StepResult {
+        bus.scheduler().set_now(self.cycles);
+        while let Some((event, deadline)) = bus.scheduler().pop_due() {
+            bus.handle_event(self, event, deadline);
+        }
+        self.cop0.set_interrupt_lines(bus.interrupt_lines());
+        self.step(bus)
+    }
+
+    // Runs until at least `cycles` more have passed, stopping early for anything other than
+    // `StepResult::Ok`
+    pub fn run_for(&mut self, bus: &mut dyn TimedBus, cycles: u64) -> StepResult {
+        let end = self.cycles.saturating_add(cycles);
+        while self.cycles < end {
+            match self.step_timed(bus) {
+                StepResult::Ok => {}
+                result => return result,
+            }
+        }
+        StepResult::Ok
+    }
+
+    fn fetch(&mut self, bus: &mut dyn Bus) -> Option<u32> {
+        if self.pc & 3 != 0 {
+            self.address_error(self.pc, Exception::AddressErrorLoad);
+            return None;
+        }
+        match bus.read32(self.pc) {
+            Ok(raw) => Some(raw),
+            Err(_) => {
+                self.raise_exception(Exception::InstructionBusError);
+                None
+            }
+        }
+    }
+
+    // Every instruction issues in one cycle, stalls on the multiply/divide unit come on top
+    pub fn exec(&mut self, bus: &mut dyn Bus, raw: u32) {
+        let pc = self.pc;
+        self.cycles += 1;
+        self.notify(|observer, cpu| observer.before_instruction(cpu, bus, raw));
+        match self.tracer.take() {
+            Some(mut tracer) if tracer.wants(pc) => {
+                let before = RegSnapshot::capture(self);
+                self.dispatch(bus, raw);
+                tracer.record(&TraceRecord {
+                    pc,
+                    raw,
+                    changes: before.changes(self),
+                });
+                self.tracer = Some(tracer);
+            }
+            tracer => {
+                self.tracer = tracer;
+                self.dispatch(bus, raw);
+            }
+        }
+        self.notify(|observer, cpu| observer.after_instruction(cpu, bus, pc, raw));
+    }
+
+    // Exceptions
+
+    pub(crate) fn raise_exception(&mut self, exception: Exception) {
+        let status = self.cop0.status();
+        let mut cause = self.cop0.cause() & !cop0::CAUSE_EXC_CODE_MASK;
+        cause |= exception.code() << cop0::CAUSE_EXC_CODE_SHIFT;
+
+        // EPC and BD are only updated when not already handling an exception
+        if status & cop0::STATUS_EXL == 0 {
+            if self.in_delay_slot {
+                self.cop0.set(cop0::EPC, self.pc.wrapping_sub(4));
+                cause |= cop0::CAUSE_BD;
+            } else {
+                self.cop0.set(cop0::EPC, self.pc);
+                cause &= !cop0::CAUSE_BD;
+            }
+            self.cop0.set(cop0::STATUS, status | cop0::STATUS_EXL);
+        }
+        self.cop0.set(cop0::CAUSE, cause);
+
+        let base = if status & cop0::STATUS_BEV != 0 {
+            0xBFC0_0200
+        } else {
+            0x8000_0000
+        };
+        let offset = match exception {
+            Exception::Interrupt => 0x200,
+            _ => 0x180,
+        };
+        // Whatever the faulting instruction was doing, execution resumes at the vector
+        self.branch_target = None;
+        self.next_pc = base + offset;
+
+        self.notify(|observer, cpu| observer.exception_taken(cpu, exception));
+    }
+
+    fn address_error(&mut self, addr: u32, exception: Exception) {
+        self.cop0.set(cop0::BAD_VADDR, addr);
+        self.raise_exception(exception);
+    }
+
+    // Memory access helpers. They return None (or false) when the access raised an exception,
+    // in which case the instruction must not have any further effect.
+
+    #[inline]
+    fn effective_address(&self, raw: u32) -> u32 {
+        let base = Self::extract_rs(raw);
+        self.read_gpr_word(base)
+            .wrapping_add(raw as i16 as i32 as u32)
+    }
+
+    fn load<T: Into<u128> + Copy>(
+        &mut self,
+        bus: &mut dyn Bus,
+        addr: u32,
+        read: impl FnOnce(&mut dyn Bus, u32) -> Result<T, bus::BusError>,
+    ) -> Option<T> {
+        let size = std::mem::size_of::<T>();
+        if addr as usize & (size - 1) != 0 {
+            self.address_error(addr, Exception::AddressErrorLoad);
+            return None;
+        }
+        match read(bus, addr) {
+            Ok(value) => {
+                self.notify(|observer, cpu| observer.memory_read(cpu, addr, size, value.into()));
+                self.with_debugger(|debugger, cpu| {
+                    debugger.check_read(cpu, addr, size, value.into())
+                });
+                Some(value)
+            }
+            Err(_) => {
+                self.raise_exception(Exception::DataBusError);
+                None
+            }
+        }
+    }
+
+    fn store<T: Into<u128> + Copy>(
+        &mut self,
+        bus: &mut dyn Bus,
+        addr: u32,
+        value: T,
+        write: impl FnOnce(&mut dyn Bus, u32, T) -> Result<(), bus::BusError>,
+    ) -> bool {
+        let size = std::mem::size_of::<T>();
+        if addr as usize & (size - 1) != 0 {
+            self.address_error(addr, Exception::AddressErrorStore);
+            return false;
+        }
+        // Value-change watchpoints need to know what was there before. It is peeked, reading a
+        // device register could have side effects.
+        let old = match &self.debugger {
+            Some(debugger) if debugger.wants_old_value(addr, size) => (0..size as u32)
+                .map(|offset| bus.peek8(addr + offset))
+                .rev()
+                .try_fold(0u128, |acc, byte| byte.map(|byte| acc << 8 | byte as u128)),
+            _ => None,
+        };
+        match write(bus, addr, value) {
+            Ok(()) => {
+                self.notify(|observer, cpu| observer.memory_write(cpu, addr, size, value.into()));
+                self.with_debugger(|debugger, cpu| {
+                    debugger.check_write(cpu, addr, size, value.into(), old)
+                });
+                true
+            }
+            Err(_) => {
+                self.raise_exception(Exception::DataBusError);
+                false
+            }
+        }
+    }
+
+    // The aligned memory an SWL/SWR/SDL/SDR merges its register into. It's part of the store, so
+    // it is read straight from the bus and observers and watchpoints only see the write.
+    fn merge_base<T>(
+        &mut self,
+        bus: &mut dyn Bus,
+        addr: u32,
+        read: impl FnOnce(&mut dyn Bus, u32) -> Result<T, bus::BusError>,
+    ) -> Option<T> {
+        match read(bus, addr) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.raise_exception(Exception::DataBusError);
+                None
+            }
+        }
+    }
+
+    fn load8(&mut self, bus: &mut dyn Bus, addr: u32) -> Option<u8> {
+        self.load(bus, addr, |bus, addr| bus.read8(addr))
+    }
+
+    fn load16(&mut self, bus: &mut dyn Bus, addr: u32) -> Option<u16> {
+        self.load(bus, addr, |bus, addr| bus.read16(addr))
+    }
+
+    fn load32(&mut self, bus: &mut dyn Bus, addr: u32) -> Option<u32> {
+        self.load(bus, addr, |bus, addr| bus.read32(addr))
+    }
+
+    fn load64(&mut self, bus: &mut dyn Bus, addr: u32) -> Option<u64> {
+        self.load(bus, addr, |bus, addr| bus.read64(addr))
+    }
+
+    fn load128(&mut self, bus: &mut dyn Bus, addr: u32) -> Option<u128> {
+        self.load(bus, addr, |bus, addr| bus.read128(addr))
+    }
+
+    fn store8(&mut self, bus: &mut dyn Bus, addr: u32, value: u8) -> bool {
+        self.store(bus, addr, value, |bus, addr, value| bus.write8(addr, value))
+    }
+
+    fn store16(&mut self, bus: &mut dyn Bus, addr: u32, value: u16) -> bool {
+        self.store(bus, addr, value, |bus, addr, value| {
+            bus.write16(addr, value)
+        })
+    }
+
+    fn store32(&mut self, bus: &mut dyn Bus, addr: u32, value: u32) -> bool {
+        self.store(bus, addr, value, |bus, addr, value| {
+            bus.write32(addr, value)
+        })
+    }
+
+    fn store64(&mut self, bus: &mut dyn Bus, addr: u32, value: u64) -> bool {
+        self.store(bus, addr, value, |bus, addr, value| {
+            bus.write64(addr, value)
+        })
+    }
+
+    fn store128(&mut self, bus: &mut dyn Bus, addr: u32, value: u128) -> bool {
+        self.store(bus, addr, value, |bus, addr, value| {
+            bus.write128(addr, value)
+        })
+    }
+
+    #[inline]
+    fn branch_to(&mut self, target: u32) {
+        self.branch_target = Some(target);
+    }
+
+    // A taken linking branch/jump, recorded on the shadow call stack
+    fn call(&mut self, target: u32) {
+        self.call_stack.push(Frame {
+            call_site: self.pc,
+            target,
+            return_addr: self.pc.wrapping_add(8),
+        });
+        self.branch_to(target);
+    }
+
+    fn dispatch(&mut self, bus: &mut dyn Bus, raw: u32) {
         let opcode = (raw >> 26) & 0b111111;
         match opcode {
-            Self::OPCODE_SPECIAL => self.handle_special(raw),
+            Self::OPCODE_SPECIAL => self.handle_special(bus, raw),
             Self::OPCODE_REGIMM => self.handle_regimm(raw),
             Self::OPCODE_J => self.do_j(raw),
             Self::OPCODE_JAL => self.do_jal(raw),
@@ -229,44 +793,45 @@ impl Cpu {
             Self::OPCODE_ORI => self.do_ori(raw),
             Self::OPCODE_XORI => self.do_xori(raw),
             Self::OPCODE_LUI => self.do_lui(raw),
+            Self::OPCODE_COP0 => self.handle_cop0(raw),
             Self::OPCODE_BEQL => self.do_beql(raw),
             Self::OPCODE_BNEL => self.do_bnel(raw),
             Self::OPCODE_BLEZL => self.do_blezl(raw),
             Self::OPCODE_BGTZL => self.do_bgtzl(raw),
             Self::OPCODE_DADDI => self.do_daddi(raw),
             Self::OPCODE_DADDIU => self.do_daddiu(raw),
-            Self::OPCODE_LDL => self.do_ldl(raw),
-            Self::OPCODE_LDR => self.do_ldr(raw),
-            Self::OPCODE_LQ => self.do_lq(raw),
-            Self::OPCODE_SQ => self.do_sq(raw),
-            Self::OPCODE_LB => self.do_lb(raw),
-            Self::OPCODE_LH => self.do_lh(raw),
-            Self::OPCODE_LWL => self.do_lwl(raw),
-            Self::OPCODE_LW => self.do_lw(raw),
-            Self::OPCODE_LBU => self.do_lbu(raw),
-            Self::OPCODE_LHU => self.do_lhu(raw),
-            Self::OPCODE_LWR => self.do_lwr(raw),
-            Self::OPCODE_LWU => self.do_lwu(raw),
-            Self::OPCODE_SB => self.do_sb(raw),
-            Self::OPCODE_SH => self.do_sh(raw),
-            Self::OPCODE_SWL => self.do_swl(raw),
-            Self::OPCODE_SW => self.do_sw(raw),
-            Self::OPCODE_SDL => self.do_sdl(raw),
-            Self::OPCODE_SDR => self.do_sdr(raw),
-            Self::OPCODE_SWR => self.do_swr(raw),
+            Self::OPCODE_LDL => self.do_ldl(bus, raw),
+            Self::OPCODE_LDR => self.do_ldr(bus, raw),
+            Self::OPCODE_LQ => self.do_lq(bus, raw),
+            Self::OPCODE_SQ => self.do_sq(bus, raw),
+            Self::OPCODE_LB => self.do_lb(bus, raw),
+            Self::OPCODE_LH => self.do_lh(bus, raw),
+            Self::OPCODE_LWL => self.do_lwl(bus, raw),
+            Self::OPCODE_LW => self.do_lw(bus, raw),
+            Self::OPCODE_LBU => self.do_lbu(bus, raw),
+            Self::OPCODE_LHU => self.do_lhu(bus, raw),
+            Self::OPCODE_LWR => self.do_lwr(bus, raw),
+            Self::OPCODE_LWU => self.do_lwu(bus, raw),
+            Self::OPCODE_SB => self.do_sb(bus, raw),
+            Self::OPCODE_SH => self.do_sh(bus, raw),
+            Self::OPCODE_SWL => self.do_swl(bus, raw),
+            Self::OPCODE_SW => self.do_sw(bus, raw),
+            Self::OPCODE_SDL => self.do_sdl(bus, raw),
+            Self::OPCODE_SDR => self.do_sdr(bus, raw),
+            Self::OPCODE_SWR => self.do_swr(bus, raw),
             Self::OPCODE_CACHE => self.do_cache(raw),
             Self::OPCODE_LWC1 => self.do_lwc1(raw),
             Self::OPCODE_PREF => self.do_pref(raw),
             Self::OPCODE_LQC2 => self.do_lqc2(raw),
-            Self::OPCODE_LD => self.do_ld(raw),
+            Self::OPCODE_LD => self.do_ld(bus, raw),
             Self::OPCODE_SWC1 => self.do_swc1(raw),
             Self::OPCODE_SQC2 => self.do_sqc2(raw),
-            Self::OPCODE_SD => self.do_sd(raw),
-            _ => unimplemented!("Opcode {:06b} not implemented", opcode),
+            Self::OPCODE_SD => self.do_sd(bus, raw),
+            _ => self.unimplemented(format_args!("Opcode {:06b} not implemented", opcode)),
         }
     }
 
-    fn handle_special(&mut self, raw: u32) {
+    fn handle_special(&mut self, bus: &mut dyn Bus, raw: u32) {
         let funct = raw & 0b111111;
         match funct {
             Self::SPECIAL_FUNCT_SLL => self.do_sll(raw),
@@ -279,7 +844,7 @@ impl Cpu {
             Self::SPECIAL_FUNCT_JALR => self.do_jalr(raw),
             Self::SPECIAL_FUNCT_MOVZ => self.do_movz(raw),
             Self::SPECIAL_FUNCT_MOVN => self.do_movn(raw),
-            Self::SPECIAL_FUNCT_SYSCALL => self.do_syscall(raw),
+            Self::SPECIAL_FUNCT_SYSCALL => self.do_syscall(bus, raw),
             Self::SPECIAL_FUNCT_BREAK => self.do_break(raw),
             Self::SPECIAL_FUNCT_SYNC => self.do_sync(raw),
             Self::SPECIAL_FUNCT_MFHI => self.do_mfhi(raw),
@@ -321,7 +886,7 @@ impl Cpu {
             Self::SPECIAL_FUNCT_DSLL32 => self.do_dsll32(raw),
             Self::SPECIAL_FUNCT_DSRL32 => self.do_dsrl32(raw),
             Self::SPECIAL_FUNCT_DSRA32 => self.do_dsra32(raw),
-            _ => unimplemented!("Function {:06b} not implemented", funct),
+            _ => self.unimplemented(format_args!("Function {:06b} not implemented", funct)),
         }
     }
 
@@ -344,7 +909,7 @@ impl Cpu {
             Self::REGIMM_BGEZALL => self.do_bgezall(raw),
             Self::REGIMM_MTSAB => self.do_mtsab(raw),
             Self::REGIMM_MTSAH => self.do_mtsah(raw),
-            _ => unimplemented!("REGIMM rt {:05b} not implemented", rt),
+            _ => self.unimplemented(format_args!("REGIMM rt {:05b} not implemented", rt)),
         }
     }
 
@@ -353,14 +918,14 @@ impl Cpu {
     fn do_j(&mut self, raw: u32) {
         // J target - Jump
         let target = raw & 0x03FF_FFFF;
-        self.next_pc = ((self.pc + 4) & 0xF000_0000) | (target << 2);
+        self.branch_to(((self.pc + 4) & 0xF000_0000) | (target << 2));
     }
 
     fn do_jal(&mut self, raw: u32) {
         // JAL target - Jump and Link
         let target = raw & 0x03FF_FFFF;
         self.write_gpr_dword(31, (self.pc + 8) as u64); // Return address
-        self.next_pc = ((self.pc + 4) & 0xF000_0000) | (target << 2);
+        self.call(((self.pc + 4) & 0xF000_0000) | (target << 2));
     }
 
     fn do_beq(&mut self, raw: u32) {
@@ -370,7 +935,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if self.read_gpr_dword(rs) == self.read_gpr_dword(rt) {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -381,7 +946,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if self.read_gpr_dword(rs) != self.read_gpr_dword(rt) {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -391,7 +956,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) <= 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -401,7 +966,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) > 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -414,7 +979,7 @@ impl Cpu {
         let rs_value = self.read_gpr_word(rs) as i32;
         match rs_value.checked_add(imm) {
             Some(result) => self.write_gpr_dword(rt, result as i64 as u64),
-            None => panic!("ADDI overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -495,7 +1060,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if self.read_gpr_dword(rs) == self.read_gpr_dword(rt) {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -509,7 +1074,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if self.read_gpr_dword(rs) != self.read_gpr_dword(rt) {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -522,7 +1087,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) <= 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -535,7 +1100,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) > 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -551,7 +1116,7 @@ impl Cpu {
         let rs_value = self.read_gpr_dword(rs) as i64;
         match rs_value.checked_add(imm) {
             Some(result) => self.write_gpr_dword(rt, result as u64),
-            None => panic!("DADDI overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -566,186 +1131,232 @@ impl Cpu {
         self.write_gpr_dword(rt, result as u64);
     }
 
-    fn do_ldl(&mut self, raw: u32) {
+    fn do_ldl(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LDL rt, offset(base) - Load Doubleword Left
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 7) * 8;
 
-        unimplemented!("LDL requires memory bus interface");
+        let Some(mem) = self.load64(bus, addr & !7) else {
+            return;
+        };
+        let mask = 0x00FF_FFFF_FFFF_FFFF_u64 >> shift;
+        self.write_gpr_dword(rt, (self.read_gpr_dword(rt) & mask) | (mem << (56 - shift)));
     }
 
-    fn do_ldr(&mut self, raw: u32) {
+    fn do_ldr(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LDR rt, offset(base) - Load Doubleword Right
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 7) * 8;
 
-        unimplemented!("LDR requires memory bus interface");
+        let Some(mem) = self.load64(bus, addr & !7) else {
+            return;
+        };
+        let mask = ((u64::MAX as u128) << (64 - shift)) as u64;
+        self.write_gpr_dword(rt, (self.read_gpr_dword(rt) & mask) | (mem >> shift));
     }
 
-    fn do_lq(&mut self, raw: u32) {
+    fn do_lq(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LQ rt, offset(base) - Load Quadword
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        // The low 4 bits of the address are ignored rather than raising an address error
+        let addr = self.effective_address(raw) & !0xF;
 
-        unimplemented!("LQ requires memory bus interface");
+        if let Some(value) = self.load128(bus, addr) {
+            self.write_gpr_qword(rt, value);
+        }
     }
 
-    fn do_sq(&mut self, raw: u32) {
+    fn do_sq(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SQ rt, offset(base) - Store Quadword
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw) & !0xF;
 
-        unimplemented!("SQ requires memory bus interface");
+        self.store128(bus, addr, self.read_gpr_qword(rt));
     }
 
-    fn do_lb(&mut self, raw: u32) {
+    fn do_lb(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LB rt, offset(base) - Load Byte (sign-extended)
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LB requires memory bus interface");
+        if let Some(value) = self.load8(bus, addr) {
+            self.write_gpr_dword(rt, value as i8 as i64 as u64);
+        }
     }
 
-    fn do_lh(&mut self, raw: u32) {
+    fn do_lh(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LH rt, offset(base) - Load Halfword (sign-extended)
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LH requires memory bus interface");
+        if let Some(value) = self.load16(bus, addr) {
+            self.write_gpr_dword(rt, value as i16 as i64 as u64);
+        }
     }
 
-    fn do_lwl(&mut self, raw: u32) {
+    fn do_lwl(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LWL rt, offset(base) - Load Word Left
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 3) * 8;
 
-        unimplemented!("LWL requires memory bus interface");
+        let Some(mem) = self.load32(bus, addr & !3) else {
+            return;
+        };
+        let mask = 0x00FF_FFFF_u32 >> shift;
+        let result = (self.read_gpr_word(rt) & mask) | (mem << (24 - shift));
+        self.write_gpr_dword(rt, result as i32 as i64 as u64);
     }
 
-    fn do_lw(&mut self, raw: u32) {
+    fn do_lw(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LW rt, offset(base) - Load Word (sign-extended)
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LW requires memory bus interface");
+        if let Some(value) = self.load32(bus, addr) {
+            self.write_gpr_dword(rt, value as i32 as i64 as u64);
+        }
     }
 
-    fn do_lbu(&mut self, raw: u32) {
+    fn do_lbu(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LBU rt, offset(base) - Load Byte Unsigned
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LBU requires memory bus interface");
+        if let Some(value) = self.load8(bus, addr) {
+            self.write_gpr_dword(rt, value as u64);
+        }
     }
 
-    fn do_lhu(&mut self, raw: u32) {
+    fn do_lhu(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LHU rt, offset(base) - Load Halfword Unsigned
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LHU requires memory bus interface");
+        if let Some(value) = self.load16(bus, addr) {
+            self.write_gpr_dword(rt, value as u64);
+        }
     }
 
-    fn do_lwr(&mut self, raw: u32) {
+    fn do_lwr(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LWR rt, offset(base) - Load Word Right
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 3) * 8;
 
-        unimplemented!("LWR requires memory bus interface");
+        let Some(mem) = self.load32(bus, addr & !3) else {
+            return;
+        };
+        let mask = (0xFFFF_FFFF_u64 << (32 - shift)) as u32;
+        let result = (self.read_gpr_word(rt) & mask) | (mem >> shift);
+        if shift == 0 {
+            // A full word was loaded, so it gets sign-extended like LW
+            self.write_gpr_dword(rt, result as i32 as i64 as u64);
+        } else {
+            // Otherwise only the low word changes
+            let upper = self.read_gpr_dword(rt) & 0xFFFF_FFFF_0000_0000;
+            self.write_gpr_dword(rt, upper | result as u64);
+        }
     }
 
-    fn do_lwu(&mut self, raw: u32) {
+    fn do_lwu(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LWU rt, offset(base) - Load Word Unsigned
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LWU requires memory bus interface");
+        if let Some(value) = self.load32(bus, addr) {
+            self.write_gpr_dword(rt, value as u64);
+        }
     }
 
-    fn do_sb(&mut self, raw: u32) {
+    fn do_sb(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SB rt, offset(base) - Store Byte
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("SB requires memory bus interface");
+        self.store8(bus, addr, self.read_gpr_word(rt) as u8);
     }
 
-    fn do_sh(&mut self, raw: u32) {
+    fn do_sh(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SH rt, offset(base) - Store Halfword
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("SH requires memory bus interface");
+        self.store16(bus, addr, self.read_gpr_word(rt) as u16);
     }
 
-    fn do_swl(&mut self, raw: u32) {
+    fn do_swl(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SWL rt, offset(base) - Store Word Left
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 3) * 8;
 
-        unimplemented!("SWL requires memory bus interface");
+        let Some(mem) = self.merge_base(bus, addr & !3, |bus, addr| bus.read32(addr)) else {
+            return;
+        };
+        let mask = (0xFFFF_FF00_u64 << shift) as u32;
+        let value = (self.read_gpr_word(rt) >> (24 - shift)) | (mem & mask);
+        self.store32(bus, addr & !3, value);
     }
 
-    fn do_sw(&mut self, raw: u32) {
+    fn do_sw(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SW rt, offset(base) - Store Word
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("SW requires memory bus interface");
+        self.store32(bus, addr, self.read_gpr_word(rt));
     }
 
-    fn do_sdl(&mut self, raw: u32) {
+    fn do_sdl(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SDL rt, offset(base) - Store Doubleword Left
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 7) * 8;
 
-        unimplemented!("SDL requires memory bus interface");
+        let Some(mem) = self.merge_base(bus, addr & !7, |bus, addr| bus.read64(addr)) else {
+            return;
+        };
+        let mask = (0xFFFF_FFFF_FFFF_FF00_u128 << shift) as u64;
+        let value = (self.read_gpr_dword(rt) >> (56 - shift)) | (mem & mask);
+        self.store64(bus, addr & !7, value);
     }
 
-    fn do_sdr(&mut self, raw: u32) {
+    fn do_sdr(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SDR rt, offset(base) - Store Doubleword Right
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 7) * 8;
 
-        unimplemented!("SDR requires memory bus interface");
+        let Some(mem) = self.merge_base(bus, addr & !7, |bus, addr| bus.read64(addr)) else {
+            return;
+        };
+        let mask = ((1_u128 << shift) - 1) as u64;
+        let value = (self.read_gpr_dword(rt) << shift) | (mem & mask);
+        self.store64(bus, addr & !7, value);
     }
 
-    fn do_swr(&mut self, raw: u32) {
+    fn do_swr(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SWR rt, offset(base) - Store Word Right
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
+        let shift = (addr & 3) * 8;
 
-        unimplemented!("SWR requires memory bus interface");
+        let Some(mem) = self.merge_base(bus, addr & !3, |bus, addr| bus.read32(addr)) else {
+            return;
+        };
+        let mask = ((1_u64 << shift) - 1) as u32;
+        let value = (self.read_gpr_word(rt) << shift) | (mem & mask);
+        self.store32(bus, addr & !3, value);
     }
 
     fn do_cache(&mut self, raw: u32) {
         // CACHE op, offset(base) - Cache operation
+        // Caches aren't modelled, so invalidating/writing back/locking lines has nothing to do
         let _base = Self::extract_rs(raw);
         let _op = Self::extract_rt(raw);
         let _offset = raw as i16;
-
-        // Cache instructions are typically no-ops in emulation
-        // or require specific cache simulation
-        unimplemented!("CACHE instruction");
     }
 
     fn do_lwc1(&mut self, raw: u32) {
@@ -754,7 +1365,7 @@ impl Cpu {
         let _ft = Self::extract_rt(raw);
         let _offset = raw as i16;
 
-        unimplemented!("LWC1 requires FPU and memory bus interface");
+        self.unimplemented(format_args!("LWC1 requires FPU and memory bus interface"));
     }
 
     fn do_pref(&mut self, _raw: u32) {
@@ -769,16 +1380,17 @@ impl Cpu {
         let _vt = Self::extract_rt(raw);
         let _offset = raw as i16;
 
-        unimplemented!("LQC2 requires VU and memory bus interface");
+        self.unimplemented(format_args!("LQC2 requires VU and memory bus interface"));
     }
 
-    fn do_ld(&mut self, raw: u32) {
+    fn do_ld(&mut self, bus: &mut dyn Bus, raw: u32) {
         // LD rt, offset(base) - Load Doubleword
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("LD requires memory bus interface");
+        if let Some(value) = self.load64(bus, addr) {
+            self.write_gpr_dword(rt, value);
+        }
     }
 
     fn do_swc1(&mut self, raw: u32) {
@@ -787,7 +1399,7 @@ impl Cpu {
         let _ft = Self::extract_rt(raw);
         let _offset = raw as i16;
 
-        unimplemented!("SWC1 requires FPU and memory bus interface");
+        self.unimplemented(format_args!("SWC1 requires FPU and memory bus interface"));
     }
 
     fn do_sqc2(&mut self, raw: u32) {
@@ -796,16 +1408,15 @@ impl Cpu {
         let _vt = Self::extract_rt(raw);
         let _offset = raw as i16;
 
-        unimplemented!("SQC2 requires VU and memory bus interface");
+        self.unimplemented(format_args!("SQC2 requires VU and memory bus interface"));
     }
 
-    fn do_sd(&mut self, raw: u32) {
+    fn do_sd(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SD rt, offset(base) - Store Doubleword
-        let _base = Self::extract_rs(raw);
-        let _rt = Self::extract_rt(raw);
-        let _offset = raw as i16;
+        let rt = Self::extract_rt(raw);
+        let addr = self.effective_address(raw);
 
-        unimplemented!("SD requires memory bus interface");
+        self.store64(bus, addr, self.read_gpr_dword(rt));
     }
 
     fn do_sll(&mut self, raw: u32) {
@@ -876,7 +1487,11 @@ impl Cpu {
         let rs = Self::extract_rs(raw);
         // TODO: check rs alignment (should be checked during the regular fetch though)
         // NOTE: technically this should 'read_gpr_dword', but on the PS2 EE the bit width of PC is 32, so...
-        self.next_pc = self.read_gpr_word(rs);
+        let target = self.read_gpr_word(rs);
+        if rs == 31 {
+            self.call_stack.ret(target);
+        }
+        self.branch_to(target);
     }
 
     fn do_jalr(&mut self, raw: u32) {
@@ -886,8 +1501,15 @@ impl Cpu {
         // TODO: check rs alignment (should be checked during the regular fetch though)
         // TODO: make sure rs != rd
 
+        // Read before linking, in case rs == rd
+        let target = self.read_gpr_word(rs);
         self.write_gpr_dword(rd, (self.pc + 8) as u64);
-        self.next_pc = self.read_gpr_word(rs);
+        // `jalr $zero, rs` is just a jump
+        if rd == 0 {
+            self.branch_to(target);
+        } else {
+            self.call(target);
+        }
     }
 
     fn do_movz(&mut self, raw: u32) {
@@ -912,16 +1534,36 @@ impl Cpu {
         }
     }
 
-    fn do_syscall(&mut self, _raw: u32) {
+    fn do_syscall(&mut self, bus: &mut dyn Bus, raw: u32) {
         // SYSCALL - System Call
-        // Trigger system call exception (implementation depends on system)
-        panic!("SYSCALL instruction executed");
+        let code = (raw >> 6) & 0xF_FFFF;
+        self.notify(|observer, cpu| observer.syscall(cpu, code));
+        if let Some(mut handler) = self.syscall_handler.take() {
+            let outcome = handler.syscall(self, bus);
+            self.syscall_handler = Some(handler);
+            match outcome {
+                SyscallOutcome::Handled => return,
+                SyscallOutcome::Exit(status) => {
+                    self.exit_status = Some(status);
+                    return;
+                }
+                SyscallOutcome::Unhandled => {}
+            }
+        }
+        self.raise_exception(Exception::Syscall);
     }
 
-    fn do_break(&mut self, _raw: u32) {
+    fn do_break(&mut self, raw: u32) {
         // BREAK - Breakpoint
-        // Trigger breakpoint exception
-        panic!("BREAK instruction executed");
+        // With a debugger attached this stops execution (past the BREAK) instead of trapping
+        let reason = StopReason::BreakInstruction {
+            pc: self.pc,
+            code: (raw >> 6) & 0xF_FFFF,
+        };
+        match self.debugger.as_mut() {
+            Some(debugger) => debugger.stop(reason),
+            None => self.raise_exception(Exception::Breakpoint),
+        }
     }
 
     // SKIPPED THIS
@@ -933,6 +1575,7 @@ impl Cpu {
     fn do_mfhi(&mut self, raw: u32) {
         // MFHI rd - Move From HI
         let rd = Self::extract_rd(raw);
+        self.wait_for_hilo();
         // TODO: need to make sure that the 2 preceding instructions don't modify HI0
         self.write_gpr_dword(rd, self.hi0);
     }
@@ -940,6 +1583,7 @@ impl Cpu {
     fn do_mthi(&mut self, raw: u32) {
         // MTHI rs - Move To HI
         let rs = Self::extract_rs(raw);
+        self.wait_for_hilo();
         // TODO: need to make sure that the 2 following instructions don't modify HI0
         self.hi0 = self.read_gpr_dword(rs);
     }
@@ -947,6 +1591,7 @@ impl Cpu {
     fn do_mflo(&mut self, raw: u32) {
         // MFLO rd - Move From LO
         let rd = Self::extract_rd(raw);
+        self.wait_for_hilo();
         // TODO: need to make sure that the 2 preceding instructions don't modify HI0
         self.write_gpr_dword(rd, self.lo0);
     }
@@ -954,6 +1599,7 @@ impl Cpu {
     fn do_mtlo(&mut self, raw: u32) {
         // MTLO rs - Move To LO
         let rs = Self::extract_rs(raw);
+        self.wait_for_hilo();
         // TODO: need to make sure that the 2 following instructions don't modify HI0
         self.lo0 = self.read_gpr_dword(rs);
     }
@@ -991,9 +1637,20 @@ impl Cpu {
         self.write_gpr_dword(rd, result as u64);
     }
 
+    // Stalls until the multiply/divide unit's last result has landed
+    fn wait_for_hilo(&mut self) {
+        self.cycles = self.cycles.max(self.hilo_ready);
+    }
+
+    fn start_hilo_op(&mut self, latency: u64) {
+        self.wait_for_hilo();
+        self.hilo_ready = self.cycles + latency;
+    }
+
     fn do_mult(&mut self, raw: u32) {
         let rs = Self::extract_rs(raw);
         let rt = Self::extract_rt(raw);
+        self.start_hilo_op(Self::MULT_LATENCY);
 
         let a = self.read_gpr_word(rs) as i32 as i64;
         let b = self.read_gpr_word(rt) as i32 as i64;
@@ -1006,6 +1663,7 @@ impl Cpu {
     fn do_multu(&mut self, raw: u32) {
         let rs = Self::extract_rs(raw);
         let rt = Self::extract_rt(raw);
+        self.start_hilo_op(Self::MULT_LATENCY);
 
         let a = self.read_gpr_word(rs) as u64;
         let b = self.read_gpr_word(rt) as u64;
@@ -1019,15 +1677,19 @@ impl Cpu {
         // DIV rs, rt - Divide Word
         let rs = Self::extract_rs(raw);
         let rt = Self::extract_rt(raw);
+        self.start_hilo_op(Self::DIV_LATENCY);
 
         let rs_value = self.read_gpr_word(rs) as i32 as i64;
         let rt_value = self.read_gpr_word(rt) as i32 as i64;
 
         if rt_value == 0 {
-            // in this case, lo0 and hi0 should be in an "undefined state". I think a NOP will do the job
+            // The R5900 leaves the dividend in HI and -1 or 1 in LO, against the dividend's sign
+            self.lo0 = if rs_value >= 0 { u64::MAX } else { 1 };
+            self.hi0 = rs_value as u64;
         } else {
-            self.lo0 = rs_value.wrapping_div(rt_value) as u64;
-            self.hi0 = rs_value.wrapping_rem(rt_value) as u64;
+            // i32::MIN / -1 overflows the word and wraps back to i32::MIN
+            self.lo0 = rs_value.wrapping_div(rt_value) as u32 as i32 as u64;
+            self.hi0 = rs_value.wrapping_rem(rt_value) as u32 as i32 as u64;
         }
     }
 
@@ -1035,15 +1697,23 @@ impl Cpu {
         // DIVU rs, rt - Divide Unsigned Word
         let rs = Self::extract_rs(raw);
         let rt = Self::extract_rt(raw);
+        self.start_hilo_op(Self::DIV_LATENCY);
 
         let rs_value = self.read_gpr_word(rs) as u64;
         let rt_value = self.read_gpr_word(rt) as u64;
 
-        if rt_value == 0 {
-            // in this case, lo0 and hi0 should be in an "undefined state". I think a NOP will do the job
-        } else {
-            self.lo0 = rs_value / rt_value;
-            self.hi0 = rs_value % rt_value;
+        match rs_value.checked_div(rt_value) {
+            // Both halves are words, sign-extended into the 64-bit registers
+            Some(quotient) => {
+                self.lo0 = quotient as u32 as i32 as u64;
+                self.hi0 = (rs_value % rt_value) as u32 as i32 as u64;
+            }
+            // The R5900 leaves all ones in LO and the dividend in HI, sign-extended like every
+            // result
+            None => {
+                self.lo0 = u64::MAX;
+                self.hi0 = rs_value as u32 as i32 as u64;
+            }
         }
     }
 
@@ -1058,7 +1728,7 @@ impl Cpu {
 
         match rs_value.checked_add(rt_value) {
             Some(result) => self.write_gpr_dword(rd, result as i64 as u64),
-            None => panic!("ADD overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -1086,7 +1756,7 @@ impl Cpu {
 
         match rs_value.checked_sub(rt_value) {
             Some(result) => self.write_gpr_dword(rd, result as i64 as u64),
-            None => panic!("SUB overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -1190,7 +1860,7 @@ impl Cpu {
 
         match rs_value.checked_add(rt_value) {
             Some(result) => self.write_gpr_dword(rd, result as u64),
-            None => panic!("DADD overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -1218,7 +1888,7 @@ impl Cpu {
 
         match rs_value.checked_sub(rt_value) {
             Some(result) => self.write_gpr_dword(rd, result as u64),
-            None => panic!("DSUB overflow exception"),
+            None => self.raise_exception(Exception::Overflow),
         }
     }
 
@@ -1242,7 +1912,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if (self.read_gpr_dword(rs) as i64) >= (self.read_gpr_dword(rt) as i64) {
-            panic!("TGE trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1252,7 +1922,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if self.read_gpr_dword(rs) >= self.read_gpr_dword(rt) {
-            panic!("TGEU trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1262,7 +1932,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if (self.read_gpr_dword(rs) as i64) < (self.read_gpr_dword(rt) as i64) {
-            panic!("TLT trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1272,7 +1942,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if self.read_gpr_dword(rs) < self.read_gpr_dword(rt) {
-            panic!("TLTU trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1282,7 +1952,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if self.read_gpr_qword(rs) == self.read_gpr_qword(rt) {
-            panic!("TEQ trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1292,7 +1962,7 @@ impl Cpu {
         let rt = Self::extract_rt(raw);
 
         if self.read_gpr_qword(rs) != self.read_gpr_qword(rt) {
-            panic!("TNE trap exception");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1356,6 +2026,95 @@ impl Cpu {
         self.write_gpr_dword(rd, result as u64);
     }
 
+    // COP0 stuff
+
+    fn handle_cop0(&mut self, raw: u32) {
+        let rs = (raw >> 21) & 0b11111;
+        match rs {
+            Self::COP0_MF0 => self.do_mfc0(raw),
+            Self::COP0_MT0 => self.do_mtc0(raw),
+            Self::COP0_C0 => {
+                let funct = raw & 0b111111;
+                match funct {
+                    Self::C0_FUNCT_TLBR
+                    | Self::C0_FUNCT_TLBWI
+                    | Self::C0_FUNCT_TLBWR
+                    | Self::C0_FUNCT_TLBP => self.do_tlb(raw),
+                    Self::C0_FUNCT_ERET => self.do_eret(raw),
+                    Self::C0_FUNCT_EI => self.do_ei(raw),
+                    Self::C0_FUNCT_DI => self.do_di(raw),
+                    _ => self.unimplemented(format_args!(
+                        "COP0 C0 function {:06b} not implemented",
+                        funct
+                    )),
+                }
+            }
+            _ => self.unimplemented(format_args!("COP0 rs {:05b} not implemented", rs)),
+        }
+    }
+
+    fn do_mfc0(&mut self, raw: u32) {
+        // MFC0 rt, rd - Move From COP0
+        let rt = Self::extract_rt(raw);
+        let rd = Self::extract_rd(raw);
+
+        self.write_gpr_dword(rt, self.cop0.read(rd) as i32 as i64 as u64);
+    }
+
+    fn do_mtc0(&mut self, raw: u32) {
+        // MTC0 rt, rd - Move To COP0
+        let rt = Self::extract_rt(raw);
+        let rd = Self::extract_rd(raw);
+
+        let value = self.read_gpr_word(rt);
+        self.cop0.write(rd, value);
+        self.notify(|observer, cpu| observer.cop0_write(cpu, rd, value));
+    }
+
+    fn do_tlb(&mut self, _raw: u32) {
+        // TLBR/TLBWI/TLBWR/TLBP
+        // The TLB isn't modelled; the bus is expected to map kuseg directly, the way the EE
+        // kernel sets it up
+    }
+
+    // Status changes made by instructions other than MTC0 are reported to observers the same way
+    pub(crate) fn set_status(&mut self, status: u32) {
+        self.cop0.set(cop0::STATUS, status);
+        self.notify(|observer, cpu| observer.cop0_write(cpu, cop0::STATUS, status));
+    }
+
+    fn do_eret(&mut self, _raw: u32) {
+        // ERET - Exception Return (no delay slot)
+        let status = self.cop0.status();
+        self.branch_target = None;
+        if status & cop0::STATUS_ERL != 0 {
+            self.next_pc = self.cop0.read(cop0::ERROR_EPC);
+            self.set_status(status & !cop0::STATUS_ERL);
+        } else {
+            self.next_pc = self.cop0.read(cop0::EPC);
+            self.set_status(status & !cop0::STATUS_EXL);
+        }
+    }
+
+    // EI and DI do nothing outside kernel mode unless Status.EDI allows them
+    fn may_toggle_eie(&self) -> bool {
+        self.cop0.kernel_mode() || self.cop0.status() & cop0::STATUS_EDI != 0
+    }
+
+    fn do_ei(&mut self, _raw: u32) {
+        // EI - Enable Interrupts
+        if self.may_toggle_eie() {
+            self.set_status(self.cop0.status() | cop0::STATUS_EIE);
+        }
+    }
+
+    fn do_di(&mut self, _raw: u32) {
+        // DI - Disable Interrupts
+        if self.may_toggle_eie() {
+            self.set_status(self.cop0.status() & !cop0::STATUS_EIE);
+        }
+    }
+
     // REGIMM stuff
 
     fn do_bltz(&mut self, raw: u32) {
@@ -1364,7 +2123,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) < 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -1374,7 +2133,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) >= 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -1384,7 +2143,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) < 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -1397,7 +2156,7 @@ impl Cpu {
         let offset = (raw as i16 as i32) << 2;
 
         if (self.read_gpr_dword(rs) as i64) >= 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.branch_to((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -1410,7 +2169,7 @@ impl Cpu {
         let imm = raw as i16 as i64;
 
         if (self.read_gpr_dword(rs) as i64) >= imm {
-            panic!("Trap exception: TGEI");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1420,7 +2179,7 @@ impl Cpu {
         let imm = raw as i16 as u64;
 
         if self.read_gpr_dword(rs) >= imm {
-            panic!("Trap exception: TGEIU");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1430,7 +2189,7 @@ impl Cpu {
         let imm = raw as i16 as i64;
 
         if (self.read_gpr_dword(rs) as i64) < imm {
-            panic!("Trap exception: TLTI");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1440,7 +2199,7 @@ impl Cpu {
         let imm = raw as i16 as i64 as u64;
 
         if self.read_gpr_dword(rs) < imm {
-            panic!("Trap exception: TLTIU");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1450,7 +2209,7 @@ impl Cpu {
         let imm = raw as i16 as i64;
 
         if (self.read_gpr_dword(rs) as i64) == imm {
-            panic!("Trap exception: TEQI");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1460,7 +2219,7 @@ impl Cpu {
         let imm = raw as i16 as i64;
 
         if (self.read_gpr_dword(rs) as i64) != imm {
-            panic!("Trap exception: TNEI");
+            self.raise_exception(Exception::Trap);
         }
     }
 
@@ -1473,7 +2232,7 @@ impl Cpu {
         self.write_gpr_dword(31, self.pc as u64 + 8);
 
         if (self.read_gpr_dword(rs) as i64) < 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.call((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -1486,7 +2245,7 @@ impl Cpu {
         self.write_gpr_dword(31, self.pc as u64 + 8);
 
         if (self.read_gpr_dword(rs) as i64) >= 0 {
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.call((self.pc as i32 + 4 + offset) as u32);
         }
     }
 
@@ -1498,7 +2257,7 @@ impl Cpu {
         if (self.read_gpr_dword(rs) as i64) < 0 {
             // Store return address in $31
             self.write_gpr_dword(31, self.pc as u64 + 8);
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.call((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -1513,7 +2272,7 @@ impl Cpu {
         if (self.read_gpr_dword(rs) as i64) >= 0 {
             // Store return address in $31
             self.write_gpr_dword(31, self.pc as u64 + 8);
-            self.next_pc = (self.pc as i32 + 4 + offset) as u32;
+            self.call((self.pc as i32 + 4 + offset) as u32);
         } else {
             // Skip delay slot (nullify)
             self.next_pc += 4;
@@ -1538,3 +2297,180 @@ impl Cpu {
         self.sa = (((rs_val ^ imm) & 0b111) as u64) * 16;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+
+    const DIV_T0_T1: u32 = 0x0109_001A; // div t0, t1
+    const DIVU_T0_T1: u32 = 0x0109_001B; // divu t0, t1
+
+    fn divide(raw: u32, dividend: u32, divisor: u32) -> (u64, u64) {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::new(0x1000);
+        cpu.gprs[8] = dividend as i32 as i64 as u64 as u128;
+        cpu.gprs[9] = divisor as i32 as i64 as u64 as u128;
+        cpu.exec(&mut ram, raw);
+        (cpu.lo0, cpu.hi0)
+    }
+
+    #[test]
+    fn divide_by_zero_gives_r5900_results() {
+        assert_eq!(divide(DIVU_T0_T1, 1234, 0), (u64::MAX, 1234));
+        assert_eq!(
+            divide(DIVU_T0_T1, 0x8000_0000, 0),
+            (u64::MAX, 0xFFFF_FFFF_8000_0000)
+        );
+        assert_eq!(divide(DIV_T0_T1, 1234, 0), (u64::MAX, 1234));
+        assert_eq!(divide(DIV_T0_T1, 0, 0), (u64::MAX, 0));
+        assert_eq!(divide(DIV_T0_T1, -5i32 as u32, 0), (1, -5i64 as u64));
+    }
+
+    #[test]
+    fn divides_normally_otherwise() {
+        assert_eq!(divide(DIVU_T0_T1, 7, 2), (3, 1));
+        assert_eq!(
+            divide(DIV_T0_T1, -7i32 as u32, 2),
+            (-3i64 as u64, -1i64 as u64)
+        );
+        assert_eq!(
+            divide(DIVU_T0_T1, 0x8000_0000, 1),
+            (0xFFFF_FFFF_8000_0000, 0)
+        );
+        assert_eq!(
+            divide(DIVU_T0_T1, 0xFFFF_FFFF, 0x8000_0000),
+            (1, 0x7FFF_FFFF)
+        );
+        assert_eq!(divide(DIVU_T0_T1, 0xFFFF_FFFF, 0xFFFF_FFFE), (1, 1));
+        assert_eq!(
+            divide(DIVU_T0_T1, 0xFFFF_FFFE, 0xFFFF_FFFF),
+            (0, 0xFFFF_FFFF_FFFF_FFFE)
+        );
+        assert_eq!(
+            divide(DIV_T0_T1, 0x8000_0000, -1i32 as u32),
+            (0xFFFF_FFFF_8000_0000, 0)
+        );
+        assert_eq!(
+            divide(DIV_T0_T1, 0x8000_0000, 1),
+            (0xFFFF_FFFF_8000_0000, 0)
+        );
+    }
+
+    const MULT_T0_T1: u32 = 0x0109_0018; // mult t0, t1
+    const MFLO_T2: u32 = 0x0000_5012; // mflo t2
+    const MFHI_T2: u32 = 0x0000_5010; // mfhi t2
+    const NOP: u32 = 0;
+
+    // Cycles taken to run `program` from the start
+    fn cycles_for(program: &[u32]) -> u64 {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::new(0x1000);
+        for (index, &raw) in program.iter().enumerate() {
+            ram.write32(4 * index as u32, raw).unwrap();
+        }
+        cpu.set_pc(0);
+        cpu.gprs[8] = 100;
+        cpu.gprs[9] = 7;
+        for _ in program {
+            cpu.step(&mut ram);
+        }
+        cpu.cycles()
+    }
+
+    #[test]
+    fn reading_hi_lo_waits_for_the_result() {
+        assert_eq!(cycles_for(&[DIV_T0_T1, MFLO_T2]), 1 + Cpu::DIV_LATENCY);
+        assert_eq!(cycles_for(&[DIV_T0_T1, MFHI_T2]), 1 + Cpu::DIV_LATENCY);
+        assert_eq!(cycles_for(&[MULT_T0_T1, MFLO_T2]), 1 + Cpu::MULT_LATENCY);
+        // A second divide can't start until the first is done
+        assert_eq!(
+            cycles_for(&[DIV_T0_T1, DIV_T0_T1, MFLO_T2]),
+            1 + 2 * Cpu::DIV_LATENCY
+        );
+    }
+
+    #[test]
+    fn independent_instructions_hide_the_latency() {
+        let mut program = vec![DIV_T0_T1];
+        program.extend([NOP; 10]);
+        program.push(MFLO_T2);
+        assert_eq!(cycles_for(&program), 1 + Cpu::DIV_LATENCY);
+
+        let mut program = vec![DIV_T0_T1];
+        program.extend([NOP; 40]);
+        program.push(MFLO_T2);
+        assert_eq!(cycles_for(&program), 42);
+        // Nothing reads HI/LO, so nothing waits
+        assert_eq!(cycles_for(&[DIV_T0_T1, NOP]), 2);
+    }
+
+    const MTC0_T0_COMPARE: u32 = 0x4088_5800; // mtc0 t0, Compare
+
+    // A CPU running nops from 0 with Compare at `compare` and Status set to `status`
+    fn timer_cpu(compare: u32, status: u32) -> (Cpu, Ram) {
+        let mut cpu = Cpu::new();
+        let ram = Ram::new(0x1000);
+        cpu.set_pc(0);
+        cpu.cop0.write(cop0::COMPARE, compare);
+        cpu.cop0.set(cop0::STATUS, status);
+        (cpu, ram)
+    }
+
+    const TIMER_ENABLED: u32 = cop0::STATUS_IE | cop0::STATUS_EIE | 1 << 15;
+
+    #[test]
+    fn count_matching_compare_takes_the_timer_interrupt() {
+        let (mut cpu, mut ram) = timer_cpu(5, TIMER_ENABLED);
+        for _ in 0..4 {
+            cpu.step(&mut ram);
+        }
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_IP7, 0);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.cop0().read(cop0::COUNT), 5);
+        assert_ne!(cpu.cop0().cause() & cop0::CAUSE_IP7, 0);
+        assert_eq!(cpu.pc(), 20);
+
+        // Taken before the next instruction runs, which is where the handler returns to
+        cpu.step(&mut ram);
+        assert_eq!(cpu.pc(), 0x8000_0200);
+        assert_eq!(cpu.cop0().read(cop0::EPC), 20);
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_EXC_CODE_MASK, 0);
+        assert_ne!(cpu.cop0().status() & cop0::STATUS_EXL, 0);
+    }
+
+    #[test]
+    fn the_timer_interrupt_waits_for_ie_eie_and_im7() {
+        for status in [
+            TIMER_ENABLED & !cop0::STATUS_IE,
+            TIMER_ENABLED & !cop0::STATUS_EIE,
+            TIMER_ENABLED & !(1 << 15),
+            TIMER_ENABLED | cop0::STATUS_EXL,
+            TIMER_ENABLED | cop0::STATUS_ERL,
+        ] {
+            let (mut cpu, mut ram) = timer_cpu(1, status);
+            cpu.step(&mut ram);
+            assert_ne!(cpu.cop0().cause() & cop0::CAUSE_IP7, 0);
+            cpu.step(&mut ram);
+            assert_eq!(cpu.pc(), 8, "status {status:#x}");
+        }
+    }
+
+    #[test]
+    fn writing_compare_acknowledges_the_timer_interrupt() {
+        // Masked, so it stays pending until the program gets round to acknowledging it
+        let (mut cpu, mut ram) = timer_cpu(1, TIMER_ENABLED & !cop0::STATUS_IE);
+        ram.write32(4, MTC0_T0_COMPARE).unwrap();
+        cpu.gprs[8] = 100;
+        cpu.step(&mut ram);
+        assert_ne!(cpu.cop0().cause() & cop0::CAUSE_IP7, 0);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.cop0().read(cop0::COMPARE), 100);
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_IP7, 0);
+
+        // So enabling interrupts afterwards takes nothing
+        cpu.cop0.set(cop0::STATUS, TIMER_ENABLED);
+        cpu.step(&mut ram);
+        assert_eq!(cpu.pc(), 12);
+    }
+}
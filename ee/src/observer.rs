@@ -0,0 +1,134 @@
+// Hooks into instruction execution. Observers are installed on a `Cpu` with `add_observer` and
+// are called in installation order; every callback has an empty default so implementations only
+// override what they care about. When no observer is installed none of this costs more than an
+// emptiness check.
+
+use crate::Cpu;
+use crate::bus::Bus;
+use crate::cop0::Exception;
+
+pub trait CpuObserver {
+    // `cpu.pc()` is the address of the instruction about to run
+    fn before_instruction(&mut self, _cpu: &Cpu, _bus: &mut dyn Bus, _raw: u32) {}
+
+    // Called with the register state the instruction left behind; `pc` is its address
+    fn after_instruction(&mut self, _cpu: &Cpu, _bus: &mut dyn Bus, _pc: u32, _raw: u32) {}
+
+    // Data accesses made by loads and stores, `size` is in bytes. Instruction fetches are not
+    // reported.
+    fn memory_read(&mut self, _cpu: &Cpu, _addr: u32, _size: usize, _value: u128) {}
+
+    fn memory_write(&mut self, _cpu: &Cpu, _addr: u32, _size: usize, _value: u128) {}
+
+    // Called once COP0 has been updated, so EPC/Cause/BadVAddr describe the exception
+    fn exception_taken(&mut self, _cpu: &Cpu, _exception: Exception) {}
+
+    fn cop0_write(&mut self, _cpu: &Cpu, _reg: usize, _value: u32) {}
+
+    // Called before the syscall exception is raised; `code` is the instruction's code field
+    fn syscall(&mut self, _cpu: &Cpu, _code: u32) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Ram;
+    use crate::cop0;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Accesses {
+        reads: Vec<u32>,
+        writes: Vec<(u32, u128)>,
+    }
+
+    struct Recorder(Rc<RefCell<Accesses>>);
+
+    impl CpuObserver for Recorder {
+        fn memory_read(&mut self, _cpu: &Cpu, addr: u32, _size: usize, _value: u128) {
+            self.0.borrow_mut().reads.push(addr);
+        }
+
+        fn memory_write(&mut self, _cpu: &Cpu, addr: u32, _size: usize, value: u128) {
+            self.0.borrow_mut().writes.push((addr, value));
+        }
+    }
+
+    struct StatusWrites(Rc<RefCell<Vec<u32>>>);
+
+    impl CpuObserver for StatusWrites {
+        fn cop0_write(&mut self, _cpu: &Cpu, reg: usize, value: u32) {
+            if reg == cop0::STATUS {
+                self.0.borrow_mut().push(value);
+            }
+        }
+    }
+
+    const EI: u32 = 0x4200_0038;
+    const DI: u32 = 0x4200_0039;
+    const ERET: u32 = 0x4200_0018;
+
+    #[test]
+    fn ei_di_and_eret_report_the_new_status() {
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let mut cpu = Cpu::new();
+        cpu.add_observer(Box::new(StatusWrites(writes.clone())));
+        let mut ram = Ram::new(0x1000);
+        cpu.cop0
+            .set(cop0::STATUS, cop0::STATUS_EXL | cop0::STATUS_IE);
+        cpu.cop0.set(cop0::EPC, 0x100);
+
+        cpu.exec(&mut ram, EI);
+        cpu.exec(&mut ram, DI);
+        cpu.exec(&mut ram, ERET);
+
+        let eie = cop0::STATUS_EXL | cop0::STATUS_IE | cop0::STATUS_EIE;
+        assert_eq!(
+            *writes.borrow(),
+            [eie, eie & !cop0::STATUS_EIE, cop0::STATUS_IE]
+        );
+    }
+
+    #[test]
+    fn di_needs_kernel_mode_or_edi() {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::new(0x1000);
+        let user = 2 << 3 | cop0::STATUS_EIE;
+        cpu.cop0.set(cop0::STATUS, user);
+        cpu.exec(&mut ram, DI);
+        assert_eq!(cpu.cop0().status(), user);
+
+        cpu.cop0.set(cop0::STATUS, user | cop0::STATUS_EDI);
+        cpu.exec(&mut ram, DI);
+        assert_eq!(
+            cpu.cop0().status(),
+            user & !cop0::STATUS_EIE | cop0::STATUS_EDI
+        );
+
+        cpu.cop0.set(cop0::STATUS, cop0::STATUS_EIE);
+        cpu.exec(&mut ram, DI);
+        assert_eq!(cpu.cop0().status(), 0);
+    }
+
+    #[test]
+    fn unaligned_stores_report_only_their_write() {
+        let accesses = Rc::new(RefCell::new(Accesses::default()));
+        let mut cpu = Cpu::new();
+        cpu.add_observer(Box::new(Recorder(accesses.clone())));
+        let mut ram = Ram::new(0x1000);
+        ram.write32(0x100, 0xAABB_CCDD).unwrap();
+        cpu.write_gpr_dword(9, 0x1122_3344);
+        cpu.write_gpr_dword(8, 0x100);
+
+        cpu.exec(&mut ram, 0xA909_0001); // swl t1, 1(t0)
+        cpu.exec(&mut ram, 0xB909_0002); // swr t1, 2(t0)
+
+        let accesses = accesses.borrow();
+        assert!(accesses.reads.is_empty());
+        assert_eq!(
+            accesses.writes,
+            [(0x100, 0xAABB_1122), (0x100, 0x3344_1122)]
+        );
+    }
+}
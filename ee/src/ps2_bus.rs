@@ -0,0 +1,841 @@
+// The EE's physical memory map behind its virtual segments.
+//
+// Virtual addresses are translated the way the BIOS leaves the TLB set up:
+//
+//     0x00000000-0x1FFFFFFF  kuseg, identity mapped
+//     0x20000000-0x21FFFFFF  main RAM, uncached
+//     0x30000000-0x31FFFFFF  main RAM, uncached accelerated
+//     0x70000000-0x70003FFF  scratchpad RAM
+//     0x80000000-0x9FFFFFFF  kseg0, physical memory (cached)
+//     0xA0000000-0xBFFFFFFF  kseg1, physical memory (uncached)
+//
+// and physical addresses are routed to:
+//
+//     0x00000000-0x0FFFFFFF  32 MiB main RAM, mirrored
+//     0x10000000-0x1000FFFF  hardware registers (timers, IPU, GIF, VIF, DMAC, INTC, SIO, ...)
+//     0x11000000-0x1100FFFF  VU0/VU1 micro and data memory
+//     0x12000000-0x12001FFF  GS privileged registers
+//     0x1FC00000-0x1FFFFFFF  4 MiB BIOS ROM, writes are ignored
+//
+// Anything else, including kseg2/kseg3, fails with a `BusError`, which the CPU turns into a bus
+// error exception. Hardware and GS registers are plain storage until the devices behind them
+// are emulated. So far that is the interrupt controller (INTC_STAT, INTC_MASK and D_STAT), the
+// timers, the DMAC and the GIF, and bytes written to the SIO transmit register go to the console.
+// Whatever the GIF DMA channel brings in goes straight on to the GIF's PATH3, and the GIF's
+// output to the GS.
+//
+// The bus also keeps the machine's event scheduler. Hblank and vblank run at NTSC timing off
+// it, vblank setting VSINT in the GS CSR and raising VBON when it starts and VBOF when it ends.
+
+use std::fmt;
+
+use crate::Cpu;
+use crate::bus::{Bus, BusError};
+use crate::console::Console;
+use crate::cop0;
+use crate::dmac::{self, DmaMemory, Dmac};
+use crate::gif::{self, Gif, Path};
+use crate::gs::Gs;
+use crate::intc::{self, DmaInterrupts, Intc};
+use crate::savestate::{self, Snapshot, StateError, StateReader, StateWriter};
+use crate::scheduler::{Event, Scheduler, TimedBus};
+use crate::timer::{TIMER_COUNT, Timers};
+
+pub const RAM_SIZE: usize = 32 * 1024 * 1024;
+pub const BIOS_SIZE: usize = 4 * 1024 * 1024;
+pub const SCRATCHPAD_SIZE: usize = 16 * 1024;
+
+pub const RAM_BASE: u32 = 0x0000_0000;
+pub const HW_BASE: u32 = 0x1000_0000;
+pub const VU_BASE: u32 = 0x1100_0000;
+pub const GS_PRIV_BASE: u32 = 0x1200_0000;
+pub const BIOS_BASE: u32 = 0x1FC0_0000;
+pub const SCRATCHPAD_BASE: u32 = 0x7000_0000;
+// SIO UART transmit FIFO, where debug output is written a byte at a time
+pub const SIO_TXFIFO: u32 = 0x1000_F180;
+pub const GS_CSR: u32 = 0x1200_1000;
+pub const GS_CSR_VSINT: u32 = 1 << 3;
+
+// NTSC video timing in EE cycles: 59.94 fields a second, vblank covering 22 of the 262.5 lines
+pub const FRAME_CYCLES: u64 = 4_920_115;
+pub const VBLANK_CYCLES: u64 = 412_361;
+pub const LINE_CYCLES: u64 = 18_743;
+
+const RAM_MIRROR_END: u32 = 0x1000_0000;
+const HW_SIZE: usize = 0x1_0000;
+const VU_SIZE: usize = 0x1_0000;
+const GS_PRIV_SIZE: usize = 0x2000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RomTooLarge {
+    pub size: usize,
+}
+
+impl fmt::Display for RomTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BIOS image is {} bytes, the ROM only holds {}",
+            self.size, BIOS_SIZE
+        )
+    }
+}
+
+impl std::error::Error for RomTooLarge {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Region {
+    Ram(usize),
+    Scratchpad(usize),
+    Hw(usize),
+    Vu(usize),
+    GsPriv(usize),
+    Bios(usize),
+}
+
+pub struct Ps2Bus {
+    ram: Vec<u8>,
+    scratchpad: Vec<u8>,
+    bios: Vec<u8>,
+    hw_regs: Vec<u8>,
+    vu_mem: Vec<u8>,
+    gs_priv: Vec<u8>,
+    // Without one, SIO output is dropped
+    console: Option<Console>,
+    scheduler: Scheduler,
+    // Vblanks started since power-on
+    frames: u64,
+    intc: Intc,
+    dma_interrupts: DmaInterrupts,
+    timers: Timers,
+    dmac: Dmac,
+    gif: Gif,
+    gs: Gs,
+}
+
+impl Default for Ps2Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ps2Bus {
+    // Starts with an empty (all zero) BIOS ROM
+    pub fn new() -> Self {
+        Ps2Bus {
+            ram: vec![0; RAM_SIZE],
+            scratchpad: vec![0; SCRATCHPAD_SIZE],
+            bios: vec![0; BIOS_SIZE],
+            hw_regs: vec![0; HW_SIZE],
+            vu_mem: vec![0; VU_SIZE],
+            gs_priv: vec![0; GS_PRIV_SIZE],
+            console: None,
+            scheduler: Self::power_on_schedule(),
+            frames: 0,
+            intc: Intc::new(),
+            dma_interrupts: DmaInterrupts::new(),
+            timers: Timers::new(),
+            dmac: Dmac::new(),
+            gif: Gif::new(),
+            gs: Gs::new(),
+        }
+    }
+
+    // The first field's active display comes before its vblank
+    fn power_on_schedule() -> Scheduler {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(Event::HBlank, LINE_CYCLES);
+        scheduler.schedule_at(Event::VBlankStart, FRAME_CYCLES - VBLANK_CYCLES);
+        scheduler
+    }
+
+    // Images smaller than the ROM are placed at its start and the rest is zero filled
+    pub fn load_bios(&mut self, image: &[u8]) -> Result<(), RomTooLarge> {
+        if image.len() > BIOS_SIZE {
+            return Err(RomTooLarge { size: image.len() });
+        }
+        self.bios.fill(0);
+        self.bios[..image.len()].copy_from_slice(image);
+        Ok(())
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn bios(&self) -> &[u8] {
+        &self.bios
+    }
+
+    pub fn set_console(&mut self, console: Console) {
+        self.console = Some(console);
+    }
+
+    pub fn take_console(&mut self) -> Option<Console> {
+        self.console.take()
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn intc(&self) -> &Intc {
+        &self.intc
+    }
+
+    pub fn intc_mut(&mut self) -> &mut Intc {
+        &mut self.intc
+    }
+
+    pub fn dma_interrupts(&self) -> &DmaInterrupts {
+        &self.dma_interrupts
+    }
+
+    pub fn dma_interrupts_mut(&mut self) -> &mut DmaInterrupts {
+        &mut self.dma_interrupts
+    }
+
+    // Raises an INTC interrupt. An SBUS interrupt also latches timers 0 and 1 into their hold
+    // registers.
+    pub fn raise_interrupt(&mut self, source: u32) {
+        if source == intc::INT_SBUS {
+            self.timers.latch_hold(self.scheduler.now(), &mut self.intc);
+        }
+        self.intc.raise(source);
+    }
+
+    pub fn dmac(&self) -> &Dmac {
+        &self.dmac
+    }
+
+    // Hands a device's data to the DMA channel coming from it
+    pub fn push_dma(&mut self, channel: usize, qword: u128) {
+        self.dmac.push(channel, qword);
+        self.schedule_kicked_dma();
+    }
+
+    // Takes data a DMA channel has brought to its device
+    pub fn pop_dma(&mut self, channel: usize) -> Option<u128> {
+        self.dmac.pop(channel)
+    }
+
+    pub fn gif(&self) -> &Gif {
+        &self.gif
+    }
+
+    pub fn gs(&self) -> &Gs {
+        &self.gs
+    }
+
+    pub fn gs_mut(&mut self) -> &mut Gs {
+        &mut self.gs
+    }
+
+    // Queues a quadword on one of the GIF's paths and lets the GIF process what it can
+    pub fn push_gif(&mut self, path: Path, qword: u128) {
+        self.gif.push(path, qword);
+        self.run_gif();
+    }
+
+    // VIF1's MSKPATH3
+    pub fn set_path3_masked(&mut self, masked: bool) {
+        self.gif.set_vif_mask(masked);
+        self.run_gif();
+    }
+
+    // Moves what the GIF DMA channel has delivered onto PATH3, then runs the GIF
+    fn run_gif(&mut self) {
+        while let Some(qword) = self.dmac.pop(dmac::GIF) {
+            self.gif.push(Path::Path3, qword);
+        }
+        self.gif.run(&mut self.gs);
+    }
+
+    // A channel already waiting on its slice's time keeps waiting
+    fn schedule_kicked_dma(&mut self) {
+        let now = self.scheduler.now();
+        for channel in self.dmac.take_kicked() {
+            let event = Event::Dma(channel as u8);
+            if self.scheduler.deadline(event).is_none() {
+                self.scheduler.schedule_at(event, now);
+            }
+        }
+    }
+
+    fn reschedule_timer(&mut self, index: usize) {
+        let event = Event::Timer(index as u8);
+        match self.timers.next_event(index) {
+            Some(cycle) => self.scheduler.schedule_at(event, cycle),
+            None => self.scheduler.cancel(event),
+        }
+    }
+
+    fn reschedule_timers(&mut self) {
+        for index in 0..TIMER_COUNT {
+            self.reschedule_timer(index);
+        }
+    }
+
+    fn gs_csr(&mut self) -> &mut [u8] {
+        let offset = (GS_CSR - GS_PRIV_BASE) as usize;
+        &mut self.gs_priv[offset..offset + 4]
+    }
+
+    fn set_gs_csr_bits(&mut self, bits: u32) {
+        let csr = self.gs_csr();
+        let value = u32::from_le_bytes(csr[..].try_into().unwrap()) | bits;
+        csr.copy_from_slice(&value.to_le_bytes());
+    }
+
+    // Virtual to physical translation, see the table at the top of the file
+    fn translate(addr: u32) -> Option<u32> {
+        match addr >> 28 {
+            0x0 | 0x1 => Some(addr),
+            0x2 | 0x3 if addr & 0x0FFF_FFFF < RAM_SIZE as u32 => Some(addr & 0x0FFF_FFFF),
+            0x8..=0xB => Some(addr & 0x1FFF_FFFF),
+            _ => None,
+        }
+    }
+
+    fn decode(addr: u32) -> Option<Region> {
+        if addr.wrapping_sub(SCRATCHPAD_BASE) < SCRATCHPAD_SIZE as u32 {
+            return Some(Region::Scratchpad((addr - SCRATCHPAD_BASE) as usize));
+        }
+        let phys = Self::translate(addr)?;
+        let region = match phys {
+            RAM_BASE..RAM_MIRROR_END => Region::Ram(phys as usize & (RAM_SIZE - 1)),
+            _ if phys.wrapping_sub(HW_BASE) < HW_SIZE as u32 => {
+                Region::Hw((phys - HW_BASE) as usize)
+            }
+            _ if phys.wrapping_sub(VU_BASE) < VU_SIZE as u32 => {
+                Region::Vu((phys - VU_BASE) as usize)
+            }
+            _ if phys.wrapping_sub(GS_PRIV_BASE) < GS_PRIV_SIZE as u32 => {
+                Region::GsPriv((phys - GS_PRIV_BASE) as usize)
+            }
+            _ if phys.wrapping_sub(BIOS_BASE) < BIOS_SIZE as u32 => {
+                Region::Bios((phys - BIOS_BASE) as usize)
+            }
+            _ => return None,
+        };
+        Some(region)
+    }
+
+    fn memory(&mut self, region: Region) -> (&mut [u8], usize) {
+        match region {
+            Region::Ram(offset) => (&mut self.ram, offset),
+            Region::Scratchpad(offset) => (&mut self.scratchpad, offset),
+            Region::Hw(offset) => (&mut self.hw_regs, offset),
+            Region::Vu(offset) => (&mut self.vu_mem, offset),
+            Region::GsPriv(offset) => (&mut self.gs_priv, offset),
+            Region::Bios(offset) => (&mut self.bios, offset),
+        }
+    }
+
+    // Hardware registers with a device behind them, by physical address
+    fn read_register(&mut self, addr: u32) -> Option<u32> {
+        if let Some((index, reg)) = Timers::decode(addr) {
+            let now = self.scheduler.now();
+            let value = self.timers.read(index, reg, now, &mut self.intc);
+            // Catching up may have passed the compare value or overflowed
+            self.reschedule_timer(index);
+            return Some(value);
+        }
+        if let Some(value) = self.dmac.read(addr) {
+            return Some(value);
+        }
+        if let Some(value) = self.gif.read(addr) {
+            return Some(value);
+        }
+        match addr {
+            intc::INTC_STAT => Some(self.intc.stat()),
+            intc::INTC_MASK => Some(self.intc.mask()),
+            intc::D_STAT => Some(self.dma_interrupts.read()),
+            _ => None,
+        }
+    }
+
+    // Returns false if the write should still go to plain storage
+    fn write_register(&mut self, addr: u32, value: u32) -> bool {
+        if let Some((index, reg)) = Timers::decode(addr) {
+            let now = self.scheduler.now();
+            self.timers.write(index, reg, value, now, &mut self.intc);
+            self.reschedule_timer(index);
+            return true;
+        }
+        if self.dmac.write(addr, value) {
+            self.schedule_kicked_dma();
+            return true;
+        }
+        if self.gif.write(addr, value) {
+            self.run_gif();
+            return true;
+        }
+        match addr {
+            SIO_TXFIFO => {
+                if let Some(console) = self.console.as_mut() {
+                    console.write_byte(value as u8);
+                }
+                return false;
+            }
+            intc::INTC_STAT => self.intc.write_stat(value),
+            intc::INTC_MASK => self.intc.write_mask(value),
+            intc::D_STAT => self.dma_interrupts.write(value),
+            _ => return false,
+        }
+        true
+    }
+
+    // Little-endian access of `size` bytes; an access running off the end of a region fails
+    // rather than spilling into whatever follows it
+    fn read(&mut self, addr: u32, size: usize) -> Result<u128, BusError> {
+        let region = Self::decode(addr).ok_or(BusError { addr })?;
+        if let Region::Hw(offset) = region
+            && let Some(value) = self.read_register(HW_BASE + offset as u32)
+        {
+            return Ok(value as u128);
+        }
+        let (memory, offset) = self.memory(region);
+        let bytes = memory.get(offset..offset + size).ok_or(BusError { addr })?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, &byte| value << 8 | byte as u128))
+    }
+
+    fn write(&mut self, addr: u32, size: usize, value: u128) -> Result<(), BusError> {
+        let region = Self::decode(addr).ok_or(BusError { addr })?;
+        let consumed = match region {
+            Region::Bios(_) => true,
+            // The FIFO only takes whole quadwords; narrower stores are dropped
+            Region::Hw(offset) if HW_BASE + offset as u32 == gif::GIF_FIFO => {
+                if size == 16 {
+                    self.push_gif(Path::Path3, value);
+                }
+                true
+            }
+            Region::Hw(offset) => self.write_register(HW_BASE + offset as u32, value as u32),
+            _ => false,
+        };
+        if consumed {
+            return Ok(());
+        }
+        let (memory, offset) = self.memory(region);
+        let bytes = memory
+            .get_mut(offset..offset + size)
+            .ok_or(BusError { addr })?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..size]);
+        Ok(())
+    }
+}
+
+impl Bus for Ps2Bus {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+        self.read(addr, 1).map(|value| value as u8)
+    }
+
+    fn read16(&mut self, addr: u32) -> Result<u16, BusError> {
+        self.read(addr, 2).map(|value| value as u16)
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, BusError> {
+        self.read(addr, 4).map(|value| value as u32)
+    }
+
+    fn read64(&mut self, addr: u32) -> Result<u64, BusError> {
+        self.read(addr, 8).map(|value| value as u64)
+    }
+
+    fn read128(&mut self, addr: u32) -> Result<u128, BusError> {
+        self.read(addr, 16)
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+        self.write(addr, 1, value as u128)
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), BusError> {
+        self.write(addr, 2, value as u128)
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), BusError> {
+        self.write(addr, 4, value as u128)
+    }
+
+    fn write64(&mut self, addr: u32, value: u64) -> Result<(), BusError> {
+        self.write(addr, 8, value as u128)
+    }
+
+    fn write128(&mut self, addr: u32, value: u128) -> Result<(), BusError> {
+        self.write(addr, 16, value)
+    }
+
+    fn memory_mut(&mut self, addr: u32, len: u32) -> Option<&mut [u8]> {
+        let (memory, offset) = match Self::decode(addr)? {
+            Region::Ram(offset) => (&mut self.ram, offset),
+            Region::Scratchpad(offset) => (&mut self.scratchpad, offset),
+            _ => return None,
+        };
+        memory.get_mut(offset..offset.checked_add(len as usize)?)
+    }
+
+    fn dma_from_device(&mut self, channel: usize, qword: u128) -> bool {
+        self.push_dma(channel, qword);
+        true
+    }
+}
+
+impl TimedBus for Ps2Bus {
+    fn scheduler(&mut self) -> &mut Scheduler {
+        &mut self.scheduler
+    }
+
+    fn handle_event(&mut self, _cpu: &mut Cpu, event: Event, deadline: u64) {
+        match event {
+            Event::VBlankStart => {
+                self.frames += 1;
+                self.intc.raise(intc::INT_VBON);
+                self.timers.vblank(true, deadline, &mut self.intc);
+                self.reschedule_timers();
+                self.set_gs_csr_bits(GS_CSR_VSINT);
+                self.scheduler
+                    .schedule_at(Event::VBlankEnd, deadline + VBLANK_CYCLES);
+                self.scheduler
+                    .schedule_at(Event::VBlankStart, deadline + FRAME_CYCLES);
+            }
+            Event::VBlankEnd => {
+                self.intc.raise(intc::INT_VBOF);
+                self.timers.vblank(false, deadline, &mut self.intc);
+                self.reschedule_timers();
+            }
+            Event::HBlank => {
+                self.timers.hblank(deadline, &mut self.intc);
+                self.reschedule_timers();
+                self.scheduler
+                    .schedule_at(Event::HBlank, deadline + LINE_CYCLES);
+            }
+            Event::Timer(index) => {
+                let now = self.scheduler.now();
+                self.timers.timer_event(index as usize, now, &mut self.intc);
+                self.reschedule_timer(index as usize);
+            }
+            Event::Dma(channel) => {
+                let mut memory = DmaMemory {
+                    ram: &mut self.ram,
+                    scratchpad: &mut self.scratchpad,
+                };
+                let channel = channel as usize;
+                if let Some(cycles) =
+                    self.dmac
+                        .service(channel, &mut memory, &mut self.dma_interrupts)
+                {
+                    self.scheduler
+                        .schedule_at(Event::Dma(channel as u8), deadline + cycles);
+                }
+                if channel == dmac::GIF {
+                    self.run_gif();
+                }
+                self.schedule_kicked_dma();
+            }
+        }
+    }
+
+    fn interrupt_lines(&self) -> u32 {
+        let mut lines = 0;
+        if self.intc.asserted() {
+            lines |= cop0::CAUSE_IP2;
+        }
+        if self.dma_interrupts.asserted() {
+            lines |= cop0::CAUSE_IP3;
+        }
+        lines
+    }
+}
+
+impl Snapshot for Ps2Bus {
+    // The BIOS isn't saved, only a checksum of it so a state can't be loaded on top of a
+    // different one
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_u32(savestate::crc32(&self.bios));
+        w.write_bytes(&self.ram);
+        w.write_bytes(&self.scratchpad);
+        w.write_bytes(&self.hw_regs);
+        w.write_bytes(&self.vu_mem);
+        w.write_bytes(&self.gs_priv);
+        self.scheduler.save_state(w);
+        w.write_u64(self.frames);
+        self.intc.save_state(w);
+        self.dma_interrupts.save_state(w);
+        self.timers.save_state(w);
+        self.dmac.save_state(w);
+        self.gif.save_state(w);
+        self.gs.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        if r.read_u32()? != savestate::crc32(&self.bios) {
+            return Err(StateError::Mismatch(
+                "it was saved with a different BIOS".to_string(),
+            ));
+        }
+        let ram = r.read_bytes()?;
+        let scratchpad = r.read_bytes()?;
+        let hw_regs = r.read_bytes()?;
+        let vu_mem = r.read_bytes()?;
+        let gs_priv = r.read_bytes()?;
+        let mut scheduler = Scheduler::new();
+        scheduler.load_state(r)?;
+        let frames = r.read_u64()?;
+        let mut intc = Intc::new();
+        intc.load_state(r)?;
+        let mut dma_interrupts = DmaInterrupts::new();
+        dma_interrupts.load_state(r)?;
+        // Vblank is under way while its end is pending
+        let in_vblank = scheduler.deadline(Event::VBlankEnd).is_some();
+        let mut timers = Timers::new();
+        timers.load_state(r, in_vblank)?;
+        let mut dmac = Dmac::new();
+        dmac.load_state(r)?;
+        let mut gif = Gif::new();
+        gif.load_state(r)?;
+        let mut gs = Gs::new();
+        gs.load_state(r)?;
+        let sizes_match = ram.len() == self.ram.len()
+            && scratchpad.len() == self.scratchpad.len()
+            && hw_regs.len() == self.hw_regs.len()
+            && vu_mem.len() == self.vu_mem.len()
+            && gs_priv.len() == self.gs_priv.len();
+        if !sizes_match {
+            return Err(StateError::Mismatch(
+                "memory sizes differ from this bus".to_string(),
+            ));
+        }
+        self.ram.copy_from_slice(ram);
+        self.scratchpad.copy_from_slice(scratchpad);
+        self.hw_regs.copy_from_slice(hw_regs);
+        self.vu_mem.copy_from_slice(vu_mem);
+        self.gs_priv.copy_from_slice(gs_priv);
+        self.scheduler = scheduler;
+        self.frames = frames;
+        self.intc = intc;
+        self.dma_interrupts = dma_interrupts;
+        self.timers = timers;
+        self.dmac = dmac;
+        self.gif = gif;
+        self.gs = gs;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cop0::Exception;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sio_stores_reach_the_console() {
+        let program = [
+            0x3C08_1000, // lui   t0, 0x1000
+            0x3508_F180, // ori   t0, t0, 0xf180
+            0x2409_0048, // addiu t1, zero, 'H'
+            0xA109_0000, // sb    t1, 0(t0)
+            0x2409_0069, // addiu t1, zero, 'i'
+            0xA109_0000, // sb    t1, 0(t0)
+            0x2409_000D, // addiu t1, zero, '\r'
+            0xA109_0000, // sb    t1, 0(t0)
+            0x2409_000A, // addiu t1, zero, '\n'
+            0xA109_0000, // sb    t1, 0(t0)
+        ];
+        let mut bus = Ps2Bus::new();
+        for (addr, &raw) in (0x1000..).step_by(4).zip(&program) {
+            bus.write32(addr, raw).unwrap();
+        }
+        let output = Capture::default();
+        bus.set_console(Console::new(output.clone()));
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x1000);
+
+        for _ in 0..program.len() - 2 {
+            cpu.step(&mut bus);
+        }
+        // Nothing is printed until the line ends
+        assert!(output.0.borrow().is_empty());
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc(), 0x1000 + 4 * program.len() as u32);
+        assert_eq!(*output.0.borrow(), b"EE: Hi\n");
+    }
+
+    #[test]
+    fn memory_ranges_stay_within_one_memory() {
+        let mut bus = Ps2Bus::new();
+        bus.memory_mut(0x8010_0000, 4)
+            .unwrap()
+            .copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(bus.read32(0x0010_0000).unwrap(), 0x0403_0201);
+        assert_eq!(
+            bus.memory_mut(0x2000_0000, RAM_SIZE as u32).unwrap().len(),
+            RAM_SIZE
+        );
+        assert!(bus.memory_mut(RAM_SIZE as u32 - 4, 8).is_none());
+        assert!(
+            bus.memory_mut(SCRATCHPAD_BASE, SCRATCHPAD_SIZE as u32)
+                .is_some()
+        );
+        assert!(
+            bus.memory_mut(SCRATCHPAD_BASE + 8, SCRATCHPAD_SIZE as u32)
+                .is_none()
+        );
+        // Devices are never handed out
+        assert!(bus.memory_mut(SIO_TXFIFO, 4).is_none());
+        assert!(bus.memory_mut(BIOS_BASE, 4).is_none());
+    }
+
+    #[test]
+    fn segments_reach_the_same_ram() {
+        let mut bus = Ps2Bus::new();
+        bus.write32(0x0010_0000, 0x1234_5678).unwrap();
+        for addr in [0x8010_0000, 0xA010_0000, 0x2010_0000, 0x3010_0000] {
+            assert_eq!(bus.read32(addr).unwrap(), 0x1234_5678, "{:08x}", addr);
+        }
+        bus.write32(0xA010_0004, 0x9ABC_DEF0).unwrap();
+        assert_eq!(bus.ram()[0x10_0004..0x10_0008], [0xF0, 0xDE, 0xBC, 0x9A]);
+
+        // The uncached segments stop at the end of RAM, the physical one mirrors it
+        assert_eq!(bus.read32(0x0210_0000).unwrap(), 0x1234_5678);
+        assert_eq!(bus.read32(0x8E10_0000).unwrap(), 0x1234_5678);
+        assert!(bus.read32(0x2210_0000).is_err());
+
+        bus.write32(SCRATCHPAD_BASE, 0x55).unwrap();
+        assert_eq!(bus.read32(SCRATCHPAD_BASE).unwrap(), 0x55);
+        assert_eq!(bus.read32(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn bios_writes_are_ignored() {
+        let mut bus = Ps2Bus::new();
+        bus.load_bios(&[1, 2, 3, 4]).unwrap();
+        bus.write32(0xBFC0_0000, 0xFFFF_FFFF).unwrap();
+        bus.write8(0x9FC0_0001, 0xFF).unwrap();
+        assert_eq!(bus.read32(0xBFC0_0000).unwrap(), 0x0403_0201);
+        assert_eq!(bus.read32(0x1FC0_0000).unwrap(), 0x0403_0201);
+        assert!(bus.load_bios(&vec![0; BIOS_SIZE + 1]).is_err());
+    }
+
+    #[test]
+    fn unmapped_accesses_raise_bus_errors() {
+        let mut bus = Ps2Bus::new();
+        for addr in [
+            0x1400_0000,
+            0x4000_0000,
+            0xC000_0000,
+            SCRATCHPAD_BASE + 0x4000,
+        ] {
+            assert_eq!(bus.read32(addr), Err(BusError { addr }), "{:08x}", addr);
+            assert_eq!(bus.write32(addr, 0), Err(BusError { addr }), "{:08x}", addr);
+        }
+
+        let program = [
+            0x3C08_C000, // lui t0, 0xc000
+            0x8D09_0000, // lw  t1, 0(t0)
+        ];
+        for (addr, &raw) in (0x1000..).step_by(4).zip(&program) {
+            bus.write32(addr, raw).unwrap();
+        }
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x1000);
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        let cause = cpu.cop0().cause();
+        assert_eq!(
+            (cause & cop0::CAUSE_EXC_CODE_MASK) >> cop0::CAUSE_EXC_CODE_SHIFT,
+            Exception::DataBusError.code()
+        );
+        assert_eq!(cpu.cop0().read(cop0::EPC), 0x1004);
+        assert_eq!(cpu.pc(), 0x8000_0180);
+        assert_eq!(cpu.gpr(9), 0);
+
+        // Fetching from nowhere is an instruction bus error
+        cpu.set_pc(0xC000_0000);
+        cpu.cop0.set(cop0::STATUS, 0);
+        cpu.step(&mut bus);
+        let cause = cpu.cop0().cause();
+        assert_eq!(
+            (cause & cop0::CAUSE_EXC_CODE_MASK) >> cop0::CAUSE_EXC_CODE_SHIFT,
+            Exception::InstructionBusError.code()
+        );
+        assert_eq!(cpu.cop0().read(cop0::EPC), 0xC000_0000);
+    }
+
+    #[test]
+    fn unmasked_requests_interrupt_through_ip2_and_ip3() {
+        let mut bus = Ps2Bus::new();
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0x1000);
+        let enabled = cop0::STATUS_IE | cop0::STATUS_EIE | cop0::STATUS_IM2 | cop0::STATUS_IM3;
+        cpu.set_status(enabled);
+        let interrupted = |cpu: &Cpu| {
+            cpu.pc() == 0x8000_0200 && cpu.cop0().cause() & cop0::CAUSE_EXC_CODE_MASK == 0
+        };
+
+        // Masked requests sit in INTC_STAT
+        bus.raise_interrupt(intc::INT_VBON);
+        cpu.step_timed(&mut bus);
+        assert_eq!(cpu.pc(), 0x1004);
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_IP2, 0);
+
+        bus.write32(intc::INTC_MASK, 1 << intc::INT_VBON).unwrap();
+        cpu.step_timed(&mut bus);
+        assert!(interrupted(&cpu));
+        assert_ne!(cpu.cop0().cause() & cop0::CAUSE_IP2, 0);
+        assert_eq!(cpu.cop0().read(cop0::EPC), 0x1004);
+
+        // Acknowledging drops the line
+        bus.write32(intc::INTC_STAT, 1 << intc::INT_VBON).unwrap();
+        cpu.set_pc(0x1000);
+        cpu.set_status(enabled);
+        cpu.step_timed(&mut bus);
+        assert_eq!(cpu.pc(), 0x1004);
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_IP2, 0);
+
+        // Likewise for a DMA channel in D_STAT, which IM3 alone can hold off
+        bus.dma_interrupts_mut().raise(dmac::GIF as u32);
+        bus.write32(intc::D_STAT, 1 << (16 + dmac::GIF)).unwrap();
+        cpu.set_status(enabled & !cop0::STATUS_IM3);
+        cpu.step_timed(&mut bus);
+        assert_eq!(cpu.pc(), 0x1008);
+        assert_ne!(cpu.cop0().cause() & cop0::CAUSE_IP3, 0);
+        cpu.set_status(enabled);
+        cpu.step_timed(&mut bus);
+        assert!(interrupted(&cpu));
+        assert_eq!(cpu.cop0().read(cop0::EPC), 0x1008);
+
+        bus.write32(intc::D_STAT, 1 << dmac::GIF).unwrap();
+        cpu.set_pc(0x1000);
+        cpu.set_status(enabled);
+        cpu.step_timed(&mut bus);
+        assert_eq!(cpu.pc(), 0x1004);
+        assert_eq!(cpu.cop0().cause() & cop0::CAUSE_IP3, 0);
+    }
+}
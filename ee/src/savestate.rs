@@ -0,0 +1,630 @@
+// Save states.
+//
+// Layout, all integers little-endian:
+//
+//     magic "LEELOOST" | version u32 | payload length u64 | CRC-32 of payload u32 | payload
+//
+// The payload is a sequence of sections, each a 4 byte tag, a u32 length and that many bytes.
+// Sections are looked up by tag. There is no release to stay compatible with, so any change to
+// what a component saves bumps `VERSION` and older states are refused rather than migrated.
+//
+// Only emulated machine state is saved; tracers, observers and debuggers are host-side and stay
+// as they are across a load. An installed syscall handler, such as the HLE kernel, is guest state
+// and gets a section of its own, so a state only loads into a machine that has a handler if it
+// was saved with one.
+
+use std::fmt;
+
+use crate::Cpu;
+use crate::bus::Ram;
+
+pub const MAGIC: &[u8; 8] = b"LEELOOST";
+pub const VERSION: u32 = 1;
+
+const HEADER_LEN: usize = 8 + 4 + 8 + 4;
+
+const SECTION_CPU: [u8; 4] = *b"CPU ";
+const SECTION_BUS: [u8; 4] = *b"BUS ";
+const SECTION_HLE: [u8; 4] = *b"HLE ";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Truncated,
+    MissingSection([u8; 4]),
+    // The state doesn't fit the machine it is being loaded into, e.g. a different RAM size
+    Mismatch(String),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(
+                f,
+                "save state version {} isn't supported (this emulator reads version {})",
+                version, VERSION
+            ),
+            StateError::ChecksumMismatch => write!(f, "save state is corrupted (bad checksum)"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::MissingSection(tag) => {
+                write!(
+                    f,
+                    "save state has no '{}' section",
+                    String::from_utf8_lossy(tag)
+                )
+            }
+            StateError::Mismatch(what) => {
+                write!(f, "save state doesn't match this machine: {}", what)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub trait Snapshot {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    // Writes a tagged section whose contents are produced by `f`
+    pub fn section(&mut self, tag: [u8; 4], f: impl FnOnce(&mut StateWriter)) {
+        self.buf.extend_from_slice(&tag);
+        let len_at = self.buf.len();
+        self.write_u32(0);
+        f(self);
+        let len = (self.buf.len() - len_at - 4) as u32;
+        self.buf[len_at..len_at + 4].copy_from_slice(&len.to_le_bytes());
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u128(&mut self, value: u128) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // Length-prefixed byte string
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    // Finds a section by tag among those remaining in this reader
+    pub fn section(&self, tag: [u8; 4]) -> Result<StateReader<'a>, StateError> {
+        let mut pos = self.pos;
+        while pos + 8 <= self.data.len() {
+            let len = u32::from_le_bytes(self.data[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            let start = pos + 8;
+            let end = start.checked_add(len).ok_or(StateError::Truncated)?;
+            if end > self.data.len() {
+                return Err(StateError::Truncated);
+            }
+            if self.data[pos..pos + 4] == tag {
+                return Ok(StateReader {
+                    data: &self.data[start..end],
+                    pos: 0,
+                });
+            }
+            pos = end;
+        }
+        Err(StateError::MissingSection(tag))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, StateError> {
+        Ok(u128::from_le_bytes(self.take(16)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u64()?;
+        self.take(usize::try_from(len).map_err(|_| StateError::Truncated)?)
+    }
+}
+
+// Serializes the whole machine
+pub fn save(cpu: &Cpu, bus: &dyn Snapshot) -> Vec<u8> {
+    let mut payload = StateWriter::default();
+    payload.section(SECTION_CPU, |w| cpu.save_state(w));
+    payload.section(SECTION_BUS, |w| bus.save_state(w));
+    if let Some(handler) = &cpu.syscall_handler {
+        payload.section(SECTION_HLE, |w| handler.save_state(w));
+    }
+    let payload = payload.buf;
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    out.extend_from_slice(&crc32(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    out
+}
+
+// Restores a machine saved with `save`. The header and checksum are validated before anything
+// is touched.
+pub fn load(data: &[u8], cpu: &mut Cpu, bus: &mut dyn Snapshot) -> Result<(), StateError> {
+    if data.len() < HEADER_LEN || &data[..8] != MAGIC {
+        return Err(StateError::BadMagic);
+    }
+    let version = u32::from_le_bytes(data[8..12].try_into().unwrap());
+    if version != VERSION {
+        return Err(StateError::UnsupportedVersion(version));
+    }
+    let len = u64::from_le_bytes(data[12..20].try_into().unwrap());
+    let checksum = u32::from_le_bytes(data[20..24].try_into().unwrap());
+    let payload = usize::try_from(len)
+        .ok()
+        .and_then(|len| data.get(HEADER_LEN..HEADER_LEN.checked_add(len)?))
+        .ok_or(StateError::Truncated)?;
+    if crc32(payload) != checksum {
+        return Err(StateError::ChecksumMismatch);
+    }
+
+    let reader = StateReader {
+        data: payload,
+        pos: 0,
+    };
+    // Look the sections up and parse the CPU first so a bad one doesn't leave a half-loaded
+    // machine. The handler and the bus each validate everything before they change anything;
+    // the handler goes first and is put back from a copy of its old state if the bus fails.
+    // Once the bus has loaded nothing can fail.
+    let mut cpu_state = reader.section(SECTION_CPU)?;
+    let mut bus_state = reader.section(SECTION_BUS)?;
+    let hle_state = match reader.section(SECTION_HLE) {
+        Ok(hle_state) => Some(hle_state),
+        Err(StateError::MissingSection(_)) => None,
+        Err(err) => return Err(err),
+    };
+    let cpu_state = CpuState::read(&mut cpu_state)?;
+    match (cpu.syscall_handler.as_mut(), hle_state) {
+        (Some(handler), Some(mut hle_state)) => {
+            let mut old = StateWriter::default();
+            handler.save_state(&mut old);
+            handler.load_state(&mut hle_state)?;
+            if let Err(err) = bus.load_state(&mut bus_state) {
+                let mut old = StateReader {
+                    data: &old.buf,
+                    pos: 0,
+                };
+                handler
+                    .load_state(&mut old)
+                    .expect("a handler reloads its own state");
+                return Err(err);
+            }
+        }
+        (None, None) => bus.load_state(&mut bus_state)?,
+        (Some(_), None) => {
+            return Err(StateError::Mismatch(
+                "the state was saved without a syscall handler (HLE kernel)".to_string(),
+            ));
+        }
+        (None, Some(_)) => {
+            return Err(StateError::Mismatch(
+                "the state was saved with a syscall handler (HLE kernel)".to_string(),
+            ));
+        }
+    }
+    cpu_state.apply(cpu);
+    Ok(())
+}
+
+impl Snapshot for Cpu {
+    // The FPU and VU0 aren't emulated yet, so there is no state of theirs to save
+    fn save_state(&self, w: &mut StateWriter) {
+        for &gpr in &self.gprs {
+            w.write_u128(gpr);
+        }
+        w.write_u64(self.hi0);
+        w.write_u64(self.lo0);
+        w.write_u64(self.sa);
+        w.write_u32(self.pc);
+        w.write_u32(self.next_pc);
+        w.write_bool(self.branch_target.is_some());
+        w.write_u32(self.branch_target.unwrap_or(0));
+        w.write_bool(self.in_delay_slot);
+        for reg in 0..32 {
+            w.write_u32(self.cop0.read(reg));
+        }
+        w.write_u64(self.cycles);
+        w.write_u64(self.hilo_ready);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        CpuState::read(r)?.apply(self);
+        Ok(())
+    }
+}
+
+// A CPU section read but not yet applied, so `load` can validate everything before committing
+struct CpuState {
+    gprs: [u128; 32],
+    hi0: u64,
+    lo0: u64,
+    sa: u64,
+    pc: u32,
+    next_pc: u32,
+    branch_target: Option<u32>,
+    in_delay_slot: bool,
+    cop0: [u32; 32],
+    cycles: u64,
+    hilo_ready: u64,
+}
+
+impl CpuState {
+    fn read(r: &mut StateReader) -> Result<Self, StateError> {
+        let mut gprs = [0u128; 32];
+        for gpr in gprs.iter_mut() {
+            *gpr = r.read_u128()?;
+        }
+        let hi0 = r.read_u64()?;
+        let lo0 = r.read_u64()?;
+        let sa = r.read_u64()?;
+        let pc = r.read_u32()?;
+        let next_pc = r.read_u32()?;
+        let has_branch = r.read_bool()?;
+        let branch_target = r.read_u32()?;
+        let in_delay_slot = r.read_bool()?;
+        let mut cop0 = [0u32; 32];
+        for reg in cop0.iter_mut() {
+            *reg = r.read_u32()?;
+        }
+        let cycles = r.read_u64()?;
+        let hilo_ready = r.read_u64()?;
+        Ok(CpuState {
+            gprs,
+            hi0,
+            lo0,
+            sa,
+            pc,
+            next_pc,
+            branch_target: has_branch.then_some(branch_target),
+            in_delay_slot,
+            cop0,
+            cycles,
+            hilo_ready,
+        })
+    }
+
+    fn apply(self, cpu: &mut Cpu) {
+        cpu.gprs = self.gprs;
+        cpu.hi0 = self.hi0;
+        cpu.lo0 = self.lo0;
+        cpu.sa = self.sa;
+        cpu.pc = self.pc;
+        cpu.next_pc = self.next_pc;
+        cpu.branch_target = self.branch_target;
+        cpu.in_delay_slot = self.in_delay_slot;
+        cpu.cycles = self.cycles;
+        cpu.hilo_ready = self.hilo_ready;
+        // The shadow call stack describes the execution we are leaving
+        cpu.call_stack.clear();
+        for (reg, value) in self.cop0.into_iter().enumerate() {
+            cpu.cop0.set(reg, value);
+        }
+    }
+}
+
+impl Snapshot for Ram {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(self.as_slice());
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let data = r.read_bytes()?;
+        if data.len() != self.as_slice().len() {
+            return Err(StateError::Mismatch(format!(
+                "RAM is {} bytes, state has {}",
+                self.as_slice().len(),
+                data.len()
+            )));
+        }
+        self.as_mut_slice().copy_from_slice(data);
+        Ok(())
+    }
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// CRC-32 (IEEE 802.3), the same one zlib and PNG use
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cop0;
+    use crate::hle::kernel::Kernel;
+    use crate::hle::syscalls::*;
+    use crate::hle::thread::THS_READY;
+    use crate::intc::{self, INT_VBON};
+    use crate::ps2_bus::{Ps2Bus, SCRATCHPAD_BASE};
+    use crate::timer::{self, CLKS_BUSCLK_16, MODE_CUE, TIMER_BASE};
+
+    fn quiet_kernel() -> Box<Kernel> {
+        let mut kernel = Kernel::new();
+        kernel.set_logger(|_| {});
+        Box::new(kernel)
+    }
+
+    fn call(cpu: &mut Cpu, bus: &mut Ram, number: u32, args: &[u32]) -> i32 {
+        cpu.gprs[3] = number as u128;
+        for (index, &arg) in args.iter().enumerate() {
+            cpu.gprs[4 + index] = arg as u128;
+        }
+        let mut handler = cpu.take_syscall_handler().unwrap();
+        handler.syscall(cpu, bus);
+        cpu.set_syscall_handler(handler);
+        cpu.gpr(2) as i32
+    }
+
+    // A started thread waiting to run and a semaphore
+    fn hle_machine() -> (Cpu, Ram) {
+        let mut cpu = Cpu::new();
+        let mut ram = Ram::new(0x1_0000);
+        cpu.set_syscall_handler(quiet_kernel());
+        // entry, stack, stack_size, gp, priority
+        for (offset, value) in [(4, 0x2000), (8, 0x4000), (12, 0x1000), (16, 0), (20, 10)] {
+            ram.write32(0x100 + offset, value).unwrap();
+        }
+        let thread = call(&mut cpu, &mut ram, CREATE_THREAD, &[0x100]);
+        assert_eq!(
+            call(&mut cpu, &mut ram, START_THREAD, &[thread as u32, 0x55]),
+            thread
+        );
+        // max_count, init_count
+        ram.write32(0x204, 4).unwrap();
+        ram.write32(0x208, 1).unwrap();
+        assert!(call(&mut cpu, &mut ram, CREATE_SEMA, &[0x200]) > 0);
+        (cpu, ram)
+    }
+
+    #[test]
+    fn hle_kernel_state_round_trips() {
+        let (cpu, ram) = hle_machine();
+        let state = save(&cpu, &ram);
+
+        let mut loaded = Cpu::new();
+        loaded.set_syscall_handler(quiet_kernel());
+        let mut loaded_ram = Ram::new(0x1_0000);
+        load(&state, &mut loaded, &mut loaded_ram).unwrap();
+        assert_eq!(save(&loaded, &loaded_ram), state);
+        // The thread is still waiting to run behind the main thread
+        assert_eq!(
+            call(&mut loaded, &mut loaded_ram, REFER_THREAD_STATUS, &[2, 0]),
+            THS_READY as i32
+        );
+    }
+
+    #[test]
+    fn hle_state_needs_a_handler_on_both_sides() {
+        let (cpu, ram) = hle_machine();
+        let state = save(&cpu, &ram);
+        let mut bare = Cpu::new();
+        let mut bare_ram = Ram::new(0x1_0000);
+        assert!(matches!(
+            load(&state, &mut bare, &mut bare_ram),
+            Err(StateError::Mismatch(_))
+        ));
+        assert!(bare_ram.as_slice().iter().all(|&byte| byte == 0));
+
+        let bare_state = save(&bare, &bare_ram);
+        let (mut cpu, mut ram) = hle_machine();
+        assert!(matches!(
+            load(&bare_state, &mut cpu, &mut ram),
+            Err(StateError::Mismatch(_))
+        ));
+    }
+
+    #[test]
+    fn failed_bus_load_puts_the_handler_back() {
+        let (cpu, ram) = hle_machine();
+        let state = save(&cpu, &ram);
+
+        let mut other = Cpu::new();
+        other.set_syscall_handler(quiet_kernel());
+        // A different RAM size fails the bus section after the handler has loaded
+        let mut other_ram = Ram::new(0x2_0000);
+        let before = save(&other, &other_ram);
+        assert!(matches!(
+            load(&state, &mut other, &mut other_ram),
+            Err(StateError::Mismatch(_))
+        ));
+        assert_eq!(save(&other, &other_ram), before);
+    }
+
+    // A machine that has run for a while with registers, memory and devices away from their
+    // power-on values. `seed` makes two such machines differ.
+    fn busy_machine(seed: u32) -> (Cpu, Ps2Bus) {
+        let mut cpu = Cpu::new();
+        let mut bus = Ps2Bus::new();
+        for (index, gpr) in cpu.gprs.iter_mut().enumerate().skip(1) {
+            *gpr = (index as u128 * 0x0101_0101_0101_0101_0101_0101_0101_0101) ^ seed as u128;
+        }
+        cpu.hi0 = 0x1111 ^ seed as u64;
+        cpu.lo0 = 0x2222 ^ seed as u64;
+        cpu.sa = 8;
+        cpu.cop0.set(cop0::STATUS, cop0::STATUS_EIE | seed);
+        cpu.cop0.set(cop0::EPC, 0x8000_1234 ^ seed);
+        cpu.cop0.set(cop0::COMPARE, 0x4000);
+        bus.write32(0x0010_0000, 0xDEAD_BEEF ^ seed).unwrap();
+        bus.write32(SCRATCHPAD_BASE + 0x10, 0x1234_5678 ^ seed)
+            .unwrap();
+        bus.write32(intc::INTC_MASK, 1 << INT_VBON).unwrap();
+        bus.write32(intc::D_STAT, 0x0003_0000).unwrap();
+        bus.write32(TIMER_BASE + timer::COMP, 0x100).unwrap();
+        bus.write32(TIMER_BASE + timer::MODE, MODE_CUE | CLKS_BUSCLK_16)
+            .unwrap();
+        // Everything from 0x1000 on is a nop
+        cpu.set_pc(0x1000);
+        cpu.run_for(&mut bus, 1000 + seed as u64);
+        (cpu, bus)
+    }
+
+    #[test]
+    fn machine_state_round_trips() {
+        let (mut cpu, mut bus) = busy_machine(0);
+        let state = save(&cpu, &bus);
+
+        let mut loaded = Cpu::new();
+        let mut loaded_bus = Ps2Bus::new();
+        load(&state, &mut loaded, &mut loaded_bus).unwrap();
+        assert!(save(&loaded, &loaded_bus) == state);
+
+        assert_eq!(loaded.gprs, cpu.gprs);
+        assert_eq!(
+            (loaded.hi(), loaded.lo(), loaded.sa(), loaded.pc()),
+            (cpu.hi(), cpu.lo(), cpu.sa(), cpu.pc())
+        );
+        assert_eq!(loaded.cycles(), cpu.cycles());
+        for reg in 0..32 {
+            assert_eq!(
+                loaded.cop0().read(reg),
+                cpu.cop0().read(reg),
+                "COP0 {}",
+                reg
+            );
+        }
+        assert!(loaded_bus.ram() == bus.ram());
+        for addr in [
+            SCRATCHPAD_BASE + 0x10,
+            intc::INTC_MASK,
+            intc::D_STAT,
+            TIMER_BASE + timer::COUNT,
+            TIMER_BASE + timer::MODE,
+            TIMER_BASE + timer::COMP,
+        ] {
+            assert_eq!(
+                loaded_bus.read32(addr).unwrap(),
+                bus.read32(addr).unwrap(),
+                "{:08x}",
+                addr
+            );
+        }
+
+        // And both carry on the same way
+        cpu.run_for(&mut bus, 5000);
+        loaded.run_for(&mut loaded_bus, 5000);
+        assert!(save(&loaded, &loaded_bus) == save(&cpu, &bus));
+    }
+
+    fn with_payload(payload: &[u8]) -> Vec<u8> {
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&VERSION.to_le_bytes());
+        data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+        data.extend_from_slice(&crc32(payload).to_le_bytes());
+        data.extend_from_slice(payload);
+        data
+    }
+
+    #[test]
+    fn bad_states_leave_the_machine_alone() {
+        let (cpu, bus) = busy_machine(0);
+        let state = save(&cpu, &bus);
+
+        let mut bad_magic = state.clone();
+        bad_magic[0] ^= 0xFF;
+        let mut new_version = state.clone();
+        new_version[8..12].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let mut corrupted = state.clone();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        // A CPU section too short for the registers
+        let mut payload = StateWriter::default();
+        payload.section(SECTION_CPU, |w| w.write_u64(0));
+        payload.section(SECTION_BUS, |w| bus.save_state(w));
+        let short_cpu = with_payload(&payload.buf);
+
+        let cases = [
+            (&bad_magic[..], StateError::BadMagic),
+            (&state[..4], StateError::BadMagic),
+            (
+                &new_version[..],
+                StateError::UnsupportedVersion(VERSION + 1),
+            ),
+            (&corrupted[..], StateError::ChecksumMismatch),
+            (&state[..state.len() - 1], StateError::Truncated),
+            (&short_cpu[..], StateError::Truncated),
+        ];
+        // Each one is refused without anything changing in the machine it was loaded into
+        let (mut other, mut other_bus) = busy_machine(1);
+        let before = save(&other, &other_bus);
+        for (data, expected) in cases {
+            assert_eq!(load(data, &mut other, &mut other_bus), Err(expected));
+        }
+        assert!(save(&other, &other_bus) == before);
+    }
+}
@@ -0,0 +1,292 @@
+// Timed events, kept in EE cycles.
+//
+// Devices schedule an event for the cycle something should happen (a timer overflowing, vblank
+// starting, a DMA transfer finishing) and the bus handles it once the CPU's cycle count gets
+// there. `Cpu::run_for` and `Cpu::step_timed` service due events between instructions, so an
+// event fires at most one instruction late. Each kind of event is pending at most once:
+// scheduling it again moves it.
+
+use crate::Cpu;
+use crate::bus::Bus;
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+// The EE core clock, in Hz
+pub const EE_CLOCK: u64 = 294_912_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    VBlankStart,
+    VBlankEnd,
+    HBlank,
+    // A timer reaching its compare value or overflowing, by timer number
+    Timer(u8),
+    // A DMA channel's next slice, by channel number
+    Dma(u8),
+}
+
+impl Event {
+    fn code(self) -> u8 {
+        match self {
+            Event::VBlankStart => 0,
+            Event::VBlankEnd => 1,
+            Event::HBlank => 2,
+            Event::Timer(index) => 0x10 + index,
+            Event::Dma(channel) => 0x20 + channel,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            0 => Some(Event::VBlankStart),
+            1 => Some(Event::VBlankEnd),
+            2 => Some(Event::HBlank),
+            0x10..=0x13 => Some(Event::Timer(code - 0x10)),
+            0x20..=0x29 => Some(Event::Dma(code - 0x20)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Scheduler {
+    // The CPU's cycle count as of the instruction being executed
+    now: u64,
+    // Sorted by deadline, earliest last so the next one pops off the end
+    events: Vec<(u64, Event)>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub(crate) fn set_now(&mut self, now: u64) {
+        self.now = now;
+    }
+
+    // Events scheduled in the past fire before the next instruction
+    pub fn schedule_at(&mut self, event: Event, cycle: u64) {
+        self.cancel(event);
+        let index = self
+            .events
+            .partition_point(|&(deadline, _)| deadline > cycle);
+        self.events.insert(index, (cycle, event));
+    }
+
+    pub fn schedule_in(&mut self, event: Event, cycles: u64) {
+        self.schedule_at(event, self.now + cycles);
+    }
+
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|&(_, pending)| pending != event);
+    }
+
+    // The cycle `event` is due at, if it's pending
+    pub fn deadline(&self, event: Event) -> Option<u64> {
+        self.events
+            .iter()
+            .find(|&&(_, pending)| pending == event)
+            .map(|&(deadline, _)| deadline)
+    }
+
+    pub fn next_deadline(&self) -> Option<u64> {
+        self.events.last().map(|&(deadline, _)| deadline)
+    }
+
+    // Removes and returns the earliest event due by `now`, with the cycle it was due at
+    pub fn pop_due(&mut self) -> Option<(Event, u64)> {
+        match self.events.last() {
+            Some(&(deadline, event)) if deadline <= self.now => {
+                self.events.pop();
+                Some((event, deadline))
+            }
+            _ => None,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_u64(self.now);
+        w.write_u32(self.events.len() as u32);
+        for &(deadline, event) in &self.events {
+            w.write_u64(deadline);
+            w.write_u8(event.code());
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let now = r.read_u64()?;
+        let count = r.read_u32()?;
+        let mut events = Vec::new();
+        for _ in 0..count {
+            let deadline = r.read_u64()?;
+            let code = r.read_u8()?;
+            let event = Event::from_code(code)
+                .ok_or_else(|| StateError::Mismatch(format!("unknown event {}", code)))?;
+            events.push((deadline, event));
+        }
+        self.now = now;
+        self.events = events;
+        Ok(())
+    }
+}
+
+// A bus with devices driven by scheduled events
+pub trait TimedBus: Bus {
+    fn scheduler(&mut self) -> &mut Scheduler;
+
+    // Called for each event once it is due. `deadline` is the cycle it was scheduled for, which
+    // periodic events should reschedule from so they don't drift.
+    fn handle_event(&mut self, cpu: &mut Cpu, event: Event, deadline: u64);
+
+    // The interrupt lines the devices assert, as Cause.IP bits. Sampled before each instruction.
+    fn interrupt_lines(&self) -> u32 {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::{BusError, Ram};
+
+    #[test]
+    fn equal_deadlines_fire_in_the_order_they_were_scheduled() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule_at(Event::Timer(1), 10);
+        scheduler.schedule_at(Event::HBlank, 5);
+        scheduler.schedule_at(Event::Timer(0), 10);
+        scheduler.schedule_at(Event::Dma(2), 10);
+        assert_eq!(scheduler.next_deadline(), Some(5));
+        assert_eq!(scheduler.pop_due(), None);
+
+        scheduler.set_now(10);
+        let order: Vec<_> = std::iter::from_fn(|| scheduler.pop_due()).collect();
+        assert_eq!(
+            order,
+            [
+                (Event::HBlank, 5),
+                (Event::Timer(1), 10),
+                (Event::Timer(0), 10),
+                (Event::Dma(2), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn scheduling_again_moves_an_event_and_cancel_drops_it() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_now(100);
+        scheduler.schedule_in(Event::VBlankStart, 50);
+        scheduler.schedule_at(Event::VBlankEnd, 120);
+        assert_eq!(scheduler.deadline(Event::VBlankStart), Some(150));
+
+        scheduler.schedule_at(Event::VBlankStart, 110);
+        assert_eq!(scheduler.deadline(Event::VBlankStart), Some(110));
+        scheduler.cancel(Event::VBlankEnd);
+        assert_eq!(scheduler.deadline(Event::VBlankEnd), None);
+
+        scheduler.set_now(200);
+        assert_eq!(scheduler.pop_due(), Some((Event::VBlankStart, 110)));
+        assert_eq!(scheduler.pop_due(), None);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    // Nops, with events that note the cycle they were handled at and HBlank repeating
+    struct TimedRam {
+        ram: Ram,
+        scheduler: Scheduler,
+        handled: Vec<(Event, u64, u64)>,
+    }
+
+    impl Bus for TimedRam {
+        fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+            self.ram.read8(addr)
+        }
+
+        fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+            self.ram.write8(addr, value)
+        }
+    }
+
+    impl TimedBus for TimedRam {
+        fn scheduler(&mut self) -> &mut Scheduler {
+            &mut self.scheduler
+        }
+
+        fn handle_event(&mut self, cpu: &mut Cpu, event: Event, deadline: u64) {
+            self.handled.push((event, deadline, cpu.cycles()));
+            if event == Event::HBlank {
+                self.scheduler.schedule_at(event, deadline + 30);
+            }
+        }
+    }
+
+    fn timed_ram() -> (Cpu, TimedRam) {
+        let mut cpu = Cpu::new();
+        cpu.set_pc(0);
+        let mut bus = TimedRam {
+            ram: Ram::new(0x1000),
+            scheduler: Scheduler::new(),
+            handled: Vec::new(),
+        };
+        bus.scheduler.schedule_at(Event::Timer(0), 0);
+        bus.scheduler.schedule_at(Event::Timer(1), 10);
+        bus.scheduler.schedule_at(Event::Timer(2), 10);
+        bus.scheduler.schedule_at(Event::HBlank, 25);
+        (cpu, bus)
+    }
+
+    #[test]
+    fn run_for_services_events_between_instructions() {
+        let (mut cpu, mut bus) = timed_ram();
+        assert_eq!(cpu.run_for(&mut bus, 100), crate::StepResult::Ok);
+        assert_eq!(cpu.cycles(), 100);
+        // One cycle per nop, so each event is handled right at its deadline
+        assert_eq!(
+            bus.handled,
+            [
+                (Event::Timer(0), 0, 0),
+                (Event::Timer(1), 10, 10),
+                (Event::Timer(2), 10, 10),
+                (Event::HBlank, 25, 25),
+                (Event::HBlank, 55, 55),
+                (Event::HBlank, 85, 85),
+            ]
+        );
+        assert_eq!(bus.scheduler.deadline(Event::HBlank), Some(115));
+    }
+
+    #[test]
+    fn an_event_due_during_a_stall_fires_after_the_instruction() {
+        let (mut cpu, mut bus) = timed_ram();
+        cpu.gprs[9] = 1;
+        bus.ram.write32(0, 0x0109_001A).unwrap(); // div t0, t1
+        bus.ram.write32(4, 0x0000_5012).unwrap(); // mflo t2
+        cpu.run_for(&mut bus, 40);
+        let stalled = 1 + Cpu::DIV_LATENCY;
+        assert_eq!(
+            bus.handled[..4],
+            [
+                (Event::Timer(0), 0, 0),
+                (Event::Timer(1), 10, stalled),
+                (Event::Timer(2), 10, stalled),
+                (Event::HBlank, 25, stalled),
+            ]
+        );
+        assert_eq!(cpu.cycles(), 40);
+
+        // run_for stops once enough cycles have passed, however far the last instruction
+        // overshoots, leaving what fell due meanwhile for the next call
+        let (mut cpu, mut bus) = timed_ram();
+        cpu.gprs[9] = 1;
+        bus.ram.write32(0, 0x0109_001A).unwrap();
+        bus.ram.write32(4, 0x0000_5012).unwrap();
+        cpu.run_for(&mut bus, 5);
+        assert_eq!(cpu.cycles(), stalled);
+        assert_eq!(bus.handled, [(Event::Timer(0), 0, 0)]);
+    }
+}
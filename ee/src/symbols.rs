@@ -0,0 +1,314 @@
+// Address to symbol lookup, so addresses can be shown as `func+0x1c`.
+//
+// Symbols come from an ELF's .symtab/.strtab or from a map file. Map files are read line by line
+// and any line in one of these shapes is taken as a symbol, everything else is skipped:
+//
+//     00100000 main              plain address/name pairs
+//     00100000 T main            `nm` output
+//     00100000 00000058 T main   `nm -S` output
+//     0x0000000000100000  main   symbol lines of a GNU ld map (-Map)
+
+use crate::elf::{Elf, ElfError, SHT_SYMTAB};
+
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHN_UNDEF: u16 = 0;
+const SYM_SIZE: usize = 16;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub addr: u32,
+    // 0 when unknown, in which case the symbol covers everything up to the next one
+    pub size: u32,
+    pub name: String,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    // Sorted by address, sized symbols first among those sharing an address
+    symbols: Vec<Symbol>,
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let digits = text.strip_prefix("0x").unwrap_or(text);
+    u64::from_str_radix(digits, 16)
+        .ok()
+        .and_then(|value| u32::try_from(value).ok())
+}
+
+fn is_symbol_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$' | ':' | '@'))
+        && !name.ends_with(".o")
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Function, object and untyped symbols from the ELF's symbol table, if it has one
+    pub fn from_elf(elf: &Elf) -> Result<Self, ElfError> {
+        let mut table = SymbolTable::new();
+        let sections = elf.sections()?;
+        for symtab in sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+            let strtab = sections
+                .get(symtab.link as usize)
+                .ok_or(ElfError::Truncated("string table"))?;
+            let strings = elf.section_data(strtab)?;
+            for sym in elf.section_data(symtab)?.chunks_exact(SYM_SIZE) {
+                let name_offset = u32::from_le_bytes(sym[0..4].try_into().unwrap()) as usize;
+                let value = u32::from_le_bytes(sym[4..8].try_into().unwrap());
+                let size = u32::from_le_bytes(sym[8..12].try_into().unwrap());
+                let kind = sym[12] & 0xF;
+                let section_index = u16::from_le_bytes(sym[14..16].try_into().unwrap());
+                if !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) || section_index == SHN_UNDEF
+                {
+                    continue;
+                }
+                let Some(name) = strings.get(name_offset..).and_then(|rest| {
+                    let end = rest.iter().position(|&b| b == 0)?;
+                    std::str::from_utf8(&rest[..end]).ok()
+                }) else {
+                    continue;
+                };
+                // Local labels like `$L12` only add noise
+                if name.is_empty() || name.starts_with('$') {
+                    continue;
+                }
+                table.symbols.push(Symbol {
+                    addr: value,
+                    size,
+                    name: name.to_string(),
+                });
+            }
+        }
+        table.sort();
+        Ok(table)
+    }
+
+    pub fn parse_map(text: &str) -> Self {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (addr, size, name) = match fields.as_slice() {
+                [addr, name] => (addr, "0", name),
+                [addr, kind, name] if kind.len() == 1 => (addr, "0", name),
+                [addr, size, kind, name] if kind.len() == 1 => (addr, *size, name),
+                _ => continue,
+            };
+            let (Some(addr), Some(size)) = (parse_hex(addr), parse_hex(size)) else {
+                continue;
+            };
+            if is_symbol_name(name) {
+                table.symbols.push(Symbol {
+                    addr,
+                    size,
+                    name: name.to_string(),
+                });
+            }
+        }
+        table.sort();
+        table
+    }
+
+    // Adds the symbols of `other`, e.g. a map file on top of an ELF's own symbols
+    pub fn merge(&mut self, other: SymbolTable) {
+        self.symbols.extend(other.symbols);
+        self.sort();
+    }
+
+    pub fn insert(&mut self, addr: u32, size: u32, name: &str) {
+        self.symbols.push(Symbol {
+            addr,
+            size,
+            name: name.to_string(),
+        });
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        self.symbols
+            .sort_by_key(|symbol| (symbol.addr, symbol.size == 0));
+        self.symbols
+            .dedup_by(|a, b| a.addr == b.addr && a.name == b.name);
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+
+    // The symbol containing `addr` and the offset into it
+    pub fn lookup(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let after = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let start = self.symbols.get(after.checked_sub(1)?)?.addr;
+        let first = self.symbols.partition_point(|symbol| symbol.addr < start);
+        let symbol = &self.symbols[first];
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((symbol, offset))
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // `func+0x1c`, `func` at its very start, or `None` when no symbol covers `addr`
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        self.lookup(addr).map(|(symbol, offset)| match offset {
+            0 => symbol.name.clone(),
+            _ => format!("{}+0x{:x}", symbol.name, offset),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+00100000 main
+00100100 T helper
+00100200 00000010 t sized_static
+not a symbol line
+Archive member included to satisfy reference by file (symbol)
+
+ .text          0x0000000000100300      0x120 crt0.o
+                0x0000000000100300                _start
+                0x0000000000100380                _exit
+ *(.rodata)
+";
+
+    #[test]
+    fn map_files_take_all_four_line_shapes() {
+        let table = SymbolTable::parse_map(MAP);
+        let symbols: Vec<(u32, u32, &str)> = table
+            .symbols()
+            .iter()
+            .map(|symbol| (symbol.addr, symbol.size, symbol.name.as_str()))
+            .collect();
+        assert_eq!(
+            symbols,
+            [
+                (0x0010_0000, 0, "main"),
+                (0x0010_0100, 0, "helper"),
+                (0x0010_0200, 0x10, "sized_static"),
+                (0x0010_0300, 0, "_start"),
+                (0x0010_0380, 0, "_exit"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sized_symbols_end_where_their_size_says() {
+        let table = SymbolTable::parse_map(MAP);
+        // Unsized symbols run up to the next one
+        assert_eq!(table.describe(0x0010_001C).as_deref(), Some("main+0x1c"));
+        assert_eq!(table.describe(0x0010_00FC).as_deref(), Some("main+0xfc"));
+        assert_eq!(table.describe(0x0010_0100).as_deref(), Some("helper"));
+        assert_eq!(
+            table.describe(0x0010_020C).as_deref(),
+            Some("sized_static+0xc")
+        );
+        // Past a sized symbol nothing covers the address until the next one starts
+        assert_eq!(table.lookup(0x0010_0210), None);
+        assert_eq!(table.lookup(0x000F_FFFC), None);
+        assert_eq!(
+            table.find("_exit").map(|symbol| symbol.addr),
+            Some(0x0010_0380)
+        );
+
+        // A sized symbol wins over an unsized one at the same address
+        let mut table = table;
+        table.insert(0x0010_0000, 0x40, "main_sized");
+        assert_eq!(table.lookup(0x0010_0010).unwrap().0.name, "main_sized");
+        assert_eq!(table.lookup(0x0010_0040), None);
+    }
+
+    fn sym(name: u32, value: u32, size: u32, info: u8, section: u16) -> Vec<u8> {
+        let mut sym = Vec::with_capacity(SYM_SIZE);
+        sym.extend_from_slice(&name.to_le_bytes());
+        sym.extend_from_slice(&value.to_le_bytes());
+        sym.extend_from_slice(&size.to_le_bytes());
+        sym.extend_from_slice(&[info, 0]);
+        sym.extend_from_slice(&section.to_le_bytes());
+        sym
+    }
+
+    fn shdr(kind: u32, offset: usize, size: usize, link: u32) -> Vec<u8> {
+        let mut shdr = vec![0; 40];
+        shdr[4..8].copy_from_slice(&kind.to_le_bytes());
+        shdr[16..20].copy_from_slice(&(offset as u32).to_le_bytes());
+        shdr[20..24].copy_from_slice(&(size as u32).to_le_bytes());
+        shdr[24..28].copy_from_slice(&link.to_le_bytes());
+        shdr
+    }
+
+    // An executable with no segments whose only sections are a symbol table and its strings
+    fn elf_with_symbols() -> Vec<u8> {
+        let strings = b"\0main\0buffer\0printf\0$L12\0";
+        let mut symtab = sym(0, 0, 0, 0, 0);
+        symtab.extend(sym(1, 0x0010_0000, 0x40, STT_FUNC, 1));
+        symtab.extend(sym(6, 0x0020_0000, 8, STT_OBJECT, 2));
+        // Undefined, a local label and a section symbol are all left out
+        symtab.extend(sym(13, 0, 0, STT_FUNC, SHN_UNDEF));
+        symtab.extend(sym(20, 0x0010_0010, 0, STT_NOTYPE, 1));
+        symtab.extend(sym(0, 0x0010_0000, 0, 3, 1));
+
+        let mut data = vec![0; 52];
+        data[..4].copy_from_slice(b"\x7fELF");
+        data[4] = 1; // ELFCLASS32
+        data[5] = 1; // ELFDATA2LSB
+        data[16..18].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        data[18..20].copy_from_slice(&8u16.to_le_bytes()); // EM_MIPS
+        data[42..44].copy_from_slice(&32u16.to_le_bytes());
+        let symtab_offset = data.len();
+        data.extend_from_slice(&symtab);
+        let strtab_offset = data.len();
+        data.extend_from_slice(strings);
+        let shoff = data.len();
+        data.extend(shdr(0, 0, 0, 0));
+        data.extend(shdr(SHT_SYMTAB, symtab_offset, symtab.len(), 2));
+        data.extend(shdr(3, strtab_offset, strings.len(), 0)); // SHT_STRTAB
+        data[32..36].copy_from_slice(&(shoff as u32).to_le_bytes());
+        data[46..48].copy_from_slice(&40u16.to_le_bytes());
+        data[48..50].copy_from_slice(&3u16.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn elf_symtabs_give_defined_functions_and_objects() {
+        let data = elf_with_symbols();
+        let table = SymbolTable::from_elf(&Elf::parse(&data).unwrap()).unwrap();
+        assert_eq!(
+            table.symbols(),
+            [
+                Symbol {
+                    addr: 0x0010_0000,
+                    size: 0x40,
+                    name: "main".to_string(),
+                },
+                Symbol {
+                    addr: 0x0020_0000,
+                    size: 8,
+                    name: "buffer".to_string(),
+                },
+            ]
+        );
+        assert_eq!(table.describe(0x0010_001C).as_deref(), Some("main+0x1c"));
+        assert_eq!(table.describe(0x0020_0008), None);
+    }
+}
@@ -0,0 +1,498 @@
+// The EE's four 16-bit timers.
+//
+// Each timer counts BUSCLK (half the EE clock), BUSCLK/16, BUSCLK/256 or hblanks. It can be gated
+// by hblank or vblank, compared against Tn_COMP and made to interrupt through the INTC on a
+// compare match or an overflow. Timers 0 and 1 also have Tn_HOLD, which latches the count when an
+// SBUS interrupt comes in.
+//
+// Counters aren't ticked per instruction: a timer's count is brought up to date from the cycle
+// count whenever it's read or reconfigured, and the bus schedules an event for the next compare
+// match or overflow that has something to do (interrupt or zero return). The hblank gate is
+// treated as a pulse at the start of each line, so gate mode 0 never stops an hblank-gated timer.
+
+use crate::intc::{self, Intc};
+use crate::savestate::{StateError, StateReader, StateWriter};
+
+pub const TIMER_BASE: u32 = 0x1000_0000;
+// Distance between the timers' register blocks
+pub const TIMER_STRIDE: u32 = 0x800;
+pub const TIMER_COUNT: usize = 4;
+
+// Register offsets within a block
+pub const COUNT: u32 = 0x00;
+pub const MODE: u32 = 0x10;
+pub const COMP: u32 = 0x20;
+pub const HOLD: u32 = 0x30;
+
+// Tn_MODE bits
+pub const MODE_CLKS_MASK: u32 = 0x3;
+pub const MODE_GATE: u32 = 1 << 2;
+pub const MODE_GATS_VBLANK: u32 = 1 << 3;
+pub const MODE_GATM_SHIFT: u32 = 4;
+pub const MODE_GATM_MASK: u32 = 0x3 << MODE_GATM_SHIFT;
+pub const MODE_ZRET: u32 = 1 << 6;
+pub const MODE_CUE: u32 = 1 << 7;
+pub const MODE_CMPE: u32 = 1 << 8;
+pub const MODE_OVFE: u32 = 1 << 9;
+pub const MODE_EQUF: u32 = 1 << 10;
+pub const MODE_OVFF: u32 = 1 << 11;
+
+// Clock sources
+pub const CLKS_BUSCLK: u32 = 0;
+pub const CLKS_BUSCLK_16: u32 = 1;
+pub const CLKS_BUSCLK_256: u32 = 2;
+pub const CLKS_HBLANK: u32 = 3;
+
+// Gate modes
+pub const GATM_WHILE_LOW: u32 = 0;
+pub const GATM_RESET_ON_RISING: u32 = 1;
+pub const GATM_RESET_ON_FALLING: u32 = 2;
+pub const GATM_RESET_ON_BOTH: u32 = 3;
+
+const MODE_WRITABLE: u32 = 0x3FF;
+const COUNTER_RANGE: u64 = 0x1_0000;
+
+#[derive(Clone, Debug, Default)]
+struct Timer {
+    count: u32,
+    mode: u32,
+    comp: u32,
+    hold: u32,
+    // EE cycle the count was last brought up to date for, less any partial tick
+    synced_at: u64,
+}
+
+impl Timer {
+    // EE cycles per tick, None when clocked by hblank
+    fn divider(&self) -> Option<u64> {
+        match self.mode & MODE_CLKS_MASK {
+            CLKS_BUSCLK => Some(2),
+            CLKS_BUSCLK_16 => Some(2 * 16),
+            CLKS_BUSCLK_256 => Some(2 * 256),
+            _ => None,
+        }
+    }
+
+    fn gate_mode(&self) -> u32 {
+        (self.mode & MODE_GATM_MASK) >> MODE_GATM_SHIFT
+    }
+
+    fn gated_by_vblank(&self) -> bool {
+        self.mode & MODE_GATE != 0 && self.mode & MODE_GATS_VBLANK != 0
+    }
+
+    fn gated_by_hblank(&self) -> bool {
+        self.mode & MODE_GATE != 0 && self.mode & MODE_GATS_VBLANK == 0
+    }
+
+    fn counting(&self, in_vblank: bool) -> bool {
+        let held = self.gated_by_vblank() && self.gate_mode() == GATM_WHILE_LOW && in_vblank;
+        self.mode & MODE_CUE != 0 && !held
+    }
+
+    // Whether reaching COMP or overflowing does anything beyond wrapping the count
+    fn watches_compare(&self) -> bool {
+        self.mode & (MODE_CMPE | MODE_ZRET) != 0
+    }
+
+    fn watches_overflow(&self) -> bool {
+        self.mode & MODE_OVFE != 0
+    }
+
+    // Ticks until the count next equals COMP and until it wraps to zero
+    fn ticks_to_compare(&self) -> u64 {
+        let (count, comp) = (self.count as u64, self.comp as u64);
+        if comp > count {
+            comp - count
+        } else {
+            comp + COUNTER_RANGE - count
+        }
+    }
+
+    fn ticks_to_overflow(&self) -> u64 {
+        COUNTER_RANGE - self.count as u64
+    }
+
+    // The flags only latch while their interrupt is enabled, and a flag that is already set
+    // doesn't interrupt again until cleared
+    fn flag(&mut self, enable: u32, flag: u32, source: u32, intc: &mut Intc) {
+        if self.mode & enable != 0 {
+            if self.mode & flag == 0 {
+                intc.raise(source);
+            }
+            self.mode |= flag;
+        }
+    }
+
+    fn advance(&mut self, mut ticks: u64, source: u32, intc: &mut Intc) {
+        if !self.watches_compare() && !self.watches_overflow() {
+            self.count = ((self.count as u64 + ticks) % COUNTER_RANGE) as u32;
+            return;
+        }
+        while ticks > 0 {
+            let to_compare = self.ticks_to_compare();
+            let to_overflow = self.ticks_to_overflow();
+            let step = ticks.min(to_compare).min(to_overflow);
+            ticks -= step;
+            let mut count = self.count as u64 + step;
+            if step == to_compare {
+                self.flag(MODE_CMPE, MODE_EQUF, source, intc);
+                if self.mode & MODE_ZRET != 0 {
+                    count = 0;
+                }
+            }
+            if count == COUNTER_RANGE {
+                self.flag(MODE_OVFE, MODE_OVFF, source, intc);
+                count = 0;
+            }
+            self.count = count as u32;
+        }
+    }
+
+    // Brings the count up to `now`
+    fn sync(&mut self, now: u64, in_vblank: bool, source: u32, intc: &mut Intc) {
+        match self.divider() {
+            Some(divider) if self.counting(in_vblank) => {
+                let ticks = now.saturating_sub(self.synced_at) / divider;
+                self.synced_at += ticks * divider;
+                self.advance(ticks, source, intc);
+            }
+            _ => self.synced_at = now,
+        }
+    }
+
+    // The EE cycle of the next compare match or overflow that needs handling
+    fn next_event(&self, in_vblank: bool) -> Option<u64> {
+        let divider = self.divider().filter(|_| self.counting(in_vblank))?;
+        let ticks = match (self.watches_compare(), self.watches_overflow()) {
+            (true, true) => self.ticks_to_compare().min(self.ticks_to_overflow()),
+            (true, false) => self.ticks_to_compare(),
+            (false, true) => self.ticks_to_overflow(),
+            (false, false) => return None,
+        };
+        Some(self.synced_at + ticks * divider)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Timers {
+    timers: [Timer; TIMER_COUNT],
+    in_vblank: bool,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // The timer and register offset `addr` falls on, if it's a timer register
+    pub fn decode(addr: u32) -> Option<(usize, u32)> {
+        let offset = addr.checked_sub(TIMER_BASE)?;
+        let index = (offset / TIMER_STRIDE) as usize;
+        let reg = offset % TIMER_STRIDE;
+        let has_hold = index < 2;
+        match reg {
+            COUNT | MODE | COMP if index < TIMER_COUNT => Some((index, reg)),
+            HOLD if has_hold => Some((index, reg)),
+            _ => None,
+        }
+    }
+
+    fn sync(&mut self, index: usize, now: u64, intc: &mut Intc) {
+        let source = intc::INT_TIM0 + index as u32;
+        self.timers[index].sync(now, self.in_vblank, source, intc);
+    }
+
+    pub fn read(&mut self, index: usize, reg: u32, now: u64, intc: &mut Intc) -> u32 {
+        self.sync(index, now, intc);
+        let timer = &self.timers[index];
+        match reg {
+            COUNT => timer.count,
+            MODE => timer.mode,
+            COMP => timer.comp,
+            _ => timer.hold,
+        }
+    }
+
+    // The caller reschedules the timer's event afterwards
+    pub fn write(&mut self, index: usize, reg: u32, value: u32, now: u64, intc: &mut Intc) {
+        self.sync(index, now, intc);
+        let timer = &mut self.timers[index];
+        match reg {
+            COUNT => timer.count = value & 0xFFFF,
+            // Writing 1 to EQUF or OVFF clears it
+            MODE => {
+                let flags = timer.mode & (MODE_EQUF | MODE_OVFF) & !value;
+                timer.mode = (value & MODE_WRITABLE) | flags;
+            }
+            COMP => timer.comp = value & 0xFFFF,
+            _ => timer.hold = value & 0xFFFF,
+        }
+    }
+
+    pub fn next_event(&self, index: usize) -> Option<u64> {
+        self.timers[index].next_event(self.in_vblank)
+    }
+
+    // A scheduled compare match or overflow is due
+    pub fn timer_event(&mut self, index: usize, now: u64, intc: &mut Intc) {
+        self.sync(index, now, intc);
+    }
+
+    pub fn hblank(&mut self, now: u64, intc: &mut Intc) {
+        for index in 0..TIMER_COUNT {
+            self.sync(index, now, intc);
+            let in_vblank = self.in_vblank;
+            let timer = &mut self.timers[index];
+            if timer.gated_by_hblank() && timer.gate_mode() != GATM_WHILE_LOW {
+                timer.count = 0;
+            }
+            if timer.mode & MODE_CLKS_MASK == CLKS_HBLANK && timer.counting(in_vblank) {
+                timer.advance(1, intc::INT_TIM0 + index as u32, intc);
+            }
+        }
+    }
+
+    // Vblank starting (the gate's rising edge) or ending (its falling edge)
+    pub fn vblank(&mut self, start: bool, now: u64, intc: &mut Intc) {
+        for index in 0..TIMER_COUNT {
+            // Counted up to the edge with the gate as it was
+            self.sync(index, now, intc);
+            let timer = &mut self.timers[index];
+            if !timer.gated_by_vblank() {
+                continue;
+            }
+            let reset = match timer.gate_mode() {
+                GATM_RESET_ON_RISING => start,
+                GATM_RESET_ON_FALLING => !start,
+                GATM_RESET_ON_BOTH => true,
+                _ => false,
+            };
+            if reset {
+                timer.count = 0;
+            }
+        }
+        self.in_vblank = start;
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        self.in_vblank
+    }
+
+    // An SBUS interrupt copies timers 0 and 1's counts to their hold registers
+    pub fn latch_hold(&mut self, now: u64, intc: &mut Intc) {
+        for index in 0..2 {
+            self.sync(index, now, intc);
+            let timer = &mut self.timers[index];
+            timer.hold = timer.count;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        for timer in &self.timers {
+            w.write_u32(timer.count);
+            w.write_u32(timer.mode);
+            w.write_u32(timer.comp);
+            w.write_u32(timer.hold);
+            w.write_u64(timer.synced_at);
+        }
+    }
+
+    // Whether vblank is in progress isn't saved, the bus knows it from its schedule
+    pub fn load_state(&mut self, r: &mut StateReader, in_vblank: bool) -> Result<(), StateError> {
+        let mut timers: [Timer; TIMER_COUNT] = Default::default();
+        for timer in timers.iter_mut() {
+            timer.count = r.read_u32()?;
+            timer.mode = r.read_u32()?;
+            timer.comp = r.read_u32()?;
+            timer.hold = r.read_u32()?;
+            timer.synced_at = r.read_u64()?;
+        }
+        self.timers = timers;
+        self.in_vblank = in_vblank;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timers(index: usize, mode: u32, comp: u32) -> (Timers, Intc) {
+        let mut timers = Timers::new();
+        let mut intc = Intc::new();
+        timers.write(index, COMP, comp, 0, &mut intc);
+        timers.write(index, MODE, mode, 0, &mut intc);
+        (timers, intc)
+    }
+
+    fn count(timers: &mut Timers, index: usize, now: u64, intc: &mut Intc) -> u32 {
+        timers.read(index, COUNT, now, intc)
+    }
+
+    #[test]
+    fn clock_sources_tick_at_their_rates() {
+        let (mut t, mut intc) = timers(0, MODE_CUE | CLKS_BUSCLK, 0);
+        assert_eq!(count(&mut t, 0, 101, &mut intc), 50);
+
+        // Partial ticks carry over between reads
+        let (mut t, mut intc) = timers(0, MODE_CUE | CLKS_BUSCLK_16, 0);
+        assert_eq!(count(&mut t, 0, 319, &mut intc), 9);
+        assert_eq!(count(&mut t, 0, 320, &mut intc), 10);
+
+        let (mut t, mut intc) = timers(0, MODE_CUE | CLKS_BUSCLK_256, 0);
+        assert_eq!(count(&mut t, 0, 3 * 512 - 1, &mut intc), 2);
+        assert_eq!(count(&mut t, 0, 3 * 512, &mut intc), 3);
+
+        let (mut t, mut intc) = timers(0, MODE_CUE | CLKS_HBLANK, 0);
+        assert_eq!(count(&mut t, 0, 100_000, &mut intc), 0);
+        for now in [100, 200, 300] {
+            t.hblank(now, &mut intc);
+        }
+        assert_eq!(count(&mut t, 0, 100_000, &mut intc), 3);
+
+        // Nothing counts without CUE, and the count is 16 bits
+        let (mut t, mut intc) = timers(0, CLKS_BUSCLK, 0);
+        assert_eq!(count(&mut t, 0, 100, &mut intc), 0);
+        let (mut t, mut intc) = timers(0, MODE_CUE | CLKS_BUSCLK, 0);
+        assert_eq!(count(&mut t, 0, 2 * 0x1_0005, &mut intc), 5);
+    }
+
+    #[test]
+    fn compare_match_flags_and_interrupts_once_until_cleared() {
+        let (mut t, mut intc) = timers(2, MODE_CUE | MODE_CMPE, 10);
+        assert_eq!(t.next_event(2), Some(20));
+        assert_eq!(count(&mut t, 2, 19, &mut intc), 9);
+        assert_eq!(intc.stat(), 0);
+        t.timer_event(2, 20, &mut intc);
+        assert_eq!(count(&mut t, 2, 20, &mut intc), 10);
+        assert_eq!(intc.stat(), 1 << intc::INT_TIM2);
+        assert_ne!(t.read(2, MODE, 20, &mut intc) & MODE_EQUF, 0);
+
+        // Still flagged the next time round, so no new request
+        intc.write_stat(u32::MAX);
+        t.timer_event(2, 20 + 2 * 0x1_0000, &mut intc);
+        assert_eq!(intc.stat(), 0);
+
+        // Writing 0 to the flag keeps it, writing 1 clears it
+        let mode = MODE_CUE | MODE_CMPE;
+        t.write(2, MODE, mode, 20 + 2 * 0x1_0000, &mut intc);
+        assert_ne!(t.read(2, MODE, 0, &mut intc) & MODE_EQUF, 0);
+        t.write(2, MODE, mode | MODE_EQUF, 20 + 2 * 0x1_0000, &mut intc);
+        assert_eq!(t.read(2, MODE, 0, &mut intc) & MODE_EQUF, 0);
+        t.timer_event(2, 20 + 4 * 0x1_0000, &mut intc);
+        assert_eq!(intc.stat(), 1 << intc::INT_TIM2);
+
+        // Without CMPE a match neither flags nor interrupts
+        let (mut t, mut intc) = timers(2, MODE_CUE, 10);
+        assert_eq!(t.next_event(2), None);
+        assert_eq!(count(&mut t, 2, 40, &mut intc), 20);
+        assert_eq!(t.read(2, MODE, 40, &mut intc) & MODE_EQUF, 0);
+        assert_eq!(intc.stat(), 0);
+    }
+
+    #[test]
+    fn overflow_flags_and_interrupts() {
+        let (mut t, mut intc) = timers(1, MODE_CUE | MODE_OVFE, 0);
+        t.write(1, COUNT, 0xFFF0, 0, &mut intc);
+        assert_eq!(t.next_event(1), Some(2 * 0x10));
+        assert_eq!(count(&mut t, 1, 2 * 0x10 - 1, &mut intc), 0xFFFF);
+        assert_eq!(intc.stat(), 0);
+        assert_eq!(count(&mut t, 1, 2 * 0x12, &mut intc), 2);
+        assert_eq!(intc.stat(), 1 << intc::INT_TIM1);
+        let mode = t.read(1, MODE, 2 * 0x12, &mut intc);
+        assert_eq!(mode & (MODE_OVFF | MODE_EQUF), MODE_OVFF);
+
+        t.write(
+            1,
+            MODE,
+            MODE_CUE | MODE_OVFE | MODE_OVFF,
+            2 * 0x12,
+            &mut intc,
+        );
+        assert_eq!(t.read(1, MODE, 2 * 0x12, &mut intc) & MODE_OVFF, 0);
+    }
+
+    #[test]
+    fn zret_restarts_the_count_at_comp() {
+        // Counting 0..=4 then starting again, without flagging anything on its own
+        let (mut t, mut intc) = timers(3, MODE_CUE | MODE_ZRET, 5);
+        assert_eq!(t.next_event(3), Some(10));
+        assert_eq!(count(&mut t, 3, 2 * 4, &mut intc), 4);
+        assert_eq!(count(&mut t, 3, 2 * 5, &mut intc), 0);
+        assert_eq!(count(&mut t, 3, 2 * 17, &mut intc), 2);
+        assert_eq!(t.read(3, MODE, 2 * 17, &mut intc) & MODE_EQUF, 0);
+        assert_eq!(intc.stat(), 0);
+
+        // It never reaches an overflow: 0x1_0003 ticks is 13107 periods of 5 and 4 more
+        let (mut t, mut intc) = timers(3, MODE_CUE | MODE_ZRET | MODE_CMPE | MODE_OVFE, 5);
+        assert_eq!(count(&mut t, 3, 2 * 0x1_0003, &mut intc), 4);
+        let mode = t.read(3, MODE, 2 * 0x1_0003, &mut intc);
+        assert_eq!(mode & (MODE_OVFF | MODE_EQUF), MODE_EQUF);
+        assert_eq!(intc.stat(), 1 << intc::INT_TIM3);
+    }
+
+    #[test]
+    fn vblank_gate_modes() {
+        let vblank_gate = MODE_CUE | MODE_GATE | MODE_GATS_VBLANK;
+        let gated = |gatm: u32| {
+            let (mut t, mut intc) = timers(0, vblank_gate | gatm << MODE_GATM_SHIFT, 0);
+            // 10 ticks before vblank, 10 during it and 10 after
+            let before = count(&mut t, 0, 20, &mut intc);
+            t.vblank(true, 20, &mut intc);
+            let start = count(&mut t, 0, 20, &mut intc);
+            let during = count(&mut t, 0, 40, &mut intc);
+            t.vblank(false, 40, &mut intc);
+            let end = count(&mut t, 0, 40, &mut intc);
+            let after = count(&mut t, 0, 60, &mut intc);
+            [before, start, during, end, after]
+        };
+        // Counting stops for the length of vblank
+        assert_eq!(gated(GATM_WHILE_LOW), [10, 10, 10, 10, 20]);
+        assert_eq!(gated(GATM_RESET_ON_RISING), [10, 0, 10, 10, 20]);
+        assert_eq!(gated(GATM_RESET_ON_FALLING), [10, 10, 20, 0, 10]);
+        assert_eq!(gated(GATM_RESET_ON_BOTH), [10, 0, 10, 0, 10]);
+
+        // Without GATE vblank changes nothing
+        let (mut t, mut intc) = timers(0, MODE_CUE | MODE_GATS_VBLANK, 0);
+        t.vblank(true, 20, &mut intc);
+        assert_eq!(count(&mut t, 0, 40, &mut intc), 20);
+    }
+
+    #[test]
+    fn hblank_gate_resets_on_each_line() {
+        let hblank_gate = MODE_CUE | MODE_GATE;
+        let (mut t, mut intc) = timers(1, hblank_gate | GATM_RESET_ON_RISING << MODE_GATM_SHIFT, 0);
+        t.hblank(20, &mut intc);
+        assert_eq!(count(&mut t, 1, 30, &mut intc), 5);
+        t.hblank(30, &mut intc);
+        assert_eq!(count(&mut t, 1, 30, &mut intc), 0);
+
+        // Mode 0 is a pulse, so it never holds the count
+        let (mut t, mut intc) = timers(1, hblank_gate, 0);
+        t.hblank(20, &mut intc);
+        assert_eq!(count(&mut t, 1, 30, &mut intc), 15);
+    }
+
+    #[test]
+    fn sbus_latches_timers_0_and_1_into_hold() {
+        let mut t = Timers::new();
+        let mut intc = Intc::new();
+        for index in 0..TIMER_COUNT {
+            t.write(index, MODE, MODE_CUE | CLKS_BUSCLK, 0, &mut intc);
+        }
+        t.write(1, COUNT, 100, 0, &mut intc);
+        t.latch_hold(40, &mut intc);
+        assert_eq!(t.read(0, HOLD, 100, &mut intc), 20);
+        assert_eq!(t.read(1, HOLD, 100, &mut intc), 120);
+        // Holding doesn't stop the count
+        assert_eq!(count(&mut t, 0, 100, &mut intc), 50);
+
+        // Only timers 0 and 1 have a hold register
+        let hold = |index: u32| Timers::decode(TIMER_BASE + index * TIMER_STRIDE + HOLD);
+        assert_eq!(hold(0), Some((0, HOLD)));
+        assert_eq!(hold(1), Some((1, HOLD)));
+        assert_eq!(hold(2), None);
+        assert_eq!(hold(3), None);
+    }
+}
@@ -0,0 +1,575 @@
+// Instruction-level execution tracer.
+//
+// Every traced instruction produces one record holding its pc, the raw instruction word and the
+// registers it changed. Three encodings are supported:
+//
+// Text, one instruction per line, so two runs can be compared with ordinary diff tools:
+//
+//     00100008: 27bdffc0  addiu   sp, sp, -0x40                 | sp=000000000007ffc0
+//
+// When the tracer has a symbol table the pc is followed by its symbol and branch targets are
+// annotated, e.g. `00100008 <main+0x8>: 27bdffc0  ...`; such lines read back the same way.
+//
+// PCSX2, for diffing against the EE disassembly log PCSX2 writes (`EE.Disasm`): a pc in
+// upper-case hex, a colon and a tab, then the mnemonic and its operands separated by a tab:
+//
+//     00100008:	addiu	sp, sp, -0x40
+//
+// Only the pc is meant to line up with PCSX2's log. Mnemonics and operands are spelled the way
+// this disassembler spells them, which needn't match PCSX2's, and neither the instruction word
+// nor register values are written, so reading a log back only recovers the pcs and `trace-diff`
+// compares such logs on the path taken alone. Lines that don't start with a pc, a colon and a
+// tab, e.g. other log output interleaved by PCSX2, are skipped.
+//
+// Binary, for long runs: an 8 byte magic, a little-endian u16 version, then per record
+// pc (u32), raw (u32), change count (u8) and for each change the register id (u8) followed by
+// its new value (u128). All integers are little-endian.
+
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::Cpu;
+use crate::disasm::{self, GPR_NAMES};
+use crate::symbols::SymbolTable;
+
+pub const BINARY_MAGIC: &[u8; 8] = b"LEETRACE";
+pub const BINARY_VERSION: u16 = 1;
+
+// Register ids used in trace records. 0..=31 are the GPRs.
+pub const REG_HI: u8 = 32;
+pub const REG_LO: u8 = 33;
+pub const REG_SA: u8 = 34;
+
+pub fn reg_name(reg: u8) -> &'static str {
+    match reg {
+        0..=31 => GPR_NAMES[reg as usize],
+        REG_HI => "hi",
+        REG_LO => "lo",
+        REG_SA => "sa",
+        _ => "?",
+    }
+}
+
+fn reg_from_name(name: &str) -> Option<u8> {
+    match name {
+        "hi" => Some(REG_HI),
+        "lo" => Some(REG_LO),
+        "sa" => Some(REG_SA),
+        _ => GPR_NAMES.iter().position(|&n| n == name).map(|i| i as u8),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    Pcsx2,
+    Binary,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegChange {
+    pub reg: u8,
+    pub value: u128,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub pc: u32,
+    pub raw: u32,
+    pub changes: Vec<RegChange>,
+}
+
+impl TraceRecord {
+    pub fn to_text(&self) -> String {
+        self.to_text_with_symbols(None)
+    }
+
+    pub fn to_text_with_symbols(&self, symbols: Option<&SymbolTable>) -> String {
+        let location = symbols
+            .and_then(|symbols| symbols.describe(self.pc))
+            .map(|name| format!(" <{}>", name))
+            .unwrap_or_default();
+        let disasm = match symbols {
+            Some(symbols) => disasm::disassemble_with_symbols(self.pc, self.raw, symbols),
+            None => disasm::disassemble(self.pc, self.raw),
+        };
+        let mut line = format!(
+            "{:08x}{}: {:08x}  {:<38}",
+            self.pc, location, self.raw, disasm
+        );
+        line.push('|');
+        for change in &self.changes {
+            line.push_str(&format!(
+                " {}={}",
+                reg_name(change.reg),
+                format_value(change.value)
+            ));
+        }
+        line
+    }
+
+    pub fn to_pcsx2(&self) -> String {
+        let disasm = disasm::disassemble(self.pc, self.raw);
+        let (mnemonic, operands) = disasm.split_once(' ').unwrap_or((&disasm, ""));
+        let operands = operands.trim_start();
+        if operands.is_empty() {
+            format!("{:08X}:\t{}", self.pc, mnemonic)
+        } else {
+            format!("{:08X}:\t{}\t{}", self.pc, mnemonic, operands)
+        }
+    }
+
+    // Only the pc survives in a PCSX2 line, so records are compared on that alone
+    pub fn pc_only(&self) -> Self {
+        TraceRecord {
+            pc: self.pc,
+            raw: 0,
+            changes: Vec::new(),
+        }
+    }
+
+    fn parse_text(line: &str) -> Option<Self> {
+        let (head, changes) = line.rsplit_once('|')?;
+        // The pc may be followed by a `<symbol>` before the colon
+        let (location, instruction) = head.split_once(": ")?;
+        let pc = u32::from_str_radix(location.split_whitespace().next()?, 16).ok()?;
+        let raw = u32::from_str_radix(instruction.split_whitespace().next()?, 16).ok()?;
+        let changes = changes
+            .split_whitespace()
+            .map(|change| {
+                let (name, value) = change.split_once('=')?;
+                Some(RegChange {
+                    reg: reg_from_name(name)?,
+                    value: u128::from_str_radix(value, 16).ok()?,
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(TraceRecord { pc, raw, changes })
+    }
+
+    fn parse_pcsx2(line: &str) -> Option<Self> {
+        let (pc, rest) = line.split_at_checked(8)?;
+        if !rest.starts_with(":\t") || !pc.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        Some(TraceRecord {
+            pc: u32::from_str_radix(pc, 16).ok()?,
+            raw: 0,
+            changes: Vec::new(),
+        })
+    }
+
+    fn write_binary(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(&self.pc.to_le_bytes())?;
+        out.write_all(&self.raw.to_le_bytes())?;
+        out.write_all(&[self.changes.len() as u8])?;
+        for change in &self.changes {
+            out.write_all(&[change.reg])?;
+            out.write_all(&change.value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+// Values that fit in 64 bits are printed as a doubleword, full quadwords otherwise
+fn format_value(value: u128) -> String {
+    if value >> 64 == 0 {
+        format!("{:016x}", value)
+    } else {
+        format!("{:032x}", value)
+    }
+}
+
+// Register state captured before an instruction runs, used to work out what it changed
+pub(crate) struct RegSnapshot {
+    gprs: [u128; 32],
+    hi0: u64,
+    lo0: u64,
+    sa: u64,
+}
+
+impl RegSnapshot {
+    pub(crate) fn capture(cpu: &Cpu) -> Self {
+        RegSnapshot {
+            gprs: cpu.gprs,
+            hi0: cpu.hi0,
+            lo0: cpu.lo0,
+            sa: cpu.sa,
+        }
+    }
+
+    pub(crate) fn changes(&self, cpu: &Cpu) -> Vec<RegChange> {
+        let mut changes = Vec::new();
+        for (reg, (&old, &new)) in self.gprs.iter().zip(cpu.gprs.iter()).enumerate() {
+            if old != new {
+                changes.push(RegChange {
+                    reg: reg as u8,
+                    value: new,
+                });
+            }
+        }
+        for (reg, old, new) in [
+            (REG_HI, self.hi0, cpu.hi0),
+            (REG_LO, self.lo0, cpu.lo0),
+            (REG_SA, self.sa, cpu.sa),
+        ] {
+            if old != new {
+                changes.push(RegChange {
+                    reg,
+                    value: new as u128,
+                });
+            }
+        }
+        changes
+    }
+}
+
+pub struct Tracer {
+    out: Box<dyn Write>,
+    format: TraceFormat,
+    ranges: Vec<Range<u32>>,
+    symbols: Option<Arc<SymbolTable>>,
+    header_written: bool,
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    pub fn new(out: impl Write + 'static, format: TraceFormat) -> Self {
+        Tracer {
+            out: Box::new(out),
+            format,
+            ranges: Vec::new(),
+            symbols: None,
+            header_written: false,
+            error: None,
+        }
+    }
+
+    // Only trace instructions whose pc falls in `range`. Can be called several times; with no
+    // ranges every instruction is traced.
+    pub fn add_range(&mut self, range: Range<u32>) {
+        self.ranges.push(range);
+    }
+
+    // Symbolizes text traces; binary traces are unaffected
+    pub fn set_symbols(&mut self, symbols: Arc<SymbolTable>) {
+        self.symbols = Some(symbols);
+    }
+
+    #[inline]
+    pub fn wants(&self, pc: u32) -> bool {
+        self.error.is_none()
+            && (self.ranges.is_empty() || self.ranges.iter().any(|r| r.contains(&pc)))
+    }
+
+    pub fn record(&mut self, record: &TraceRecord) {
+        if let Err(err) = self.write_record(record) {
+            // Keep the first error around for `finish`, and stop tracing
+            self.error = Some(err);
+        }
+    }
+
+    fn write_record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(
+                self.out,
+                "{}",
+                record.to_text_with_symbols(self.symbols.as_deref())
+            ),
+            TraceFormat::Pcsx2 => writeln!(self.out, "{}", record.to_pcsx2()),
+            TraceFormat::Binary => {
+                if !self.header_written {
+                    self.out.write_all(BINARY_MAGIC)?;
+                    self.out.write_all(&BINARY_VERSION.to_le_bytes())?;
+                    self.header_written = true;
+                }
+                record.write_binary(&mut self.out)
+            }
+        }
+    }
+
+    // Flushes the output and reports the first write error, if any
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.out.flush()
+    }
+}
+
+// Reads back a trace in either format; the format is detected from the first bytes
+pub struct TraceReader<R: BufRead> {
+    input: R,
+    format: TraceFormat,
+    line: String,
+}
+
+impl<R: BufRead> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let format = if input.fill_buf()?.starts_with(BINARY_MAGIC) {
+            let mut header = [0u8; 10];
+            input.read_exact(&mut header)?;
+            let version = u16::from_le_bytes([header[8], header[9]]);
+            if version != BINARY_VERSION {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported binary trace version {}", version),
+                ));
+            }
+            TraceFormat::Binary
+        } else if Self::looks_like_pcsx2(input.fill_buf()?) {
+            TraceFormat::Pcsx2
+        } else {
+            TraceFormat::Text
+        };
+        Ok(TraceReader {
+            input,
+            format,
+            line: String::new(),
+        })
+    }
+
+    pub fn format(&self) -> TraceFormat {
+        self.format
+    }
+
+    // Any line of a PCSX2 log within the first buffer in the shape of an instruction
+    fn looks_like_pcsx2(buf: &[u8]) -> bool {
+        buf.split(|&b| b == b'\n')
+            .filter_map(|line| std::str::from_utf8(line).ok())
+            .any(|line| TraceRecord::parse_pcsx2(line.trim_end_matches('\r')).is_some())
+    }
+
+    fn next_pcsx2(&mut self) -> io::Result<Option<TraceRecord>> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            if let Some(record) = TraceRecord::parse_pcsx2(self.line.trim_end()) {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    fn next_text(&mut self) -> io::Result<Option<TraceRecord>> {
+        loop {
+            self.line.clear();
+            if self.input.read_line(&mut self.line)? == 0 {
+                return Ok(None);
+            }
+            let line = self.line.trim_end();
+            if line.is_empty() {
+                continue;
+            }
+            return TraceRecord::parse_text(line).map(Some).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed trace line: {}", line),
+                )
+            });
+        }
+    }
+
+    fn next_binary(&mut self) -> io::Result<Option<TraceRecord>> {
+        if self.input.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut head = [0u8; 9];
+        self.input.read_exact(&mut head)?;
+        let pc = u32::from_le_bytes(head[0..4].try_into().unwrap());
+        let raw = u32::from_le_bytes(head[4..8].try_into().unwrap());
+        let mut changes = Vec::with_capacity(head[8] as usize);
+        for _ in 0..head[8] {
+            let mut change = [0u8; 17];
+            self.input.read_exact(&mut change)?;
+            changes.push(RegChange {
+                reg: change[0],
+                value: u128::from_le_bytes(change[1..].try_into().unwrap()),
+            });
+        }
+        Ok(Some(TraceRecord { pc, raw, changes }))
+    }
+}
+
+impl<R: BufRead> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.format {
+            TraceFormat::Text => self.next_text(),
+            TraceFormat::Pcsx2 => self.next_pcsx2(),
+            TraceFormat::Binary => self.next_binary(),
+        }
+        .transpose()
+    }
+}
+
+#[derive(Debug)]
+pub struct Divergence {
+    // Index of the first record that differs
+    pub index: usize,
+    // `None` when that trace ended early
+    pub left: Option<TraceRecord>,
+    pub right: Option<TraceRecord>,
+}
+
+// Walks two traces in lockstep and returns the first record where they differ, along with the
+// number of records compared
+pub fn first_divergence(
+    left: impl Iterator<Item = io::Result<TraceRecord>>,
+    right: impl Iterator<Item = io::Result<TraceRecord>>,
+) -> io::Result<(Option<Divergence>, usize)> {
+    let mut left = left.fuse();
+    let mut right = right.fuse();
+    let mut index = 0;
+    loop {
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        match (l, r) {
+            (None, None) => return Ok((None, index)),
+            (Some(l), Some(r)) if l == r => index += 1,
+            (left, right) => return Ok((Some(Divergence { index, left, right }), index)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Capture(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn records() -> Vec<TraceRecord> {
+        vec![
+            TraceRecord {
+                pc: 0x0010_0008,
+                raw: 0x27BD_FFC0, // addiu sp, sp, -0x40
+                changes: vec![RegChange {
+                    reg: 29,
+                    value: 0x7_FFC0,
+                }],
+            },
+            TraceRecord {
+                pc: 0x0010_000C,
+                raw: 0x0000_0000, // nop
+                changes: Vec::new(),
+            },
+            TraceRecord {
+                pc: 0x0010_0010,
+                raw: 0x7000_0000, // a quadword and hi/lo
+                changes: vec![
+                    RegChange {
+                        reg: 8,
+                        value: 0x0123_4567_89AB_CDEF_0011_2233_4455_6677,
+                    },
+                    RegChange {
+                        reg: REG_HI,
+                        value: 0xFFFF_FFFF_8000_0000,
+                    },
+                    RegChange {
+                        reg: REG_LO,
+                        value: 0,
+                    },
+                ],
+            },
+        ]
+    }
+
+    fn write_and_read(format: TraceFormat, symbols: Option<SymbolTable>) -> Vec<TraceRecord> {
+        let output = Capture::default();
+        let mut tracer = Tracer::new(output.clone(), format);
+        if let Some(symbols) = symbols {
+            tracer.set_symbols(Arc::new(symbols));
+        }
+        for record in records() {
+            tracer.record(&record);
+        }
+        tracer.finish().unwrap();
+        let data = output.0.borrow().clone();
+        let reader = TraceReader::new(data.as_slice()).unwrap();
+        assert_eq!(reader.format(), format);
+        reader.collect::<io::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn records_round_trip_in_both_formats() {
+        assert_eq!(write_and_read(TraceFormat::Binary, None), records());
+        assert_eq!(write_and_read(TraceFormat::Text, None), records());
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x0010_0000, 0x100, "main");
+        assert_eq!(write_and_read(TraceFormat::Text, Some(symbols)), records());
+    }
+
+    #[test]
+    fn text_lines_are_one_per_instruction() {
+        let line = records()[0].to_text();
+        assert!(line.starts_with("00100008: 27bdffc0  addiu"));
+        assert!(line.ends_with("| sp=000000000007ffc0"));
+    }
+
+    #[test]
+    fn pcsx2_lines_read_back_as_pcs() {
+        assert_eq!(records()[0].to_pcsx2(), "00100008:\taddiu\tsp, sp, -0x40");
+        assert_eq!(records()[1].to_pcsx2(), "0010000C:\tnop");
+        let pcs_only: Vec<_> = records().iter().map(TraceRecord::pc_only).collect();
+        assert_eq!(write_and_read(TraceFormat::Pcsx2, None), pcs_only);
+
+        // Other log output between instructions is skipped
+        let log = "EE: booting\n00100008:\taddiu\tsp, sp, -0x40\nsyscall 0x3c\n0010000C:\tnop\n";
+        let reader = TraceReader::new(log.as_bytes()).unwrap();
+        assert_eq!(reader.format(), TraceFormat::Pcsx2);
+        let pcs: Vec<u32> = reader.map(|record| record.unwrap().pc).collect();
+        assert_eq!(pcs, [0x0010_0008, 0x0010_000C]);
+    }
+
+    fn ok(records: Vec<TraceRecord>) -> impl Iterator<Item = io::Result<TraceRecord>> {
+        records.into_iter().map(Ok)
+    }
+
+    #[test]
+    fn first_divergence_finds_the_first_difference() {
+        let (divergence, compared) = first_divergence(ok(records()), ok(records())).unwrap();
+        assert!(divergence.is_none());
+        assert_eq!(compared, 3);
+
+        let mut changed = records();
+        changed[1].changes.push(RegChange { reg: 2, value: 1 });
+        changed[2].pc += 4;
+        let (divergence, compared) = first_divergence(ok(records()), ok(changed.clone())).unwrap();
+        let divergence = divergence.unwrap();
+        assert_eq!((divergence.index, compared), (1, 1));
+        assert_eq!(divergence.left, Some(records()[1].clone()));
+        assert_eq!(divergence.right, Some(changed[1].clone()));
+
+        // A trace that stops early diverges where it ends
+        let (divergence, _) = first_divergence(ok(records()), ok(records()[..2].to_vec())).unwrap();
+        let divergence = divergence.unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.right, None);
+    }
+
+    #[test]
+    fn first_divergence_reports_read_errors() {
+        let broken = vec![
+            Ok(records()[0].clone()),
+            Err(io::Error::new(io::ErrorKind::InvalidData, "bad record")),
+        ];
+        assert!(first_divergence(ok(records()), broken.into_iter()).is_err());
+    }
+}
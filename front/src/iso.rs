@@ -0,0 +1,259 @@
+// Just enough ISO 9660 to boot a disc image: find SYSTEM.CNF, read its BOOT2 line and pull the
+// ELF it names out of the image. Only the ISO 9660 directory tree is read, which PS2 DVDs carry
+// alongside UDF.
+
+const SECTOR_SIZE: usize = 2048;
+const PVD_SECTOR: usize = 16;
+const DIR_FLAG: u8 = 0x02;
+
+pub fn is_iso(data: &[u8]) -> bool {
+    let pvd = PVD_SECTOR * SECTOR_SIZE;
+    data.get(pvd..pvd + 6) == Some(b"\x01CD001")
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Extent {
+    lba: usize,
+    size: usize,
+    is_dir: bool,
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn extent_data(image: &[u8], extent: Extent) -> Result<&[u8], String> {
+    let start = extent.lba * SECTOR_SIZE;
+    image
+        .get(start..start + extent.size)
+        .ok_or_else(|| "disc image is truncated".to_string())
+}
+
+// Looks `name` up in a directory, ignoring case and the `;1` version suffix
+fn find_entry(image: &[u8], dir: Extent, name: &str) -> Result<Option<Extent>, String> {
+    let data = extent_data(image, dir)?;
+    let mut offset = 0;
+    while offset < data.len() {
+        let len = data[offset] as usize;
+        // Records don't cross sectors, a zero length pads out the rest of one
+        if len == 0 {
+            offset = (offset / SECTOR_SIZE + 1) * SECTOR_SIZE;
+            continue;
+        }
+        let record = data
+            .get(offset..offset + len)
+            .filter(|record| record.len() >= 33)
+            .ok_or_else(|| "corrupt directory record".to_string())?;
+        let name_len = record[32] as usize;
+        let entry_name = record
+            .get(33..33 + name_len)
+            .ok_or_else(|| "corrupt directory record".to_string())?;
+        let entry_name = String::from_utf8_lossy(entry_name);
+        let entry_name = entry_name.split(';').next().unwrap_or_default();
+        if entry_name.eq_ignore_ascii_case(name) {
+            return Ok(Some(Extent {
+                lba: u32_at(record, 2) as usize,
+                size: u32_at(record, 10) as usize,
+                is_dir: record[25] & DIR_FLAG != 0,
+            }));
+        }
+        offset += len;
+    }
+    Ok(None)
+}
+
+// Reads a file by its path on the disc, `\` or `/` separated
+pub fn read_file<'a>(image: &'a [u8], path: &str) -> Result<&'a [u8], String> {
+    if !is_iso(image) {
+        return Err("not an ISO 9660 image".to_string());
+    }
+    let pvd = PVD_SECTOR * SECTOR_SIZE;
+    let root = image
+        .get(pvd + 156..pvd + 190)
+        .ok_or_else(|| "disc image is truncated".to_string())?;
+    let mut extent = Extent {
+        lba: u32_at(root, 2) as usize,
+        size: u32_at(root, 10) as usize,
+        is_dir: true,
+    };
+    for part in path.split(['\\', '/']).filter(|part| !part.is_empty()) {
+        if !extent.is_dir {
+            return Err(format!("{}: not found on disc", path));
+        }
+        extent = find_entry(image, extent, part.split(';').next().unwrap_or_default())?
+            .ok_or_else(|| format!("{}: not found on disc", path))?;
+    }
+    if extent.is_dir {
+        return Err(format!("{}: is a directory", path));
+    }
+    extent_data(image, extent)
+}
+
+// The boot path from SYSTEM.CNF as the BIOS would pass it in argv[0], e.g.
+// `cdrom0:\SLUS_200.02;1`, and the ELF it names
+pub fn boot_elf(image: &[u8]) -> Result<(String, &[u8]), String> {
+    let config = String::from_utf8_lossy(read_file(image, "SYSTEM.CNF")?).into_owned();
+    let boot = config
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| key.trim() == "BOOT2")
+        .map(|(_, value)| value.trim().to_string())
+        .ok_or_else(|| "SYSTEM.CNF has no BOOT2 line".to_string())?;
+    let path = boot
+        .strip_prefix("cdrom0:")
+        .ok_or_else(|| format!("unsupported boot path {}", boot))?;
+    let elf = read_file(image, path)?;
+    Ok((boot, elf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT_LBA: usize = 20;
+    const DATA_LBA: usize = 22;
+
+    fn record(name: &str, lba: usize, size: usize, is_dir: bool) -> Vec<u8> {
+        let mut record = vec![0; (33 + name.len() + 1) & !1];
+        record[0] = record.len() as u8;
+        record[2..6].copy_from_slice(&(lba as u32).to_le_bytes());
+        record[6..10].copy_from_slice(&(lba as u32).to_be_bytes());
+        record[10..14].copy_from_slice(&(size as u32).to_le_bytes());
+        record[14..18].copy_from_slice(&(size as u32).to_be_bytes());
+        record[25] = if is_dir { DIR_FLAG } else { 0 };
+        record[32] = name.len() as u8;
+        record[33..33 + name.len()].copy_from_slice(name.as_bytes());
+        record
+    }
+
+    fn put(image: &mut Vec<u8>, lba: usize, data: &[u8]) {
+        let start = lba * SECTOR_SIZE;
+        if image.len() < start + data.len() {
+            image.resize((start + data.len()).next_multiple_of(SECTOR_SIZE), 0);
+        }
+        image[start..start + data.len()].copy_from_slice(data);
+    }
+
+    // A root holding SYSTEM.CNF and the boot ELF, and a DATA directory whose one file sits in
+    // its second sector
+    fn image(config: &str) -> Vec<u8> {
+        let elf = b"\x7fELF not really";
+        let mut image = Vec::new();
+
+        let mut pvd = vec![0; SECTOR_SIZE];
+        pvd[..6].copy_from_slice(b"\x01CD001");
+        // An L path table with just the root in it, which the lookups don't need
+        pvd[132..136].copy_from_slice(&10u32.to_le_bytes());
+        pvd[140..144].copy_from_slice(&18u32.to_le_bytes());
+        pvd[156..190].copy_from_slice(&record("\0", ROOT_LBA, SECTOR_SIZE, true));
+        put(&mut image, PVD_SECTOR, &pvd);
+        put(&mut image, 18, &[1, 0, ROOT_LBA as u8, 0, 0, 0, 1, 0, 0, 0]);
+
+        let root = [
+            record("\0", ROOT_LBA, SECTOR_SIZE, true),
+            record("\x01", ROOT_LBA, SECTOR_SIZE, true),
+            record("DATA", DATA_LBA, 2 * SECTOR_SIZE, true),
+            record("SLUS_200.02;1", 30, elf.len(), false),
+            record("SYSTEM.CNF;1", 31, config.len(), false),
+        ]
+        .concat();
+        put(&mut image, ROOT_LBA, &root);
+        put(
+            &mut image,
+            DATA_LBA,
+            &record("\0", DATA_LBA, 2 * SECTOR_SIZE, true),
+        );
+        put(
+            &mut image,
+            DATA_LBA + 1,
+            &record("FILE.BIN;1", 32, 4, false),
+        );
+        put(&mut image, 30, elf);
+        put(&mut image, 31, config.as_bytes());
+        put(&mut image, 32, b"data");
+        image
+    }
+
+    const CONFIG: &str = "BOOT2 = cdrom0:\\SLUS_200.02;1\r\nVER = 1.00\r\nVMODE = NTSC\r\n";
+
+    #[test]
+    fn boots_the_elf_system_cnf_names() {
+        let image = image(CONFIG);
+        assert!(is_iso(&image));
+        let (argv0, elf) = boot_elf(&image).unwrap();
+        assert_eq!(argv0, "cdrom0:\\SLUS_200.02;1");
+        assert_eq!(elf, b"\x7fELF not really");
+    }
+
+    #[test]
+    fn paths_ignore_case_separators_and_versions() {
+        let image = image(CONFIG);
+        for path in ["DATA/FILE.BIN", "\\data\\file.bin;1", "/Data//File.bin"] {
+            assert_eq!(read_file(&image, path), Ok(&b"data"[..]), "{}", path);
+        }
+        assert_eq!(read_file(&image, "system.cnf"), Ok(CONFIG.as_bytes()));
+    }
+
+    #[test]
+    fn missing_files_and_directories_are_errors() {
+        let image = image(CONFIG);
+        assert_eq!(
+            read_file(&image, "NOPE.BIN"),
+            Err("NOPE.BIN: not found on disc".to_string())
+        );
+        assert_eq!(
+            read_file(&image, "SYSTEM.CNF/FILE.BIN"),
+            Err("SYSTEM.CNF/FILE.BIN: not found on disc".to_string())
+        );
+        assert_eq!(
+            read_file(&image, "DATA"),
+            Err("DATA: is a directory".to_string())
+        );
+    }
+
+    #[test]
+    fn damaged_images_are_errors() {
+        let image = image(CONFIG);
+        assert!(!is_iso(&image[..PVD_SECTOR * SECTOR_SIZE + 4]));
+        assert_eq!(
+            read_file(&image[..SECTOR_SIZE], "SYSTEM.CNF"),
+            Err("not an ISO 9660 image".to_string())
+        );
+        // The signature is there but the root record isn't
+        let truncated = &image[..PVD_SECTOR * SECTOR_SIZE + 100];
+        assert_eq!(
+            read_file(truncated, "SYSTEM.CNF"),
+            Err("disc image is truncated".to_string())
+        );
+        assert_eq!(
+            read_file(&image[..31 * SECTOR_SIZE], "SYSTEM.CNF"),
+            Err("disc image is truncated".to_string())
+        );
+
+        let mut corrupt = image.clone();
+        // A name running off the end of its record
+        let name = corrupt
+            .windows(12)
+            .position(|window| window == b"SYSTEM.CNF;1")
+            .unwrap();
+        corrupt[name - 1] = 200;
+        assert_eq!(
+            read_file(&corrupt, "NOPE.BIN"),
+            Err("corrupt directory record".to_string())
+        );
+    }
+
+    #[test]
+    fn system_cnf_must_name_a_disc_boot_path() {
+        let no_boot = image("VER = 1.00\n");
+        assert_eq!(
+            boot_elf(&no_boot),
+            Err("SYSTEM.CNF has no BOOT2 line".to_string())
+        );
+        let host_boot = image("BOOT2 = host:game.elf\n");
+        assert_eq!(
+            boot_elf(&host_boot),
+            Err("unsupported boot path host:game.elf".to_string())
+        );
+    }
+}
@@ -1,11 +1,221 @@
-use ee::Cpu;
-
-fn main() {
-    let mut cpu = Cpu::new();
-    let bits = 0xa0000000_u32;
-    loop {
-        // read bits
-        cpu.update_pc();
-        cpu.exec(bits);
+mod iso;
+mod run;
+
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::process::ExitCode;
+
+use ee::disasm;
+use ee::elf::Elf;
+use ee::symbols::SymbolTable;
+use ee::trace::{self, TraceFormat, TraceReader, TraceRecord};
+
+use run::Command;
+
+fn trace_diff(left_path: &str, right_path: &str) -> ExitCode {
+    let open = |path: &str| {
+        File::open(path)
+            .and_then(|file| TraceReader::new(BufReader::new(file)))
+            .map_err(|err| eprintln!("{}: {}", path, err))
+    };
+    let (Ok(left), Ok(right)) = (open(left_path), open(right_path)) else {
+        return ExitCode::from(2);
+    };
+
+    // A PCSX2 log only has pcs, so against one only the path taken is compared
+    let pc_only = left.format() == TraceFormat::Pcsx2 || right.format() == TraceFormat::Pcsx2;
+    let strip = move |record: std::io::Result<TraceRecord>| {
+        if pc_only {
+            record.map(|record| record.pc_only())
+        } else {
+            record
+        }
+    };
+    match trace::first_divergence(left.map(strip), right.map(strip)) {
+        Ok((None, count)) => {
+            println!("traces match ({} instructions)", count);
+            ExitCode::SUCCESS
+        }
+        Ok((Some(divergence), _)) => {
+            println!("first divergence at instruction {}", divergence.index);
+            for (path, record) in [(left_path, divergence.left), (right_path, divergence.right)] {
+                match record {
+                    Some(record) if pc_only => println!("  {}: {:08x}", path, record.pc),
+                    Some(record) => println!("  {}: {}", path, record.to_text()),
+                    None => println!("  {}: <end of trace>", path),
+                }
+            }
+            ExitCode::from(1)
+        }
+        Err(err) => {
+            eprintln!("error reading traces: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+// Hex with or without `0x`
+fn parse_addr(text: &str) -> Option<u32> {
+    u32::from_str_radix(text.trim_start_matches("0x"), 16).ok()
+}
+
+// Disassembles `count` instructions from `start`. For an ELF, `start` is a virtual address and
+// defaults to the entry point; for anything else it is `base` plus a file offset.
+fn disasm(args: &[String]) -> Option<ExitCode> {
+    let mut base = 0;
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--base" => base = parse_addr(args.next()?)?,
+            _ if arg.starts_with("--") => return None,
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let (path, start, count) = match positional.as_slice() {
+        [path] => (*path, None, 32),
+        [path, start] => (*path, Some(parse_addr(start)?), 32),
+        [path, start, count] => (*path, Some(parse_addr(start)?), count.parse().ok()?),
+        _ => return None,
+    };
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return Some(ExitCode::from(run::EXIT_SETUP));
+        }
+    };
+
+    // (address, file offset) pairs that are mapped, and symbols if there are any
+    let (segments, symbols, start) = match Elf::parse(&data) {
+        Ok(elf) => {
+            let segments: Vec<(u32, u32, u32)> = elf
+                .segments
+                .iter()
+                .map(|segment| (segment.vaddr, segment.offset, segment.file_size))
+                .collect();
+            let symbols = SymbolTable::from_elf(&elf).unwrap_or_default();
+            (segments, symbols, start.unwrap_or(elf.entry))
+        }
+        Err(_) => (
+            vec![(base, 0, data.len() as u32)],
+            SymbolTable::new(),
+            start.unwrap_or(base),
+        ),
+    };
+    let word_at = |addr: u32| {
+        let (vaddr, offset, _) = segments
+            .iter()
+            .find(|&&(vaddr, _, size)| addr.wrapping_sub(vaddr) < size)?;
+        let offset = (offset + addr - vaddr) as usize;
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    };
+
+    for index in 0..count {
+        let addr = start.wrapping_add(4 * index);
+        let Some(raw) = word_at(addr) else {
+            eprintln!("{:08x} is outside {}", addr, path);
+            return Some(ExitCode::from(run::EXIT_SETUP));
+        };
+        if let Some((symbol, 0)) = symbols.lookup(addr) {
+            println!("{}:", symbol.name);
+        }
+        println!(
+            "{:08x}: {:08x}  {}",
+            addr,
+            raw,
+            disasm::disassemble_with_symbols(addr, raw, &symbols)
+        );
+    }
+    Some(ExitCode::SUCCESS)
+}
+
+fn usage() -> ExitCode {
+    eprintln!("usage: front [run] [options] [game.elf|game.iso [args...]]");
+    eprintln!("       front trace [options] [--out <file>] [--format text|binary|pcsx2]");
+    eprintln!("                   [--range <start>-<end>] [game.elf [args...]]");
+    eprintln!("       front bench [options] [game.elf [args...]]");
+    eprintln!("       front disasm [--base <addr>] <file> [<start> [<count>]]");
+    eprintln!("       front trace-diff <left> <right>");
+    eprintln!();
+    eprintln!("options: --bios <rom>  --map <file>  --hle  --host-dir <dir>  --console <file>");
+    eprintln!("         --max-instructions <n>  --headless  --dump-regs-on-exit");
+    eprintln!("         --load-slot <n>  --save-slot <n> (run)  --gdb <host:port> (run)");
+    eprintln!("         --save-slot writes the state once the run stops, however it stops,");
+    eprintln!("         unless the emulator panics");
+    eprintln!();
+    eprintln!("exit status: the guest's own on exit, or 123 if that is non-zero and doesn't fit");
+    eprintln!("             in a byte or clashes with another code; 1 on a setup error, 2 on a");
+    eprintln!("             usage error, 124 when --max-instructions runs out, 125 on a guest");
+    eprintln!("             fault (--headless), 126 if the emulator panics");
+    ExitCode::from(run::EXIT_USAGE)
+}
+
+// Splits off the subcommand. Without one it's `run`, so `front --bios rom.bin` and
+// `front game.elf` still work.
+fn subcommand(all_args: &[String]) -> Option<(&str, &[String])> {
+    let (command, args) = all_args.split_first()?;
+    match command.as_str() {
+        "run" | "trace" | "bench" | "disasm" | "trace-diff" => Some((command.as_str(), args)),
+        _ => Some(("run", all_args)),
+    }
+}
+
+fn main() -> ExitCode {
+    let all_args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((command, args)) = subcommand(&all_args) else {
+        return usage();
+    };
+    let code = match (command, args) {
+        ("run", args) => run::run(args, Command::Run),
+        ("trace", args) => run::run(args, Command::Trace),
+        ("bench", args) => run::run(args, Command::Bench),
+        ("disasm", args) => disasm(args),
+        ("trace-diff", [left, right]) => Some(trace_diff(left, right)),
+        _ => None,
+    };
+    code.unwrap_or_else(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn subcommands_are_split_off() {
+        let args = strings(&["trace", "--out", "t.log", "game.elf"]);
+        assert_eq!(subcommand(&args), Some(("trace", &args[1..])));
+        let args = strings(&["trace-diff", "a.log", "b.log"]);
+        assert_eq!(subcommand(&args), Some(("trace-diff", &args[1..])));
+        let args = strings(&["run"]);
+        assert_eq!(subcommand(&args), Some(("run", &args[1..])));
+        assert_eq!(subcommand(&[]), None);
+    }
+
+    #[test]
+    fn anything_else_is_run_with_every_argument() {
+        for args in [
+            strings(&["--bios", "rom.bin"]),
+            strings(&["game.elf", "arg"]),
+            strings(&["runner.elf"]),
+        ] {
+            assert_eq!(subcommand(&args), Some(("run", args.as_slice())));
+        }
+    }
+
+    #[test]
+    fn malformed_subcommands_are_usage_errors() {
+        assert_eq!(disasm(&strings(&["--bogus", "file.bin"])), None);
+        assert_eq!(disasm(&strings(&["file.bin", "zz"])), None);
+        assert_eq!(disasm(&[]), None);
+        assert_eq!(
+            disasm(&strings(&["/nonexistent/file.bin"])),
+            Some(ExitCode::from(run::EXIT_SETUP))
+        );
     }
 }
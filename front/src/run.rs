@@ -0,0 +1,551 @@
+// The `run`, `trace` and `bench` subcommands: booting a machine from a BIOS, ELF or disc image
+// and running it until the guest exits, faults or runs out of instructions.
+
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
+use ee::callstack::{self, FaultReporter};
+use ee::checkpoint::CheckpointObserver;
+use ee::console::Console;
+use ee::cop0::{self, Exception};
+use ee::disasm::GPR_NAMES;
+use ee::elf::{self, Elf};
+use ee::gdb::{GdbStub, SessionEnd};
+use ee::hle::hostfs::HostFs;
+use ee::hle::kernel::Kernel;
+use ee::observer::CpuObserver;
+use ee::ps2_bus::Ps2Bus;
+use ee::savestate;
+use ee::symbols::SymbolTable;
+use ee::trace::{TraceFormat, Tracer};
+use ee::{Cpu, StepResult};
+
+use crate::{iso, parse_addr};
+
+// Exit codes besides the guest's own exit status, which is passed through unless it would be
+// mistaken for one of these
+pub const EXIT_SETUP: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+// The guest exited with a non-zero status that doesn't fit in a byte or collides with another
+// code here
+pub const EXIT_GUEST_FAILED: u8 = 123;
+// `--max-instructions` ran out first, the same code `timeout(1)` uses
+pub const EXIT_TIMEOUT: u8 = 124;
+// A headless run stopped at a guest fault
+pub const EXIT_FAULT: u8 = 125;
+// The emulator itself panicked
+pub const EXIT_PANIC: u8 = 126;
+
+const RESERVED_EXITS: [u8; 6] = [
+    EXIT_SETUP,
+    EXIT_USAGE,
+    EXIT_GUEST_FAILED,
+    EXIT_TIMEOUT,
+    EXIT_FAULT,
+    EXIT_PANIC,
+];
+
+fn guest_exit_code(status: i32) -> u8 {
+    match u8::try_from(status) {
+        Ok(code) if !RESERVED_EXITS.contains(&code) => code,
+        _ => EXIT_GUEST_FAILED,
+    }
+}
+
+const BENCH_INSTRUCTIONS: u64 = 100_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Trace,
+    Bench,
+}
+
+#[derive(Default)]
+struct Options {
+    bios: Option<String>,
+    // An ELF or a disc image
+    image: Option<String>,
+    map: Option<String>,
+    // Extra arguments passed to the ELF after argv[0]
+    guest_args: Vec<String>,
+    gdb_addr: Option<String>,
+    load_slot: Option<u32>,
+    save_slot: Option<u32>,
+    max_instructions: Option<u64>,
+    // Service syscalls with the HLE kernel instead of a BIOS
+    hle: bool,
+    // Guest debug output goes here instead of stdout
+    console: Option<String>,
+    // Directory behind the HLE kernel's `host:` device, the ELF's own by default
+    host_dir: Option<String>,
+    // For scripts: no display (there is none yet either way) and a guest fault ends the run
+    headless: bool,
+    dump_regs: bool,
+    // Trace output, stdout by default
+    trace_out: Option<String>,
+    trace_format: Option<TraceFormat>,
+    trace_ranges: Vec<(u32, u32)>,
+}
+
+fn parse_options(args: &[String], command: Command) -> Option<Options> {
+    let mut options = Options::default();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        if !flag.starts_with("--") {
+            match options.image {
+                None => options.image = Some(flag.clone()),
+                Some(_) => options.guest_args.push(flag.clone()),
+            }
+            continue;
+        }
+        match flag.as_str() {
+            "--hle" => options.hle = true,
+            "--headless" => options.headless = true,
+            "--dump-regs-on-exit" => options.dump_regs = true,
+            _ => {
+                let value = args.next()?;
+                match flag.as_str() {
+                    "--bios" => options.bios = Some(value.clone()),
+                    "--map" => options.map = Some(value.clone()),
+                    "--console" => options.console = Some(value.clone()),
+                    "--host-dir" => options.host_dir = Some(value.clone()),
+                    "--max-instructions" => options.max_instructions = Some(value.parse().ok()?),
+                    "--gdb" if command == Command::Run => options.gdb_addr = Some(value.clone()),
+                    "--load-slot" => options.load_slot = Some(value.parse().ok()?),
+                    "--save-slot" if command == Command::Run => {
+                        options.save_slot = Some(value.parse().ok()?)
+                    }
+                    "--out" if command == Command::Trace => options.trace_out = Some(value.clone()),
+                    "--format" if command == Command::Trace => {
+                        options.trace_format = Some(match value.as_str() {
+                            "text" => TraceFormat::Text,
+                            "binary" => TraceFormat::Binary,
+                            "pcsx2" => TraceFormat::Pcsx2,
+                            _ => return None,
+                        })
+                    }
+                    "--range" if command == Command::Trace => {
+                        let (start, end) = value.split_once('-')?;
+                        options
+                            .trace_ranges
+                            .push((parse_addr(start)?, parse_addr(end)?));
+                    }
+                    _ => return None,
+                }
+            }
+        }
+    }
+    if command == Command::Bench {
+        options.max_instructions.get_or_insert(BENCH_INSTRUCTIONS);
+    }
+    Some(options)
+}
+
+// Save slots live in `states/` under the working directory
+fn slot_path(slot: u32) -> PathBuf {
+    PathBuf::from("states").join(format!("slot{}.state", slot))
+}
+
+fn save_slot(slot: u32, cpu: &Cpu, bus: &Ps2Bus) -> Result<(), String> {
+    let path = slot_path(slot);
+    let data = savestate::save(cpu, bus);
+    fs::create_dir_all("states")
+        .and_then(|()| fs::write(&path, data))
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    println!("saved slot {} to {}", slot, path.display());
+    Ok(())
+}
+
+fn load_slot(slot: u32, cpu: &mut Cpu, bus: &mut Ps2Bus) -> Result<(), String> {
+    let path = slot_path(slot);
+    let data = fs::read(&path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    savestate::load(&data, cpu, bus).map_err(|err| format!("{}: {}", path.display(), err))?;
+    println!("loaded slot {} from {}", slot, path.display());
+    Ok(())
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("{}: {}", path, err))
+}
+
+// Loads the ELF, directly or from a disc image, and returns its symbols
+fn boot_image(
+    path: &str,
+    options: &Options,
+    cpu: &mut Cpu,
+    bus: &mut Ps2Bus,
+) -> Result<SymbolTable, String> {
+    let data = read(path)?;
+    let (argv0, elf_data) = if iso::is_iso(&data) {
+        iso::boot_elf(&data).map_err(|err| format!("{}: {}", path, err))?
+    } else {
+        let name = Path::new(path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        (format!("host:{}", name), data.as_slice())
+    };
+    let args: Vec<&str> = std::iter::once(argv0.as_str())
+        .chain(options.guest_args.iter().map(String::as_str))
+        .collect();
+    elf::boot(elf_data, &args, cpu, bus).map_err(|err| format!("{}: {}", path, err))?;
+    // A broken symbol table only costs the names, the program still runs
+    match Elf::parse(elf_data).and_then(|elf| SymbolTable::from_elf(&elf)) {
+        Ok(table) => Ok(table),
+        Err(err) => {
+            eprintln!("{}: ignoring symbols: {}", path, err);
+            Ok(SymbolTable::new())
+        }
+    }
+}
+
+fn setup(options: &Options) -> Result<(Cpu, Ps2Bus), String> {
+    let mut cpu = Cpu::new();
+    let mut bus = Ps2Bus::new();
+    match &options.console {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+            bus.set_console(Console::new(file));
+        }
+        None => bus.set_console(Console::stdout()),
+    }
+    if let Some(path) = &options.bios {
+        bus.load_bios(&read(path)?)
+            .map_err(|err| format!("{}: {}", path, err))?;
+        cpu.reset();
+    }
+    let mut symbols = SymbolTable::new();
+    // Booting an ELF skips the BIOS, which stays mapped if one was given
+    if let Some(path) = &options.image {
+        symbols.merge(boot_image(path, options, &mut cpu, &mut bus)?);
+    }
+    if let Some(path) = &options.map {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        symbols.merge(SymbolTable::parse_map(&text));
+    }
+    if !symbols.is_empty() {
+        cpu.set_symbols(Some(Arc::new(symbols)));
+    }
+    if options.bios.is_some() || options.image.is_some() {
+        cpu.add_observer(Box::new(CheckpointObserver::new(
+            |checkpoint, instructions| {
+                println!(
+                    "checkpoint: {} (after {} instructions)",
+                    checkpoint, instructions
+                )
+            },
+        )));
+    }
+    if options.hle {
+        let mut kernel = Kernel::new();
+        let host_dir = options.host_dir.clone().or_else(|| {
+            let image = Path::new(options.image.as_ref()?);
+            let dir = image.parent().filter(|dir| !dir.as_os_str().is_empty());
+            Some(dir.unwrap_or(Path::new(".")).to_string_lossy().into_owned())
+        });
+        if let Some(dir) = host_dir {
+            let host_fs = HostFs::new(&dir).map_err(|err| format!("{}: {}", dir, err))?;
+            kernel.set_host_fs(host_fs);
+        }
+        cpu.set_syscall_handler(Box::new(kernel));
+    }
+    if let Some(slot) = options.load_slot {
+        load_slot(slot, &mut cpu, &mut bus)?;
+    }
+    cpu.add_observer(Box::new(FaultReporter));
+    Ok((cpu, bus))
+}
+
+fn tracer(options: &Options, cpu: &Cpu) -> Result<Tracer, String> {
+    let format = options.trace_format.unwrap_or(TraceFormat::Text);
+    let mut tracer = match &options.trace_out {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+            Tracer::new(BufWriter::new(file), format)
+        }
+        None => Tracer::new(BufWriter::new(io::stdout()), format),
+    };
+    for &(start, end) in &options.trace_ranges {
+        tracer.add_range(start..end);
+    }
+    if let Some(symbols) = cpu.symbols() {
+        tracer.set_symbols(symbols.clone());
+    }
+    Ok(tracer)
+}
+
+// Remembers the first fault the guest takes
+struct FaultWatch(Rc<Cell<Option<(Exception, u32)>>>);
+
+impl CpuObserver for FaultWatch {
+    fn exception_taken(&mut self, cpu: &Cpu, exception: Exception) {
+        if callstack::is_fault(exception) && self.0.get().is_none() {
+            self.0.set(Some((exception, cpu.cop0().read(cop0::EPC))));
+        }
+    }
+}
+
+enum Outcome {
+    Exited(i32),
+    Fault(Exception, u32),
+    Timeout,
+    // GDB killed the target or hung up
+    Killed,
+    Panicked,
+}
+
+// Runs until something ends the run; returns how and the number of instructions executed
+fn execute(
+    options: &Options,
+    cpu: &mut Cpu,
+    bus: &mut Ps2Bus,
+    fault: &Cell<Option<(Exception, u32)>>,
+) -> Result<(Outcome, u64), String> {
+    if let Some(addr) = &options.gdb_addr {
+        println!("waiting for gdb on {}", addr);
+        let end = GdbStub::listen(addr.as_str())
+            .and_then(|mut stub| stub.run(cpu, bus))
+            .map_err(|err| format!("gdb: {}", err))?;
+        match end {
+            // A detached guest keeps running, and ends the way any other run does
+            SessionEnd::Detached => {}
+            SessionEnd::Killed => return Ok((Outcome::Killed, 0)),
+            SessionEnd::Exited(status) => return Ok((Outcome::Exited(status), 0)),
+        }
+    }
+    let mut count = 0;
+    while options.max_instructions.is_none_or(|limit| count < limit) {
+        let result = cpu.step_timed(bus);
+        count += 1;
+        if let StepResult::Exited(status) = result {
+            return Ok((Outcome::Exited(status), count));
+        }
+        if let Some((exception, epc)) = fault.get() {
+            return Ok((Outcome::Fault(exception, epc), count));
+        }
+    }
+    Ok((Outcome::Timeout, count))
+}
+
+fn dump_regs(cpu: &Cpu) {
+    println!("pc   {}", cpu.describe_addr(cpu.pc()));
+    for (index, names) in GPR_NAMES.chunks(2).enumerate() {
+        println!(
+            "{:<4} {:032x}  {:<4} {:032x}",
+            names[0],
+            cpu.gpr(2 * index),
+            names[1],
+            cpu.gpr(2 * index + 1)
+        );
+    }
+    println!(
+        "hi   {:016x}  lo   {:016x}  sa {:x}",
+        cpu.hi(),
+        cpu.lo(),
+        cpu.sa()
+    );
+    println!("cycles {}", cpu.cycles());
+}
+
+pub fn run(args: &[String], command: Command) -> Option<ExitCode> {
+    let options = parse_options(args, command)?;
+    let fail = |err: String| {
+        eprintln!("{}", err);
+        Some(ExitCode::from(EXIT_SETUP))
+    };
+    let (mut cpu, mut bus) = match setup(&options) {
+        Ok(machine) => machine,
+        Err(err) => return fail(err),
+    };
+    let fault = Rc::new(Cell::new(None));
+    if options.headless {
+        cpu.add_observer(Box::new(FaultWatch(fault.clone())));
+    }
+    if command == Command::Trace {
+        match tracer(&options, &cpu) {
+            Ok(tracer) => cpu.set_tracer(tracer),
+            Err(err) => return fail(err),
+        }
+    }
+
+    let start = Instant::now();
+    // On a panic in the core, show how the guest got there
+    let run = panic::catch_unwind(AssertUnwindSafe(|| {
+        execute(&options, &mut cpu, &mut bus, &fault)
+    }));
+    let elapsed = start.elapsed();
+    // The machine state is saved wherever execution stopped: the guest exiting or faulting,
+    // --max-instructions running out, or the gdb session ending, with or without an error. A
+    // panic can leave an instruction half done, so nothing is saved then.
+    if run.is_ok()
+        && let Some(slot) = options.save_slot
+        && let Err(err) = save_slot(slot, &cpu, &bus)
+    {
+        return fail(err);
+    }
+    let (outcome, count) = match run {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => return fail(err),
+        Err(_) => {
+            eprint!("guest backtrace:\n{}", cpu.backtrace());
+            (Outcome::Panicked, 0)
+        }
+    };
+
+    let code = match outcome {
+        Outcome::Exited(status) => {
+            println!("guest exited with status {}", status);
+            ExitCode::from(guest_exit_code(status))
+        }
+        Outcome::Fault(exception, epc) => {
+            println!("guest fault: {:?} at {}", exception, cpu.describe_addr(epc));
+            ExitCode::from(EXIT_FAULT)
+        }
+        Outcome::Timeout if command == Command::Bench => ExitCode::SUCCESS,
+        Outcome::Timeout => {
+            println!("stopped after {} instructions", count);
+            ExitCode::from(EXIT_TIMEOUT)
+        }
+        Outcome::Killed => ExitCode::SUCCESS,
+        Outcome::Panicked => ExitCode::from(EXIT_PANIC),
+    };
+    if command == Command::Bench {
+        let seconds = elapsed.as_secs_f64();
+        println!(
+            "{} instructions in {:.3}s, {:.2} MIPS",
+            count,
+            seconds,
+            count as f64 / seconds / 1e6
+        );
+    }
+    if options.dump_regs {
+        dump_regs(&cpu);
+    }
+    if let Some(tracer) = cpu.take_tracer()
+        && let Err(err) = tracer.finish()
+    {
+        return fail(format!("trace: {}", err));
+    }
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    // A BIOS image of the given instructions, written under the temp directory
+    fn bios(name: &str, code: &[u32]) -> String {
+        let path =
+            std::env::temp_dir().join(format!("front-test-{}-{}.bin", name, std::process::id()));
+        let image: Vec<u8> = code.iter().flat_map(|word| word.to_le_bytes()).collect();
+        fs::write(&path, image).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn options_and_the_image_arguments_are_parsed() {
+        let args = strings(&[
+            "--bios",
+            "rom.bin",
+            "--hle",
+            "--max-instructions",
+            "1000",
+            "game.elf",
+            "--headless",
+            "a",
+            "b",
+        ]);
+        let options = parse_options(&args, Command::Run).unwrap();
+        assert_eq!(options.bios.as_deref(), Some("rom.bin"));
+        assert_eq!(options.image.as_deref(), Some("game.elf"));
+        assert_eq!(options.guest_args, ["a", "b"]);
+        assert_eq!(options.max_instructions, Some(1000));
+        assert!(options.hle && options.headless && !options.dump_regs);
+
+        let args = strings(&[
+            "--format",
+            "pcsx2",
+            "--range",
+            "0x100-200",
+            "--out",
+            "t.log",
+        ]);
+        let options = parse_options(&args, Command::Trace).unwrap();
+        assert_eq!(options.trace_format, Some(TraceFormat::Pcsx2));
+        assert_eq!(options.trace_ranges, [(0x100, 0x200)]);
+        assert_eq!(options.trace_out.as_deref(), Some("t.log"));
+
+        let options = parse_options(&[], Command::Bench).unwrap();
+        assert_eq!(options.max_instructions, Some(BENCH_INSTRUCTIONS));
+    }
+
+    #[test]
+    fn bad_options_are_rejected() {
+        for (args, command) in [
+            (&["--bogus"][..], Command::Run),
+            (&["--bios"], Command::Run),
+            (&["--max-instructions", "lots"], Command::Run),
+            (&["--format", "xml"], Command::Trace),
+            (&["--range", "100"], Command::Trace),
+            // Flags belonging to other subcommands
+            (&["--out", "t.log"], Command::Run),
+            (&["--gdb", "localhost:1234"], Command::Trace),
+            (&["--save-slot", "1"], Command::Bench),
+        ] {
+            assert!(
+                parse_options(&strings(args), command).is_none(),
+                "{:?}",
+                args
+            );
+            assert_eq!(run(&strings(args), command), None, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn exit_codes_say_how_the_run_ended() {
+        let missing = strings(&["--bios", "/nonexistent/rom.bin"]);
+        assert_eq!(
+            run(&missing, Command::Run),
+            Some(ExitCode::from(EXIT_SETUP))
+        );
+
+        // b . / nop
+        let spin = bios("spin", &[0x1000_FFFF, 0]);
+        let args = strings(&["--bios", &spin, "--max-instructions", "100"]);
+        assert_eq!(run(&args, Command::Run), Some(ExitCode::from(EXIT_TIMEOUT)));
+        assert_eq!(run(&args, Command::Bench), Some(ExitCode::SUCCESS));
+
+        // lw t0, 1(zero), a misaligned load
+        let fault = bios("fault", &[0x8C08_0001]);
+        let args = strings(&["--bios", &fault, "--headless", "--max-instructions", "100"]);
+        assert_eq!(run(&args, Command::Run), Some(ExitCode::from(EXIT_FAULT)));
+        // Without --headless the guest's handler gets the fault and the run goes on
+        let args = strings(&["--bios", &fault, "--max-instructions", "100"]);
+        assert_eq!(run(&args, Command::Run), Some(ExitCode::from(EXIT_TIMEOUT)));
+
+        fs::remove_file(spin).unwrap();
+        fs::remove_file(fault).unwrap();
+    }
+
+    #[test]
+    fn guest_statuses_never_read_as_emulator_codes() {
+        assert_eq!(guest_exit_code(0), 0);
+        assert_eq!(guest_exit_code(3), 3);
+        assert_eq!(guest_exit_code(255), 255);
+        for status in [1, 2, 123, 124, 125, 126, 256, -1] {
+            assert_eq!(guest_exit_code(status), EXIT_GUEST_FAILED, "{}", status);
+        }
+    }
+}